@@ -56,11 +56,12 @@ pub fn copy_bidirectional(
         let mut a = SocketFunctionBuf::new(a_tcp.deref_mut(), a_fn);
         let mut b = SocketFunctionBuf::new(b_tcp.deref_mut(), b_fn);
 
-        socksx::copy_bidirectional(&mut a, &mut b)
+        let stats = socksx::copy_bidirectional(&mut a, &mut b)
             .await
-            .map_err(|_| PyOSError::new_err("TODO: custom errors"))?;
+            .map_err(|error| PyOSError::new_err(error.to_string()))?;
 
+        // The bytes relayed from `a` to `b`, and from `b` to `a`.
         let gil = Python::acquire_gil();
-        Ok(gil.python().None())
+        Ok((stats.upstream, stats.downstream).into_py(gil.python()))
     })
 }
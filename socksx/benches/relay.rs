@@ -0,0 +1,106 @@
+//! Compares the throughput of relaying with `tokio::io::copy_bidirectional` and `socksx::relay`, through userspace
+//! buffers, with that of `socksx::splice`, which moves the data between the sockets in the kernel.
+//!
+//! Run with `cargo bench --bench relay`. `RELAY_BENCH_SIZE` sets the MiB relayed per run (default: 1024).
+
+use std::env;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+use socksx::relay::Relay;
+
+/// The number of runs per relay, of which the fastest is reported.
+const RUNS: usize = 5;
+
+const MIB: usize = 1 << 20;
+
+/// Returns both ends of a TCP connection over the loopback interface.
+async fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+
+    (client, server)
+}
+
+/// Sends `size` MiB from a client, through `relay`, to a server that discards it, and returns how long it took.
+async fn run<F, R>(
+    size: usize,
+    relay: F,
+) -> Duration
+where
+    F: FnOnce(TcpStream, TcpStream) -> R,
+    R: Future<Output = (u64, u64)> + Send + 'static,
+{
+    let (mut client, a) = socket_pair().await;
+    let (b, mut server) = socket_pair().await;
+    let relay = tokio::spawn(relay(a, b));
+
+    let started = Instant::now();
+    let send = tokio::spawn(async move {
+        let chunk = vec![0x5a; MIB];
+        for _ in 0..size {
+            client.write_all(&chunk).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+        client
+    });
+
+    let mut buffer = vec![0; MIB];
+    let mut received = 0;
+    loop {
+        match server.read(&mut buffer).await.unwrap() {
+            0 => break,
+            read => received += read,
+        }
+    }
+    let elapsed = started.elapsed();
+
+    assert_eq!(received, size * MIB);
+    drop((server, send.await.unwrap()));
+    assert_eq!(relay.await.unwrap().0, (size * MIB) as u64);
+
+    elapsed
+}
+
+/// Runs `relay` several times, and prints the throughput of the fastest run.
+fn bench<F, R>(
+    runtime: &Runtime,
+    name: &str,
+    size: usize,
+    relay: F,
+) where
+    F: Fn(TcpStream, TcpStream) -> R,
+    R: Future<Output = (u64, u64)> + Send + 'static,
+{
+    let fastest = (0..RUNS).map(|_| runtime.block_on(run(size, &relay))).min().unwrap();
+    let throughput = size as f64 / fastest.as_secs_f64();
+
+    println!("{:<20} {:>10.0} MiB/s ({} MiB in {:?})", name, throughput, size, fastest);
+}
+
+fn main() {
+    let size = env::var("RELAY_BENCH_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(1024);
+    let runtime = Runtime::new().unwrap();
+
+    bench(&runtime, "copy_bidirectional", size, |mut a, mut b| async move {
+        tokio::io::copy_bidirectional(&mut a, &mut b).await.unwrap()
+    });
+
+    for buffer_size in [8 * 1024, 64 * 1024] {
+        let name = format!("relay ({} KiB)", buffer_size / 1024);
+        bench(&runtime, &name, size, |mut a, mut b| async move {
+            let stats = Relay::new().with_buffer_size(buffer_size).run(&mut a, &mut b).await.unwrap();
+            (stats.upstream, stats.downstream)
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    bench(&runtime, "splice", size, |mut a, mut b| async move {
+        socksx::splice::copy_bidirectional(&mut a, &mut b, |_, _| {}).await.unwrap()
+    });
+}
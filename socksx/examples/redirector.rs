@@ -1,11 +1,15 @@
 /// This is a simple redirector that redirects all incoming TCP connections through a SOCKS proxy to
 /// a different destination. This is useful for redirecting traffic from a specific application
 /// through a proxy.
+///
+/// The same functionality, with more options, is available as `socksx redirect`.
+use std::sync::Arc;
+
 use anyhow::Result;
 use clap::Parser;
-use tokio::net::{TcpListener, TcpStream};
 
-use socksx::{self, Socks5Client, Socks6Client};
+use socksx::redirect::{RedirectMode, Redirector};
+use socksx::ProxyAddress;
 
 
 /***** ARGUMENTS *****/
@@ -29,62 +33,16 @@ struct Arguments {
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Arguments::parse();
-    let proxy_addr = format!("{}:{}", args.proxy_host, args.proxy_port);
-
-    let listener = TcpListener::bind("127.0.0.1:42000").await?;
-    // Determine the appropriate SOCKS handler based on the specified version and restricting them to 5 and 6
-    match args.version {
-        5 => {
-            let client = Socks5Client::new(proxy_addr, None).await?;
-
-            loop {
-                let (stream, _) = listener.accept().await?;
-                tokio::spawn(redirect_v5(stream, client.clone()));
-            }
-        }
-        6 => {
-            let client = Socks6Client::new(proxy_addr, None).await?;
-
-            loop {
-                let (stream, _) = listener.accept().await?;
-                tokio::spawn(redirect_v6(stream, client.clone()));
-            }
-        }
-        version => { eprintln!("ERROR: Unsupported SOCKS-version '{version}' (supported: `5`, `6`)"); std::process::exit(1); },
-    };
-}
-
-/// Redirect an incoming TCP stream through a SOCKS5
-/// proxy. The original destination of the stream has
-/// been preserved, by iptables, as an socket option.
-async fn redirect_v5(
-    incoming: TcpStream,
-    client: Socks5Client,
-) -> Result<()> {
-    let mut incoming = incoming;
-
-    let dst_addr = socksx::get_original_dst(&incoming)?.to_string();
-    let (mut outgoing, _) = client.connect(dst_addr).await?;
-
-    socksx::copy_bidirectional(&mut incoming, &mut outgoing).await?;
-
-    Ok(())
-}
-
-/// Redirect an incoming TCP stream through a SOCKS6
-/// proxy. The original destination of the stream has
-/// been preserved, by iptables, as an socket option.
-async fn redirect_v6(
-    incoming: TcpStream,
-    client: Socks6Client,
-) -> Result<()> {
-    let mut incoming = incoming;
+    let upstream = ProxyAddress::new(args.version, args.proxy_host, args.proxy_port, None);
 
-    let dst_addr = socksx::get_original_dst(&incoming)?.to_string();
-    let initial_data = socksx::try_read_initial_data(&mut incoming).await?;
-    let (mut outgoing, _) = client.connect(dst_addr, initial_data, None).await?;
+    let redirector = Arc::new(Redirector::new(upstream, vec![], RedirectMode::Nat).await?);
+    let listener = redirector.bind("127.0.0.1:42000".parse()?)?;
 
-    socksx::copy_bidirectional(&mut incoming, &mut outgoing).await?;
+    loop {
+        let (incoming, _) = listener.accept().await?;
+        let redirector = Arc::clone(&redirector);
 
-    Ok(())
+        // The original destination of the stream has been preserved, by iptables, as a socket option.
+        tokio::spawn(async move { redirector.redirect(incoming).await });
+    }
 }
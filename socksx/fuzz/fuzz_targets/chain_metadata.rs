@@ -0,0 +1,43 @@
+//! Fuzzes the reconstruction of a proxy chain from the metadata of a SOCKS6 request.
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use socksx::codec::Decode;
+use socksx::socks6::options::SocksOption;
+use socksx::socks6::Socks6Request;
+use socksx::ProxyAddress;
+
+fuzz_target!(|data: &[u8]| {
+    let request = match Socks6Request::decode(&mut BytesMut::from(data)) {
+        Ok(Some(request)) => request,
+        _ => return,
+    };
+
+    // Without static links, a valid chain is re-encoded into metadata and must produce the same chain again.
+    if let Ok(Some(chain)) = request.chain(&[]) {
+        let mut again = request.clone();
+        again.metadata = chain
+            .as_options()
+            .into_iter()
+            .filter_map(|option| match option {
+                SocksOption::Metadata(option) => Some((option.key, option.value)),
+                _ => None,
+            })
+            .collect();
+
+        let again = again.chain(&[]).expect("re-encoded chain must decode").unwrap();
+        assert_eq!(again.index, chain.index);
+        assert_eq!(
+            again.links.iter().map(|l| l.to_string()).collect::<Vec<_>>(),
+            chain.links.iter().map(|l| l.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    // With static links, the chain is spliced at the current index, which must never panic.
+    let detour = [ProxyAddress::new(6, String::from("127.0.0.1"), 1080, None)];
+    if let Ok(Some(mut chain)) = request.chain(&detour) {
+        while chain.next_link().is_some() {}
+        let _ = chain.as_options();
+    }
+});
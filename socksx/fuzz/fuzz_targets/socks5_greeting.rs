@@ -0,0 +1,24 @@
+//! Fuzzes decoding of the SOCKS5 method selection and username/password authentication messages.
+#![no_main]
+
+use std::fmt::Debug;
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use socksx::codec::{Decode, Encode};
+use socksx::socks5::{MethodSelectionReply, MethodSelectionRequest, PasswordAuthReply, PasswordAuthRequest};
+
+fuzz_target!(|data: &[u8]| {
+    round_trip::<MethodSelectionRequest>(data);
+    round_trip::<MethodSelectionReply>(data);
+    round_trip::<PasswordAuthRequest>(data);
+    round_trip::<PasswordAuthReply>(data);
+});
+
+/// Decodes a message from `data` and, if that succeeds, checks that re-encoding and decoding it yields the same message.
+fn round_trip<T: Decode + Encode + Debug + PartialEq>(data: &[u8]) {
+    if let Ok(Some(message)) = T::decode(&mut BytesMut::from(data)) {
+        let decoded = T::decode(&mut message.to_bytes());
+        assert_eq!(decoded, Ok(Some(message)));
+    }
+}
@@ -0,0 +1,23 @@
+//! Fuzzes decoding of SOCKS5 requests, operation replies, and UDP headers.
+#![no_main]
+
+use std::fmt::Debug;
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use socksx::codec::{Decode, Encode};
+use socksx::socks5::{Socks5OperationReply, Socks5Request, UdpHeader};
+
+fuzz_target!(|data: &[u8]| {
+    round_trip::<Socks5Request>(data);
+    round_trip::<Socks5OperationReply>(data);
+    round_trip::<UdpHeader>(data);
+});
+
+/// Decodes a message from `data` and, if that succeeds, checks that re-encoding and decoding it yields the same message.
+fn round_trip<T: Decode + Encode + Debug + PartialEq>(data: &[u8]) {
+    if let Ok(Some(message)) = T::decode(&mut BytesMut::from(data)) {
+        let decoded = T::decode(&mut message.to_bytes());
+        assert_eq!(decoded, Ok(Some(message)));
+    }
+}
@@ -0,0 +1,21 @@
+//! Fuzzes decoding of a SOCKS6 options section and of the individual option constructors.
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use socksx::codec::{Decode, Encode};
+use socksx::socks6::options::{
+    AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption,
+};
+
+fuzz_target!(|data: &[u8]| {
+    // The constructors receive the option data without the kind and length fields.
+    let _ = AuthMethodAdvertisementOption::from_socks_bytes(data.to_vec());
+    let _ = AuthMethodSelectionOption::from_socks_bytes(data.to_vec());
+    let _ = MetadataOption::from_socks_bytes(data.to_vec());
+
+    if let Ok(Some(options)) = Vec::<SocksOption>::decode(&mut BytesMut::from(data)) {
+        let decoded = Vec::<SocksOption>::decode(&mut options.to_bytes());
+        assert_eq!(decoded, Ok(Some(options)));
+    }
+});
@@ -0,0 +1,452 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::metrics::FailureReason;
+
+/// The size up to which a `FileLogger` grows a file before rotating it, unless configured otherwise.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+/// The number of rotated files that a `FileLogger` keeps, unless configured otherwise.
+pub const DEFAULT_KEEP_FILES: usize = 5;
+
+/// The record of a single session, from accepting the connection until closing it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AccessRecord {
+    /// When the connection was accepted, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The address of the client.
+    pub client: Option<SocketAddr>,
+    /// The user that the client authenticated as, if any.
+    pub user: Option<String>,
+    /// The protocol of the handler, e.g., `socks5`.
+    pub protocol: String,
+    /// The command of the request, e.g., `Connect`.
+    pub command: Option<String>,
+    /// The destination that the client requested.
+    pub destination: Option<String>,
+    /// The address that the proxy connected to: the resolved destination, or the next proxy of the chain.
+    pub resolved: Option<SocketAddr>,
+    /// The addresses of the links of the chain of the request, if it is part of one.
+    pub chain: Vec<String>,
+    /// The position of the proxy in `chain`.
+    pub chain_index: Option<usize>,
+    /// The last reply sent to the client, e.g., `Success`.
+    pub reply: Option<String>,
+    /// The number of bytes relayed from the client to the destination.
+    pub upstream: u64,
+    /// The number of bytes relayed from the destination to the client.
+    pub downstream: u64,
+    /// How long the session lasted, in milliseconds.
+    pub duration_ms: u64,
+    pub close_reason: CloseReason,
+}
+
+impl AccessRecord {
+    /// Creates the record of a session of the given protocol, accepted from `client` just now.
+    pub fn new(
+        protocol: &str,
+        client: Option<SocketAddr>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+
+        Self {
+            timestamp,
+            client,
+            user: None,
+            protocol: protocol.to_string(),
+            command: None,
+            destination: None,
+            resolved: None,
+            chain: vec![],
+            chain_index: None,
+            reply: None,
+            upstream: 0,
+            downstream: 0,
+            duration_ms: 0,
+            close_reason: CloseReason::Completed,
+        }
+    }
+}
+
+/// Why a session was closed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Both sides finished, after a successful handshake.
+    Completed,
+    /// The connection was refused, because the connection limit was reached.
+    Refused,
+    /// The handshake failed, as the client could not, or did not, authenticate.
+    Authentication,
+    /// The request was refused, as the client, or its user, exceeded a quota.
+    Quota,
+    /// The handshake failed, as the client sent a malformed or unsupported message.
+    Protocol,
+    /// The handshake failed, as the destination, or the next proxy, could not be reached.
+    Connect,
+    /// The handshake failed, as the client went away or its connection failed otherwise.
+    Io,
+    /// The handshake failed otherwise.
+    Other,
+    /// Relaying data failed, or a network function closed the connection.
+    Relay,
+    /// Neither side sent data for the idle timeout.
+    Idle,
+    /// The session was killed, e.g., from the admin API.
+    Killed,
+}
+
+impl From<FailureReason> for CloseReason {
+    fn from(reason: FailureReason) -> Self {
+        match reason {
+            FailureReason::Authentication => CloseReason::Authentication,
+            FailureReason::Quota => CloseReason::Quota,
+            FailureReason::Protocol => CloseReason::Protocol,
+            FailureReason::Connect => CloseReason::Connect,
+            FailureReason::Io => CloseReason::Io,
+            FailureReason::Other => CloseReason::Other,
+        }
+    }
+}
+
+/// Receives the record of every session, once it ends.
+///
+/// Handlers call loggers from their connection tasks, so implementations should not block for long.
+pub trait AccessLogger: Send + Sync {
+    fn log(
+        &self,
+        record: &AccessRecord,
+    );
+}
+
+/// Logs every record to all loggers.
+impl<L: AccessLogger> AccessLogger for Vec<L> {
+    fn log(
+        &self,
+        record: &AccessRecord,
+    ) {
+        for logger in self {
+            logger.log(record);
+        }
+    }
+}
+
+impl<L: AccessLogger + ?Sized> AccessLogger for Box<L> {
+    fn log(
+        &self,
+        record: &AccessRecord,
+    ) {
+        (**self).log(record);
+    }
+}
+
+/// Encodes a record as a line of JSON.
+fn to_json_line(record: &AccessRecord) -> Vec<u8> {
+    let mut line = serde_json::to_vec(record).expect("records are serializable");
+    line.push(b'\n');
+    line
+}
+
+/// Writes every record to a writer, as a line of JSON.
+pub struct JsonLinesLogger<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLinesLogger<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl JsonLinesLogger<io::Stdout> {
+    /// Writes every record to standard output.
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl<W: Write + Send> AccessLogger for JsonLinesLogger<W> {
+    fn log(
+        &self,
+        record: &AccessRecord,
+    ) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(error) = writer.write_all(&to_json_line(record)).and_then(|_| writer.flush()) {
+            warn!("Failed to write access record: {}", error);
+        }
+    }
+}
+
+/// Appends every record to a file, as a line of JSON, and rotates the file once it grows too large.
+///
+/// Rotated files get a numbered suffix: `access.log` becomes `access.log.1`, the previous `access.log.1` becomes
+/// `access.log.2`, and so on, up to the number of files to keep.
+pub struct FileLogger {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: Mutex<(File, u64)>,
+}
+
+impl FileLogger {
+    /// Opens (or creates) the file at `path`, rotating it at `DEFAULT_MAX_FILE_SIZE`.
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path)?;
+
+        Ok(Self {
+            path,
+            max_size: DEFAULT_MAX_FILE_SIZE,
+            keep: DEFAULT_KEEP_FILES,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Rotates the file once it would grow beyond `max_size` bytes.
+    pub fn with_max_size(
+        mut self,
+        max_size: u64,
+    ) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Keeps up to `keep` rotated files, deleting older ones. With zero, the file is truncated instead.
+    pub fn with_keep(
+        mut self,
+        keep: usize,
+    ) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Returns the path of the `index`th rotated file.
+    fn rotated(
+        &self,
+        index: usize,
+    ) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&self) -> io::Result<(File, u64)> {
+        if self.keep > 0 {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        open_append(&self.path)
+    }
+
+    fn write(
+        &self,
+        line: &[u8],
+    ) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+
+        let (_, size) = &*file;
+        if *size > 0 && size + line.len() as u64 > self.max_size {
+            *file = self.rotate()?;
+        }
+
+        let (file, size) = &mut *file;
+        file.write_all(line)?;
+        *size += line.len() as u64;
+
+        Ok(())
+    }
+}
+
+/// Opens a file to append to, together with its current size.
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+
+    Ok((file, size))
+}
+
+impl AccessLogger for FileLogger {
+    fn log(
+        &self,
+        record: &AccessRecord,
+    ) {
+        if let Err(error) = self.write(&to_json_line(record)) {
+            warn!("Failed to write access record to {:?}: {}", self.path, error);
+        }
+    }
+}
+
+/// Where a `SyslogLogger` sends its messages to.
+enum SyslogTarget {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+/// Sends every record to syslog, as a message ([rfc3164]) with the record as JSON.
+///
+/// Messages are sent with the `daemon` facility and the `info` severity. The syslog daemon adds the timestamp and
+/// hostname.
+///
+/// [rfc3164]: https://tools.ietf.org/html/rfc3164
+pub struct SyslogLogger {
+    target: SyslogTarget,
+    tag: String,
+}
+
+/// The priority of messages: the `daemon` facility (3), with the `info` severity (6).
+const SYSLOG_PRIORITY: u8 = 3 * 8 + 6;
+
+impl SyslogLogger {
+    /// Sends records to the local syslog daemon, e.g., at `/dev/log`.
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+
+        Ok(Self::with_target(SyslogTarget::Unix(socket)))
+    }
+
+    /// Sends records to a remote syslog daemon, over UDP.
+    pub fn udp(address: SocketAddr) -> io::Result<Self> {
+        let local: SocketAddr = if address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+
+        Ok(Self::with_target(SyslogTarget::Udp(socket)))
+    }
+
+    fn with_target(target: SyslogTarget) -> Self {
+        Self {
+            target,
+            tag: format!("socksx[{}]", std::process::id()),
+        }
+    }
+
+    fn message(
+        &self,
+        record: &AccessRecord,
+    ) -> Vec<u8> {
+        let mut message = format!("<{}>{}: ", SYSLOG_PRIORITY, self.tag).into_bytes();
+        message.extend(serde_json::to_vec(record).expect("records are serializable"));
+        message
+    }
+}
+
+impl AccessLogger for SyslogLogger {
+    fn log(
+        &self,
+        record: &AccessRecord,
+    ) {
+        let message = self.message(record);
+        let result = match &self.target {
+            #[cfg(unix)]
+            SyslogTarget::Unix(socket) => socket.send(&message),
+            SyslogTarget::Udp(socket) => socket.send(&message),
+        };
+
+        if let Err(error) = result {
+            warn!("Failed to send access record to syslog: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(user: &str) -> AccessRecord {
+        let mut record = AccessRecord::new("socks5", Some(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        record.user = Some(user.to_string());
+        record.destination = Some(String::from("example.com:443"));
+        record.upstream = 12;
+        record
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let logger = JsonLinesLogger::new(vec![]);
+        logger.log(&record("alice"));
+        logger.log(&record("bob"));
+
+        let output = String::from_utf8(logger.writer.into_inner().unwrap()).unwrap();
+        let records: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["user"], "alice");
+        assert_eq!(records[0]["client"], "127.0.0.1:40000");
+        assert_eq!(records[0]["protocol"], "socks5");
+        assert_eq!(records[0]["upstream"], 12);
+        assert_eq!(records[0]["close_reason"], "completed");
+        assert_eq!(records[1]["user"], "bob");
+    }
+
+    #[test]
+    fn test_file_rotation() {
+        let directory = std::env::temp_dir().join(format!("socksx-access-log-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("access.log");
+
+        let line = to_json_line(&record("alice"));
+        let logger = FileLogger::new(&path).unwrap().with_max_size(line.len() as u64 * 2).with_keep(2);
+        for _ in 0..7 {
+            logger.log(&record("alice"));
+        }
+
+        // Seven records, two per file: the oldest file was deleted.
+        let size = |path: PathBuf| fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+        assert_eq!(size(path.clone()), line.len() as u64);
+        assert_eq!(size(logger.rotated(1)), line.len() as u64 * 2);
+        assert_eq!(size(logger.rotated(2)), line.len() as u64 * 2);
+        assert!(!logger.rotated(3).exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_syslog() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let logger = SyslogLogger::udp(server.local_addr().unwrap()).unwrap();
+        logger.log(&record("alice"));
+
+        let mut datagram = [0u8; 1024];
+        let length = server.recv(&mut datagram).unwrap();
+        let message = std::str::from_utf8(&datagram[..length]).unwrap();
+
+        let prefix = format!("<30>socksx[{}]: ", std::process::id());
+        assert!(message.starts_with(&prefix));
+
+        let record: serde_json::Value = serde_json::from_str(&message[prefix.len()..]).unwrap();
+        assert_eq!(record["user"], "alice");
+    }
+
+    #[test]
+    fn test_close_reason() {
+        assert_eq!(CloseReason::from(FailureReason::Connect), CloseReason::Connect);
+        assert_eq!(serde_json::to_string(&CloseReason::Refused).unwrap(), "\"refused\"");
+    }
+}
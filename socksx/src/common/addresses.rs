@@ -1,11 +1,13 @@
 use std::convert::{TryFrom, TryInto};
-use std::net::{IpAddr, SocketAddr};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 
 use anyhow::Result;
-use tokio::io::{AsyncRead, AsyncReadExt};
-use url::Url;
+use percent_encoding::{percent_decode_str, percent_encode, NON_ALPHANUMERIC};
+use tokio::io::AsyncRead;
+use url::{Host, Url};
 
-use crate::{constants::*, Credentials};
+use crate::{codec, constants::*, Credentials};
 
 /// Represents a SOCKS proxy address.
 #[derive(Clone, Debug, PartialEq)]
@@ -44,10 +46,29 @@ impl ProxyAddress {
 }
 
 
-impl ToString for ProxyAddress {
-    // Converts the `ProxyAddress` to a string representation.
-    fn to_string(&self) -> String {
-        format!("socks{}://{}:{}", self.socks_version, self.host, self.port)
+impl fmt::Display for ProxyAddress {
+    // Formats the `ProxyAddress` as a string representation.
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "socks{}://", self.socks_version)?;
+
+        if let Some(credentials) = &self.credentials {
+            write!(
+                f,
+                "{}:{}@",
+                percent_encode(&credentials.username, NON_ALPHANUMERIC),
+                percent_encode(&credentials.password, NON_ALPHANUMERIC),
+            )?;
+        }
+
+        // IPv6 hosts are stored without brackets, but need them to separate the port.
+        if self.host.parse::<Ipv6Addr>().is_ok() {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
     }
 }
 
@@ -70,20 +91,22 @@ impl TryFrom<String> for ProxyAddress {
             scheme => bail!("Unrecognized SOCKS scheme: {}", scheme),
         };
 
+        // The URL parser leaves credentials percent-encoded.
         let username = proxy_addr.username();
         let credentials = if username.is_empty() {
             None
         } else {
-            let password = proxy_addr.password().unwrap_or_default();
+            let username: Vec<u8> = percent_decode_str(username).collect();
+            let password: Vec<u8> = percent_decode_str(proxy_addr.password().unwrap_or_default()).collect();
             Some(Credentials::new(username, password))
         };
 
-        Ok(Self::new(
-            socks_version,
-            proxy_addr.host().map(|h| h.to_string()).unwrap(),
-            proxy_addr.port().unwrap(),
-            credentials,
-        ))
+        let host = match proxy_addr.host().unwrap() {
+            Host::Ipv6(host) => host.to_string(),
+            host => host.to_string(),
+        };
+
+        Ok(Self::new(socks_version, host, proxy_addr.port().unwrap(), credentials))
     }
 }
 
@@ -112,6 +135,11 @@ impl Address {
         }
     }
 
+    /// Returns the unspecified IPv4 address (`0.0.0.0:0`), used in replies without a meaningful binding.
+    pub fn unspecified() -> Self {
+        Address::Ip(SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+
     /// Converts the `Address` into a byte sequence compatible with the SOCKS protocol.
     pub fn as_socks_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
@@ -146,12 +174,15 @@ impl Address {
     }
 }
 
-impl ToString for Address {
-    // Converts the `Address` to a string representation.
-    fn to_string(&self) -> String {
+impl fmt::Display for Address {
+    // Formats the `Address` as a string representation.
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
         match self {
-            Address::Domainname { host, port } => format!("{}:{}", host, port),
-            Address::Ip(socket_addr) => socket_addr.to_string(),
+            Address::Domainname { host, port } => write!(f, "{}:{}", host, port),
+            Address::Ip(socket_addr) => write!(f, "{}", socket_addr),
         }
     }
 }
@@ -170,7 +201,9 @@ impl TryFrom<String> for Address {
     type Error = anyhow::Error;
 
     fn try_from(addr: String) -> Result<Self> {
-        if let Some((host, port)) = addr.split_once(':') {
+        // Split on the last ':', as IPv6 hosts contain colons themselves.
+        if let Some((host, port)) = addr.rsplit_once(':') {
+            let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
             Ok(Address::new(host, port.parse()?))
         } else {
             bail!("Address doesn't seperate host and port by ':'.")
@@ -183,7 +216,7 @@ impl TryFrom<&ProxyAddress> for Address {
     type Error = anyhow::Error;
 
     fn try_from(addr: &ProxyAddress) -> Result<Self> {
-        format!("{}:{}", addr.host, addr.port).try_into()
+        Ok(Address::new(addr.host.clone(), addr.port))
     }
 }
 
@@ -192,42 +225,7 @@ pub async fn read_address<S>(stream: &mut S) -> Result<Address>
 where
     S: AsyncRead + Unpin,
 {
-    // Read address type.
-    let mut address_type = [0; 1];
-    stream.read_exact(&mut address_type).await?;
-
-    let dst_addr = match address_type[0] {
-        SOCKS_ATYP_IPV4 => {
-            let mut dst_addr = [0; 4];
-            stream.read_exact(&mut dst_addr).await?;
-
-            IpAddr::from(dst_addr).to_string()
-        }
-        SOCKS_ATYP_IPV6 => {
-            let mut dst_addr = [0; 16];
-            stream.read_exact(&mut dst_addr).await?;
-
-            IpAddr::from(dst_addr).to_string()
-        }
-        SOCKS_ATYP_DOMAINNAME => {
-            let mut length = [0; 1];
-            stream.read_exact(&mut length).await?;
-
-            let mut dst_addr = vec![0; length[0] as usize];
-            stream.read_exact(&mut dst_addr).await?;
-
-            String::from_utf8_lossy(&dst_addr[..]).to_string()
-        }
-        _ => unreachable!(),
-    };
-
-    // Read destination port.
-    let mut dst_port = [0; 2];
-    stream.read_exact(&mut dst_port).await?;
-
-    let dst_port = ((dst_port[0] as u16) << 8) | dst_port[1] as u16;
-
-    Ok(Address::new(dst_addr, dst_port))
+    codec::read_message(stream).await
 }
 
 #[cfg(test)]
@@ -235,8 +233,13 @@ mod tests {
     use std::net::SocketAddr;
 
     use anyhow::Result;
+    use bytes::BytesMut;
+    use proptest::prelude::*;
 
     use super::*;
+    use crate::arbitrary;
+    use crate::codec::{Decode, Encode};
+    use crate::ProtocolError;
 
     #[test]
     fn test_proxy_address_new() {
@@ -346,5 +349,65 @@ mod tests {
         Ok(())
     }
 
-    // TODO: Add tests for `read_address` function once we have a way to mock the `AsyncRead`.
+    #[tokio::test]
+    async fn test_read_address_unknown_type() {
+        let mut bytes: &[u8] = &[0x07, 127, 0, 0, 1, 0, 80];
+        let result = read_address(&mut bytes).await;
+
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::UnsupportedAddressType(0x07))
+        );
+    }
+
+    #[test]
+    fn test_address_try_from_ipv6_string() -> Result<()> {
+        let address: Address = String::from("[::1]:1080").try_into()?;
+        assert_eq!(address, Address::new("::1", 1080));
+        Ok(())
+    }
+
+    #[test]
+    fn test_proxy_address_ipv6_with_credentials() -> Result<()> {
+        let proxy_address: ProxyAddress = String::from("socks6://us%3Aer:p%40ss@[::1]:1080").try_into()?;
+        assert_eq!(proxy_address.host, "::1");
+        assert_eq!(proxy_address.credentials, Some(Credentials::new("us:er", "p@ss")));
+        assert_eq!(Address::try_from(&proxy_address)?.to_string(), "[::1]:1080");
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn prop_address_socks_bytes_round_trip(address in arbitrary::address()) {
+            let mut bytes = BytesMut::from(&address.as_socks_bytes()[..]);
+            prop_assert_eq!(Address::decode(&mut bytes), Ok(Some(address)));
+            prop_assert!(bytes.is_empty());
+        }
+
+        #[test]
+        fn prop_address_string_round_trip(address in arbitrary::socket_address().prop_map(Address::Ip)) {
+            prop_assert_eq!(Address::try_from(address.to_string()).unwrap(), address);
+        }
+
+        #[test]
+        fn prop_domain_address_string_round_trip(host in arbitrary::host_name(), port in any::<u16>()) {
+            let address = Address::new(host, port);
+            prop_assert_eq!(Address::try_from(address.to_string()).unwrap(), address);
+        }
+
+        #[test]
+        fn prop_proxy_address_round_trip(proxy_address in arbitrary::proxy_address()) {
+            prop_assert_eq!(ProxyAddress::try_from(proxy_address.to_string()).unwrap(), proxy_address);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_address_max_length_domain() {
+        let address = Address::new("a".repeat(255), 443);
+        let bytes = address.to_bytes();
+        let result = read_address(&mut &bytes[..]).await.unwrap();
+
+        assert_eq!(result, address);
+    }
 }
@@ -0,0 +1,231 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::functions::counter::Counter;
+use crate::http;
+use crate::sessions::SessionRegistry;
+
+/// Reloads the configuration of the proxy, on request of the admin API.
+type Reload = dyn Fn() -> Result<()> + Send + Sync;
+
+/// The state behind the admin API.
+///
+/// The API has no authentication of its own, so it should only be served on a trusted address, e.g., `127.0.0.1`.
+#[derive(Clone)]
+pub struct Admin {
+    sessions: SessionRegistry,
+    config: Value,
+    counter: Option<Counter>,
+    reload: Option<Arc<Reload>>,
+    started: Instant,
+}
+
+impl Admin {
+    /// Creates a new `Admin`, for the sessions of the given registry.
+    pub fn new(sessions: SessionRegistry) -> Self {
+        Self {
+            sessions,
+            config: json!({}),
+            counter: None,
+            reload: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Shows the given configuration at `/config`. It should not contain secrets.
+    pub fn with_config(
+        mut self,
+        config: Value,
+    ) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Shows the running totals of `counter` at `/counters`.
+    pub fn with_counter(
+        mut self,
+        counter: Counter,
+    ) -> Self {
+        self.counter = Some(counter);
+        self
+    }
+
+    /// Calls `reload` on `POST /reload`.
+    pub fn with_reload<F>(
+        mut self,
+        reload: F,
+    ) -> Self
+    where
+        F: Fn() -> Result<()> + Send + Sync + 'static,
+    {
+        self.reload = Some(Arc::new(reload));
+        self
+    }
+
+    /// Handles a request, and returns the status and body of the response.
+    fn handle(
+        &self,
+        method: &str,
+        path: &str,
+    ) -> (&'static str, Value) {
+        let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+
+        match (method, segments.as_slice()) {
+            ("GET", ["health"]) => {
+                let health = json!({
+                    "status": "ok",
+                    "uptime_s": self.started.elapsed().as_secs(),
+                    "sessions": self.sessions.len(),
+                });
+                ("200 OK", health)
+            }
+            ("GET", ["config"]) => ("200 OK", self.config.clone()),
+            ("GET", ["sessions"]) => ("200 OK", json!(self.sessions.list())),
+            ("GET", ["counters"]) => match &self.counter {
+                Some(counter) => {
+                    let totals: Vec<_> = counter
+                        .totals()
+                        .into_iter()
+                        .map(|(key, totals)| json!({ "key": key, "totals": totals }))
+                        .collect();
+                    ("200 OK", json!({ "total": counter.total(), "totals": totals }))
+                }
+                None => ("404 Not Found", json!({ "error": "The `counter` function is not enabled." })),
+            },
+            ("DELETE", ["sessions", id]) => match id.parse() {
+                Ok(id) if self.sessions.kill(id) => ("202 Accepted", json!({ "killed": id })),
+                Ok(id) => ("404 Not Found", json!({ "error": format!("No active session with ID {}.", id) })),
+                Err(_) => ("400 Bad Request", json!({ "error": format!("Invalid session ID: {:?}", id) })),
+            },
+            ("POST", ["reload"]) => match &self.reload {
+                Some(reload) => match reload() {
+                    Ok(()) => ("200 OK", json!({ "status": "reloaded" })),
+                    Err(error) => ("500 Internal Server Error", json!({ "error": format!("{:#}", error) })),
+                },
+                None => ("501 Not Implemented", json!({ "error": "Reloading is not supported." })),
+            },
+            (_, ["health"])
+            | (_, ["config"])
+            | (_, ["sessions"])
+            | (_, ["sessions", _])
+            | (_, ["counters"])
+            | (_, ["reload"]) => {
+                ("405 Method Not Allowed", json!({ "error": format!("{} is not allowed on {}.", method, path) }))
+            }
+            _ => ("404 Not Found", json!({ "error": format!("Unknown endpoint: {}", path) })),
+        }
+    }
+}
+
+/// Serves the admin API on every connection accepted by `listener`:
+/// - `GET /health`: the status and uptime of the proxy, and the number of active sessions;
+/// - `GET /config`: the effective configuration;
+/// - `GET /sessions`: the active sessions;
+/// - `DELETE /sessions/<id>`: kills a session;
+/// - `GET /counters`: the running totals of the counter, if any;
+/// - `POST /reload`: reloads the configuration.
+///
+/// All responses are JSON.
+pub async fn serve(
+    listener: TcpListener,
+    admin: Admin,
+) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let admin = admin.clone();
+        tokio::spawn(async move {
+            if let Err(error) = respond(stream, &admin).await {
+                debug!("Failed to serve admin request: {}", error);
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    admin: &Admin,
+) -> Result<()> {
+    let request = http::read_request(&mut stream).await?;
+
+    let (status, body) = admin.handle(&request.method, &request.path);
+    info!("Admin request: {} {} ({})", request.method, request.path, status);
+
+    http::write_response(&mut stream, status, "application/json", body.to_string().as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let registry = SessionRegistry::new();
+        let admin = Admin::new(registry.clone());
+        let session = registry.register("socks5", None);
+
+        let (status, body) = admin.handle("GET", "/sessions");
+        assert_eq!(status, "200 OK");
+        assert_eq!(body[0]["id"], session.id());
+
+        let (status, _) = admin.handle("DELETE", &format!("/sessions/{}", session.id()));
+        assert_eq!(status, "202 Accepted");
+
+        drop(session);
+        assert_eq!(admin.handle("DELETE", "/sessions/1").0, "404 Not Found");
+        assert_eq!(admin.handle("DELETE", "/sessions/first").0, "400 Bad Request");
+        assert_eq!(admin.handle("GET", "/health").1["sessions"], 0);
+    }
+
+    #[test]
+    fn test_reload() {
+        let admin = Admin::new(SessionRegistry::new());
+        assert_eq!(admin.handle("POST", "/reload").0, "501 Not Implemented");
+
+        let admin = admin.with_reload(|| bail!("Invalid rules"));
+        let (status, body) = admin.handle("POST", "/reload");
+        assert_eq!(status, "500 Internal Server Error");
+        assert_eq!(body["error"], "Invalid rules");
+
+        let admin = admin.with_reload(|| Ok(()));
+        assert_eq!(admin.handle("POST", "/reload").0, "200 OK");
+    }
+
+    #[test]
+    fn test_counters() {
+        use crate::functions::{Flow, FunctionFactory};
+
+        let admin = Admin::new(SessionRegistry::new());
+        assert_eq!(admin.handle("GET", "/counters").0, "404 Not Found");
+
+        let counter = Counter::new();
+        let admin = admin.with_counter(counter.clone());
+        let flow = Flow {
+            source: Some("10.0.0.1:5000".parse().unwrap()),
+            destination: Some("10.0.0.2:80".parse().unwrap()),
+            ..Default::default()
+        };
+        let _function = counter.create(&flow).unwrap();
+
+        let (status, body) = admin.handle("GET", "/counters");
+        assert_eq!(status, "200 OK");
+        assert_eq!(body["total"]["flows"], 1);
+        assert_eq!(body["totals"][0]["key"]["client"], "10.0.0.1");
+        assert_eq!(body["totals"][0]["totals"]["active"], 1);
+        assert_eq!(admin.handle("DELETE", "/counters").0, "405 Method Not Allowed");
+    }
+
+    #[test]
+    fn test_routing() {
+        let admin = Admin::new(SessionRegistry::new()).with_config(json!({ "port": 1080 }));
+
+        assert_eq!(admin.handle("GET", "/config").1["port"], 1080);
+        assert_eq!(admin.handle("POST", "/sessions").0, "405 Method Not Allowed");
+        assert_eq!(admin.handle("GET", "/reload").0, "405 Method Not Allowed");
+        assert_eq!(admin.handle("GET", "/").0, "404 Not Found");
+    }
+}
@@ -0,0 +1,93 @@
+use std::net::{IpAddr, SocketAddr};
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use crate::constants::*;
+use crate::socks6::options::{
+    AuthMethod, AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption,
+    UnrecognizedOption,
+};
+use crate::{Address, Credentials, ProxyAddress};
+
+/// Generates domain names of up to 255 bytes (the SOCKS maximum), including the empty name.
+pub fn domain_name() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[a-z0-9.-]{0,255}",
+        "\\PC{0,63}".prop_filter("must fit in 255 bytes", |d| d.len() <= 255),
+    ]
+    .prop_filter("must not be an IP address", |d| d.parse::<IpAddr>().is_err())
+}
+
+/// Generates host names that survive being embedded in a URL.
+pub fn host_name() -> impl Strategy<Value = String> {
+    "[a-z0-9]{1,16}(\\.[a-z0-9-]{1,16}){0,3}".prop_filter("must not be an IP address", |d| d.parse::<IpAddr>().is_err())
+}
+
+/// Generates IPv4 and IPv6 socket addresses.
+pub fn socket_address() -> impl Strategy<Value = SocketAddr> {
+    (any::<IpAddr>(), any::<u16>()).prop_map(|(ip, port)| SocketAddr::new(ip, port))
+}
+
+/// Generates addresses of all three address types.
+pub fn address() -> impl Strategy<Value = Address> {
+    prop_oneof![
+        socket_address().prop_map(Address::Ip),
+        (domain_name(), any::<u16>()).prop_map(|(host, port)| Address::Domainname { host, port }),
+    ]
+}
+
+/// Generates credentials with usernames and passwords of up to 255 bytes each.
+pub fn credentials() -> impl Strategy<Value = Credentials> {
+    (vec(any::<u8>(), 0..=255), vec(any::<u8>(), 0..=255)).prop_map(|(u, p)| Credentials::new(u, p))
+}
+
+/// Generates proxy addresses, with or without credentials.
+pub fn proxy_address() -> impl Strategy<Value = ProxyAddress> {
+    let host = prop_oneof![host_name(), any::<IpAddr>().prop_map(|ip| ip.to_string())];
+    // An empty username is indistinguishable from the absence of credentials.
+    let credentials = credentials().prop_filter("username must not be empty", |c| !c.username.is_empty());
+
+    (
+        prop_oneof![Just(SOCKS_VER_5), Just(SOCKS_VER_6)],
+        host,
+        any::<u16>(),
+        proptest::option::of(credentials),
+    )
+        .prop_map(|(version, host, port, credentials)| ProxyAddress::new(version, host, port, credentials))
+}
+
+/// Generates all authentication methods.
+pub fn auth_method() -> impl Strategy<Value = AuthMethod> {
+    prop_oneof![
+        Just(AuthMethod::NoAuthentication),
+        Just(AuthMethod::Gssapi),
+        Just(AuthMethod::UsernamePassword),
+        Just(AuthMethod::NoAcceptableMethods),
+    ]
+}
+
+/// Generates options of every kind.
+///
+/// Advertisements never contain the methods that are implied or meaningless in an advertisement,
+/// and unrecognized options carry aligned data, as padding cannot be told apart from data.
+pub fn socks_option() -> impl Strategy<Value = SocksOption> {
+    let advertised = prop_oneof![Just(AuthMethod::Gssapi), Just(AuthMethod::UsernamePassword)];
+    let unrecognized_kind = any::<u16>().prop_filter("must be unrecognized", |k| {
+        ![SOCKS_OKIND_AUTH_METH_ADV, SOCKS_OKIND_AUTH_METH_SEL, SOCKS_OKIND_METADATA].contains(k)
+    });
+    let unrecognized_data = vec(any::<[u8; 4]>(), 0..64).prop_map(|words| words.concat());
+
+    prop_oneof![
+        (any::<u16>(), vec(advertised, 0..4))
+            .prop_map(|(length, methods)| AuthMethodAdvertisementOption::new(length, methods).wrap()),
+        auth_method().prop_map(|method| AuthMethodSelectionOption::new(method).wrap()),
+        (any::<u16>(), "\\PC{0,128}").prop_map(|(key, value)| MetadataOption::new(key, value).wrap()),
+        (unrecognized_kind, unrecognized_data).prop_map(|(kind, data)| UnrecognizedOption::new(kind, data).wrap()),
+    ]
+}
+
+/// Generates options sections that stay within the limits on their number and size.
+pub fn socks_options() -> impl Strategy<Value = Vec<SocksOption>> {
+    vec(socks_option(), 0..16)
+}
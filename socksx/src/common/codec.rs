@@ -0,0 +1,294 @@
+use std::convert::TryInto;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{constants::*, Address, ProtocolError};
+
+/// Represents the reasons why a message could not be decoded from a buffer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The buffer ends before the message does. Contains the minimum total
+    /// number of bytes the buffer must hold before decoding can make progress.
+    Incomplete(usize),
+    /// The bytes in the buffer violate the SOCKS wire format.
+    Protocol(ProtocolError),
+}
+
+impl From<ProtocolError> for DecodeError {
+    fn from(error: ProtocolError) -> Self {
+        DecodeError::Protocol(error)
+    }
+}
+
+/// A read cursor over a (possibly partially received) message.
+#[derive(Debug)]
+pub struct Cursor<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    /// Creates a new `Cursor` positioned at the start of `buffer`.
+    pub fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer, position: 0 }
+    }
+
+    /// Returns the number of bytes consumed so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Consumes and returns the next `length` bytes.
+    pub fn read_bytes(
+        &mut self,
+        length: usize,
+    ) -> Result<&'a [u8], DecodeError> {
+        let end = self.position + length;
+        let bytes = self.buffer.get(self.position..end).ok_or(DecodeError::Incomplete(end))?;
+        self.position = end;
+
+        Ok(bytes)
+    }
+
+    /// Consumes and returns the next byte.
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    /// Consumes and returns the next two bytes as a big-endian `u16`.
+    pub fn read_u16(&mut self) -> Result<u16, DecodeError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+/// A SOCKS message that can be decoded from bytes without performing any I/O.
+pub trait Decode: Sized {
+    /// Decodes a message from the cursor, consuming exactly the bytes that make up the message.
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError>;
+
+    /// Decodes a message from the front of `src`.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(None)` if `src` does not yet hold a complete message, in which case nothing
+    /// is consumed. Otherwise, the message is removed from `src` and returned.
+    fn decode(src: &mut BytesMut) -> Result<Option<Self>, ProtocolError> {
+        let mut cursor = Cursor::new(&src[..]);
+        let message = match Self::decode_from(&mut cursor) {
+            Ok(message) => message,
+            Err(DecodeError::Incomplete(_)) => return Ok(None),
+            Err(DecodeError::Protocol(error)) => return Err(error),
+        };
+
+        let consumed = cursor.position();
+        src.advance(consumed);
+
+        Ok(Some(message))
+    }
+}
+
+/// A SOCKS message that can be encoded into bytes without performing any I/O.
+pub trait Encode {
+    /// Appends the wire representation of the message to `dst`.
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    );
+
+    /// Returns the wire representation of the message.
+    fn to_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        self.encode(&mut bytes);
+
+        bytes
+    }
+}
+
+/// Reads exactly one message from the stream.
+///
+/// Only the bytes that belong to the message are read, so any data that follows it
+/// (e.g., initial data, or relayed traffic) remains available on the stream.
+pub async fn read_message<T, S>(stream: &mut S) -> Result<T>
+where
+    T: Decode,
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = vec![];
+    loop {
+        match T::decode_from(&mut Cursor::new(&buffer)) {
+            Ok(message) => return Ok(message),
+            Err(DecodeError::Incomplete(required)) => {
+                let received = buffer.len();
+                buffer.resize(required, 0);
+                stream.read_exact(&mut buffer[received..]).await?;
+            }
+            Err(DecodeError::Protocol(error)) => return Err(error.into()),
+        }
+    }
+}
+
+/// Writes one message to the stream.
+pub async fn write_message<T, S>(
+    stream: &mut S,
+    message: &T,
+) -> Result<()>
+where
+    T: Encode,
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(&message.to_bytes()).await?;
+
+    Ok(())
+}
+
+/// A `tokio_util` codec that frames a stream of SOCKS messages of type `T`.
+#[derive(Debug)]
+pub struct SocksCodec<T> {
+    message: PhantomData<fn() -> T>,
+}
+
+impl<T> SocksCodec<T> {
+    /// Creates a new `SocksCodec`.
+    pub fn new() -> Self {
+        Self { message: PhantomData }
+    }
+}
+
+impl<T> Default for SocksCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Decode> Decoder for SocksCodec<T> {
+    type Item = T;
+    type Error = anyhow::Error;
+
+    fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<T>> {
+        Ok(T::decode(src)?)
+    }
+}
+
+impl<T: Encode> Encoder<T> for SocksCodec<T> {
+    type Error = anyhow::Error;
+
+    fn encode(
+        &mut self,
+        item: T,
+        dst: &mut BytesMut,
+    ) -> Result<()> {
+        item.encode(dst);
+        Ok(())
+    }
+}
+
+impl Decode for Address {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let address_type = cursor.read_u8()?;
+        let dst_addr = match address_type {
+            SOCKS_ATYP_IPV4 => {
+                let octets: [u8; 4] = cursor.read_bytes(4)?.try_into().unwrap();
+                IpAddr::from(Ipv4Addr::from(octets)).to_string()
+            }
+            SOCKS_ATYP_IPV6 => {
+                let octets: [u8; 16] = cursor.read_bytes(16)?.try_into().unwrap();
+                IpAddr::from(Ipv6Addr::from(octets)).to_string()
+            }
+            SOCKS_ATYP_DOMAINNAME => {
+                let length = cursor.read_u8()?;
+                let dst_addr = cursor.read_bytes(length as usize)?;
+
+                String::from_utf8(dst_addr.to_vec()).map_err(|_| ProtocolError::InvalidDomainName(dst_addr.to_vec()))?
+            }
+            address_type => return Err(ProtocolError::UnsupportedAddressType(address_type).into()),
+        };
+
+        let dst_port = cursor.read_u16()?;
+
+        Ok(Address::new(dst_addr, dst_port))
+    }
+}
+
+impl Encode for Address {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_slice(&self.as_socks_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_incomplete() {
+        let mut cursor = Cursor::new(&[0x01, 0x02, 0x03]);
+        assert_eq!(cursor.read_u16(), Ok(0x0102));
+        assert_eq!(cursor.read_u16(), Err(DecodeError::Incomplete(4)));
+        assert_eq!(cursor.position(), 2);
+    }
+
+    #[test]
+    fn test_decode_address_needs_more_data() {
+        let mut src = BytesMut::from(&[SOCKS_ATYP_DOMAINNAME, 11, b'e', b'x'][..]);
+        assert_eq!(Address::decode(&mut src), Ok(None));
+        assert_eq!(src.len(), 4);
+
+        src.put_slice(b"ample.com");
+        src.put_slice(&[0x00, 0x50, 0xFF]);
+        assert_eq!(Address::decode(&mut src), Ok(Some(Address::new("example.com", 80))));
+        assert_eq!(&src[..], &[0xFF]);
+    }
+
+    #[test]
+    fn test_decode_address_unknown_type() {
+        let mut src = BytesMut::from(&[0x05, 0x00][..]);
+        assert_eq!(
+            Address::decode(&mut src),
+            Err(ProtocolError::UnsupportedAddressType(0x05))
+        );
+    }
+
+    // Regression (fuzzing): invalid UTF-8 used to be replaced, which could grow the name beyond 255 bytes.
+    #[test]
+    fn test_decode_address_invalid_utf8() {
+        let mut src = BytesMut::from(&[SOCKS_ATYP_DOMAINNAME, 2, 0xB6, 0xB6, 0x00, 0x50][..]);
+        assert_eq!(
+            Address::decode(&mut src),
+            Err(ProtocolError::InvalidDomainName(vec![0xB6, 0xB6]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_message_leaves_trailing_data() {
+        let mut bytes: &[u8] = &[SOCKS_ATYP_IPV4, 10, 0, 0, 1, 0x1F, 0x90, b'h', b'i'];
+        let address: Address = read_message(&mut bytes).await.unwrap();
+
+        assert_eq!(address, Address::new("10.0.0.1", 8080));
+        assert_eq!(bytes, b"hi");
+    }
+
+    #[test]
+    fn test_socks_codec() {
+        let mut codec = SocksCodec::<Address>::new();
+        let mut buffer = BytesMut::new();
+
+        codec.encode(Address::new("::1", 443), &mut buffer).unwrap();
+        codec.encode(Address::new("localhost", 22), &mut buffer).unwrap();
+
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(Address::new("::1", 443)));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(Address::new("localhost", 22)));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+    }
+}
@@ -24,6 +24,13 @@ pub const SOCKS_OKIND_AUTH_METH_ADV: u16 = 0x02u16;
 pub const SOCKS_OKIND_AUTH_METH_SEL: u16 = 0x03u16;
 /// Option kind for authentication data.
 pub const SOCKS_OKIND_AUTH_DATA: u16 = 0x04u16;
+/// Option kind for key-value metadata (experimental, used for chaining).
+pub const SOCKS_OKIND_METADATA: u16 = 0xFDE8u16;
+
+/// Maximum total length, in bytes, of the options section in a SOCKS6 message.
+pub const SOCKS6_MAX_OPTIONS_LENGTH: u16 = 0x4000u16;
+/// Maximum number of options accepted in a single SOCKS6 message.
+pub const SOCKS6_MAX_OPTIONS: usize = 128;
 
 /// Command code for no operation.
 pub const SOCKS_CMD_NOOP: u8 = 0x00u8;
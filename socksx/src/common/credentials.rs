@@ -44,7 +44,12 @@ impl Credentials {
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
+    use crate::arbitrary;
+    use crate::codec::{Decode, Encode};
+    use crate::socks5::PasswordAuthRequest;
 
     #[test]
     fn test_credentials_new() {
@@ -59,4 +64,20 @@ mod tests {
         let socks_bytes = credentials.as_socks_bytes();
         assert_eq!(socks_bytes, vec![8, 117, 115, 101, 114, 110, 97, 109, 101, 8, 112, 97, 115, 115, 119, 111, 114, 100]);
     }
+
+    #[test]
+    fn test_credentials_as_socks_bytes_empty() {
+        let credentials = Credentials::new("", "");
+        assert_eq!(credentials.as_socks_bytes(), vec![0, 0]);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_credentials_round_trip(credentials in arbitrary::credentials()) {
+            let request = PasswordAuthRequest::new(credentials);
+            let mut bytes = request.to_bytes();
+            prop_assert_eq!(PasswordAuthRequest::decode(&mut bytes), Ok(Some(request)));
+            prop_assert!(bytes.is_empty());
+        }
+    }
 }
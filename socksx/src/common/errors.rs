@@ -0,0 +1,118 @@
+use std::net::IpAddr;
+
+use thiserror::Error;
+
+/// Represents a violation of the SOCKS wire format by the remote peer.
+///
+/// Parsers return these (wrapped in an `anyhow::Error`) instead of panicking, so handlers can
+/// downcast them and answer with the appropriate failure reply before closing the connection.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ProtocolError {
+    /// The peer speaks a different SOCKS version than expected.
+    #[error("Unsupported SOCKS version: {0}")]
+    UnsupportedVersion(u8),
+    /// The requested command is not supported.
+    #[error("Unsupported command: {0}")]
+    UnsupportedCommand(u8),
+    /// The reply code is unknown.
+    #[error("Unknown reply code: {0}")]
+    UnknownReplyCode(u8),
+    /// The address type (ATYP) is unknown.
+    #[error("Unsupported address type: {0}")]
+    UnsupportedAddressType(u8),
+    /// A domain name is not valid UTF-8.
+    #[error("Domain name is not valid UTF-8: {0:?}")]
+    InvalidDomainName(Vec<u8>),
+    /// An option declares a length that is too short, unaligned, or exceeds the options section.
+    #[error("Invalid option length: {0}")]
+    InvalidOptionLength(u16),
+    /// The options section is larger than allowed.
+    #[error("Options length of {0} bytes exceeds the limit of {1} bytes")]
+    OptionsTooLong(u16, u16),
+    /// The options section contains more options than allowed.
+    #[error("Number of options exceeds the limit of {0}")]
+    TooManyOptions(usize),
+    /// The chain metadata of a SOCKS6 request is incomplete or inconsistent.
+    #[error("Invalid chain metadata: {0}")]
+    InvalidChain(String),
+    /// An option has a valid length, but its contents are malformed.
+    #[error("Malformed option (kind {0}): {1}")]
+    MalformedOption(u16, String),
+}
+
+/// Represents a client that could not, or did not, authenticate.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum AuthenticationError {
+    /// None of the methods that the client proposed are acceptable.
+    #[error("None of the authentication methods proposed by the client are acceptable.")]
+    NoAcceptableMethods,
+    /// The client proposed a method, but failed to authenticate with it.
+    #[error("Username/password authentication failed.")]
+    Failed,
+}
+
+/// Represents a request that was refused, as its client or user exceeded a quota (see `quotas::Quotas`).
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum QuotaExceeded {
+    /// The client already has the maximum number of concurrent connections.
+    #[error("Client {0} has reached its limit of {1} concurrent connections.")]
+    ClientConnections(IpAddr, usize),
+    /// The client opens new connections faster than allowed.
+    #[error("Client {0} has reached its limit of {1} new connections per second.")]
+    ClientRate(IpAddr, u32),
+    /// The user already has the maximum number of concurrent connections.
+    #[error("User {0} has reached its limit of {1} concurrent connections.")]
+    UserConnections(String, usize),
+    /// The user opens new connections faster than allowed.
+    #[error("User {0} has reached its limit of {1} new connections per second.")]
+    UserRate(String, u32),
+}
+
+impl QuotaExceeded {
+    /// Returns what the quota applies to: `client` or `user`.
+    pub fn scope(&self) -> &'static str {
+        match self {
+            QuotaExceeded::ClientConnections(..) | QuotaExceeded::ClientRate(..) => "client",
+            QuotaExceeded::UserConnections(..) | QuotaExceeded::UserRate(..) => "user",
+        }
+    }
+
+    /// Returns what the quota limits: concurrent `connections`, or the connection `rate`.
+    pub fn limit(&self) -> &'static str {
+        match self {
+            QuotaExceeded::ClientConnections(..) | QuotaExceeded::UserConnections(..) => "connections",
+            QuotaExceeded::ClientRate(..) | QuotaExceeded::UserRate(..) => "rate",
+        }
+    }
+}
+
+/// Represents a session that was killed, e.g., from the admin API.
+#[derive(Clone, Debug, Error, PartialEq)]
+#[error("The session was killed.")]
+pub struct SessionKilled;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_error_display() {
+        let error = ProtocolError::UnsupportedAddressType(0x07);
+        assert_eq!(error.to_string(), "Unsupported address type: 7");
+
+        let error = ProtocolError::OptionsTooLong(20000, 16384);
+        assert_eq!(
+            error.to_string(),
+            "Options length of 20000 bytes exceeds the limit of 16384 bytes"
+        );
+    }
+
+    #[test]
+    fn test_protocol_error_downcast() {
+        let error: anyhow::Error = ProtocolError::InvalidOptionLength(2).into();
+        assert_eq!(
+            error.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::InvalidOptionLength(2))
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// The largest request head that is accepted.
+const MAX_HEAD_LENGTH: usize = 8 * 1024;
+
+/// How long a client may take to send the head of its request.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The parts of an HTTP/1 request that the built-in endpoints look at.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+}
+
+/// Reads the head of a request from `stream`, which must arrive within `HEAD_TIMEOUT`.
+pub(crate) async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    read_request_within(stream, HEAD_TIMEOUT).await
+}
+
+/// Reads the head of a request from `stream`, which must arrive within `timeout`.
+async fn read_request_within(
+    stream: &mut TcpStream,
+    timeout: Duration,
+) -> Result<Request> {
+    let read_head = async {
+        let mut buffer = Vec::with_capacity(1024);
+        let end = loop {
+            if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                break end;
+            }
+            ensure!(buffer.len() < MAX_HEAD_LENGTH, "Request head exceeds {} bytes.", MAX_HEAD_LENGTH);
+
+            let mut chunk = [0u8; 1024];
+            let read = stream.read(&mut chunk).await?;
+            ensure!(read > 0, "Connection closed before the end of the request head.");
+            buffer.extend_from_slice(&chunk[..read]);
+        };
+
+        buffer.truncate(end);
+        Ok(buffer)
+    };
+
+    let buffer = match time::timeout(timeout, read_head).await {
+        Ok(buffer) => buffer?,
+        Err(_) => bail!("Timed out reading the request head after {:?}.", timeout),
+    };
+
+    let head = std::str::from_utf8(&buffer)?;
+    let request_line = head.split("\r\n").next().unwrap_or_default();
+
+    let mut parts = request_line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) if !method.is_empty() => Ok(Request {
+            method: method.to_string(),
+            path: path.to_string(),
+        }),
+        _ => bail!("Malformed request line: {:?}", request_line),
+    }
+}
+
+/// Writes a complete response to `stream`, and closes the connection.
+pub(crate) async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn roundtrip(request: &'static [u8]) -> Result<Request> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(request).await.unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_request(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let request = roundtrip(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/metrics");
+    }
+
+    #[tokio::test]
+    async fn test_read_request_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\n").await.unwrap();
+
+        // The client never finishes its request head.
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let error = read_request_within(&mut stream, Duration::from_millis(50)).await.unwrap_err();
+        assert!(error.to_string().contains("Timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_read_invalid_request() {
+        assert!(roundtrip(b"\r\n\r\n").await.is_err());
+        assert!(roundtrip(b"GET /metrics HTTP/1.1\r\n").await.is_err());
+    }
+}
@@ -0,0 +1,432 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use anyhow::Result;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::errors::{AuthenticationError, ProtocolError, QuotaExceeded};
+use crate::functions::Direction;
+use crate::http;
+
+/// The buckets of the latency histograms, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Metrics of SOCKS handlers, in a Prometheus registry.
+///
+/// Clones share the same metrics, so a single `Metrics` can be handed to several handlers (and to `serve`).
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    connections_active: IntGauge,
+    connections_total: IntCounter,
+    connections_refused: IntCounter,
+    handshake_failures: IntCounterVec,
+    replies: IntCounterVec,
+    relayed_bytes: IntCounterVec,
+    handshake_duration: Histogram,
+    connect_duration: HistogramVec,
+    upstream_requests: IntCounterVec,
+    upstream_up: IntGaugeVec,
+    upstream_pool_checkouts: IntCounterVec,
+    quota_rejections: IntCounterVec,
+    quota_wait_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    /// Creates a new `Metrics`, with a registry of its own.
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("socksx")), None).expect("valid registry prefix");
+
+        let latency = |name: &str, help: &str| HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec());
+
+        let metrics = Self {
+            connections_active: IntGauge::new("connections_active", "Connections that are being handled.").unwrap(),
+            connections_total: IntCounter::new("connections_total", "Connections accepted.").unwrap(),
+            connections_refused: IntCounter::new(
+                "connections_refused_total",
+                "Connections refused, because the connection limit was reached.",
+            )
+            .unwrap(),
+            handshake_failures: IntCounterVec::new(
+                Opts::new("handshake_failures_total", "Handshakes that failed, by reason."),
+                &["reason"],
+            )
+            .unwrap(),
+            replies: IntCounterVec::new(
+                Opts::new("replies_total", "Replies sent to clients, by SOCKS version and reply code."),
+                &["version", "reply"],
+            )
+            .unwrap(),
+            relayed_bytes: IntCounterVec::new(
+                Opts::new("relayed_bytes_total", "Bytes relayed after the handshake, by direction."),
+                &["direction"],
+            )
+            .unwrap(),
+            handshake_duration: Histogram::with_opts(latency(
+                "handshake_duration_seconds",
+                "Time from accepting a connection to the end of a successful handshake.",
+            ))
+            .unwrap(),
+            connect_duration: HistogramVec::new(
+                latency(
+                    "connect_duration_seconds",
+                    "Time to connect to the destination (`direct`) or next proxy (`upstream`).",
+                ),
+                &["target"],
+            )
+            .unwrap(),
+            upstream_requests: IntCounterVec::new(
+                Opts::new("upstream_requests_total", "Requests through upstream proxies, by proxy and result."),
+                &["upstream", "result"],
+            )
+            .unwrap(),
+            upstream_up: IntGaugeVec::new(
+                Opts::new("upstream_up", "Whether the last request through an upstream proxy succeeded."),
+                &["upstream"],
+            )
+            .unwrap(),
+            upstream_pool_checkouts: IntCounterVec::new(
+                Opts::new(
+                    "upstream_pool_checkouts_total",
+                    "Connections to upstream proxies taken from the pool (`pooled`) or opened on demand (`fresh`).",
+                ),
+                &["upstream", "source"],
+            )
+            .unwrap(),
+            quota_rejections: IntCounterVec::new(
+                Opts::new(
+                    "quota_rejections_total",
+                    "Requests refused, because a client or user exceeded a quota, by scope and limit.",
+                ),
+                &["scope", "limit"],
+            )
+            .unwrap(),
+            quota_wait_duration: Histogram::with_opts(latency(
+                "quota_wait_duration_seconds",
+                "Time that admitted requests waited for the quotas of their client and user, if they had to.",
+            ))
+            .unwrap(),
+            registry,
+        };
+
+        metrics.register().expect("metrics are registered once");
+        metrics
+    }
+
+    fn register(&self) -> prometheus::Result<()> {
+        self.registry.register(Box::new(self.connections_active.clone()))?;
+        self.registry.register(Box::new(self.connections_total.clone()))?;
+        self.registry.register(Box::new(self.connections_refused.clone()))?;
+        self.registry.register(Box::new(self.handshake_failures.clone()))?;
+        self.registry.register(Box::new(self.replies.clone()))?;
+        self.registry.register(Box::new(self.relayed_bytes.clone()))?;
+        self.registry.register(Box::new(self.handshake_duration.clone()))?;
+        self.registry.register(Box::new(self.connect_duration.clone()))?;
+        self.registry.register(Box::new(self.upstream_requests.clone()))?;
+        self.registry.register(Box::new(self.upstream_up.clone()))?;
+        self.registry.register(Box::new(self.upstream_pool_checkouts.clone()))?;
+        self.registry.register(Box::new(self.quota_rejections.clone()))?;
+        self.registry.register(Box::new(self.quota_wait_duration.clone()))
+    }
+
+    /// Returns the registry, e.g., to add metrics of the application.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Encodes all metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("text encoding does not fail");
+
+        String::from_utf8(buffer).expect("text encoding is UTF-8")
+    }
+
+    /// Counts an accepted connection as active, until the returned guard is dropped.
+    pub fn connection_opened(&self) -> ConnectionGuard {
+        self.connections_total.inc();
+        self.connections_active.inc();
+
+        ConnectionGuard(self.connections_active.clone())
+    }
+
+    pub fn connection_refused(&self) {
+        self.connections_refused.inc();
+    }
+
+    pub fn handshake_failed(
+        &self,
+        reason: FailureReason,
+    ) {
+        self.handshake_failures.with_label_values(&[reason.as_str()]).inc();
+    }
+
+    pub fn handshake_completed(
+        &self,
+        duration: Duration,
+    ) {
+        self.handshake_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Counts a reply, e.g., `ConnectionRefused`, sent by a handler of the given SOCKS version.
+    pub fn reply(
+        &self,
+        version: u8,
+        reply: &impl fmt::Debug,
+    ) {
+        self.replies.with_label_values(&[&version.to_string(), &format!("{:?}", reply)]).inc();
+    }
+
+    pub fn relayed(
+        &self,
+        upstream: u64,
+        downstream: u64,
+    ) {
+        self.relayed_bytes.with_label_values(&["upstream"]).inc_by(upstream);
+        self.relayed_bytes.with_label_values(&["downstream"]).inc_by(downstream);
+    }
+
+    /// Returns the counters of the relayed bytes, for relays to add to as the data moves.
+    pub fn relayed_bytes(&self) -> RelayedBytes {
+        RelayedBytes {
+            upstream: self.relayed_bytes.with_label_values(&["upstream"]),
+            downstream: self.relayed_bytes.with_label_values(&["downstream"]),
+        }
+    }
+
+    pub fn connected(
+        &self,
+        target: ConnectTarget,
+        duration: Duration,
+    ) {
+        let target = match target {
+            ConnectTarget::Direct => "direct",
+            ConnectTarget::Upstream => "upstream",
+        };
+        self.connect_duration.with_label_values(&[target]).observe(duration.as_secs_f64());
+    }
+
+    /// Records the outcome of a request through the upstream proxy at `upstream` (e.g., `10.0.0.2:1080`).
+    pub fn upstream_result(
+        &self,
+        upstream: &str,
+        success: bool,
+    ) {
+        let result = if success { "success" } else { "failure" };
+        self.upstream_requests.with_label_values(&[upstream, result]).inc();
+        self.upstream_up.with_label_values(&[upstream]).set(success as i64);
+    }
+
+    /// Records whether a connection to the upstream proxy at `upstream` was taken from the pool.
+    pub fn pool_checkout(
+        &self,
+        upstream: &str,
+        pooled: bool,
+    ) {
+        let source = if pooled { "pooled" } else { "fresh" };
+        self.upstream_pool_checkouts.with_label_values(&[upstream, source]).inc();
+    }
+
+    pub fn quota_exceeded(
+        &self,
+        exceeded: &QuotaExceeded,
+    ) {
+        self.quota_rejections.with_label_values(&[exceeded.scope(), exceeded.limit()]).inc();
+    }
+
+    /// Records how long an admitted request waited for its quotas, if it had to.
+    pub fn quota_waited(
+        &self,
+        duration: Duration,
+    ) {
+        self.quota_wait_duration.observe(duration.as_secs_f64());
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+/// Keeps a connection counted as active.
+#[derive(Debug)]
+pub struct ConnectionGuard(IntGauge);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// The counters of the bytes relayed in either direction, see `Metrics::relayed_bytes`.
+#[derive(Clone, Debug)]
+pub struct RelayedBytes {
+    upstream: IntCounter,
+    downstream: IntCounter,
+}
+
+impl RelayedBytes {
+    pub fn add(
+        &self,
+        direction: Direction,
+        bytes: u64,
+    ) {
+        match direction {
+            Direction::Upstream => self.upstream.inc_by(bytes),
+            Direction::Downstream => self.downstream.inc_by(bytes),
+        }
+    }
+}
+
+/// What a connection was made to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectTarget {
+    Direct,
+    Upstream,
+}
+
+/// Why a handshake failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureReason {
+    /// The client could not, or did not, authenticate.
+    Authentication,
+    /// The client, or its user, exceeded a quota.
+    Quota,
+    /// The client sent a malformed or unsupported message.
+    Protocol,
+    /// The destination, or the next proxy, could not be reached.
+    Connect,
+    /// The client went away, or its connection failed otherwise.
+    Io,
+    Other,
+}
+
+impl FailureReason {
+    /// Classifies the error of a failed handshake.
+    pub fn of(error: &anyhow::Error) -> Self {
+        if error.downcast_ref::<AuthenticationError>().is_some() {
+            FailureReason::Authentication
+        } else if error.downcast_ref::<QuotaExceeded>().is_some() {
+            FailureReason::Quota
+        } else if error.downcast_ref::<ProtocolError>().is_some() {
+            FailureReason::Protocol
+        } else if let Some(error) = error.downcast_ref::<io::Error>() {
+            match error.kind() {
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable
+                | io::ErrorKind::AddrNotAvailable => FailureReason::Connect,
+                _ => FailureReason::Io,
+            }
+        } else {
+            FailureReason::Other
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureReason::Authentication => "authentication",
+            FailureReason::Quota => "quota",
+            FailureReason::Protocol => "protocol",
+            FailureReason::Connect => "connect",
+            FailureReason::Io => "io",
+            FailureReason::Other => "other",
+        }
+    }
+}
+
+/// Serves the metrics at `/metrics`, in the Prometheus text format, on every connection accepted by `listener`.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Metrics,
+) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(error) = respond(stream, &metrics).await {
+                debug!("Failed to serve metrics: {}", error);
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+) -> Result<()> {
+    let request = http::read_request(&mut stream).await?;
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let body = metrics.encode();
+            http::write_response(&mut stream, "200 OK", TextEncoder::new().format_type(), body.as_bytes()).await
+        }
+        (_, "/metrics") => http::write_response(&mut stream, "405 Method Not Allowed", "text/plain", b"").await,
+        _ => http::write_response(&mut stream, "404 Not Found", "text/plain", b"").await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new();
+
+        let guard = metrics.connection_opened();
+        metrics.reply(5, &crate::socks5::Socks5Reply::ConnectionRefused);
+        metrics.relayed(12, 34);
+        metrics.handshake_failed(FailureReason::Protocol);
+        metrics.upstream_result("10.0.0.2:1080", false);
+
+        let text = metrics.encode();
+        assert!(text.contains("socksx_connections_active 1"));
+        assert!(text.contains("socksx_connections_total 1"));
+        assert!(text.contains("socksx_replies_total{reply=\"ConnectionRefused\",version=\"5\"} 1"));
+        assert!(text.contains("socksx_relayed_bytes_total{direction=\"downstream\"} 34"));
+        assert!(text.contains("socksx_handshake_failures_total{reason=\"protocol\"} 1"));
+        assert!(text.contains("socksx_upstream_up{upstream=\"10.0.0.2:1080\"} 0"));
+
+        drop(guard);
+        assert!(metrics.encode().contains("socksx_connections_active 0"));
+    }
+
+    #[test]
+    fn test_failure_reason() {
+        let error: anyhow::Error = AuthenticationError::Failed.into();
+        assert_eq!(FailureReason::of(&error), FailureReason::Authentication);
+
+        let error: anyhow::Error = ProtocolError::UnsupportedCommand(9).into();
+        assert_eq!(FailureReason::of(&error), FailureReason::Protocol);
+
+        let error: anyhow::Error = QuotaExceeded::UserRate(String::from("alice"), 10).into();
+        assert_eq!(FailureReason::of(&error), FailureReason::Quota);
+
+        let error: anyhow::Error = io::Error::from(io::ErrorKind::ConnectionRefused).into();
+        assert_eq!(FailureReason::of(&error), FailureReason::Connect);
+
+        let error: anyhow::Error = io::Error::from(io::ErrorKind::UnexpectedEof).into();
+        assert_eq!(FailureReason::of(&error), FailureReason::Io);
+    }
+}
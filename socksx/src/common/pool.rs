@@ -0,0 +1,337 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+/// How long a connection is kept in the pool, unless configured otherwise.
+pub const DEFAULT_MAX_IDLE: Duration = Duration::from_secs(60);
+
+/// How often the connections in the pool are checked, unless configured otherwise.
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A connection to an upstream, that no request was sent over yet.
+#[derive(Debug)]
+struct IdleConnection {
+    stream: TcpStream,
+    since: Instant,
+}
+
+impl IdleConnection {
+    /// Returns whether the connection is still open, without waiting.
+    ///
+    /// An upstream does not send anything before it received a request, so anything readable (data, EOF, or an error)
+    /// means that the connection cannot be used.
+    fn is_healthy(&self) -> bool {
+        let mut buffer = [0; 1];
+        matches!(self.stream.try_read(&mut buffer), Err(error) if error.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+/// The idle connections to a single upstream.
+#[derive(Debug)]
+struct Upstream {
+    idle: VecDeque<IdleConnection>,
+    /// Wakes up the task that refills the pool of the upstream.
+    refill: Arc<Notify>,
+    used: Instant,
+    /// Whether the upstream was pre-warmed, and is kept in the pool while unused.
+    pinned: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    upstreams: Mutex<HashMap<SocketAddr, Upstream>>,
+}
+
+/// A pool of TCP connections to upstream proxies, that are opened before they are needed.
+///
+/// For every upstream that a connection was taken for, or that was pre-warmed, a background task keeps up to `size`
+/// idle connections ready, replaces the ones that are closed or were idle for longer than the maximum, and checks them
+/// periodically. Upstreams that are not pre-warmed are forgotten once they were not used for the maximum idle time.
+/// Clones share the same connections.
+///
+/// This saves the TCP handshake to the upstream, not the SOCKS one: nothing is sent over a connection before it is
+/// taken. A SOCKS6 request carries the authentication method advertisement and the authentication data itself, so
+/// there is no method or authentication exchange to run before the destination is known, and without SOCKS6 sessions
+/// there is no token to authenticate a connection ahead with either.
+#[derive(Clone, Debug)]
+pub struct UpstreamPool {
+    size: usize,
+    max_idle: Duration,
+    health_check_interval: Duration,
+    shared: Arc<Shared>,
+}
+
+impl UpstreamPool {
+    /// Creates a pool that keeps up to `size` idle connections to every upstream.
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            max_idle: DEFAULT_MAX_IDLE,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            shared: Arc::default(),
+        }
+    }
+
+    /// Closes connections that were idle for `max_idle`, which should be shorter than the upstreams allow.
+    pub fn with_max_idle(
+        mut self,
+        max_idle: Duration,
+    ) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Checks the idle connections every `interval`, in addition to before they are taken.
+    pub fn with_health_check_interval(
+        mut self,
+        interval: Duration,
+    ) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// Starts to keep connections to `upstream` ready, before any are taken.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn prewarm(
+        &self,
+        upstream: SocketAddr,
+    ) {
+        let mut upstreams = self.shared.upstreams.lock().unwrap();
+        self.register(&mut upstreams, upstream).pinned = true;
+    }
+
+    /// Takes an idle connection to `upstream`, or connects to it if there is none.
+    ///
+    /// # Returns
+    ///
+    /// The connection, and whether it was taken from the pool.
+    pub async fn get(
+        &self,
+        upstream: SocketAddr,
+    ) -> io::Result<(TcpStream, bool)> {
+        if let Some(stream) = self.take(upstream) {
+            return Ok((stream, true));
+        }
+
+        Ok((TcpStream::connect(upstream).await?, false))
+    }
+
+    /// Returns the number of idle connections to `upstream`.
+    pub fn idle(
+        &self,
+        upstream: SocketAddr,
+    ) -> usize {
+        let upstreams = self.shared.upstreams.lock().unwrap();
+        upstreams.get(&upstream).map_or(0, |upstream| upstream.idle.len())
+    }
+
+    /// Takes the most recent idle connection to `upstream` that is still healthy, and has the pool refill.
+    fn take(
+        &self,
+        upstream: SocketAddr,
+    ) -> Option<TcpStream> {
+        let mut upstreams = self.shared.upstreams.lock().unwrap();
+        let entry = self.register(&mut upstreams, upstream);
+        entry.used = Instant::now();
+        entry.refill.notify_one();
+
+        while let Some(connection) = entry.idle.pop_back() {
+            if connection.since.elapsed() < self.max_idle && connection.is_healthy() {
+                return Some(connection.stream);
+            }
+        }
+
+        None
+    }
+
+    /// Returns the entry of `upstream`, and starts the task that refills it if there was none.
+    fn register<'a>(
+        &self,
+        upstreams: &'a mut HashMap<SocketAddr, Upstream>,
+        upstream: SocketAddr,
+    ) -> &'a mut Upstream {
+        upstreams.entry(upstream).or_insert_with(|| {
+            let refill = Arc::new(Notify::new());
+            tokio::spawn(maintain(self.clone_weak(), upstream, Arc::clone(&refill)));
+
+            Upstream {
+                idle: VecDeque::new(),
+                refill,
+                used: Instant::now(),
+                pinned: false,
+            }
+        })
+    }
+
+    fn clone_weak(&self) -> WeakPool {
+        WeakPool {
+            size: self.size,
+            max_idle: self.max_idle,
+            health_check_interval: self.health_check_interval,
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+}
+
+/// The configuration of a pool, and its connections while the pool is still in use.
+struct WeakPool {
+    size: usize,
+    max_idle: Duration,
+    health_check_interval: Duration,
+    shared: Weak<Shared>,
+}
+
+/// Keeps the connections to `upstream` in `pool` healthy and refilled, until the pool is dropped or the upstream is
+/// forgotten.
+async fn maintain(
+    pool: WeakPool,
+    upstream: SocketAddr,
+    refill: Arc<Notify>,
+) {
+    loop {
+        let shared = match pool.shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+
+        // Drop the connections that cannot be used anymore, and forget about unused upstreams.
+        let idle = {
+            let mut upstreams = shared.upstreams.lock().unwrap();
+            let entry = match upstreams.get_mut(&upstream) {
+                Some(entry) => entry,
+                None => return,
+            };
+
+            if !entry.pinned && entry.used.elapsed() >= pool.max_idle {
+                upstreams.remove(&upstream);
+                return;
+            }
+
+            entry.idle.retain(|connection| connection.since.elapsed() < pool.max_idle && connection.is_healthy());
+            entry.idle.len()
+        };
+
+        for _ in idle..pool.size {
+            match TcpStream::connect(upstream).await {
+                Ok(stream) => {
+                    let connection = IdleConnection {
+                        stream,
+                        since: Instant::now(),
+                    };
+
+                    let mut upstreams = shared.upstreams.lock().unwrap();
+                    match upstreams.get_mut(&upstream) {
+                        Some(entry) => entry.idle.push_back(connection),
+                        None => return,
+                    }
+                }
+                Err(error) => {
+                    // Requests still connect on their own, until the next attempt.
+                    debug!(%upstream, "Failed to connect to the upstream ahead: {}", error);
+                    break;
+                }
+            }
+        }
+        drop(shared);
+
+        tokio::select! {
+            _ = refill.notified() => {}
+            _ = time::sleep(pool.health_check_interval) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Waits until `pool` has `count` idle connections to `upstream`.
+    async fn wait_for_idle(
+        pool: &UpstreamPool,
+        upstream: SocketAddr,
+        count: usize,
+    ) {
+        for _ in 0..50 {
+            if pool.idle(upstream) == count {
+                return;
+            }
+
+            time::sleep(Duration::from_millis(20)).await;
+        }
+
+        panic!("Expected {} idle connections, found {}", count, pool.idle(upstream));
+    }
+
+    #[tokio::test]
+    async fn test_prewarm_and_refill() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+
+        let pool = UpstreamPool::new(2);
+        pool.prewarm(upstream);
+        wait_for_idle(&pool, upstream, 2).await;
+
+        let (stream, pooled) = pool.get(upstream).await.unwrap();
+        assert!(pooled);
+        assert_eq!(stream.peer_addr().unwrap(), upstream);
+        wait_for_idle(&pool, upstream, 2).await;
+    }
+
+    #[tokio::test]
+    async fn test_nothing_sent_ahead() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+
+        let pool = UpstreamPool::new(1);
+        pool.prewarm(upstream);
+        wait_for_idle(&pool, upstream, 1).await;
+
+        let (accepted, _) = listener.accept().await.unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        let error = accepted.try_read(&mut [0; 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[tokio::test]
+    async fn test_drop_closed_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+
+        let pool = UpstreamPool::new(1).with_health_check_interval(Duration::from_secs(60));
+        pool.prewarm(upstream);
+        wait_for_idle(&pool, upstream, 1).await;
+
+        // The upstream closes the pooled connection, which is then no longer taken.
+        let (accepted, _) = listener.accept().await.unwrap();
+        drop(accepted);
+        time::sleep(Duration::from_millis(50)).await;
+
+        let (_, pooled) = pool.get(upstream).await.unwrap();
+        assert!(!pooled);
+    }
+
+    #[tokio::test]
+    async fn test_max_idle() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+
+        let pool = UpstreamPool::new(1).with_max_idle(Duration::from_millis(100));
+        let (_, pooled) = pool.get(upstream).await.unwrap();
+        assert!(!pooled);
+        wait_for_idle(&pool, upstream, 1).await;
+
+        // The connection expired before it was taken.
+        time::sleep(Duration::from_millis(150)).await;
+        let (_, pooled) = pool.get(upstream).await.unwrap();
+        assert!(!pooled);
+    }
+}
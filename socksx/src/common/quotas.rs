@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+use crate::errors::QuotaExceeded;
+
+/// How many entries are kept, at least, before idle ones are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Limits on the connections of a single client IP, or of a single user.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quota {
+    /// The maximum number of concurrent connections.
+    pub max_connections: Option<usize>,
+    /// The maximum number of new connections per second, with bursts of up to as many.
+    pub connection_rate: Option<u32>,
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_connections.is_none() && self.connection_rate.is_none()
+    }
+}
+
+/// The client IP, or the user, that a quota applies to.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Key {
+    Client(IpAddr),
+    User(String),
+}
+
+/// The connections of a client IP or user so far.
+#[derive(Debug)]
+struct Usage {
+    active: usize,
+    /// New connections that may be opened right away, refilled at the connection rate.
+    tokens: f64,
+    refilled: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    usage: Mutex<HashMap<Key, Usage>>,
+    /// Wakes up the requests that wait for a connection to close.
+    released: Notify,
+}
+
+/// Quotas on the concurrent connections and the connection rate of every client IP and every user.
+///
+/// Clones share the same usage, so a single `Quotas` can be handed to several handlers. Requests that exceed a quota
+/// wait for up to the configured time for it to free up, and are refused after that.
+#[derive(Clone, Debug, Default)]
+pub struct Quotas {
+    client: Quota,
+    user: Quota,
+    wait: Duration,
+    shared: Arc<Shared>,
+}
+
+impl Quotas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `quota` to every client IP.
+    pub fn per_client(
+        mut self,
+        quota: Quota,
+    ) -> Self {
+        self.client = quota;
+        self
+    }
+
+    /// Applies `quota` to every authenticated user.
+    pub fn per_user(
+        mut self,
+        quota: Quota,
+    ) -> Self {
+        self.user = quota;
+        self
+    }
+
+    /// Lets requests that exceed a quota wait for up to `wait` before they are refused.
+    pub fn with_wait(
+        mut self,
+        wait: Duration,
+    ) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.client.is_unlimited() && self.user.is_unlimited()
+    }
+
+    /// Admits a new connection of `client`, authenticated as `user` (if any), once it is within all quotas.
+    ///
+    /// # Returns
+    ///
+    /// A guard that counts the connection until it is dropped, and how long the connection waited for it (zero if it
+    /// was admitted right away); or the quota that was still exceeded when the wait ended.
+    pub async fn admit(
+        &self,
+        client: Option<IpAddr>,
+        user: Option<&str>,
+    ) -> Result<(QuotaGuard, Duration), QuotaExceeded> {
+        let mut keys = vec![];
+        if let Some(client) = client.filter(|_| !self.client.is_unlimited()) {
+            keys.push(Key::Client(client));
+        }
+        if let Some(user) = user.filter(|_| !self.user.is_unlimited()) {
+            keys.push(Key::User(user.to_string()));
+        }
+
+        let started = Instant::now();
+        let deadline = started + self.wait;
+        let mut waited = Duration::ZERO;
+        loop {
+            // Listen for released connections before checking, so that none are missed.
+            let released = self.shared.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let (exceeded, retry) = match self.try_admit(&keys) {
+                Ok(()) => {
+                    let guard = QuotaGuard {
+                        keys,
+                        shared: Arc::clone(&self.shared),
+                    };
+
+                    return Ok((guard, waited));
+                }
+                Err(exceeded) => exceeded,
+            };
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(exceeded);
+            }
+
+            // A connection quota frees up once a connection closes, a rate quota once a token is refilled.
+            let retry_at = retry.map_or(deadline, |retry| deadline.min(now + retry));
+            tokio::select! {
+                _ = released, if retry.is_none() => {}
+                _ = time::sleep_until(retry_at) => {}
+            }
+            waited = started.elapsed();
+        }
+    }
+
+    /// Takes a connection, and a token for it, of every key, if all of them are within their quotas.
+    ///
+    /// Otherwise, returns the first quota that is exceeded, and when a token will be available for a rate quota.
+    fn try_admit(
+        &self,
+        keys: &[Key],
+    ) -> Result<(), (QuotaExceeded, Option<Duration>)> {
+        let mut usage = self.shared.usage.lock().unwrap();
+        let now = Instant::now();
+
+        // Forget about idle clients and users, once there are many of them.
+        if usage.len() >= PRUNE_THRESHOLD {
+            usage.retain(|key, usage| usage.active > 0 || self.refill(key, usage, now) < self.burst(key));
+        }
+
+        for key in keys {
+            let quota = self.quota(key);
+            let entry = usage.entry(key.clone()).or_insert_with(|| Usage {
+                active: 0,
+                tokens: self.burst(key),
+                refilled: now,
+            });
+
+            if let Some(max) = quota.max_connections {
+                if entry.active >= max {
+                    let exceeded = match key {
+                        Key::Client(client) => QuotaExceeded::ClientConnections(*client, max),
+                        Key::User(user) => QuotaExceeded::UserConnections(user.clone(), max),
+                    };
+
+                    return Err((exceeded, None));
+                }
+            }
+
+            if let Some(rate) = quota.connection_rate {
+                let tokens = self.refill(key, entry, now);
+                if tokens < 1.0 {
+                    let exceeded = match key {
+                        Key::Client(client) => QuotaExceeded::ClientRate(*client, rate),
+                        Key::User(user) => QuotaExceeded::UserRate(user.clone(), rate),
+                    };
+                    let retry = Duration::from_secs_f64((1.0 - tokens) / rate as f64);
+
+                    return Err((exceeded, Some(retry)));
+                }
+            }
+        }
+
+        for key in keys {
+            let entry = usage.get_mut(key).expect("usage of every key");
+            entry.active += 1;
+            entry.tokens -= 1.0;
+        }
+
+        Ok(())
+    }
+
+    fn quota(
+        &self,
+        key: &Key,
+    ) -> &Quota {
+        match key {
+            Key::Client(_) => &self.client,
+            Key::User(_) => &self.user,
+        }
+    }
+
+    /// The number of tokens that a full bucket of `key` holds.
+    fn burst(
+        &self,
+        key: &Key,
+    ) -> f64 {
+        self.quota(key).connection_rate.map_or(f64::INFINITY, |rate| rate.max(1) as f64)
+    }
+
+    /// Refills the tokens of `key` up to `now`, and returns them.
+    fn refill(
+        &self,
+        key: &Key,
+        usage: &mut Usage,
+        now: Instant,
+    ) -> f64 {
+        if let Some(rate) = self.quota(key).connection_rate {
+            let refilled = now.duration_since(usage.refilled).as_secs_f64() * rate as f64;
+            usage.tokens = (usage.tokens + refilled).min(self.burst(key));
+            usage.refilled = now;
+        }
+
+        usage.tokens
+    }
+
+    /// Returns the number of active connections of `client`.
+    pub fn client_connections(
+        &self,
+        client: IpAddr,
+    ) -> usize {
+        self.active(&Key::Client(client))
+    }
+
+    /// Returns the number of active connections of `user`.
+    pub fn user_connections(
+        &self,
+        user: &str,
+    ) -> usize {
+        self.active(&Key::User(user.to_string()))
+    }
+
+    fn active(
+        &self,
+        key: &Key,
+    ) -> usize {
+        self.shared.usage.lock().unwrap().get(key).map_or(0, |usage| usage.active)
+    }
+}
+
+/// Counts a connection against the quotas of its client and user, until it is dropped.
+#[derive(Debug)]
+pub struct QuotaGuard {
+    keys: Vec<Key>,
+    shared: Arc<Shared>,
+}
+
+impl Drop for QuotaGuard {
+    fn drop(&mut self) {
+        let mut usage = self.shared.usage.lock().unwrap();
+        for key in &self.keys {
+            if let Some(entry) = usage.get_mut(key) {
+                entry.active -= 1;
+            }
+        }
+        drop(usage);
+
+        self.shared.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let quota = Quota {
+            max_connections: Some(2),
+            connection_rate: None,
+        };
+        let quotas = Quotas::new().per_client(quota).per_user(Quota {
+            max_connections: Some(1),
+            connection_rate: None,
+        });
+
+        let (first, _) = quotas.admit(Some(CLIENT), None).await.unwrap();
+        let (second, _) = quotas.admit(Some(CLIENT), Some("alice")).await.unwrap();
+        assert_eq!(quotas.client_connections(CLIENT), 2);
+        assert_eq!(quotas.user_connections("alice"), 1);
+
+        let exceeded = quotas.admit(Some(CLIENT), None).await.unwrap_err();
+        assert_eq!(exceeded, QuotaExceeded::ClientConnections(CLIENT, 2));
+
+        // Neither quota is taken when one of them is exceeded.
+        drop(first);
+        let exceeded = quotas.admit(Some(CLIENT), Some("alice")).await.unwrap_err();
+        assert_eq!(exceeded, QuotaExceeded::UserConnections(String::from("alice"), 1));
+        assert_eq!(quotas.client_connections(CLIENT), 1);
+
+        drop(second);
+        assert!(quotas.admit(Some(CLIENT), Some("alice")).await.is_ok());
+        assert_eq!(quotas.user_connections("alice"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_release() {
+        let quota = Quota {
+            max_connections: Some(1),
+            connection_rate: None,
+        };
+        let quotas = Quotas::new().per_client(quota).with_wait(Duration::from_secs(5));
+
+        let (first, _) = quotas.admit(Some(CLIENT), None).await.unwrap();
+        let release = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(100)).await;
+            drop(first);
+        });
+
+        let (_second, waited) = quotas.admit(Some(CLIENT), None).await.unwrap();
+        assert!(waited >= Duration::from_millis(100) && waited < Duration::from_secs(5), "{:?}", waited);
+        release.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_rate() {
+        let quota = Quota {
+            max_connections: None,
+            connection_rate: Some(10),
+        };
+        let quotas = Quotas::new().per_user(quota);
+
+        for _ in 0..10 {
+            quotas.admit(None, Some("bob")).await.unwrap();
+        }
+        let exceeded = quotas.admit(None, Some("bob")).await.unwrap_err();
+        assert_eq!(exceeded, QuotaExceeded::UserRate(String::from("bob"), 10));
+
+        // Other users, and unauthenticated clients, have quotas of their own.
+        assert!(quotas.admit(Some(CLIENT), Some("carol")).await.is_ok());
+        assert!(quotas.admit(Some(CLIENT), None).await.is_ok());
+
+        // A token is refilled every 100 ms, which is within the wait.
+        let quotas = quotas.with_wait(Duration::from_millis(500));
+        let (_, waited) = quotas.admit(None, Some("bob")).await.unwrap();
+        assert!(waited < Duration::from_millis(500), "{:?}", waited);
+    }
+}
@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use futures::ready;
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Sleep};
+
+use crate::addresses::Address;
+use crate::functions::firewall::HostPattern;
+use crate::functions::{Direction, Flow};
+
+/// A token bucket, that refills at `rate` bytes per second up to a burst of one second's worth of bytes.
+///
+/// Takes that exceed the tokens in the bucket are granted anyway, but put it in debt: the returned delay is how long
+/// the taker should wait before it takes again, so that all takers together stay within the rate.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket, that refills at `rate` bytes per second.
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+
+        Self {
+            rate,
+            burst: rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Takes `amount` tokens, and returns how long to wait until the bucket is out of debt.
+    pub fn take(
+        &self,
+        amount: usize,
+    ) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, refilled) = &mut *state;
+
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * self.rate).min(self.burst);
+        *refilled = now;
+        *tokens -= amount as f64;
+
+        if *tokens < 0.0 {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// The connections to which a rate limit applies.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scope {
+    /// All connections, together.
+    Global,
+    /// The connections of the given authenticated user, or of each user on their own (`None`).
+    User(Option<String>),
+    /// The connections from the given network, together, or from each client IP on its own (`None`).
+    Client(Option<IpNet>),
+    /// The connections to the matching destination hosts, together, or to each host on its own (`None`).
+    Destination(Option<HostPattern>),
+}
+
+impl fmt::Display for Scope {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        fn or_any<T: fmt::Display>(value: &Option<T>) -> String {
+            value.as_ref().map(T::to_string).unwrap_or_else(|| String::from("*"))
+        }
+
+        match self {
+            Scope::Global => write!(f, "global"),
+            Scope::User(user) => write!(f, "user:{}", or_any(user)),
+            Scope::Client(network) => write!(f, "client:{}", or_any(network)),
+            Scope::Destination(pattern) => write!(f, "destination:{}", or_any(pattern)),
+        }
+    }
+}
+
+/// A rate limit, in bytes per second, on the upload (client to destination) and download (destination to client) of
+/// the connections within its scope.
+///
+/// Rate limits can be read from text, as the scope followed by the rates, in bytes per second with an optional `K`,
+/// `M` or `G` suffix (powers of 1024):
+/// ```text
+/// global up 100M down 100M
+/// user:* up 1M down 5M
+/// user:alice down 20M
+/// client:10.0.0.0/8 up 10M
+/// destination:*.example.com down 2M
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub scope: Scope,
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(limit: &str) -> Result<Self> {
+        let mut words = limit.split_whitespace();
+
+        let scope = words.next().context("Empty rate limit.")?;
+        let scope = match scope.split_once(':') {
+            None if scope == "global" => Scope::Global,
+            Some(("user", "*")) => Scope::User(None),
+            Some(("user", user)) => Scope::User(Some(user.to_string())),
+            Some(("client", "*")) => Scope::Client(None),
+            Some(("client", network)) => Scope::Client(Some(parse_network(network)?)),
+            Some(("destination", "*")) => Scope::Destination(None),
+            Some(("destination", pattern)) => Scope::Destination(Some(pattern.parse()?)),
+            _ => bail!("Unknown rate limit scope: {} (expected global, user:, client: or destination:)", scope),
+        };
+
+        let mut upload = None;
+        let mut download = None;
+        while let Some(direction) = words.next() {
+            let rate = words.next().with_context(|| format!("Missing rate after `{}`.", direction))?;
+            let rate = Some(parse_rate(rate)?);
+
+            match direction {
+                "up" => upload = rate,
+                "down" => download = rate,
+                _ => bail!("Unknown rate limit direction: {} (expected up or down)", direction),
+            }
+        }
+        ensure!(upload.is_some() || download.is_some(), "Rate limit without rates: {}", limit);
+
+        Ok(Self {
+            scope,
+            upload,
+            download,
+        })
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "{}", self.scope)?;
+        if let Some(upload) = self.upload {
+            write!(f, " up {}", upload)?;
+        }
+        if let Some(download) = self.download {
+            write!(f, " down {}", download)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a rate in bytes per second, with an optional `K`, `M` or `G` suffix.
+fn parse_rate(rate: &str) -> Result<u64> {
+    let (number, multiplier) = match rate.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&rate[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&rate[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&rate[..i], 1 << 30),
+        _ => (rate, 1),
+    };
+
+    let number: u64 = number.parse().with_context(|| format!("Invalid rate: {}", rate))?;
+    ensure!(number > 0, "Rates must be positive: {}", rate);
+
+    number.checked_mul(multiplier).with_context(|| format!("Rate too large: {}", rate))
+}
+
+/// Parses a network in CIDR notation, or a single IP address.
+fn parse_network(network: &str) -> Result<IpNet> {
+    match network.parse::<IpAddr>() {
+        Ok(ip) => Ok(IpNet::from(ip)),
+        Err(_) => network.parse().with_context(|| format!("Invalid network: {}", network)),
+    }
+}
+
+/// The buckets of one rate limit, shared by the connections within its scope.
+#[derive(Debug)]
+struct Buckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+/// The buckets in use, by the index of their limit and the key within its scope.
+type SharedBuckets = HashMap<(usize, String), Weak<Buckets>>;
+
+/// Applies rate limits to connections, with buckets that are shared by all connections within the same scope.
+///
+/// All global rate limits apply to every connection. Of the user, client and destination limits, the first that names
+/// the user, client network or destination of a connection applies, and otherwise the one for each (`*`), if any.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    limits: Arc<Vec<RateLimit>>,
+    buckets: Arc<Mutex<SharedBuckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Vec<RateLimit>) -> Self {
+        Self {
+            limits: Arc::new(limits),
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Parses the rate limits from text, one per line (`#` starts a comment).
+    pub fn parse(limits: &str) -> Result<Self> {
+        let limits = limits
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i, line.split('#').next().unwrap_or_default().trim()))
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| line.parse().with_context(|| format!("Invalid rate limit on line {}", i + 1)))
+            .collect::<Result<_>>()?;
+
+        Ok(Self::new(limits))
+    }
+
+    pub fn limits(&self) -> &[RateLimit] {
+        &self.limits
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    /// Returns the buckets that apply to `flow`, whose client authenticated as `user`, if any.
+    pub fn throttle(
+        &self,
+        flow: &Flow,
+        user: Option<&str>,
+    ) -> Throttle {
+        let client = flow.source.map(|source| source.ip());
+        let host = flow.target.as_ref().map(|target| match target {
+            Address::Domainname { host, .. } => host.to_lowercase(),
+            Address::Ip(address) => address.ip().to_string(),
+        });
+
+        // The index and key of the limits that apply.
+        let mut applicable = vec![];
+        let mut user_limit = None;
+        let mut client_limit = None;
+        let mut destination_limit = None;
+
+        for (i, limit) in self.limits.iter().enumerate() {
+            match &limit.scope {
+                Scope::Global => applicable.push((i, String::new())),
+                Scope::User(Some(name)) if user == Some(name.as_str()) => {
+                    user_limit = user_limit.filter(|(_, named)| *named).or(Some((i, true)));
+                }
+                Scope::User(None) if user.is_some() => user_limit = user_limit.or(Some((i, false))),
+                Scope::Client(Some(network)) if client.is_some_and(|client| network.contains(&client)) => {
+                    client_limit = client_limit.filter(|(_, named)| *named).or(Some((i, true)));
+                }
+                Scope::Client(None) if client.is_some() => client_limit = client_limit.or(Some((i, false))),
+                Scope::Destination(Some(pattern)) if host.as_ref().is_some_and(|host| pattern.matches(host)) => {
+                    destination_limit = destination_limit.filter(|(_, named)| *named).or(Some((i, true)));
+                }
+                Scope::Destination(None) if host.is_some() => {
+                    destination_limit = destination_limit.or(Some((i, false)));
+                }
+                _ => {}
+            }
+        }
+
+        // Named limits are shared by everything they match, the others by everything with the same key.
+        if let Some((i, named)) = user_limit {
+            let key = if named { String::new() } else { user.unwrap_or_default().to_string() };
+            applicable.push((i, key));
+        }
+        if let Some((i, named)) = client_limit {
+            let key = if named { String::new() } else { client.map(|c| c.to_string()).unwrap_or_default() };
+            applicable.push((i, key));
+        }
+        if let Some((i, named)) = destination_limit {
+            let key = if named { String::new() } else { host.clone().unwrap_or_default() };
+            applicable.push((i, key));
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, shared| shared.strong_count() > 0);
+
+        let buckets = applicable
+            .into_iter()
+            .map(|(i, key)| {
+                let shared = buckets.entry((i, key)).or_default();
+                shared.upgrade().unwrap_or_else(|| {
+                    let limit = &self.limits[i];
+                    let created = Arc::new(Buckets {
+                        upload: limit.upload.map(TokenBucket::new),
+                        download: limit.download.map(TokenBucket::new),
+                    });
+
+                    *shared = Arc::downgrade(&created);
+                    created
+                })
+            })
+            .collect();
+
+        Throttle { buckets }
+    }
+}
+
+/// The buckets that apply to a connection.
+#[derive(Clone, Debug, Default)]
+pub struct Throttle {
+    buckets: Vec<Arc<Buckets>>,
+}
+
+impl Throttle {
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Takes `amount` tokens from the buckets of the given direction, and returns how long to wait before the next.
+    pub fn take(
+        &self,
+        direction: Direction,
+        amount: usize,
+    ) -> Duration {
+        self.buckets
+            .iter()
+            .filter_map(|buckets| match direction {
+                Direction::Upstream => buckets.upload.as_ref(),
+                Direction::Downstream => buckets.download.as_ref(),
+            })
+            .map(|bucket| bucket.take(amount))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Wraps the stream to the client, to limit the rate at which it is read from (upload) and written to (download).
+    pub fn apply<S>(
+        self,
+        stream: S,
+    ) -> ThrottledStream<S> {
+        ThrottledStream {
+            inner: stream,
+            throttle: self,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// A stream to the client of a connection, that waits after every read or write until its buckets are out of debt.
+pub struct ThrottledStream<S> {
+    inner: S,
+    throttle: Throttle,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> ThrottledStream<S> {
+    /// Takes tokens for `amount` bytes in `direction`, and returns the delay before the next read or write, if any.
+    fn take(
+        &self,
+        direction: Direction,
+        amount: usize,
+    ) -> Option<Pin<Box<Sleep>>> {
+        let delay = self.throttle.take(direction, amount);
+        if delay.is_zero() {
+            None
+        } else {
+            Some(Box::pin(time::sleep(delay)))
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if let Some(delay) = &mut this.read_delay {
+            ready!(delay.as_mut().poll(cx));
+            this.read_delay = None;
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.read_delay = this.take(Direction::Upstream, buf.filled().len() - filled);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if let Some(delay) = &mut this.write_delay {
+            ready!(delay.as_mut().poll(cx));
+            this.write_delay = None;
+        }
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.write_delay = this.take(Direction::Downstream, written);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn flow(
+        client: [u8; 4],
+        host: &str,
+    ) -> Flow {
+        let target = Address::Domainname {
+            host: host.to_string(),
+            port: 443,
+        };
+
+        Flow::new(Some(SocketAddr::from((client, 40000))), None).with_target(target)
+    }
+
+    #[test]
+    fn test_parse() {
+        let limiter = RateLimiter::parse(
+            "# Everyone together\n\
+             global up 100M down 1G\n\
+             user:* down 512k  # Each user\n\
+             client:10.0.0.0/8 up 64\n\
+             destination:*.example.com down 2M\n",
+        )
+        .unwrap();
+
+        let limits = limiter.limits();
+        assert_eq!(limits.len(), 4);
+        assert_eq!(limits[0].scope, Scope::Global);
+        assert_eq!((limits[0].upload, limits[0].download), (Some(100 << 20), Some(1 << 30)));
+        assert_eq!(limits[1].to_string(), "user:* down 524288");
+        assert_eq!(limits[2].scope, Scope::Client(Some("10.0.0.0/8".parse().unwrap())));
+        assert_eq!(limits[3].to_string(), "destination:*.example.com down 2097152");
+
+        assert!("user:alice".parse::<RateLimit>().is_err());
+        assert!("tenant:alice up 1M".parse::<RateLimit>().is_err());
+        assert!("global sideways 1M".parse::<RateLimit>().is_err());
+        assert!("global up 0".parse::<RateLimit>().is_err());
+        assert!("global up 20000000000G".parse::<RateLimit>().is_err());
+        assert!("client:10.0.0.0/33 up 1M".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        let delay = bucket.take(500);
+        assert!(delay > Duration::from_millis(450) && delay <= Duration::from_millis(500), "{:?}", delay);
+        let delay = bucket.take(500);
+        assert!(delay > Duration::from_millis(950) && delay <= Duration::from_secs(1), "{:?}", delay);
+    }
+
+    #[test]
+    fn test_shared_buckets() {
+        let limiter = RateLimiter::parse("user:* up 1000\nuser:bob up 4000\nclient:10.0.0.0/8 down 1000").unwrap();
+
+        // The connections of a user share a bucket, but not with those of other users.
+        let first = limiter.throttle(&flow([10, 0, 0, 1], "example.com"), Some("alice"));
+        let second = limiter.throttle(&flow([10, 0, 0, 2], "example.org"), Some("alice"));
+        let other = limiter.throttle(&flow([192, 168, 0, 1], "example.com"), Some("carol"));
+        assert_eq!(first.take(Direction::Upstream, 1000), Duration::ZERO);
+        assert!(second.take(Direction::Upstream, 1000) > Duration::from_millis(900));
+        assert_eq!(other.take(Direction::Upstream, 1000), Duration::ZERO);
+
+        // The network is limited as a whole, and the named user limit overrides the one for each user.
+        assert_eq!(first.take(Direction::Downstream, 1000), Duration::ZERO);
+        assert!(second.take(Direction::Downstream, 1000) > Duration::from_millis(900));
+        assert!(other.take(Direction::Downstream, 1_000_000).is_zero());
+
+        let bob = limiter.throttle(&flow([192, 168, 0, 1], "example.com"), Some("bob"));
+        assert_eq!(bob.take(Direction::Upstream, 4000), Duration::ZERO);
+
+        // Buckets are dropped with the last connection that uses them.
+        drop((first, second));
+        let third = limiter.throttle(&flow([172, 16, 0, 1], "example.com"), Some("alice"));
+        assert_eq!(third.take(Direction::Upstream, 1000), Duration::ZERO);
+        assert!(limiter.throttle(&flow([172, 16, 0, 1], "example.com"), None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_throttled_stream() {
+        let limiter = RateLimiter::parse("global up 1K down 2K").unwrap();
+        let (stream, mut peer) = tokio::io::duplex(4096);
+        let mut stream = limiter.throttle(&Flow::default(), None).apply(stream);
+
+        let started = Instant::now();
+        peer.write_all(&[0; 1537]).await.unwrap();
+        stream.read_exact(&mut [0; 1536]).await.unwrap();
+        stream.write_all(&[0; 3072]).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(250));
+
+        // Both directions are in debt, so the next read and write wait for it to be paid off.
+        stream.read_exact(&mut [0; 1]).await.unwrap();
+        stream.write_all(&[0; 1]).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(450), "{:?}", started.elapsed());
+    }
+}
@@ -0,0 +1,660 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use ipnet::IpNet;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+use crate::constants::*;
+use crate::relay::{Relay, RelayEnd};
+use crate::socks6::options::SocksOption;
+use crate::socks6::SocksChain;
+use crate::{Address, ProxyAddress, Socks5Client, Socks5UdpSession, Socks6Client};
+
+/// How long a UDP flow is kept without traffic in either direction.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How intercepted connections are delivered to the redirector.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedirectMode {
+    /// iptables `REDIRECT`: the destination is rewritten, and recovered with `SO_ORIGINAL_DST`.
+    Nat,
+    /// iptables `TPROXY`: the destination is preserved, and connections are accepted on an `IP_TRANSPARENT` socket.
+    Tproxy,
+}
+
+impl FromStr for RedirectMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode.to_lowercase().as_str() {
+            "nat" | "redirect" => Ok(RedirectMode::Nat),
+            "tproxy" => Ok(RedirectMode::Tproxy),
+            mode => bail!("Unrecognized redirect mode: {} (supported: `nat`, `tproxy`)", mode),
+        }
+    }
+}
+
+impl fmt::Display for RedirectMode {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            RedirectMode::Nat => write!(f, "nat"),
+            RedirectMode::Tproxy => write!(f, "tproxy"),
+        }
+    }
+}
+
+/// The proxy that redirected connections are forwarded through.
+#[derive(Clone)]
+enum Upstream {
+    Socks5(Socks5Client),
+    Socks6(Socks6Client, Option<Vec<SocksOption>>),
+}
+
+/// Forwards connections intercepted by iptables through a SOCKS proxy, to their original destination.
+#[derive(Clone)]
+pub struct Redirector {
+    mode: RedirectMode,
+    upstream: Upstream,
+    upstream_addr: SocketAddr,
+    exclusions: Vec<IpNet>,
+    relay: Relay,
+}
+
+impl Redirector {
+    /// Creates a new `Redirector`.
+    ///
+    /// # Parameters
+    /// - `upstream`: The SOCKS proxy to forward connections through.
+    /// - `chain`: Additional SOCKS6 proxies that the upstream should chain connections through.
+    /// - `mode`: How intercepted connections are delivered to the redirector.
+    ///
+    /// # Returns
+    /// A `Result` containing the new `Redirector`, or an error if the upstream cannot be resolved.
+    pub async fn new(
+        upstream: ProxyAddress,
+        chain: Vec<ProxyAddress>,
+        mode: RedirectMode,
+    ) -> Result<Self> {
+        let proxy_addr = crate::resolve_addr(Address::try_from(&upstream)?.to_string()).await?;
+        let credentials = upstream.credentials.clone();
+
+        let upstream = match upstream.socks_version {
+            SOCKS_VER_5 => {
+                ensure!(chain.is_empty(), "Chaining is only supported by SOCKS6 proxies.");
+                Upstream::Socks5(Socks5Client::new(proxy_addr.to_string(), credentials).await?)
+            }
+            SOCKS_VER_6 => {
+                // The upstream is the first link, and forwards to the next one.
+                let options = if chain.is_empty() {
+                    None
+                } else {
+                    let links = std::iter::once(upstream).chain(chain).collect();
+                    Some(SocksChain::new(0, links).as_options())
+                };
+
+                Upstream::Socks6(Socks6Client::new(proxy_addr.to_string(), credentials).await?, options)
+            }
+            version => bail!("Unsupported SOCKS version: {}", version),
+        };
+
+        Ok(Redirector {
+            mode,
+            upstream,
+            upstream_addr: proxy_addr,
+            exclusions: vec![],
+            relay: Relay::default(),
+        })
+    }
+
+    /// Connects to destinations in the given networks directly, instead of through the upstream.
+    pub fn with_exclusions(
+        mut self,
+        exclusions: Vec<IpNet>,
+    ) -> Self {
+        self.exclusions.extend(exclusions);
+        self
+    }
+
+    /// Relays the data of redirected connections as configured by `relay`, e.g., with an idle timeout.
+    pub fn with_relay(
+        mut self,
+        relay: Relay,
+    ) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Returns whether connections to `destination` bypass the upstream.
+    pub fn is_excluded(
+        &self,
+        destination: &SocketAddr,
+    ) -> bool {
+        is_excluded(destination, &self.upstream_addr, &self.exclusions)
+    }
+
+    /// Binds a listener for intercepted connections, on a transparent socket in `Tproxy` mode.
+    pub fn bind(
+        &self,
+        address: SocketAddr,
+    ) -> Result<TcpListener> {
+        let socket = match address {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+
+        socket.set_reuseaddr(true)?;
+        if self.mode == RedirectMode::Tproxy {
+            set_transparent(&socket, address.is_ipv6())?;
+        }
+
+        socket.bind(address)?;
+        Ok(socket.listen(1024)?)
+    }
+
+    /// Recovers the destination that an intercepted connection was originally meant for.
+    pub fn original_dst(
+        &self,
+        incoming: &TcpStream,
+    ) -> Result<SocketAddr> {
+        match self.mode {
+            RedirectMode::Nat => crate::get_original_dst(incoming),
+            // With TPROXY, the connection is accepted on behalf of its original destination.
+            RedirectMode::Tproxy => Ok(incoming.local_addr()?),
+        }
+    }
+
+    /// Forwards an intercepted connection to its original destination.
+    pub async fn redirect(
+        &self,
+        incoming: TcpStream,
+    ) -> Result<()> {
+        let destination = self.original_dst(&incoming)?;
+        self.forward(incoming, destination).await
+    }
+
+    /// Forwards a connection to `destination`, through the upstream unless the destination is excluded.
+    pub async fn forward(
+        &self,
+        incoming: TcpStream,
+        destination: SocketAddr,
+    ) -> Result<()> {
+        let mut incoming = incoming;
+
+        let mut outgoing = if self.is_excluded(&destination) {
+            debug!("Connecting directly to excluded destination: {}", destination);
+            TcpStream::connect(destination).await?
+        } else {
+            match &self.upstream {
+                Upstream::Socks5(client) => client.connect(destination.to_string()).await?.0,
+                Upstream::Socks6(client, options) => {
+                    let initial_data = try_take_initial_data(&incoming)?;
+                    client.connect(destination.to_string(), initial_data, options.clone()).await?.0
+                }
+            }
+        };
+
+        let stats = self.relay.run(&mut incoming, &mut outgoing).await?;
+        if stats.end == RelayEnd::Idle {
+            debug!("Closed idle connection to {}.", destination);
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns whether traffic to `destination` bypasses `upstream`, by being in one of the `exclusions`.
+fn is_excluded(
+    destination: &SocketAddr,
+    upstream: &SocketAddr,
+    exclusions: &[IpNet],
+) -> bool {
+    // Traffic to the upstream itself must never be redirected back to it.
+    destination == upstream || exclusions.iter().any(|network| network.contains(&destination.ip()))
+}
+
+/// Takes the data the client already sent, without waiting for it: the server may have to speak first.
+fn try_take_initial_data(incoming: &TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut initial_data = Vec::with_capacity(2usize.pow(14)); // 16KB is the max
+
+    match incoming.try_read_buf(&mut initial_data) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(initial_data)),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A UDP flow, from a client to the original destination of its datagrams.
+type Flow = (SocketAddr, SocketAddr);
+
+/// Forwards UDP datagrams intercepted by an iptables `TPROXY` rule through a SOCKS5 UDP association, to their
+/// original destination. Replies are sent back from the original destination, so that the redirection is invisible.
+#[derive(Clone)]
+pub struct UdpRedirector {
+    client: Socks5Client,
+    upstream_addr: SocketAddr,
+    exclusions: Vec<IpNet>,
+    socket: Arc<UdpSocket>,
+    flows: Arc<Mutex<HashMap<Flow, mpsc::Sender<Vec<u8>>>>>,
+    idle_timeout: Duration,
+}
+
+impl UdpRedirector {
+    /// Binds a transparent UDP socket for intercepted datagrams (requires `CAP_NET_ADMIN`).
+    ///
+    /// # Parameters
+    /// - `upstream`: The SOCKS5 proxy to forward datagrams through.
+    /// - `address`: The address that the `TPROXY` rule delivers datagrams to.
+    ///
+    /// # Returns
+    /// A `Result` containing the new `UdpRedirector`, or an error if the socket cannot be set up.
+    pub async fn bind(
+        upstream: ProxyAddress,
+        address: SocketAddr,
+    ) -> Result<Self> {
+        ensure!(
+            upstream.socks_version == SOCKS_VER_5,
+            "UDP redirection is only supported through SOCKS5 proxies."
+        );
+
+        let proxy_addr = crate::resolve_addr(Address::try_from(&upstream)?.to_string()).await?;
+        let client = Socks5Client::new(proxy_addr.to_string(), upstream.credentials).await?;
+
+        let socket = bind_transparent_udp(address, true)?;
+
+        Ok(UdpRedirector {
+            client,
+            upstream_addr: proxy_addr,
+            exclusions: vec![],
+            socket: Arc::new(socket),
+            flows: Arc::default(),
+            idle_timeout: UDP_IDLE_TIMEOUT,
+        })
+    }
+
+    /// Sends datagrams to destinations in the given networks directly, instead of through the upstream.
+    pub fn with_exclusions(
+        mut self,
+        exclusions: Vec<IpNet>,
+    ) -> Self {
+        self.exclusions.extend(exclusions);
+        self
+    }
+
+    /// Returns whether datagrams to `destination` bypass the upstream.
+    pub fn is_excluded(
+        &self,
+        destination: &SocketAddr,
+    ) -> bool {
+        is_excluded(destination, &self.upstream_addr, &self.exclusions)
+    }
+
+    /// Closes flows, and their UDP associations, after `idle_timeout` without traffic.
+    pub fn with_idle_timeout(
+        mut self,
+        idle_timeout: Duration,
+    ) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Returns the address of the socket that intercepted datagrams are received on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Receives intercepted datagrams, and forwards each one over the UDP association of its flow.
+    pub async fn run(&self) -> Result<()> {
+        let mut buffer = vec![0u8; u16::MAX as usize];
+
+        loop {
+            let (length, source, destination) = recv_with_original_dst(&self.socket, &mut buffer).await?;
+            self.dispatch(buffer[..length].to_vec(), source, destination);
+        }
+    }
+
+    /// Hands a datagram to the task of its flow, starting one if there is none yet.
+    fn dispatch(
+        &self,
+        payload: Vec<u8>,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) {
+        let mut flows = self.flows.lock().unwrap();
+
+        let payload = match flows.get(&(source, destination)) {
+            Some(sender) => match sender.try_send(payload) {
+                Ok(()) => return,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    debug!("Dropping datagram from {} to {}: flow is congested.", source, destination);
+                    return;
+                }
+                // The flow has just timed out; start a new one.
+                Err(mpsc::error::TrySendError::Closed(payload)) => payload,
+            },
+            None => payload,
+        };
+
+        let (sender, receiver) = mpsc::channel(64);
+        sender.try_send(payload).expect("new channel has capacity");
+        flows.insert((source, destination), sender);
+
+        let redirector = self.clone();
+        tokio::spawn(async move {
+            if let Err(error) = redirector.relay(source, destination, receiver).await {
+                warn!("UDP flow from {} to {} failed: {:?}", source, destination, error);
+            }
+
+            let mut flows = redirector.flows.lock().unwrap();
+            if flows.get(&(source, destination)).is_some_and(|sender| sender.is_closed()) {
+                flows.remove(&(source, destination));
+            }
+        });
+    }
+
+    /// Relays the datagrams of a single flow, until it has been idle for too long.
+    async fn relay(
+        &self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        mut receiver: mpsc::Receiver<Vec<u8>>,
+    ) -> Result<()> {
+        let outgoing = if self.is_excluded(&destination) {
+            debug!("Sending datagrams directly to excluded destination: {}", destination);
+            UdpOutgoing::direct(destination).await?
+        } else {
+            UdpOutgoing::Proxied(self.client.udp_associate().await?)
+        };
+
+        // Replies must appear to come from the address the client sent its datagrams to. The socket is connected
+        // to the client, so that later datagrams of the flow may be delivered to it instead of to the redirector.
+        let spoofed = bind_transparent_udp(destination, false)?;
+        spoofed.connect(source).await?;
+
+        let mut outbound = vec![0u8; u16::MAX as usize];
+        let mut inbound = vec![0u8; u16::MAX as usize];
+
+        loop {
+            tokio::select! {
+                payload = receiver.recv() => match payload {
+                    Some(payload) => {
+                        outgoing.send_to(&payload, destination).await?;
+                    }
+                    None => break,
+                },
+                length = spoofed.recv(&mut outbound) => {
+                    outgoing.send_to(&outbound[..length?], destination).await?;
+                }
+                length = outgoing.recv(&mut inbound) => {
+                    spoofed.send(&inbound[..length?]).await?;
+                }
+                _ = tokio::time::sleep(self.idle_timeout) => break,
+            }
+        }
+
+        receiver.close();
+        Ok(())
+    }
+}
+
+/// Where the datagrams of a flow are sent: through a SOCKS5 UDP association, or directly to an excluded destination.
+enum UdpOutgoing {
+    Proxied(Socks5UdpSession),
+    Direct(UdpSocket),
+}
+
+impl UdpOutgoing {
+    /// Binds a socket that exchanges datagrams with `destination` only.
+    async fn direct(destination: SocketAddr) -> Result<Self> {
+        let unspecified: SocketAddr = match destination {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(unspecified).await?;
+        socket.connect(destination).await?;
+
+        Ok(UdpOutgoing::Direct(socket))
+    }
+
+    async fn send_to(
+        &self,
+        payload: &[u8],
+        destination: SocketAddr,
+    ) -> Result<()> {
+        match self {
+            UdpOutgoing::Proxied(session) => session.send_to(payload, destination).await.map(|_| ()),
+            UdpOutgoing::Direct(socket) => Ok(socket.send(payload).await.map(|_| ())?),
+        }
+    }
+
+    /// Receives the payload of a reply into `buffer`.
+    async fn recv(
+        &self,
+        buffer: &mut [u8],
+    ) -> Result<usize> {
+        match self {
+            UdpOutgoing::Proxied(session) => Ok(session.recv_from(buffer).await?.0),
+            UdpOutgoing::Direct(socket) => Ok(socket.recv(buffer).await?),
+        }
+    }
+}
+
+/// Binds a UDP socket that may use addresses that are not local (requires `CAP_NET_ADMIN`). Sockets that
+/// receive intercepted datagrams also report the original destination of every datagram.
+fn bind_transparent_udp(
+    address: SocketAddr,
+    recv_original_dst: bool,
+) -> Result<UdpSocket> {
+    let socket = match address {
+        SocketAddr::V4(_) => socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)?,
+        SocketAddr::V6(_) => socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None)?,
+    };
+
+    // Replies from the same original destination to different clients each get their own socket.
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    set_transparent(&socket, address.is_ipv6())?;
+    if recv_original_dst {
+        set_recv_original_dst(&socket, address.is_ipv6())?;
+    }
+
+    socket.bind(&address.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Allows the socket to use addresses that are not local (requires `CAP_NET_ADMIN`).
+#[cfg(target_os = "linux")]
+fn set_transparent<S: std::os::unix::io::AsFd>(
+    socket: &S,
+    ipv6: bool,
+) -> Result<()> {
+    use nix::sys::socket::{self, sockopt};
+
+    if ipv6 {
+        // There is no `IPV6_TRANSPARENT` socket option in nix.
+        set_ipv6_option(socket, libc::IPV6_TRANSPARENT)
+    } else {
+        Ok(socket::setsockopt(socket, sockopt::IpTransparent, &true)?)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_transparent<S>(
+    _socket: &S,
+    _ipv6: bool,
+) -> Result<()> {
+    bail!("Transparent proxying (TPROXY) is only supported on Linux.")
+}
+
+/// Makes `recvmsg` report the original destination of every datagram.
+#[cfg(target_os = "linux")]
+fn set_recv_original_dst<S: std::os::unix::io::AsFd>(
+    socket: &S,
+    ipv6: bool,
+) -> Result<()> {
+    use nix::sys::socket::{self, sockopt};
+
+    if ipv6 {
+        socket::setsockopt(socket, sockopt::Ipv6OrigDstAddr, &true)?;
+    }
+
+    // Dual-stack sockets receive IPv4 datagrams too.
+    match socket::setsockopt(socket, sockopt::Ipv4OrigDstAddr, &true) {
+        Err(_) if ipv6 => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_recv_original_dst<S>(
+    _socket: &S,
+    _ipv6: bool,
+) -> Result<()> {
+    bail!("Transparent proxying (TPROXY) is only supported on Linux.")
+}
+
+/// Enables a boolean `SOL_IPV6` socket option.
+#[cfg(target_os = "linux")]
+fn set_ipv6_option<S: std::os::unix::io::AsFd>(
+    socket: &S,
+    option: libc::c_int,
+) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_fd().as_raw_fd(),
+            libc::SOL_IPV6,
+            option,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// Receives a datagram, along with its source and the destination it was originally sent to.
+#[cfg(target_os = "linux")]
+async fn recv_with_original_dst(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> Result<(usize, SocketAddr, SocketAddr)> {
+    use std::io::IoSliceMut;
+    use std::os::unix::io::AsRawFd;
+
+    use nix::sys::socket::{self, ControlMessageOwned, MsgFlags, SockaddrStorage};
+    use tokio::io::Interest;
+
+    use crate::util::{from_sockaddr_in, from_sockaddr_in6};
+
+    let received = socket
+        .async_io(Interest::READABLE, || {
+            let mut iov = [IoSliceMut::new(buffer)];
+            let mut cmsg = nix::cmsg_space!(libc::sockaddr_in6);
+
+            let message = socket::recvmsg::<SockaddrStorage>(
+                socket.as_raw_fd(),
+                &mut iov,
+                Some(&mut cmsg),
+                MsgFlags::empty(),
+            )
+            .map_err(io::Error::from)?;
+
+            let source = message.address.and_then(|address| {
+                address
+                    .as_sockaddr_in()
+                    .map(|address| SocketAddr::from(std::net::SocketAddrV4::from(*address)))
+                    .or_else(|| address.as_sockaddr_in6().map(|address| SocketAddr::from(std::net::SocketAddrV6::from(*address))))
+            });
+
+            let mut destination = None;
+            for cmsg in message.cmsgs().map_err(io::Error::from)? {
+                match cmsg {
+                    ControlMessageOwned::Ipv4OrigDstAddr(address) => {
+                        destination = Some(SocketAddr::from(from_sockaddr_in(&address)));
+                    }
+                    ControlMessageOwned::Ipv6OrigDstAddr(address) => {
+                        destination = Some(SocketAddr::from(from_sockaddr_in6(&address)));
+                    }
+                    _ => {}
+                }
+            }
+
+            Ok((message.bytes, source, destination))
+        })
+        .await?;
+
+    match received {
+        (length, Some(source), Some(destination)) => Ok((length, source, destination)),
+        (_, source, _) => bail!("Unable to recover the original destination of a datagram from {:?}.", source),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn recv_with_original_dst(
+    _socket: &UdpSocket,
+    _buffer: &mut [u8],
+) -> Result<(usize, SocketAddr, SocketAddr)> {
+    bail!("Transparent proxying (TPROXY) is only supported on Linux.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(scheme: &str) -> ProxyAddress {
+        ProxyAddress::try_from(format!("{}://127.0.0.1:1080", scheme)).unwrap()
+    }
+
+    #[test]
+    fn test_redirect_mode_from_str() {
+        assert_eq!("nat".parse::<RedirectMode>().unwrap(), RedirectMode::Nat);
+        assert_eq!("TPROXY".parse::<RedirectMode>().unwrap(), RedirectMode::Tproxy);
+        assert!("masquerade".parse::<RedirectMode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_is_excluded() {
+        let redirector = Redirector::new(upstream("socks6"), vec![], RedirectMode::Nat).await.unwrap();
+
+        assert!(redirector.is_excluded(&"127.0.0.1:1080".parse().unwrap()));
+        assert!(!redirector.is_excluded(&"127.0.0.1:443".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_exclusions() {
+        let exclusions = vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+        let redirector = Redirector::new(upstream("socks5"), vec![], RedirectMode::Nat)
+            .await
+            .unwrap()
+            .with_exclusions(exclusions);
+
+        assert!(redirector.is_excluded(&"10.1.2.3:80".parse().unwrap()));
+        assert!(redirector.is_excluded(&"[fd12::1]:80".parse().unwrap()));
+        assert!(!redirector.is_excluded(&"11.1.2.3:80".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_socks5_chain_is_rejected() {
+        let result = Redirector::new(upstream("socks5"), vec![upstream("socks6")], RedirectMode::Nat).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,479 @@
+use std::future::{self, Future};
+use std::io;
+use std::pin::Pin;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::ready;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(target_os = "linux")]
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+use tokio_util::sync::CancellationToken;
+
+#[cfg(target_os = "linux")]
+use crate::functions::Direction;
+#[cfg(target_os = "linux")]
+use crate::splice;
+
+/// The size of the buffer of either direction, unless configured otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Why a relay ended.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayEnd {
+    /// Both sides reached EOF, and the EOF was passed on to the other side.
+    Completed,
+    /// No data was relayed in either direction for the idle timeout.
+    Idle,
+    /// The relay was cancelled with its cancellation token.
+    Cancelled,
+    /// Either stream failed, see the `RelayError`.
+    Failed,
+}
+
+/// What a relay moved, and how it ended.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct RelayStats {
+    /// The bytes relayed from `a` to `b`.
+    pub upstream: u64,
+    /// The bytes relayed from `b` to `a`.
+    pub downstream: u64,
+    pub duration: Duration,
+    pub end: RelayEnd,
+}
+
+/// An error of either stream of a relay, along with what was relayed before it.
+#[derive(Debug, Error)]
+#[error("{error}")]
+pub struct RelayError {
+    /// What was relayed until the error, which ends as `RelayEnd::Failed`.
+    pub stats: RelayStats,
+    pub error: io::Error,
+}
+
+impl From<io::Error> for RelayError {
+    /// An error before any data was relayed.
+    fn from(error: io::Error) -> Self {
+        Self {
+            stats: RelayStats {
+                upstream: 0,
+                downstream: 0,
+                duration: Duration::ZERO,
+                end: RelayEnd::Failed,
+            },
+            error,
+        }
+    }
+}
+
+impl From<RelayError> for io::Error {
+    fn from(error: RelayError) -> Self {
+        error.error
+    }
+}
+
+/// Returns the stats of a relay that ended, or failed, after moving `upstream` and `downstream` bytes.
+fn finish(
+    end: io::Result<RelayEnd>,
+    upstream: u64,
+    downstream: u64,
+    started: Instant,
+) -> Result<RelayStats, RelayError> {
+    let stats = |end| RelayStats {
+        upstream,
+        downstream,
+        duration: started.elapsed(),
+        end,
+    };
+
+    match end {
+        Ok(end) => Ok(stats(end)),
+        Err(error) => Err(RelayError {
+            stats: stats(RelayEnd::Failed),
+            error,
+        }),
+    }
+}
+
+/// Relays data between two streams, in both directions, until both reached EOF.
+///
+/// Once one side reaches EOF, the other side is shut down for writing, so that it sees the EOF as well, while data
+/// keeps flowing in the opposite direction (a TCP half-close). A relay can also end early, once neither direction moved
+/// data for the idle timeout, or once it is cancelled; neither is an error, both are reported in the `RelayStats`.
+#[derive(Clone, Debug)]
+pub struct Relay {
+    buffer_size: usize,
+    idle_timeout: Option<Duration>,
+    cancellation: Option<CancellationToken>,
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        Self {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            idle_timeout: None,
+            cancellation: None,
+        }
+    }
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffers up to `buffer_size` bytes in either direction (at least one).
+    ///
+    /// This does not apply to `splice`, which moves data through pipes of `splice::PIPE_SIZE` instead.
+    pub fn with_buffer_size(
+        mut self,
+        buffer_size: usize,
+    ) -> Self {
+        self.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    /// Ends the relay once neither direction moved data for `idle_timeout`.
+    pub fn with_idle_timeout(
+        mut self,
+        idle_timeout: Duration,
+    ) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Ends the relay once `cancellation` is cancelled.
+    pub fn with_cancellation(
+        mut self,
+        cancellation: CancellationToken,
+    ) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Relays data between `a` and `b`, where data from `a` to `b` is upstream.
+    ///
+    /// # Returns
+    ///
+    /// What was relayed in either direction, and why the relay ended; or the first error of either stream, along with
+    /// what was relayed before it.
+    pub async fn run<A, B>(
+        &self,
+        a: &mut A,
+        b: &mut B,
+    ) -> Result<RelayStats, RelayError>
+    where
+        A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+        B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    {
+        let started = Instant::now();
+        let mut upstream = Transfer::new(self.buffer_size);
+        let mut downstream = Transfer::new(self.buffer_size);
+
+        let cancellation = self.cancellation.clone().unwrap_or_default();
+        let cancelled = cancellation.cancelled();
+        tokio::pin!(cancelled);
+        let idle = time::sleep(self.idle_timeout.unwrap_or_default());
+        tokio::pin!(idle);
+
+        let end = future::poll_fn(|cx| {
+            if cancelled.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Ok(RelayEnd::Cancelled));
+            }
+
+            let mut active = false;
+            if let Poll::Ready(Err(error)) = upstream.poll(cx, &mut *a, &mut *b, &mut active) {
+                return Poll::Ready(Err(error));
+            }
+            if let Poll::Ready(Err(error)) = downstream.poll(cx, &mut *b, &mut *a, &mut active) {
+                return Poll::Ready(Err(error));
+            }
+            if upstream.done && downstream.done {
+                return Poll::Ready(Ok(RelayEnd::Completed));
+            }
+
+            if let Some(idle_timeout) = self.idle_timeout {
+                if active {
+                    idle.as_mut().reset(Instant::now() + idle_timeout);
+                }
+                if idle.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Ok(RelayEnd::Idle));
+                }
+            }
+
+            Poll::Pending
+        })
+        .await;
+
+        finish(end, upstream.bytes, downstream.bytes, started)
+    }
+
+    /// Relays data between the sockets `a` and `b` with `splice(2)`, so that it is never copied to userspace.
+    ///
+    /// `progress` is called with the direction and the number of bytes after every move, see
+    /// `splice::copy_bidirectional`. Otherwise, this behaves like `run`.
+    #[cfg(target_os = "linux")]
+    pub async fn splice<F>(
+        &self,
+        a: &mut TcpStream,
+        b: &mut TcpStream,
+        progress: F,
+    ) -> Result<RelayStats, RelayError>
+    where
+        F: Fn(Direction, u64),
+    {
+        let started = Instant::now();
+        let upstream = AtomicU64::new(0);
+        let downstream = AtomicU64::new(0);
+        // Milliseconds since the start, at which data was last moved.
+        let active_at = AtomicU64::new(0);
+
+        let copy = splice::copy_bidirectional(a, b, |direction, bytes| {
+            let counter = match direction {
+                Direction::Upstream => &upstream,
+                Direction::Downstream => &downstream,
+            };
+            counter.fetch_add(bytes, Ordering::Relaxed);
+            active_at.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+            progress(direction, bytes);
+        });
+
+        let idle = async {
+            let idle_timeout = match self.idle_timeout {
+                Some(idle_timeout) => idle_timeout,
+                None => return future::pending().await,
+            };
+
+            loop {
+                let deadline = started + Duration::from_millis(active_at.load(Ordering::Relaxed)) + idle_timeout;
+                if Instant::now() >= deadline {
+                    return;
+                }
+                time::sleep_until(deadline).await;
+            }
+        };
+
+        let cancellation = self.cancellation.clone().unwrap_or_default();
+        let end = tokio::select! {
+            copied = copy => copied.map(|_| RelayEnd::Completed),
+            _ = idle => Ok(RelayEnd::Idle),
+            _ = cancellation.cancelled() => Ok(RelayEnd::Cancelled),
+        };
+
+        finish(end, upstream.load(Ordering::Relaxed), downstream.load(Ordering::Relaxed), started)
+    }
+}
+
+/// Relays data between `a` and `b` with the defaults of `Relay`: without an idle timeout or cancellation.
+pub async fn copy_bidirectional<A, B>(
+    a: &mut A,
+    b: &mut B,
+) -> Result<RelayStats, RelayError>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    Relay::default().run(a, b).await
+}
+
+/// One direction of a relay, from a reader to a writer, through a buffer.
+struct Transfer {
+    buffer: Box<[u8]>,
+    /// The range of the buffer that was read, but not written yet.
+    start: usize,
+    end: usize,
+    eof: bool,
+    needs_flush: bool,
+    done: bool,
+    bytes: u64,
+}
+
+impl Transfer {
+    fn new(buffer_size: usize) -> Self {
+        Self {
+            buffer: vec![0; buffer_size].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            eof: false,
+            needs_flush: false,
+            done: false,
+            bytes: 0,
+        }
+    }
+
+    /// Moves data from `reader` to `writer` until `reader` reaches EOF, and then shuts down `writer` for writing.
+    ///
+    /// Sets `active` once data was read or written.
+    fn poll<R, W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        reader: &mut R,
+        writer: &mut W,
+        active: &mut bool,
+    ) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        if self.done {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            // Only read once everything that was read before is written.
+            if self.start == self.end && !self.eof {
+                let mut buffer = ReadBuf::new(&mut self.buffer);
+                match Pin::new(&mut *reader).poll_read(cx, &mut buffer) {
+                    Poll::Ready(Ok(())) if buffer.filled().is_empty() => self.eof = true,
+                    Poll::Ready(Ok(())) => {
+                        self.start = 0;
+                        self.end = buffer.filled().len();
+                        *active = true;
+                    }
+                    Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                    Poll::Pending => {
+                        // Don't hold back what was written while waiting for more.
+                        if self.needs_flush {
+                            ready!(Pin::new(&mut *writer).poll_flush(cx))?;
+                            self.needs_flush = false;
+                        }
+
+                        return Poll::Pending;
+                    }
+                }
+            }
+
+            while self.start < self.end {
+                let written = ready!(Pin::new(&mut *writer).poll_write(cx, &self.buffer[self.start..self.end]))?;
+                if written == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+
+                self.start += written;
+                self.bytes += written as u64;
+                self.needs_flush = true;
+                *active = true;
+            }
+
+            if self.eof {
+                // Pass the EOF on, which also flushes the writer.
+                ready!(Pin::new(&mut *writer).poll_shutdown(cx))?;
+                self.done = true;
+
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_half_close() {
+        let (mut client, mut a) = tokio::io::duplex(64);
+        let (mut b, mut server) = tokio::io::duplex(64);
+
+        let relay = Relay::new().with_buffer_size(16);
+        let relay = async { relay.run(&mut a, &mut b).await.unwrap() };
+
+        // The client is done sending, but still receives the response.
+        let client = async {
+            client.write_all(&[1; 100]).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let mut response = vec![];
+            client.read_to_end(&mut response).await.unwrap();
+            response
+        };
+        let server = async {
+            let mut request = vec![];
+            server.read_to_end(&mut request).await.unwrap();
+            server.write_all(&[2; 300]).await.unwrap();
+            drop(server);
+            request
+        };
+
+        let (stats, response, request) = tokio::join!(relay, client, server);
+        assert_eq!(request, [1; 100]);
+        assert_eq!(response, [2; 300]);
+        assert_eq!((stats.upstream, stats.downstream, stats.end), (100, 300, RelayEnd::Completed));
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (mut client, mut a) = tokio::io::duplex(64);
+        let (mut b, _server) = tokio::io::duplex(64);
+
+        let relay = Relay::new().with_idle_timeout(Duration::from_millis(200));
+        let relay = async { relay.run(&mut a, &mut b).await.unwrap() };
+
+        // Activity defers the timeout.
+        let client = async {
+            for _ in 0..3 {
+                time::sleep(Duration::from_millis(100)).await;
+                client.write_all(b"ping").await.unwrap();
+            }
+            client
+        };
+
+        let (stats, _client) = tokio::join!(relay, client);
+        assert_eq!(stats.end, RelayEnd::Idle);
+        assert_eq!(stats.upstream, 12);
+        assert!(stats.duration >= Duration::from_millis(500), "{:?}", stats.duration);
+    }
+
+    #[tokio::test]
+    async fn test_cancellation() {
+        let (_client, mut a) = tokio::io::duplex(64);
+        let (mut b, _server) = tokio::io::duplex(64);
+
+        let cancellation = CancellationToken::new();
+        let relay = Relay::new().with_cancellation(cancellation.clone());
+        cancellation.cancel();
+
+        let stats = relay.run(&mut a, &mut b).await.unwrap();
+        assert_eq!(stats.end, RelayEnd::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_failure() {
+        let (mut client, mut a) = tokio::io::duplex(64);
+        let (mut b, mut server) = tokio::io::duplex(64);
+
+        let relay = async { Relay::new().run(&mut a, &mut b).await.unwrap_err() };
+
+        // The server goes away after the first request, so relaying the second one fails.
+        let peers = async {
+            client.write_all(&[1; 10]).await.unwrap();
+            let mut request = [0; 10];
+            server.read_exact(&mut request).await.unwrap();
+            drop(server);
+
+            client.write_all(&[1; 5]).await.unwrap();
+            client
+        };
+
+        let (error, _client) = tokio::join!(relay, peers);
+        assert_eq!(error.error.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!((error.stats.upstream, error.stats.downstream, error.stats.end), (10, 0, RelayEnd::Failed));
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use futures::ready;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::sync::CancellationToken;
+
+use crate::access_log::AccessRecord;
+use crate::functions::Direction;
+use crate::metrics::RelayedBytes;
+
+/// The ID of the next session, unique within the process.
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Returns a new session ID, that is unique within the process.
+///
+/// Sessions and the spans of their connections share IDs, so that the sessions listed by the admin API can be found in
+/// the log.
+pub fn next_id() -> u64 {
+    NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A snapshot of an active session.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    /// The address of the client.
+    pub client: Option<SocketAddr>,
+    /// The user that the client authenticated as, if any.
+    pub user: Option<String>,
+    /// The protocol of the handler, e.g., `socks5`.
+    pub protocol: String,
+    /// The destination that the client requested, once the handshake completed.
+    pub destination: Option<String>,
+    /// The addresses of the links of the chain of the request, if it is part of one.
+    pub chain: Vec<String>,
+    /// The position of the proxy in `chain`.
+    pub chain_index: Option<usize>,
+    /// When the session started, in milliseconds since the Unix epoch.
+    pub started_at: u64,
+    /// How long the session has been active, in milliseconds.
+    pub age_ms: u64,
+    /// The number of bytes relayed from the client to the destination so far.
+    pub upstream: u64,
+    /// The number of bytes relayed from the destination to the client so far.
+    pub downstream: u64,
+}
+
+/// The bytes relayed by a session so far.
+#[derive(Debug, Default)]
+struct Traffic {
+    upstream: AtomicU64,
+    downstream: AtomicU64,
+}
+
+/// An active session, as kept by the registry.
+struct Entry {
+    info: SessionInfo,
+    started: Instant,
+    traffic: Arc<Traffic>,
+    cancel: CancellationToken,
+}
+
+/// The active sessions of one or more handlers.
+///
+/// Clones share the same sessions. Handlers register every connection they accept, and the registry lists them and
+/// kills them on request, e.g., from the admin API.
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<u64, Entry>>>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a session of the given protocol with `client`, that remains active until the returned handle drops.
+    pub fn register(
+        &self,
+        protocol: &str,
+        client: Option<SocketAddr>,
+    ) -> Session {
+        let id = next_id();
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+
+        let info = SessionInfo {
+            id,
+            client,
+            user: None,
+            protocol: protocol.to_string(),
+            destination: None,
+            chain: vec![],
+            chain_index: None,
+            started_at,
+            age_ms: 0,
+            upstream: 0,
+            downstream: 0,
+        };
+
+        let traffic = Arc::new(Traffic::default());
+        let cancel = CancellationToken::new();
+        let entry = Entry {
+            info,
+            started: Instant::now(),
+            traffic: Arc::clone(&traffic),
+            cancel: cancel.clone(),
+        };
+        self.sessions.lock().unwrap().insert(id, entry);
+
+        Session {
+            id,
+            registry: self.clone(),
+            traffic,
+            relayed: None,
+            cancel,
+        }
+    }
+
+    /// Returns a snapshot of every active session, oldest first.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+
+        let mut list: Vec<_> = sessions
+            .values()
+            .map(|entry| SessionInfo {
+                age_ms: entry.started.elapsed().as_millis() as u64,
+                upstream: entry.traffic.upstream.load(Ordering::Relaxed),
+                downstream: entry.traffic.downstream.load(Ordering::Relaxed),
+                ..entry.info.clone()
+            })
+            .collect();
+
+        list.sort_by_key(|info| info.id);
+        list
+    }
+
+    /// Returns the number of active sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Kills the session with the given ID, and returns whether it was active.
+    ///
+    /// The handler closes the connection of the session as soon as it notices, but the session remains listed until
+    /// it did.
+    pub fn kill(
+        &self,
+        id: u64,
+    ) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The handle of an active session, that unregisters it when dropped.
+pub struct Session {
+    id: u64,
+    registry: SessionRegistry,
+    traffic: Arc<Traffic>,
+    relayed: Option<RelayedBytes>,
+    cancel: CancellationToken,
+}
+
+impl Session {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Adds the bytes that the session relays to `relayed` as well, as they move.
+    pub fn with_relayed_bytes(
+        mut self,
+        relayed: RelayedBytes,
+    ) -> Self {
+        self.relayed = Some(relayed);
+        self
+    }
+
+    /// Updates the session with what the handshake revealed, as recorded for the access log.
+    pub fn update(
+        &self,
+        record: &AccessRecord,
+    ) {
+        if let Some(entry) = self.registry.sessions.lock().unwrap().get_mut(&self.id) {
+            entry.info.user = record.user.clone();
+            entry.info.destination = record.destination.clone();
+            entry.info.chain = record.chain.clone();
+            entry.info.chain_index = record.chain_index;
+        }
+    }
+
+    /// Wraps the stream to the client, to count the bytes relayed over it.
+    pub fn track<S>(
+        &self,
+        stream: S,
+    ) -> TrackedStream<S> {
+        TrackedStream {
+            inner: stream,
+            traffic: Arc::clone(&self.traffic),
+            relayed: self.relayed.clone(),
+        }
+    }
+
+    /// Counts `bytes` relayed in `direction`, for relays that bypass the stream of `track`.
+    pub fn count(
+        &self,
+        direction: Direction,
+        bytes: u64,
+    ) {
+        let counter = match direction {
+            Direction::Upstream => &self.traffic.upstream,
+            Direction::Downstream => &self.traffic.downstream,
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+
+        if let Some(relayed) = &self.relayed {
+            relayed.add(direction, bytes);
+        }
+    }
+
+    /// Completes once the session is killed.
+    pub async fn killed(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// Returns a token that is cancelled once the session is killed, e.g., to cancel its relay.
+    pub fn cancellation(&self) -> CancellationToken {
+        self.cancel.child_token()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// A stream to the client of a session, that counts the bytes read from it as upstream, and the bytes written to it as
+/// downstream.
+pub struct TrackedStream<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+    relayed: Option<RelayedBytes>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TrackedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        let read = (buf.filled().len() - filled) as u64;
+        this.traffic.upstream.fetch_add(read, Ordering::Relaxed);
+        if let Some(relayed) = &this.relayed {
+            relayed.add(Direction::Upstream, read);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TrackedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.traffic.downstream.fetch_add(written as u64, Ordering::Relaxed);
+        if let Some(relayed) = &this.relayed {
+            relayed.add(Direction::Downstream, written as u64);
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_registry() {
+        let registry = SessionRegistry::new();
+        let client = SocketAddr::from(([127, 0, 0, 1], 40000));
+
+        let first = registry.register("socks5", Some(client));
+        let second = registry.register("socks6", None);
+        assert!(second.id() > first.id());
+
+        let mut record = AccessRecord::new("socks6", None);
+        record.destination = Some(String::from("example.com:443"));
+        record.chain_index = Some(1);
+        second.update(&record);
+
+        let (mut stream, mut peer) = tokio::io::duplex(64);
+        let mut stream = first.track(&mut stream);
+        peer.write_all(b"hello").await.unwrap();
+        stream.read_exact(&mut [0u8; 5]).await.unwrap();
+        stream.write_all(b"hi").await.unwrap();
+
+        let list = registry.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].client, Some(client));
+        assert_eq!((list[0].upstream, list[0].downstream), (5, 2));
+        assert_eq!(list[1].destination.as_deref(), Some("example.com:443"));
+        assert_eq!(list[1].chain_index, Some(1));
+
+        drop(second);
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_kill() {
+        let registry = SessionRegistry::new();
+        let session = registry.register("socks5", None);
+
+        assert!(registry.kill(session.id()));
+        session.killed().await;
+
+        let id = session.id();
+        drop(session);
+        assert!(!registry.kill(id));
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::TcpStream;
+
+/// The length of the queue of pending connections of a listener, unless configured otherwise.
+pub const DEFAULT_BACKLOG: i32 = 1024;
+
+/// Options of TCP sockets, that are left at the defaults of the OS unless set.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SocketOptions {
+    /// Disables Nagle's algorithm (`TCP_NODELAY`).
+    pub nodelay: bool,
+    /// Sends keepalive probes once a connection was idle for this long (`SO_KEEPALIVE`).
+    pub keepalive: Option<Duration>,
+    /// The size of the receive buffer (`SO_RCVBUF`), in bytes.
+    pub recv_buffer_size: Option<usize>,
+    /// The size of the send buffer (`SO_SNDBUF`), in bytes.
+    pub send_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    pub fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Applies the options to a connected socket.
+    ///
+    /// Buffer sizes that are set once connected no longer affect the window scale that was agreed on, so listeners set
+    /// them ahead for the sockets they accept, see `bind`.
+    pub fn apply(
+        &self,
+        stream: &TcpStream,
+    ) -> io::Result<()> {
+        if self.is_default() {
+            return Ok(());
+        }
+
+        let socket = SockRef::from(stream);
+        self.apply_to(&socket)?;
+        if self.nodelay {
+            socket.set_tcp_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the buffer sizes of `socket`.
+    fn apply_to(
+        &self,
+        socket: &Socket,
+    ) -> io::Result<()> {
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Binds a non-blocking listener to `address`, that accepts sockets with the buffer sizes of `options`.
+///
+/// With `reuse_port`, the address can be shared with other listeners that set it as well (`SO_REUSEPORT`, on Unix
+/// only), among which the OS spreads the incoming connections.
+pub fn bind(
+    address: SocketAddr,
+    backlog: i32,
+    reuse_port: bool,
+    options: &SocketOptions,
+) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT is only supported on Unix"));
+    }
+    options.apply_to(&socket)?;
+
+    socket.bind(&address.into())?;
+    socket.listen(backlog)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
+}
+
+/// Binds `count` listeners to `address`, which share it with `SO_REUSEPORT` if there are several.
+///
+/// If the port of `address` is 0, the listeners share the port that the first one was given.
+pub fn bind_many(
+    address: SocketAddr,
+    count: usize,
+    backlog: i32,
+    options: &SocketOptions,
+) -> io::Result<Vec<TcpListener>> {
+    let reuse_port = count > 1;
+    let first = bind(address, backlog, reuse_port, options)?;
+    let address = first.local_addr()?;
+
+    let mut listeners = vec![first];
+    for _ in 1..count {
+        listeners.push(bind(address, backlog, reuse_port, options)?);
+    }
+
+    Ok(listeners)
+}
+
+/// Pins the current thread to the CPU at `index`, modulo the number of CPUs (on Linux only).
+#[cfg(target_os = "linux")]
+pub fn pin_to_cpu(index: usize) -> io::Result<()> {
+    let cpus = std::thread::available_parallelism()?.get();
+
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(index % cpus, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_many() {
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(30)),
+            recv_buffer_size: Some(64 * 1024),
+            send_buffer_size: None,
+        };
+        let listeners = bind_many("127.0.0.1:0".parse().unwrap(), 3, DEFAULT_BACKLOG, &options).unwrap();
+        let address = listeners[0].local_addr().unwrap();
+        assert!(listeners.iter().all(|listener| listener.local_addr().unwrap() == address));
+
+        // The OS picks one of the listeners for every connection.
+        let listeners: Vec<_> = listeners.into_iter().map(TcpListener::from_std).collect::<io::Result<_>>().unwrap();
+        let client = TcpStream::connect(address).await.unwrap();
+        let accepted = futures::future::select_all(listeners.iter().map(|listener| Box::pin(listener.accept())));
+        let (accepted, _) = accepted.await.0.unwrap();
+
+        options.apply(&accepted).unwrap();
+        options.apply(&client).unwrap();
+        assert!(accepted.nodelay().unwrap());
+        assert!(SockRef::from(&accepted).keepalive().unwrap());
+        // Linux doubles the requested size, to account for its bookkeeping.
+        assert!(SockRef::from(&accepted).recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[test]
+    fn test_default_options() {
+        assert!(SocketOptions::default().is_default());
+        assert!(!SocketOptions {
+            nodelay: true,
+            ..Default::default()
+        }
+        .is_default());
+    }
+}
@@ -0,0 +1,96 @@
+use std::net::SocketAddr;
+
+use tracing::field::{display, Empty};
+use tracing::Span;
+
+/// Creates the span of a connection, accepted from `client` by a handler of the given SOCKS version.
+///
+/// The span starts out with the ID of the session (see `sessions::next_id`), and the client address. Handlers record
+/// the `destination`, the `chain_index` and the authenticated `user` as soon as the handshake reveals them, so that
+/// every event of the handshake and relay phases carries them. To follow a flow along a chain, match the `local`
+/// address with which a proxy connected to the next hop against the `client` of the span of that hop.
+pub fn connection(
+    id: u64,
+    version: u8,
+    client: Option<SocketAddr>,
+) -> Span {
+    let span = info_span!(
+        "connection",
+        id,
+        client = Empty,
+        version,
+        destination = Empty,
+        chain_index = Empty,
+        user = Empty
+    );
+
+    if let Some(client) = client {
+        span.record("client", display(client));
+    }
+
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use tracing_subscriber::fmt::MakeWriter;
+
+    use super::*;
+
+    /// A writer that can still be read after it was handed to a subscriber.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(
+            &mut self,
+            buf: &[u8],
+        ) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_connection_span() {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::fmt().json().with_writer(buffer.clone()).finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let first = connection(1, 5, Some(SocketAddr::from(([127, 0, 0, 1], 40000))));
+            let second = connection(2, 6, None);
+
+            second.record("destination", display("example.com:443"));
+            second.record("chain_index", 1);
+            second.in_scope(|| info!("Connection closed."));
+            first.in_scope(|| info!("Connection closed."));
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(events.len(), 2);
+
+        let (second, first) = (&events[0]["span"], &events[1]["span"]);
+        assert_eq!(first["client"], "127.0.0.1:40000");
+        assert_eq!(first["version"], 5);
+        assert!(first.get("destination").is_none());
+        assert_eq!(second["version"], 6);
+        assert_eq!(second["destination"], "example.com:443");
+        assert_eq!(second["chain_index"], 1);
+        assert_eq!((first["id"].as_u64(), second["id"].as_u64()), (Some(1), Some(2)));
+    }
+}
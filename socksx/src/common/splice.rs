@@ -0,0 +1,202 @@
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+use socket2::SockRef;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+use crate::functions::Direction;
+
+/// The size requested for the pipes between two sockets, which bounds the bytes moved per `splice` call. The kernel
+/// caps it at `/proc/sys/fs/pipe-max-size`, and keeps the default (64 KiB) if that is smaller.
+pub const PIPE_SIZE: usize = 1 << 20;
+
+/// Relays data between `a` and `b`, in both directions, until both reached EOF, like
+/// `tokio::io::copy_bidirectional`.
+///
+/// Data moves from one socket to the other through a pipe, with `splice(2)`, so that it is never copied to userspace.
+/// `progress` is called with the direction (from `a` to `b` is upstream) and the number of bytes after every move.
+///
+/// # Returns
+///
+/// The number of bytes relayed from `a` to `b`, and from `b` to `a`.
+pub async fn copy_bidirectional<F>(
+    a: &mut TcpStream,
+    b: &mut TcpStream,
+    progress: F,
+) -> io::Result<(u64, u64)>
+where
+    F: Fn(Direction, u64),
+{
+    let (a, b) = (&*a, &*b);
+
+    let upstream = copy(a, b, |bytes| progress(Direction::Upstream, bytes));
+    let downstream = copy(b, a, |bytes| progress(Direction::Downstream, bytes));
+
+    tokio::try_join!(upstream, downstream)
+}
+
+/// Relays data from `source` to `destination` until `source` reaches EOF, and then shuts down `destination` for
+/// writing.
+async fn copy<F>(
+    source: &TcpStream,
+    destination: &TcpStream,
+    progress: F,
+) -> io::Result<u64>
+where
+    F: Fn(u64),
+{
+    let pipe = Pipe::new()?;
+    let mut total = 0;
+
+    loop {
+        // The pipe is empty here, so only the source can keep this from making progress.
+        let moved = source
+            .async_io(Interest::READABLE, || splice(source.as_raw_fd(), pipe.write.as_raw_fd(), pipe.size))
+            .await?;
+        if moved == 0 {
+            break;
+        }
+
+        let mut buffered = moved;
+        while buffered > 0 {
+            buffered -= destination
+                .async_io(Interest::WRITABLE, || splice(pipe.read.as_raw_fd(), destination.as_raw_fd(), buffered))
+                .await?;
+        }
+
+        total += moved as u64;
+        progress(moved as u64);
+    }
+
+    match SockRef::from(destination).shutdown(Shutdown::Write) {
+        Err(error) if error.kind() != io::ErrorKind::NotConnected => Err(error),
+        _ => Ok(total),
+    }
+}
+
+/// A non-blocking pipe, that is closed when dropped.
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    size: usize,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        // Keep the default size if the larger one is not allowed.
+        let size = unsafe {
+            libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, PIPE_SIZE as libc::c_int);
+            libc::fcntl(write.as_raw_fd(), libc::F_GETPIPE_SZ)
+        };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            read,
+            write,
+            size: size as usize,
+        })
+    }
+}
+
+/// Moves up to `length` bytes from `from` to `to`, of which at least one is a pipe, without blocking.
+fn splice(
+    from: RawFd,
+    to: RawFd,
+    length: usize,
+) -> io::Result<usize> {
+    let moved = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            length,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    if moved < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(moved as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Returns both ends of a TCP connection over the loopback interface.
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_copy_bidirectional() {
+        let (mut client, mut a) = socket_pair().await;
+        let (mut b, mut server) = socket_pair().await;
+
+        let upstream = AtomicU64::new(0);
+        let relay = async {
+            copy_bidirectional(&mut a, &mut b, |direction, bytes| {
+                if direction == Direction::Upstream {
+                    upstream.fetch_add(bytes, Ordering::Relaxed);
+                }
+            })
+            .await
+        };
+
+        // More than fits in a pipe, so that it takes several moves.
+        let data: Vec<u8> = (0..4 * PIPE_SIZE).map(|i| i as u8).collect();
+        let peers = async {
+            let (mut client_read, mut client_write) = client.split();
+            let (mut server_read, mut server_write) = server.split();
+
+            let send = async {
+                client_write.write_all(&data).await.unwrap();
+                client_write.shutdown().await.unwrap();
+            };
+            let echo = async {
+                let mut received = vec![];
+                server_read.read_to_end(&mut received).await.unwrap();
+                server_write.write_all(&received[..1000]).await.unwrap();
+                server_write.shutdown().await.unwrap();
+                received
+            };
+            let receive = async {
+                let mut received = vec![];
+                client_read.read_to_end(&mut received).await.unwrap();
+                received
+            };
+
+            let (_, echoed, received) = tokio::join!(send, echo, receive);
+            (echoed, received)
+        };
+
+        let (relayed, (echoed, received)) = tokio::join!(relay, peers);
+        assert_eq!(relayed.unwrap(), (data.len() as u64, 1000));
+        assert!(echoed == data);
+        assert_eq!(received, &data[..1000]);
+        assert_eq!(upstream.load(Ordering::Relaxed), data.len() as u64);
+    }
+}
@@ -5,24 +5,57 @@ use tokio::net::{self, TcpStream};
 
 /// Retrieves the original destination address from a socket on a Linux system.
 ///
+/// Connections redirected by iptables are queried with `SO_ORIGINAL_DST`, and those redirected by ip6tables
+/// with `IP6T_SO_ORIGINAL_DST`. IPv4 connections accepted on a dual-stack (IPv6) socket count as the former.
+///
 /// # Parameters
 ///
-/// * `socket`: A reference to a socket implementing `AsRawFd`.
+/// * `socket`: A reference to a socket implementing `AsFd`.
 ///
 /// # Returns
 ///
 /// Returns a `Result` containing the original `SocketAddr` or an error.
 #[cfg(target_os = "linux")]
 pub fn get_original_dst<S: std::os::unix::io::AsFd>(socket: &S) -> Result<SocketAddr> {
-    use nix::sys::socket::{self, sockopt};
+    use std::os::unix::io::AsRawFd;
+
+    use nix::sys::socket::{self, sockopt, SockaddrLike, SockaddrStorage};
+
+    let local_addr: SockaddrStorage = socket::getsockname(socket.as_fd().as_raw_fd())?;
+    let is_ipv6 = local_addr
+        .as_sockaddr_in6()
+        .map(|addr| addr.ip().to_ipv4_mapped().is_none())
+        .unwrap_or(false);
 
-    let original_dst = socket::getsockopt(socket, sockopt::OriginalDst)?;
-    let original_dst = SocketAddr::V4(std::net::SocketAddrV4::new(std::net::Ipv4Addr::from(original_dst.sin_addr.s_addr), original_dst.sin_port));
+    let original_dst = if is_ipv6 {
+        SocketAddr::V6(from_sockaddr_in6(&socket::getsockopt(socket, sockopt::Ip6tOriginalDst)?))
+    } else {
+        SocketAddr::V4(from_sockaddr_in(&socket::getsockopt(socket, sockopt::OriginalDst)?))
+    };
 
-    println!("{original_dst}");
+    debug!("Original destination ({:?}): {}", local_addr.family(), original_dst);
     Ok(original_dst)
 }
 
+/// Converts a `sockaddr_in`, whose address and port are in network byte order.
+#[cfg(target_os = "linux")]
+pub(crate) fn from_sockaddr_in(addr: &libc::sockaddr_in) -> std::net::SocketAddrV4 {
+    let ip = std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+    std::net::SocketAddrV4::new(ip, u16::from_be(addr.sin_port))
+}
+
+/// Converts a `sockaddr_in6`, whose port and flow information are in network byte order.
+#[cfg(target_os = "linux")]
+pub(crate) fn from_sockaddr_in6(addr: &libc::sockaddr_in6) -> std::net::SocketAddrV6 {
+    let ip = std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr);
+    std::net::SocketAddrV6::new(
+        ip,
+        u16::from_be(addr.sin6_port),
+        u32::from_be(addr.sin6_flowinfo),
+        addr.sin6_scope_id,
+    )
+}
+
 /// Retrieves the original destination address from a socket on a Windows system.
 ///
 /// # Parameters
@@ -44,7 +77,7 @@ pub fn get_original_dst<S: std::os::windows::io::AsRawSocket>(socket: &S) -> Res
         let mut original_dst : [u8; 256] = [0; 256];
         let mut n_bytes      : i32       = 256;
         if getsockopt(SOCKET(socket.as_raw_socket() as usize), SOL_SOCKET, SO_ORIGINAL_DST as i32, PSTR((&mut original_dst) as *mut u8), &mut n_bytes) != 0 {
-            panic!("Failed to get original address from socket");
+            bail!("Failed to get original address from socket");
         }
 
         // Parse it as an address
@@ -52,13 +85,12 @@ pub fn get_original_dst<S: std::os::windows::io::AsRawSocket>(socket: &S) -> Res
     };
 
     // Now return the parsed socket address
-    println!("{original_dst}");
     Ok(SocketAddr::from_str(&original_dst)?)
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "windows")))]
 pub fn get_original_dst<S>(_socket: S) -> Result<SocketAddr> {
-    todo!();
+    bail!("Retrieving the original destination is not supported on this platform");
 }
 
 /// Resolves a given address to a `SocketAddr`.
@@ -102,9 +134,7 @@ pub async fn try_read_initial_data(stream: &mut TcpStream) -> Result<Option<Vec<
     match stream.try_read_buf(&mut initial_data) {
         Ok(0) => Ok(None),
         Ok(_) => Ok(Some(initial_data)),
-        Err(e) => {
-            return Err(e.into());
-        }
+        Err(e) => Err(e.into()),
     }
 }
 
@@ -125,9 +155,9 @@ mod tests {
         }
     }
 
-    impl Into<String> for MockSocketAddr {
-        fn into(self) -> String {
-            self.addr
+    impl From<MockSocketAddr> for String {
+        fn from(addr: MockSocketAddr) -> String {
+            addr.addr
         }
     }
 
@@ -150,4 +180,44 @@ mod tests {
         let result = resolve_addr(mock_addr).await;
         assert!(result.is_ok());
     }
+
+    // Test conversion of an IPv4 original destination, which is in network byte order
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_from_sockaddr_in() {
+        let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_addr.s_addr = u32::from_ne_bytes([192, 168, 1, 2]);
+        addr.sin_port = 8080u16.to_be();
+
+        assert_eq!(from_sockaddr_in(&addr).to_string(), "192.168.1.2:8080");
+    }
+
+    // Test conversion of an IPv6 original destination, which is in network byte order
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_from_sockaddr_in6() {
+        let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        addr.sin6_addr.s6_addr = "2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets();
+        addr.sin6_port = 443u16.to_be();
+
+        assert_eq!(from_sockaddr_in6(&addr).to_string(), "[2001:db8::1]:443");
+    }
+
+    // Test that sockets which were not redirected either fail, or report their own destination
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_get_original_dst_not_redirected() {
+        for host in ["127.0.0.1:0", "[::1]:0"] {
+            let listener = tokio::net::TcpListener::bind(host).await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let _stream = TcpStream::connect(address).await.unwrap();
+            let (incoming, _) = listener.accept().await.unwrap();
+
+            if let Ok(original_dst) = get_original_dst(&incoming) {
+                assert_eq!(original_dst, address);
+            }
+        }
+    }
 }
@@ -0,0 +1,695 @@
+//! An encrypted link between two hops, using ChaCha20-Poly1305 ([rfc8439]).
+//!
+//! Each direction of a connection is encrypted independently. Both ends start the direction that they encrypt with a
+//! random 32-byte salt, and derive the key of either direction from both salts (HKDF-SHA256 over the shared key, with
+//! the direction as info). As the receiving end contributes a salt of its own, recorded streams cannot be replayed to
+//! it, and as the directions have different keys, neither can a stream be reflected back to its sender. An end only
+//! encrypts data once the salt of the other end arrived, which delays the first data by a round trip.
+//!
+//! Data then follows in frames: a two-byte length (authenticated), and the sealed payload. Nonces are frame counters,
+//! which never repeat for a key as every direction of every connection has a key of its own. An empty frame marks the
+//! end of a direction, so that truncation is detected as well.
+//!
+//! [rfc8439]: https://tools.ietf.org/html/rfc8439
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::functions::{Direction, StreamFunction};
+
+/// The length of the salt that starts every direction.
+const SALT_LENGTH: usize = 32;
+/// The length of the authentication tag of every frame.
+const TAG_LENGTH: usize = 16;
+/// The maximum length of the payload of a frame.
+const MAX_PAYLOAD_LENGTH: usize = u16::MAX as usize - TAG_LENGTH;
+
+/// Domain separation for the keys derived from passphrases and salts.
+const PASSPHRASE_SALT: &[u8] = b"socksx chacha20-poly1305 passphrase";
+const HKDF_INFO_UPSTREAM: &[u8] = b"socksx chacha20-poly1305 upstream";
+const HKDF_INFO_DOWNSTREAM: &[u8] = b"socksx chacha20-poly1305 downstream";
+
+/// Which end of the encrypted link a function is on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// Encrypts upstream data, and decrypts downstream data: the end closest to the client.
+    Encrypt,
+    /// Decrypts upstream data, and encrypts downstream data: the end closest to the destination.
+    Decrypt,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(role: &str) -> Result<Self> {
+        match role.to_lowercase().as_str() {
+            "encrypt" => Ok(Role::Encrypt),
+            "decrypt" => Ok(Role::Decrypt),
+            role => bail!("Unrecognized ChaCha20 role: {} (supported: `encrypt`, `decrypt`)", role),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            Role::Encrypt => write!(f, "encrypt"),
+            Role::Decrypt => write!(f, "decrypt"),
+        }
+    }
+}
+
+/// The 256-bit key that both ends of the link share.
+#[derive(Clone, PartialEq)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    /// Uses the given bytes as key.
+    pub fn from_bytes(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// Derives a key from a passphrase, with Argon2id.
+    pub fn from_passphrase(passphrase: &str) -> Result<Self> {
+        ensure!(!passphrase.is_empty(), "The ChaCha20 passphrase MUST NOT be empty.");
+
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), PASSPHRASE_SALT, &mut key)
+            .map_err(|error| anyhow!("Unable to derive a key from the passphrase: {}", error))?;
+
+        Ok(Self(key))
+    }
+
+    /// Reads a key from a file, which contains either exactly 32 bytes, or 64 hexadecimal characters.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let contents = fs::read(path.as_ref())?;
+
+        if let Ok(key) = <[u8; 32]>::try_from(&contents[..]) {
+            return Ok(Self(key));
+        }
+
+        let hex = String::from_utf8(contents).unwrap_or_default();
+        match decode_hex(hex.trim()) {
+            Some(key) => Ok(Self(key)),
+            None => bail!(
+                "Key file {} must contain 32 bytes, or 64 hexadecimal characters.",
+                path.as_ref().display()
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "SecretKey(..)")
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(key)
+}
+
+/// Derives the cipher of a direction from the shared key, and the salts of both ends.
+fn cipher(
+    key: &SecretKey,
+    direction: Direction,
+    encrypt_salt: &[u8; SALT_LENGTH],
+    decrypt_salt: &[u8; SALT_LENGTH],
+) -> ChaCha20Poly1305 {
+    let info = match direction {
+        Direction::Upstream => HKDF_INFO_UPSTREAM,
+        Direction::Downstream => HKDF_INFO_DOWNSTREAM,
+    };
+
+    let mut salt = [0u8; 2 * SALT_LENGTH];
+    salt[..SALT_LENGTH].copy_from_slice(encrypt_salt);
+    salt[SALT_LENGTH..].copy_from_slice(decrypt_salt);
+
+    let mut direction_key = Key::default();
+    Hkdf::<Sha256>::new(Some(&salt), &key.0)
+        .expand(info, &mut direction_key)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+
+    ChaCha20Poly1305::new(&direction_key)
+}
+
+/// The nonce of the frame with the given number.
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// The sending end of a direction, which holds back data until the salt of the other end arrived.
+#[derive(Default)]
+struct Sealer {
+    cipher: Option<ChaCha20Poly1305>,
+    counter: u64,
+    held: BytesMut,
+    eof: bool,
+    finished: bool,
+}
+
+impl Sealer {
+    fn seal(
+        &mut self,
+        plaintext: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        let cipher = self.cipher.as_ref().expect("data is held back until the cipher is known");
+
+        let length = ((plaintext.len() + TAG_LENGTH) as u16).to_be_bytes();
+        let payload = Payload {
+            msg: plaintext,
+            aad: &length,
+        };
+        let ciphertext = cipher
+            .encrypt(&nonce(self.counter), payload)
+            .map_err(|_| invalid_data("ChaCha20-Poly1305 encryption failed"))?;
+
+        self.counter = self.counter.checked_add(1).ok_or_else(|| invalid_data("Too many frames for one key"))?;
+        output.put_slice(&length);
+        output.put_slice(&ciphertext);
+
+        Ok(())
+    }
+
+    fn on_chunk(
+        &mut self,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        if self.cipher.is_none() || !self.held.is_empty() {
+            self.held.extend_from_slice(chunk);
+            return self.flush(output);
+        }
+
+        chunk.chunks(MAX_PAYLOAD_LENGTH).try_for_each(|plaintext| self.seal(plaintext, output))
+    }
+
+    fn on_eof(
+        &mut self,
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        self.eof = true;
+        self.flush(output)
+    }
+
+    /// Seals the data that was held back, and the end of the direction, once the cipher is known.
+    fn flush(
+        &mut self,
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        if self.cipher.is_none() {
+            return Ok(());
+        }
+
+        let held = self.held.split();
+        held.chunks(MAX_PAYLOAD_LENGTH).try_for_each(|plaintext| self.seal(plaintext, output))?;
+
+        if self.eof && !self.finished {
+            self.finished = true;
+            self.seal(&[], output)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The receiving end of a direction.
+#[derive(Default)]
+struct Opener {
+    cipher: Option<ChaCha20Poly1305>,
+    counter: u64,
+    buffer: BytesMut,
+    finished: bool,
+}
+
+impl Opener {
+    /// Opens the frames in the buffer, once the cipher is known.
+    fn open(
+        &mut self,
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(()),
+        };
+
+        while self.buffer.len() >= 2 {
+            let length = u16::from_be_bytes([self.buffer[0], self.buffer[1]]) as usize;
+            if length < TAG_LENGTH {
+                return Err(invalid_data("Invalid frame length in the encrypted stream"));
+            }
+            if self.buffer.len() < 2 + length {
+                break;
+            }
+
+            let aad = [self.buffer[0], self.buffer[1]];
+            self.buffer.advance(2);
+            let frame = self.buffer.split_to(length);
+
+            let payload = Payload { msg: &frame, aad: &aad };
+            let plaintext = cipher
+                .decrypt(&nonce(self.counter), payload)
+                .map_err(|_| invalid_data("Integrity check of the encrypted stream failed"))?;
+            self.counter += 1;
+
+            if plaintext.is_empty() {
+                self.finished = true;
+                if !self.buffer.is_empty() {
+                    return Err(invalid_data("Data after the end of the encrypted stream"));
+                }
+                break;
+            }
+
+            output.put_slice(&plaintext);
+        }
+
+        Ok(())
+    }
+
+    fn on_eof(&mut self) -> io::Result<()> {
+        if !self.finished {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The encrypted stream was truncated"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Encrypts one direction of a connection and decrypts the other, depending on its `Role`.
+pub struct ChaCha20Function {
+    role: Role,
+    key: SecretKey,
+    /// The salt of this end, which starts the direction that it encrypts.
+    salt: [u8; SALT_LENGTH],
+    salt_sent: bool,
+    sealer: Sealer,
+    opener: Opener,
+}
+
+impl ChaCha20Function {
+    /// Creates a new `ChaCha20Function`.
+    ///
+    /// # Parameters
+    /// - `role`: Which end of the encrypted link the function is on.
+    /// - `key`: The key that both ends share.
+    pub fn new(
+        role: Role,
+        key: SecretKey,
+    ) -> Self {
+        let mut salt = [0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+
+        Self::with_salt(role, key, salt)
+    }
+
+    fn with_salt(
+        role: Role,
+        key: SecretKey,
+        salt: [u8; SALT_LENGTH],
+    ) -> Self {
+        Self {
+            role,
+            key,
+            salt,
+            salt_sent: false,
+            sealer: Sealer::default(),
+            opener: Opener::default(),
+        }
+    }
+
+    /// Returns whether data travelling in `direction` is encrypted, rather than decrypted.
+    fn encrypts(
+        &self,
+        direction: Direction,
+    ) -> bool {
+        (self.role == Role::Encrypt) == (direction == Direction::Upstream)
+    }
+
+    /// Starts the direction that this end encrypts with its salt, if it did not yet.
+    fn send_salt(
+        &mut self,
+        output: &mut BytesMut,
+    ) {
+        if !self.salt_sent {
+            self.salt_sent = true;
+            output.put_slice(&self.salt);
+        }
+    }
+
+    /// Derives the ciphers of both directions, once the salt of the other end arrived.
+    fn derive_ciphers(
+        &mut self,
+        peer_salt: &[u8; SALT_LENGTH],
+    ) -> io::Result<()> {
+        // Otherwise, the other end could be this one, reflected.
+        if peer_salt == &self.salt {
+            return Err(invalid_data("The encrypted stream starts with the salt of this end"));
+        }
+
+        let (encrypt_salt, decrypt_salt) = match self.role {
+            Role::Encrypt => (&self.salt, peer_salt),
+            Role::Decrypt => (peer_salt, &self.salt),
+        };
+        let upstream = cipher(&self.key, Direction::Upstream, encrypt_salt, decrypt_salt);
+        let downstream = cipher(&self.key, Direction::Downstream, encrypt_salt, decrypt_salt);
+
+        let (sealing, opening) = match self.role {
+            Role::Encrypt => (upstream, downstream),
+            Role::Decrypt => (downstream, upstream),
+        };
+        self.sealer.cipher = Some(sealing);
+        self.opener.cipher = Some(opening);
+
+        Ok(())
+    }
+
+    /// Decrypts a chunk, which starts with the salt of the other end.
+    fn open(
+        &mut self,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        if self.opener.finished {
+            return Err(invalid_data("Data after the end of the encrypted stream"));
+        }
+        self.opener.buffer.extend_from_slice(chunk);
+
+        if self.opener.cipher.is_none() {
+            if self.opener.buffer.len() < SALT_LENGTH {
+                return Ok(());
+            }
+
+            let mut peer_salt = [0u8; SALT_LENGTH];
+            self.opener.buffer.copy_to_slice(&mut peer_salt);
+            self.derive_ciphers(&peer_salt)?;
+        }
+
+        self.opener.open(output)
+    }
+}
+
+impl StreamFunction for ChaCha20Function {
+    fn on_chunk(
+        &mut self,
+        direction: Direction,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        if self.encrypts(direction) {
+            self.send_salt(output);
+            self.sealer.on_chunk(chunk, output)
+        } else {
+            self.open(chunk, output)
+        }
+    }
+
+    fn on_eof(
+        &mut self,
+        direction: Direction,
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        if self.encrypts(direction) {
+            self.send_salt(output);
+            self.sealer.on_eof(output)
+        } else {
+            self.opener.on_eof()
+        }
+    }
+
+    fn on_poll(
+        &mut self,
+        direction: Direction,
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        if self.encrypts(direction) {
+            self.send_salt(output);
+            self.sealer.flush(output)?;
+        }
+
+        Ok(())
+    }
+
+    fn is_waiting(
+        &self,
+        direction: Direction,
+    ) -> bool {
+        self.encrypts(direction) && self.sealer.cipher.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENCRYPT_SALT: [u8; SALT_LENGTH] = [1; SALT_LENGTH];
+    const DECRYPT_SALT: [u8; SALT_LENGTH] = [2; SALT_LENGTH];
+
+    fn key() -> SecretKey {
+        SecretKey::from_bytes([7; 32])
+    }
+
+    /// The encrypting end of a link, once the salt of the decrypting end, `decrypt_end`, arrived.
+    fn encrypt_end() -> ChaCha20Function {
+        let mut encrypt = ChaCha20Function::with_salt(Role::Encrypt, key(), ENCRYPT_SALT);
+        encrypt.on_chunk(Direction::Downstream, &DECRYPT_SALT, &mut BytesMut::new()).unwrap();
+        encrypt
+    }
+
+    fn decrypt_end() -> ChaCha20Function {
+        ChaCha20Function::with_salt(Role::Decrypt, key(), DECRYPT_SALT)
+    }
+
+    /// Encrypts `chunks` upstream on one end, and decrypts them on the other, in pieces of `piece` bytes.
+    fn round_trip(
+        chunks: &[&[u8]],
+        piece: usize,
+    ) -> io::Result<Vec<u8>> {
+        let mut encrypt = encrypt_end();
+        let mut decrypt = decrypt_end();
+
+        let mut ciphertext = BytesMut::new();
+        for chunk in chunks {
+            encrypt.on_chunk(Direction::Upstream, chunk, &mut ciphertext)?;
+        }
+        encrypt.on_eof(Direction::Upstream, &mut ciphertext)?;
+
+        let mut plaintext = BytesMut::new();
+        for piece in ciphertext.chunks(piece) {
+            decrypt.on_chunk(Direction::Upstream, piece, &mut plaintext)?;
+        }
+        decrypt.on_eof(Direction::Upstream, &mut plaintext)?;
+
+        Ok(plaintext.to_vec())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let large = vec![0xAB; MAX_PAYLOAD_LENGTH * 2 + 100];
+
+        for piece in [1, 7, 1024, usize::MAX] {
+            assert_eq!(round_trip(&[b"hello, ", b"world"], piece).unwrap(), b"hello, world");
+            assert_eq!(round_trip(&[&large], piece).unwrap(), large);
+            assert_eq!(round_trip(&[], piece).unwrap(), b"");
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut encrypt = ChaCha20Function::new(Role::Encrypt, key());
+        let mut decrypt = ChaCha20Function::new(Role::Decrypt, key());
+
+        // The encrypting end sends its salt right away, but holds back data until the salt of the other end arrived.
+        let mut upstream = BytesMut::new();
+        encrypt.on_chunk(Direction::Upstream, b"hello", &mut upstream).unwrap();
+        assert_eq!(upstream.len(), SALT_LENGTH);
+        assert!(encrypt.is_waiting(Direction::Upstream));
+
+        let mut downstream = BytesMut::new();
+        decrypt.on_poll(Direction::Downstream, &mut downstream).unwrap();
+        assert!(decrypt.is_waiting(Direction::Downstream));
+        encrypt.on_chunk(Direction::Downstream, &downstream, &mut BytesMut::new()).unwrap();
+        assert!(!encrypt.is_waiting(Direction::Upstream));
+
+        encrypt.on_poll(Direction::Upstream, &mut upstream).unwrap();
+        let mut plaintext = BytesMut::new();
+        decrypt.on_chunk(Direction::Upstream, &upstream, &mut plaintext).unwrap();
+        assert_eq!(&plaintext[..], b"hello");
+        assert!(!decrypt.is_waiting(Direction::Downstream));
+    }
+
+    #[test]
+    fn test_downstream_is_reversed() {
+        let mut encrypt = ChaCha20Function::with_salt(Role::Encrypt, key(), ENCRYPT_SALT);
+        let mut decrypt = decrypt_end();
+        decrypt.on_chunk(Direction::Upstream, &ENCRYPT_SALT, &mut BytesMut::new()).unwrap();
+
+        let mut ciphertext = BytesMut::new();
+        decrypt.on_chunk(Direction::Downstream, b"reply", &mut ciphertext).unwrap();
+
+        let mut plaintext = BytesMut::new();
+        encrypt.on_chunk(Direction::Downstream, &ciphertext, &mut plaintext).unwrap();
+        assert_eq!(&plaintext[..], b"reply");
+    }
+
+    #[test]
+    fn test_salts_are_random() {
+        let mut first = BytesMut::new();
+        let mut second = BytesMut::new();
+        ChaCha20Function::new(Role::Encrypt, key()).on_poll(Direction::Upstream, &mut first).unwrap();
+        ChaCha20Function::new(Role::Encrypt, key()).on_poll(Direction::Upstream, &mut second).unwrap();
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_reflection_is_detected() {
+        // The salt of an end, reflected back to it.
+        let mut encrypt = ChaCha20Function::with_salt(Role::Encrypt, key(), ENCRYPT_SALT);
+        let mut salt = BytesMut::new();
+        encrypt.on_poll(Direction::Upstream, &mut salt).unwrap();
+        let error = encrypt.on_chunk(Direction::Downstream, &salt, &mut BytesMut::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Frames of an end, reflected back to it after the handshake.
+        let mut encrypt = encrypt_end();
+        let mut ciphertext = BytesMut::new();
+        encrypt.on_chunk(Direction::Upstream, b"hello", &mut ciphertext).unwrap();
+        let reflected = &ciphertext[SALT_LENGTH..];
+        let error = encrypt.on_chunk(Direction::Downstream, reflected, &mut BytesMut::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_replay_is_detected() {
+        let mut encrypt = encrypt_end();
+        let mut recorded = BytesMut::new();
+        encrypt.on_chunk(Direction::Upstream, b"POST /transfer HTTP/1.1\r\n\r\n", &mut recorded).unwrap();
+        encrypt.on_eof(Direction::Upstream, &mut recorded).unwrap();
+
+        let mut plaintext = BytesMut::new();
+        decrypt_end().on_chunk(Direction::Upstream, &recorded, &mut plaintext).unwrap();
+        assert_eq!(&plaintext[..], b"POST /transfer HTTP/1.1\r\n\r\n");
+
+        // A new connection has a decrypting end with a salt of its own.
+        let mut decrypt = ChaCha20Function::new(Role::Decrypt, key());
+        let error = decrypt.on_chunk(Direction::Upstream, &recorded, &mut BytesMut::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let mut encrypt = encrypt_end();
+        let mut ciphertext = BytesMut::new();
+        encrypt.on_chunk(Direction::Upstream, b"hello, world", &mut ciphertext).unwrap();
+
+        encrypt.on_eof(Direction::Upstream, &mut ciphertext).unwrap();
+
+        // A larger frame length leaves the decrypting end waiting for the rest of the frame, until the end.
+        for position in 0..ciphertext.len() {
+            let mut tampered = ciphertext.clone();
+            tampered[position] ^= 0x01;
+
+            let mut decrypt = decrypt_end();
+            let mut plaintext = BytesMut::new();
+            let result = decrypt
+                .on_chunk(Direction::Upstream, &tampered, &mut plaintext)
+                .and_then(|_| decrypt.on_eof(Direction::Upstream, &mut plaintext));
+
+            assert!(result.is_err(), "byte {}", position);
+        }
+    }
+
+    #[test]
+    fn test_wrong_key_is_detected() {
+        let mut encrypt = encrypt_end();
+        let mut decrypt = ChaCha20Function::with_salt(Role::Decrypt, SecretKey::from_bytes([8; 32]), DECRYPT_SALT);
+
+        let mut ciphertext = BytesMut::new();
+        encrypt.on_chunk(Direction::Upstream, b"hello", &mut ciphertext).unwrap();
+        assert!(decrypt.on_chunk(Direction::Upstream, &ciphertext, &mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn test_truncation_is_detected() {
+        let mut encrypt = encrypt_end();
+        let mut decrypt = decrypt_end();
+
+        let mut ciphertext = BytesMut::new();
+        encrypt.on_chunk(Direction::Upstream, b"hello", &mut ciphertext).unwrap();
+        decrypt.on_chunk(Direction::Upstream, &ciphertext, &mut BytesMut::new()).unwrap();
+
+        let error = decrypt.on_eof(Direction::Upstream, &mut BytesMut::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_key_from_passphrase() {
+        let key = SecretKey::from_passphrase("correct horse battery staple").unwrap();
+
+        assert_eq!(key, SecretKey::from_passphrase("correct horse battery staple").unwrap());
+        assert_ne!(key, SecretKey::from_passphrase("correct horse battery").unwrap());
+        assert!(SecretKey::from_passphrase("").is_err());
+    }
+
+    #[test]
+    fn test_key_from_file() {
+        let directory = std::env::temp_dir().join(format!("socksx-chacha20-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let raw = directory.join("raw");
+        fs::write(&raw, [7u8; 32]).unwrap();
+        assert_eq!(SecretKey::from_file(&raw).unwrap(), key());
+
+        let hex = directory.join("hex");
+        fs::write(&hex, format!("{}\n", "07".repeat(32))).unwrap();
+        assert_eq!(SecretKey::from_file(&hex).unwrap(), key());
+
+        let short = directory.join("short");
+        fs::write(&short, "0707").unwrap();
+        assert!(SecretKey::from_file(&short).is_err());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_role_from_str() {
+        assert_eq!("Encrypt".parse::<Role>().unwrap(), Role::Encrypt);
+        assert_eq!("decrypt".parse::<Role>().unwrap(), Role::Decrypt);
+        assert!("both".parse::<Role>().is_err());
+    }
+}
@@ -0,0 +1,657 @@
+//! Compression of the link between two hops, with zstd or deflate.
+//!
+//! The compressing end starts every direction that it compresses with a four-byte header, which names the algorithm,
+//! followed by a single compressed stream. How often that stream is flushed is configurable: flushing after every
+//! chunk keeps interactive protocols responsive, while holding back data compresses bulk transfers better.
+//!
+//! The decompressing end decompresses into a buffer of a fixed size, and rejects streams that expand beyond its limits,
+//! so that a small compressed stream from a peer cannot exhaust the memory of the proxy.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use zstd::stream::raw::Operation;
+
+use crate::functions::stream::CHUNK_SIZE;
+use crate::functions::{Direction, StreamFunction};
+
+/// Identifies compressed streams, and is followed by the algorithm.
+const MAGIC: &[u8; 3] = b"SXC";
+const HEADER_LENGTH: usize = MAGIC.len() + 1;
+
+/// How many times larger decompressed data may be than the compressed data, unless configured otherwise.
+pub const DEFAULT_MAX_RATIO: u64 = 1024;
+
+/// How much data a single chunk of compressed data may decompress to, unless configured otherwise.
+pub const DEFAULT_MAX_CHUNK_OUTPUT: usize = 16 * 1024 * 1024;
+
+/// The compression algorithms that are supported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Deflate,
+    Zstd,
+}
+
+impl Algorithm {
+    /// The identifier of the algorithm in the header of a compressed stream.
+    fn id(&self) -> u8 {
+        match self {
+            Algorithm::Deflate => 0x01,
+            Algorithm::Zstd => 0x02,
+        }
+    }
+
+    /// The level that is used unless another is configured.
+    pub fn default_level(&self) -> i32 {
+        match self {
+            Algorithm::Deflate => 6,
+            Algorithm::Zstd => 3,
+        }
+    }
+
+    /// The levels that the algorithm supports.
+    pub fn levels(&self) -> std::ops::RangeInclusive<i32> {
+        match self {
+            Algorithm::Deflate => 0..=9,
+            Algorithm::Zstd => 1..=22,
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(algorithm: &str) -> Result<Self> {
+        match algorithm.to_lowercase().as_str() {
+            "deflate" => Ok(Algorithm::Deflate),
+            "zstd" => Ok(Algorithm::Zstd),
+            algorithm => bail!("Unrecognized compression algorithm: {} (supported: `deflate`, `zstd`)", algorithm),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            Algorithm::Deflate => write!(f, "deflate"),
+            Algorithm::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// When compressed data is passed on, rather than held back to compress better.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flush {
+    /// After every chunk, so that interactive protocols are never stalled.
+    Chunk,
+    /// Once at least this many bytes have been compressed since the last flush (and at the end).
+    Bytes(usize),
+}
+
+impl FromStr for Flush {
+    type Err = anyhow::Error;
+
+    fn from_str(flush: &str) -> Result<Self> {
+        match flush.to_lowercase().as_str() {
+            "chunk" => Ok(Flush::Chunk),
+            bytes => match bytes.parse() {
+                Ok(bytes) => Ok(Flush::Bytes(bytes)),
+                Err(_) => bail!("Unrecognized flush behaviour: {} (supported: `chunk`, or a number of bytes)", flush),
+            },
+        }
+    }
+}
+
+/// Which end of the compressed link a function is on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// Compresses upstream data, and decompresses downstream data: the end closest to the client.
+    Compress,
+    /// Decompresses upstream data, and compresses downstream data: the end closest to the destination.
+    Decompress,
+}
+
+/// How a `CompressionFunction` compresses data, and how far it lets data expand when decompressing it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompressionConfig {
+    pub algorithm: Algorithm,
+    pub level: i32,
+    pub flush: Flush,
+    /// How many times larger the decompressed data of a direction may be than its compressed data, so far.
+    pub max_ratio: u64,
+    /// How much data a single chunk of compressed data may decompress to.
+    pub max_chunk_output: usize,
+}
+
+impl CompressionConfig {
+    /// Creates a new `CompressionConfig`, with the default level of the algorithm, that flushes every chunk, and with
+    /// the default decompression limits.
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self {
+            algorithm,
+            level: algorithm.default_level(),
+            flush: Flush::Chunk,
+            max_ratio: DEFAULT_MAX_RATIO,
+            max_chunk_output: DEFAULT_MAX_CHUNK_OUTPUT,
+        }
+    }
+
+    /// Compresses with the given level, which must be supported by the algorithm.
+    pub fn with_level(
+        mut self,
+        level: i32,
+    ) -> Result<Self> {
+        ensure!(
+            self.algorithm.levels().contains(&level),
+            "Compression level {} is not supported by {} (supported: {:?}).",
+            level,
+            self.algorithm,
+            self.algorithm.levels()
+        );
+
+        self.level = level;
+        Ok(self)
+    }
+
+    /// Flushes compressed data as configured.
+    pub fn with_flush(
+        mut self,
+        flush: Flush,
+    ) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Rejects compressed streams whose decompressed data grows to more than `max_ratio` times their compressed data.
+    pub fn with_max_ratio(
+        mut self,
+        max_ratio: u64,
+    ) -> Self {
+        self.max_ratio = max_ratio;
+        self
+    }
+
+    /// Rejects compressed streams of which a single chunk decompresses to more than `max_chunk_output` bytes.
+    pub fn with_max_chunk_output(
+        mut self,
+        max_chunk_output: usize,
+    ) -> Self {
+        self.max_chunk_output = max_chunk_output;
+        self
+    }
+}
+
+/// A streaming compressor, writing into a buffer.
+enum Encoder {
+    Deflate(flate2::write::DeflateEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(config: &CompressionConfig) -> io::Result<Self> {
+        match config.algorithm {
+            Algorithm::Deflate => {
+                let level = flate2::Compression::new(config.level as u32);
+                Ok(Encoder::Deflate(flate2::write::DeflateEncoder::new(vec![], level)))
+            }
+            Algorithm::Zstd => Ok(Encoder::Zstd(zstd::stream::write::Encoder::new(vec![], config.level)?)),
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Deflate(encoder) => encoder,
+            Encoder::Zstd(encoder) => encoder,
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Deflate(encoder) => encoder.try_finish(),
+            Encoder::Zstd(encoder) => encoder.do_finish(),
+        }
+    }
+
+    /// Takes the compressed data that is ready.
+    fn take(&mut self) -> Vec<u8> {
+        match self {
+            Encoder::Deflate(encoder) => std::mem::take(encoder.get_mut()),
+            Encoder::Zstd(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+}
+
+/// A streaming decompressor, decompressing into a buffer of a fixed size.
+enum Decoder {
+    Deflate(flate2::Decompress),
+    Zstd(zstd::stream::raw::Decoder<'static>),
+}
+
+impl Decoder {
+    fn new(algorithm: Algorithm) -> io::Result<Self> {
+        match algorithm {
+            Algorithm::Deflate => Ok(Decoder::Deflate(flate2::Decompress::new(false))),
+            Algorithm::Zstd => Ok(Decoder::Zstd(zstd::stream::raw::Decoder::new()?)),
+        }
+    }
+
+    /// Decompresses as much of `input` as fits into `output`.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes read from `input` and written to `output`, and whether the compressed stream ended.
+    fn decompress(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> io::Result<(usize, usize, bool)> {
+        match self {
+            Decoder::Deflate(decoder) => {
+                let (total_in, total_out) = (decoder.total_in(), decoder.total_out());
+                let status = decoder.decompress(input, output, flate2::FlushDecompress::None)?;
+
+                let read = (decoder.total_in() - total_in) as usize;
+                let written = (decoder.total_out() - total_out) as usize;
+                Ok((read, written, status == flate2::Status::StreamEnd))
+            }
+            Decoder::Zstd(decoder) => {
+                let status = decoder.run_on_buffers(input, output)?;
+                Ok((status.bytes_read, status.bytes_written, status.remaining == 0))
+            }
+        }
+    }
+}
+
+/// The compressing end of a direction.
+struct Compressor {
+    config: CompressionConfig,
+    encoder: Option<Encoder>,
+    pending: usize,
+}
+
+impl Compressor {
+    fn new(config: CompressionConfig) -> Self {
+        Self {
+            config,
+            encoder: None,
+            pending: 0,
+        }
+    }
+
+    fn encoder(
+        &mut self,
+        output: &mut BytesMut,
+    ) -> io::Result<&mut Encoder> {
+        if self.encoder.is_none() {
+            self.encoder = Some(Encoder::new(&self.config)?);
+
+            output.extend_from_slice(MAGIC);
+            output.extend_from_slice(&[self.config.algorithm.id()]);
+        }
+
+        Ok(self.encoder.as_mut().expect("encoder is set above"))
+    }
+
+    fn on_chunk(
+        &mut self,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        let flush = match self.config.flush {
+            Flush::Chunk => true,
+            Flush::Bytes(bytes) => self.pending + chunk.len() >= bytes,
+        };
+
+        let encoder = self.encoder(output)?;
+        encoder.writer().write_all(chunk)?;
+        if flush {
+            encoder.writer().flush()?;
+        }
+        output.extend_from_slice(&encoder.take());
+
+        self.pending = if flush { 0 } else { self.pending + chunk.len() };
+        Ok(())
+    }
+
+    fn on_eof(
+        &mut self,
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        let encoder = self.encoder(output)?;
+        encoder.finish()?;
+        output.extend_from_slice(&encoder.take());
+
+        Ok(())
+    }
+}
+
+/// The decompressing end of a direction.
+struct Decompressor {
+    config: CompressionConfig,
+    decoder: Option<Decoder>,
+    header: Vec<u8>,
+    buffer: Box<[u8]>,
+    /// The compressed bytes that were decompressed so far, and the bytes that they decompressed to.
+    compressed: u64,
+    decompressed: u64,
+    /// Whether the compressed stream ended.
+    finished: bool,
+}
+
+impl Decompressor {
+    fn new(config: CompressionConfig) -> Self {
+        Self {
+            config,
+            decoder: None,
+            header: Vec::with_capacity(HEADER_LENGTH),
+            buffer: vec![0; CHUNK_SIZE].into_boxed_slice(),
+            compressed: 0,
+            decompressed: 0,
+            finished: false,
+        }
+    }
+
+    fn on_chunk(
+        &mut self,
+        mut chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        if self.decoder.is_none() {
+            let length = chunk.len().min(HEADER_LENGTH - self.header.len());
+            self.header.extend_from_slice(&chunk[..length]);
+            chunk = &chunk[length..];
+
+            if self.header.len() < HEADER_LENGTH {
+                return Ok(());
+            }
+            let algorithm = self.config.algorithm;
+            if &self.header[..MAGIC.len()] != MAGIC || self.header[MAGIC.len()] != algorithm.id() {
+                let message = format!("The compressed stream does not start with a {} header", algorithm);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+
+            self.decoder = Some(Decoder::new(algorithm)?);
+        }
+
+        let decoder = self.decoder.as_mut().expect("decoder is set once the header is received");
+        let mut produced = 0;
+        // Whether the buffer was filled, in which case the decoder may have more data to write.
+        let mut full = false;
+        while !chunk.is_empty() || full {
+            if self.finished {
+                if chunk.is_empty() {
+                    break;
+                }
+
+                let message = "The compressed stream is followed by more data";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+
+            let (read, written, finished) = decoder.decompress(chunk, &mut self.buffer)?;
+            chunk = &chunk[read..];
+            self.finished = finished;
+
+            self.compressed += read as u64;
+            self.decompressed += written as u64;
+            produced += written;
+            if produced > self.config.max_chunk_output
+                || self.decompressed > self.compressed.saturating_mul(self.config.max_ratio)
+            {
+                let message = "The compressed stream expands beyond the limits of decompression";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+
+            output.extend_from_slice(&self.buffer[..written]);
+            full = written == self.buffer.len();
+            if read == 0 && !full {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_eof(&mut self) -> io::Result<()> {
+        if !self.finished {
+            let message = "The compressed stream ended before it was complete";
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message));
+        }
+
+        Ok(())
+    }
+}
+
+/// Compresses one direction of a connection and decompresses the other, depending on its `Role`.
+pub struct CompressionFunction {
+    role: Role,
+    compressor: Compressor,
+    decompressor: Decompressor,
+}
+
+impl CompressionFunction {
+    /// Creates a new `CompressionFunction`.
+    ///
+    /// # Parameters
+    /// - `role`: Which end of the compressed link the function is on.
+    /// - `config`: How data is compressed; both ends must use the same algorithm.
+    pub fn new(
+        role: Role,
+        config: CompressionConfig,
+    ) -> Self {
+        Self {
+            role,
+            compressor: Compressor::new(config),
+            decompressor: Decompressor::new(config),
+        }
+    }
+
+    /// Returns whether data travelling in `direction` is compressed, rather than decompressed.
+    fn compresses(
+        &self,
+        direction: Direction,
+    ) -> bool {
+        (self.role == Role::Compress) == (direction == Direction::Upstream)
+    }
+}
+
+impl StreamFunction for CompressionFunction {
+    fn on_chunk(
+        &mut self,
+        direction: Direction,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        if self.compresses(direction) {
+            self.compressor.on_chunk(chunk, output)
+        } else {
+            self.decompressor.on_chunk(chunk, output)
+        }
+    }
+
+    fn on_eof(
+        &mut self,
+        direction: Direction,
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        if self.compresses(direction) {
+            self.compressor.on_eof(output)
+        } else {
+            self.decompressor.on_eof()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [Algorithm; 2] = [Algorithm::Deflate, Algorithm::Zstd];
+
+    /// Compresses `chunks` upstream, and returns the compressed data after every chunk and at the end.
+    fn compress(
+        config: CompressionConfig,
+        chunks: &[&[u8]],
+    ) -> Vec<BytesMut> {
+        let mut function = CompressionFunction::new(Role::Compress, config);
+
+        let mut outputs = vec![];
+        for chunk in chunks {
+            let mut output = BytesMut::new();
+            function.on_chunk(Direction::Upstream, chunk, &mut output).unwrap();
+            outputs.push(output);
+        }
+
+        let mut output = BytesMut::new();
+        function.on_eof(Direction::Upstream, &mut output).unwrap();
+        outputs.push(output);
+
+        outputs
+    }
+
+    /// Decompresses the output of `compress` upstream, and returns the data after every output.
+    fn decompress(
+        algorithm: Algorithm,
+        outputs: &[BytesMut],
+    ) -> Vec<Vec<u8>> {
+        let mut function = CompressionFunction::new(Role::Decompress, CompressionConfig::new(algorithm));
+
+        outputs
+            .iter()
+            .map(|compressed| {
+                let mut output = BytesMut::new();
+                function.on_chunk(Direction::Upstream, compressed, &mut output).unwrap();
+                output.to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = b"the quick brown fox jumps over the lazy dog ".repeat(1000);
+
+        for algorithm in ALGORITHMS {
+            let outputs = compress(CompressionConfig::new(algorithm), &[&text, b"and more"]);
+            assert!(outputs[0].len() < text.len() / 10, "{}", algorithm);
+
+            let decompressed: Vec<u8> = decompress(algorithm, &outputs).concat();
+            assert_eq!(decompressed, [&text[..], b"and more"].concat(), "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_flush_every_chunk() {
+        for algorithm in ALGORITHMS {
+            let outputs = compress(CompressionConfig::new(algorithm), &[b"ping", b"pong"]);
+
+            // Every chunk can be decompressed as soon as it arrives.
+            let decompressed = decompress(algorithm, &outputs);
+            assert_eq!(decompressed[..2], [b"ping".to_vec(), b"pong".to_vec()], "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_flush_after_bytes() {
+        for algorithm in ALGORITHMS {
+            let config = CompressionConfig::new(algorithm).with_flush(Flush::Bytes(8));
+            let outputs = compress(config, &[b"ping", b"pong", b"ping"]);
+
+            let decompressed = decompress(algorithm, &outputs);
+            assert_eq!(decompressed[0], b"", "{}", algorithm);
+            assert_eq!(decompressed[1], b"pingpong", "{}", algorithm);
+            assert_eq!(decompressed[2..].concat(), b"ping", "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_downstream_is_reversed() {
+        for algorithm in ALGORITHMS {
+            let mut near = CompressionFunction::new(Role::Compress, CompressionConfig::new(algorithm));
+            let mut far = CompressionFunction::new(Role::Decompress, CompressionConfig::new(algorithm));
+
+            let mut compressed = BytesMut::new();
+            far.on_chunk(Direction::Downstream, b"reply", &mut compressed).unwrap();
+
+            let mut decompressed = BytesMut::new();
+            near.on_chunk(Direction::Downstream, &compressed, &mut decompressed).unwrap();
+            assert_eq!(&decompressed[..], b"reply", "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_algorithm_mismatch() {
+        let outputs = compress(CompressionConfig::new(Algorithm::Zstd), &[b"hello"]);
+
+        let mut function = CompressionFunction::new(Role::Decompress, CompressionConfig::new(Algorithm::Deflate));
+        let result = function.on_chunk(Direction::Upstream, &outputs[0], &mut BytesMut::new());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_decompression_limits() {
+        let zeros = vec![0; 1024 * 1024];
+        let text = b"the quick brown fox jumps over the lazy dog ".repeat(1000);
+
+        for algorithm in ALGORITHMS {
+            let config = CompressionConfig::new(algorithm).with_max_ratio(u64::MAX).with_max_chunk_output(64 * 1024);
+            let outputs = compress(config, &[&zeros]);
+
+            let mut function = CompressionFunction::new(Role::Decompress, config);
+            let mut output = BytesMut::new();
+            let result = function.on_chunk(Direction::Upstream, &outputs[0], &mut output);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData, "{}", algorithm);
+            assert!(output.len() <= 64 * 1024, "{}", algorithm);
+
+            let config = CompressionConfig::new(algorithm).with_max_ratio(10);
+            let outputs = compress(config, &[&text]);
+
+            let mut function = CompressionFunction::new(Role::Decompress, config);
+            let result = function.on_chunk(Direction::Upstream, &outputs[0], &mut BytesMut::new());
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_truncated_stream() {
+        for algorithm in ALGORITHMS {
+            let outputs = compress(CompressionConfig::new(algorithm), &[b"hello"]);
+            let mut function = CompressionFunction::new(Role::Decompress, CompressionConfig::new(algorithm));
+
+            // The end of the compressed stream, which the compressing end writes at EOF, is missing.
+            function.on_chunk(Direction::Upstream, &outputs[0], &mut BytesMut::new()).unwrap();
+            let result = function.on_eof(Direction::Upstream, &mut BytesMut::new());
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof, "{}", algorithm);
+
+            function.on_chunk(Direction::Upstream, &outputs[1], &mut BytesMut::new()).unwrap();
+            function.on_eof(Direction::Upstream, &mut BytesMut::new()).unwrap();
+
+            // Nothing may follow the end.
+            let result = function.on_chunk(Direction::Upstream, b"more", &mut BytesMut::new());
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData, "{}", algorithm);
+        }
+
+        let mut function = CompressionFunction::new(Role::Decompress, CompressionConfig::new(Algorithm::Zstd));
+        let result = function.on_eof(Direction::Upstream, &mut BytesMut::new());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_levels() {
+        assert!(CompressionConfig::new(Algorithm::Zstd).with_level(19).is_ok());
+        assert!(CompressionConfig::new(Algorithm::Zstd).with_level(0).is_err());
+        assert!(CompressionConfig::new(Algorithm::Deflate).with_level(9).is_ok());
+        assert!(CompressionConfig::new(Algorithm::Deflate).with_level(10).is_err());
+    }
+
+    #[test]
+    fn test_flush_from_str() {
+        assert_eq!("chunk".parse::<Flush>().unwrap(), Flush::Chunk);
+        assert_eq!("65536".parse::<Flush>().unwrap(), Flush::Bytes(65536));
+        assert!("never".parse::<Flush>().is_err());
+    }
+}
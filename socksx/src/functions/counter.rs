@@ -0,0 +1,453 @@
+//! Accounting of the traffic of every flow.
+//!
+//! The counter passes data on as is. It keeps running totals per client, destination and chain position, and emits a
+//! `FlowRecord` for every flow once it closes, as a line of JSON. To bound its memory, it only keeps the totals of as
+//! many keys as configured, plus those of keys that still have open flows.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+use serde::Serialize;
+
+use crate::functions::{Direction, Flow, FunctionFactory, StreamFunction};
+
+/// How many keys the running totals are kept for, unless configured otherwise.
+pub const DEFAULT_MAX_KEYS: usize = 10_000;
+
+/// The traffic in one direction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Traffic {
+    /// The number of bytes.
+    pub bytes: u64,
+    /// The number of chunks that the bytes arrived in (a read from the socket each), the closest that a stream
+    /// function gets to packets.
+    pub packets: u64,
+}
+
+impl Traffic {
+    fn add(
+        &mut self,
+        other: &Traffic,
+    ) {
+        self.bytes += other.bytes;
+        self.packets += other.packets;
+    }
+}
+
+/// What the running totals are grouped by.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct FlowKey {
+    /// The IP address of the client.
+    pub client: Option<IpAddr>,
+    /// The destination that the client requested, or else the address that the proxy connected to.
+    pub destination: Option<String>,
+    /// The position of the proxy in the chain of the request, if it is part of one.
+    pub chain_index: Option<usize>,
+}
+
+impl From<&Flow> for FlowKey {
+    fn from(flow: &Flow) -> Self {
+        let destination = match (&flow.target, flow.destination) {
+            (Some(target), _) => Some(target.to_string()),
+            (None, destination) => destination.map(|destination| destination.to_string()),
+        };
+
+        Self {
+            client: flow.source.map(|source| source.ip()),
+            destination,
+            chain_index: flow.chain_index,
+        }
+    }
+}
+
+/// The running totals of all flows with the same `FlowKey`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Totals {
+    /// The number of flows, including those that are still open.
+    pub flows: u64,
+    /// The number of flows that are still open.
+    pub active: u64,
+    pub upstream: Traffic,
+    pub downstream: Traffic,
+}
+
+/// The accounting of a single flow, emitted once it closes.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FlowRecord {
+    /// The address of the client.
+    pub client: Option<SocketAddr>,
+    /// The destination that the client requested, or else the address that the proxy connected to.
+    pub destination: Option<String>,
+    /// The position of the proxy in the chain of the request, if it is part of one.
+    pub chain_index: Option<usize>,
+    /// When the flow started, in milliseconds since the Unix epoch.
+    pub started_at: u64,
+    /// How long the flow was open, in milliseconds.
+    pub duration_ms: u64,
+    /// How long it took for the first downstream byte to arrive, in milliseconds, if any did.
+    pub first_byte_ms: Option<u64>,
+    pub upstream: Traffic,
+    pub downstream: Traffic,
+}
+
+impl Totals {
+    fn add(
+        &mut self,
+        other: &Totals,
+    ) {
+        self.flows += other.flows;
+        self.active += other.active;
+        self.upstream.add(&other.upstream);
+        self.downstream.add(&other.downstream);
+    }
+}
+
+/// Where flow records are written to.
+type Sink = Arc<Mutex<Box<dyn Write + Send>>>;
+
+/// The running totals of a `Counter`.
+#[derive(Default)]
+struct State {
+    totals: HashMap<FlowKey, Totals>,
+    /// The totals of the keys that were dropped, without open flows, to make room for others.
+    dropped: Totals,
+}
+
+/// Counts the traffic of every flow it is applied to.
+///
+/// A `Counter` is a `FunctionFactory`: add it to a `Pipeline`, and keep a clone to query the running totals.
+#[derive(Clone)]
+pub struct Counter {
+    state: Arc<Mutex<State>>,
+    max_keys: usize,
+    sink: Option<Sink>,
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self {
+            state: Arc::default(),
+            max_keys: DEFAULT_MAX_KEYS,
+            sink: None,
+        }
+    }
+}
+
+impl Counter {
+    /// Creates a new `Counter`, that only keeps running totals.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps the running totals of up to `max_keys` keys, not counting those with open flows.
+    pub fn with_max_keys(
+        mut self,
+        max_keys: usize,
+    ) -> Self {
+        self.max_keys = max_keys;
+        self
+    }
+
+    /// Writes a `FlowRecord` to `sink`, as a line of JSON, whenever a flow closes.
+    pub fn with_sink<W>(
+        mut self,
+        sink: W,
+    ) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        self.sink = Some(Arc::new(Mutex::new(Box::new(sink))));
+        self
+    }
+
+    /// Returns the running totals, per client, destination and chain position.
+    ///
+    /// Once there are totals for more than the maximum number of keys, those of the keys without open flows are
+    /// dropped: they are no longer returned here, but still count towards `total`, and were emitted as `FlowRecord`s.
+    pub fn totals(&self) -> HashMap<FlowKey, Totals> {
+        self.state.lock().unwrap().totals.clone()
+    }
+
+    /// Returns the running totals of all flows together.
+    pub fn total(&self) -> Totals {
+        let state = self.state.lock().unwrap();
+        state.totals.values().fold(state.dropped, |mut total, totals| {
+            total.add(totals);
+            total
+        })
+    }
+
+    /// Applies `update` to the running totals of `key`, and makes room for them if there are too many keys.
+    fn update(
+        &self,
+        key: &FlowKey,
+        update: impl FnOnce(&mut Totals),
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if state.totals.len() >= self.max_keys && !state.totals.contains_key(key) {
+            let State { totals, dropped } = &mut *state;
+            totals.retain(|_, totals| {
+                if totals.active == 0 {
+                    dropped.add(totals);
+                }
+                totals.active > 0
+            });
+        }
+
+        update(state.totals.entry(key.clone()).or_default());
+    }
+
+    /// Writes `record` to the sink, if there is one.
+    fn emit(
+        &self,
+        record: &FlowRecord,
+    ) {
+        if let Some(sink) = &self.sink {
+            let mut sink = sink.lock().unwrap();
+
+            let result = serde_json::to_writer(&mut *sink, record)
+                .map_err(io::Error::from)
+                .and_then(|_| writeln!(sink))
+                .and_then(|_| sink.flush());
+
+            if let Err(error) = result {
+                warn!("Failed to write flow record: {}", error);
+            }
+        }
+    }
+}
+
+impl FunctionFactory for Counter {
+    fn create(
+        &self,
+        flow: &Flow,
+    ) -> io::Result<Box<dyn StreamFunction>> {
+        Ok(Box::new(CounterFunction::new(self.clone(), flow)))
+    }
+}
+
+/// Counts the traffic of a single flow, and accounts for it in its `Counter`.
+pub struct CounterFunction {
+    counter: Counter,
+    key: FlowKey,
+    record: FlowRecord,
+    started: Instant,
+}
+
+impl CounterFunction {
+    /// Creates a new `CounterFunction`, and counts the flow as open.
+    pub fn new(
+        counter: Counter,
+        flow: &Flow,
+    ) -> Self {
+        let key = FlowKey::from(flow);
+        counter.update(&key, |totals| {
+            totals.flows += 1;
+            totals.active += 1;
+        });
+
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+
+        let record = FlowRecord {
+            client: flow.source,
+            destination: key.destination.clone(),
+            chain_index: key.chain_index,
+            started_at,
+            duration_ms: 0,
+            first_byte_ms: None,
+            upstream: Traffic::default(),
+            downstream: Traffic::default(),
+        };
+
+        Self {
+            counter,
+            key,
+            record,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl StreamFunction for CounterFunction {
+    fn on_chunk(
+        &mut self,
+        direction: Direction,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        output.extend_from_slice(chunk);
+
+        let traffic = Traffic {
+            bytes: chunk.len() as u64,
+            packets: 1,
+        };
+
+        match direction {
+            Direction::Upstream => self.record.upstream.add(&traffic),
+            Direction::Downstream => {
+                if self.record.first_byte_ms.is_none() {
+                    self.record.first_byte_ms = Some(self.started.elapsed().as_millis() as u64);
+                }
+                self.record.downstream.add(&traffic);
+            }
+        }
+
+        self.counter.update(&self.key, |totals| match direction {
+            Direction::Upstream => totals.upstream.add(&traffic),
+            Direction::Downstream => totals.downstream.add(&traffic),
+        });
+
+        Ok(())
+    }
+}
+
+impl Drop for CounterFunction {
+    /// The flow closes with its `FunctionStream`, whether it ended cleanly or not.
+    fn drop(&mut self) {
+        self.record.duration_ms = self.started.elapsed().as_millis() as u64;
+
+        self.counter.update(&self.key, |totals| totals.active -= 1);
+        self.counter.emit(&self.record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::addresses::Address;
+
+    use super::*;
+
+    /// A sink that can still be read after it was handed to a `Counter`.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(
+            &mut self,
+            buf: &[u8],
+        ) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn flow(port: u16) -> Flow {
+        let source = SocketAddr::from(([127, 0, 0, 1], port));
+        let destination = SocketAddr::from(([10, 0, 0, 1], 443));
+
+        Flow::new(Some(source), Some(destination))
+            .with_target(Address::new("example.com", 443))
+            .with_chain_index(Some(1))
+    }
+
+    fn relay(
+        function: &mut dyn StreamFunction,
+        direction: Direction,
+        chunk: &[u8],
+    ) {
+        let mut output = BytesMut::new();
+        function.on_chunk(direction, chunk, &mut output).unwrap();
+        assert_eq!(&output[..], chunk);
+    }
+
+    #[test]
+    fn test_running_totals() {
+        let counter = Counter::new();
+
+        let mut first = counter.create(&flow(40000)).unwrap();
+        let mut second = counter.create(&flow(40001)).unwrap();
+        relay(first.as_mut(), Direction::Upstream, b"hello");
+        relay(second.as_mut(), Direction::Upstream, b"hi");
+        relay(second.as_mut(), Direction::Downstream, b"hey");
+        drop(first);
+
+        let totals = counter.totals();
+        assert_eq!(totals.len(), 1);
+
+        let key = FlowKey {
+            client: Some(IpAddr::from([127, 0, 0, 1])),
+            destination: Some(String::from("example.com:443")),
+            chain_index: Some(1),
+        };
+        let expected = Totals {
+            flows: 2,
+            active: 1,
+            upstream: Traffic { bytes: 7, packets: 2 },
+            downstream: Traffic { bytes: 3, packets: 1 },
+        };
+        assert_eq!(totals[&key], expected);
+        assert_eq!(counter.total(), expected);
+    }
+
+    #[test]
+    fn test_flow_record() {
+        let buffer = Buffer::default();
+        let counter = Counter::new().with_sink(buffer.clone());
+
+        let mut function = counter.create(&flow(40000)).unwrap();
+        relay(function.as_mut(), Direction::Upstream, b"GET / HTTP/1.1\r\n\r\n");
+        relay(function.as_mut(), Direction::Downstream, b"HTTP/1.1 200 OK\r\n");
+        relay(function.as_mut(), Direction::Downstream, b"\r\n");
+        assert!(buffer.0.lock().unwrap().is_empty());
+        drop(function);
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.lines().count(), 1);
+
+        let record: serde_json::Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(record["client"], "127.0.0.1:40000");
+        assert_eq!(record["destination"], "example.com:443");
+        assert_eq!(record["chain_index"], 1);
+        assert_eq!(record["upstream"]["bytes"], 18);
+        assert_eq!(record["upstream"]["packets"], 1);
+        assert_eq!(record["downstream"]["bytes"], 19);
+        assert_eq!(record["downstream"]["packets"], 2);
+        assert!(record["first_byte_ms"].is_u64());
+        assert!(record["duration_ms"].is_u64());
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let counter = Counter::new().with_max_keys(2);
+        let destination = SocketAddr::from(([10, 0, 0, 1], 443));
+        let flow = |client: u8| Flow::new(Some(SocketAddr::from(([127, 0, 0, client], 40000))), Some(destination));
+
+        let open = counter.create(&flow(1)).unwrap();
+        drop(counter.create(&flow(2)).unwrap());
+        assert_eq!(counter.totals().len(), 2);
+
+        // The closed flow makes room, while the open one is kept.
+        let mut third = counter.create(&flow(3)).unwrap();
+        relay(third.as_mut(), Direction::Upstream, b"hello");
+        let totals = counter.totals();
+        assert_eq!(totals.len(), 2);
+        assert!(totals.contains_key(&FlowKey::from(&flow(1))));
+        assert!(totals.contains_key(&FlowKey::from(&flow(3))));
+
+        let total = counter.total();
+        assert_eq!(total.flows, 3);
+        assert_eq!(total.active, 2);
+        assert_eq!(total.upstream.bytes, 5);
+        drop(open);
+    }
+
+    #[test]
+    fn test_key_without_target() {
+        let source = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let flow = Flow::new(Some(source), Some(SocketAddr::from(([10, 0, 0, 1], 80))));
+
+        let key = FlowKey::from(&flow);
+        assert_eq!(key.destination.as_deref(), Some("10.0.0.1:80"));
+        assert_eq!(key.chain_index, None);
+    }
+}
@@ -0,0 +1,955 @@
+//! Filtering of connections by the first bytes of their data.
+//!
+//! The firewall holds back the start of each direction until its rules are decided. Rules are evaluated in order: the
+//! first `allow` or `deny` whose matcher matches decides the direction, while `tag` rules only log the flow and let
+//! evaluation continue. When no rule decides, upstream data falls back to the default action and downstream data is
+//! allowed. Data that a `deny` rule cannot inspect, e.g., a malformed ClientHello or headers that exceed the inspect
+//! limit, is denied, so that the rule cannot be evaded with such data. A denied flow is closed on both ends.
+//!
+//! Rules can also be read from text, one per line (`#` starts a comment):
+//! ```text
+//! default deny
+//! allow sni *.example.com
+//! allow host example.org
+//! tag:bittorrent bytes 13426974546f7272656e742070726f746f636f6c
+//! deny bytes:downstream 5353482d
+//! ```
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+
+use crate::functions::{Direction, Flow, FunctionFactory, StreamFunction};
+
+/// How many bytes of each direction are held back, at most, to decide the rules.
+pub const DEFAULT_INSPECT_LIMIT: usize = 4096;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+
+/// The outcome of inspecting the first bytes of a direction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Inspection {
+    Match,
+    NoMatch,
+    /// The bytes so far are not enough to decide.
+    NeedMore,
+    /// The bytes are of the kind that the matcher inspects, but cannot be, e.g., because they are malformed.
+    Undecided,
+}
+
+/// Decides whether the first bytes of a direction match.
+pub trait Matcher: fmt::Display + Send + Sync {
+    /// The direction whose data the matcher inspects.
+    fn direction(&self) -> Direction {
+        Direction::Upstream
+    }
+
+    /// Inspects `data`, all of the direction that arrived so far.
+    fn inspect(
+        &self,
+        data: &[u8],
+    ) -> Inspection;
+}
+
+/// A host name, or a wildcard (`*.example.com`) that matches all subdomains of one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HostPattern(String);
+
+impl HostPattern {
+    /// Returns whether `host` matches the pattern, ignoring case and a trailing dot.
+    pub fn matches(
+        &self,
+        host: &str,
+    ) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+
+        match self.0.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => self.0 == "*" || host == self.0,
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        let pattern = pattern.trim_end_matches('.').to_lowercase();
+        ensure!(!pattern.is_empty(), "Empty host pattern.");
+        ensure!(
+            pattern == "*" || !pattern.trim_start_matches("*.").contains('*'),
+            "Invalid host pattern: {} (wildcards are only supported as `*.` prefix)",
+            pattern
+        );
+
+        Ok(Self(pattern))
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Matches the server name (SNI) of a TLS ClientHello.
+#[derive(Clone, Debug)]
+pub struct SniMatcher(HostPattern);
+
+impl SniMatcher {
+    pub fn new(pattern: HostPattern) -> Self {
+        Self(pattern)
+    }
+}
+
+impl Matcher for SniMatcher {
+    fn inspect(
+        &self,
+        data: &[u8],
+    ) -> Inspection {
+        match tls_server_name(data) {
+            Parse::Done(Some(name)) if self.0.matches(&name) => Inspection::Match,
+            Parse::Incomplete => Inspection::NeedMore,
+            // Data that is not TLS at all has no server name to match.
+            Parse::Invalid if data.first() == Some(&CONTENT_TYPE_HANDSHAKE) => Inspection::Undecided,
+            _ => Inspection::NoMatch,
+        }
+    }
+}
+
+impl fmt::Display for SniMatcher {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "sni {}", self.0)
+    }
+}
+
+/// Matches the `Host` header of an HTTP/1 request, without its port.
+#[derive(Clone, Debug)]
+pub struct HostMatcher(HostPattern);
+
+impl HostMatcher {
+    pub fn new(pattern: HostPattern) -> Self {
+        Self(pattern)
+    }
+}
+
+impl Matcher for HostMatcher {
+    fn inspect(
+        &self,
+        data: &[u8],
+    ) -> Inspection {
+        match http_host(data) {
+            Parse::Done(Some(host)) if self.0.matches(&host) => Inspection::Match,
+            Parse::Incomplete => Inspection::NeedMore,
+            // Data that is not HTTP at all has no host to match.
+            Parse::Invalid if starts_with_method(data) => Inspection::Undecided,
+            _ => Inspection::NoMatch,
+        }
+    }
+}
+
+impl fmt::Display for HostMatcher {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "host {}", self.0)
+    }
+}
+
+/// Matches data that starts with a byte pattern.
+#[derive(Clone, Debug)]
+pub struct BytesMatcher {
+    direction: Direction,
+    pattern: Vec<u8>,
+}
+
+impl BytesMatcher {
+    /// Creates a new `BytesMatcher`, for data travelling in `direction`.
+    pub fn new(
+        direction: Direction,
+        pattern: Vec<u8>,
+    ) -> Self {
+        Self { direction, pattern }
+    }
+}
+
+impl Matcher for BytesMatcher {
+    fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    fn inspect(
+        &self,
+        data: &[u8],
+    ) -> Inspection {
+        if data.starts_with(&self.pattern) {
+            Inspection::Match
+        } else if self.pattern.starts_with(data) {
+            Inspection::NeedMore
+        } else {
+            Inspection::NoMatch
+        }
+    }
+}
+
+impl fmt::Display for BytesMatcher {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        let pattern: String = self.pattern.iter().map(|byte| format!("{:02x}", byte)).collect();
+        write!(f, "bytes:{} {}", self.direction, pattern)
+    }
+}
+
+/// What happens to a flow when a rule matches.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+    /// Logs the flow with the tag, and continues with the next rule.
+    Tag(String),
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(action: &str) -> Result<Self> {
+        match action.split_once(':') {
+            None if action == "allow" => Ok(Action::Allow),
+            None if action == "deny" => Ok(Action::Deny),
+            Some(("tag", tag)) if !tag.is_empty() => Ok(Action::Tag(tag.to_string())),
+            _ => bail!("Unrecognized firewall action: {} (supported: `allow`, `deny`, `tag:<name>`)", action),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            Action::Allow => write!(f, "allow"),
+            Action::Deny => write!(f, "deny"),
+            Action::Tag(tag) => write!(f, "tag:{}", tag),
+        }
+    }
+}
+
+/// A matcher, and what to do with flows that it matches.
+#[derive(Clone)]
+pub struct Rule {
+    pub action: Action,
+    pub matcher: Arc<dyn Matcher>,
+}
+
+impl Rule {
+    /// Creates a new `Rule`.
+    pub fn new<M>(
+        action: Action,
+        matcher: M,
+    ) -> Self
+    where
+        M: Matcher + 'static,
+    {
+        Self {
+            action,
+            matcher: Arc::new(matcher),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    /// Parses a rule in the form `<action> <matcher> <argument>`, e.g., `deny sni *.example.com`.
+    fn from_str(rule: &str) -> Result<Self> {
+        let parts: Vec<&str> = rule.split_whitespace().collect();
+        let (action, matcher, argument) = match parts[..] {
+            [action, matcher, argument] => (action.parse()?, matcher, argument),
+            _ => bail!("Invalid firewall rule: {:?} (expected `<action> <matcher> <argument>`)", rule),
+        };
+
+        let rule = match matcher {
+            "sni" => Rule::new(action, SniMatcher::new(argument.parse()?)),
+            "host" => Rule::new(action, HostMatcher::new(argument.parse()?)),
+            "bytes" | "bytes:upstream" | "bytes:downstream" => {
+                let direction = match matcher {
+                    "bytes:downstream" => Direction::Downstream,
+                    _ => Direction::Upstream,
+                };
+
+                let pattern = decode_hex(argument).with_context(|| format!("Invalid byte pattern: {:?}", argument))?;
+
+                Rule::new(action, BytesMatcher::new(direction, pattern))
+            }
+            matcher => bail!(
+                "Unrecognized firewall matcher: {} (supported: `sni`, `host`, `bytes[:upstream|:downstream]`)",
+                matcher
+            ),
+        };
+
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "{} {}", self.action, self.matcher)
+    }
+}
+
+impl fmt::Debug for Rule {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "Rule({})", self)
+    }
+}
+
+/// Allows, denies or tags every flow it is applied to, according to its rules.
+#[derive(Clone, Debug)]
+pub struct Firewall {
+    rules: Arc<Vec<Rule>>,
+    deny_by_default: bool,
+    inspect_limit: usize,
+}
+
+impl Default for Firewall {
+    fn default() -> Self {
+        Self {
+            rules: Arc::default(),
+            deny_by_default: false,
+            inspect_limit: DEFAULT_INSPECT_LIMIT,
+        }
+    }
+}
+
+impl Firewall {
+    /// Creates a new `Firewall`, without rules, that allows every flow.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a rule, evaluated after the rules that are already there.
+    pub fn with_rule(
+        mut self,
+        rule: Rule,
+    ) -> Self {
+        Arc::make_mut(&mut self.rules).push(rule);
+        self
+    }
+
+    /// Denies upstream data that no rule allows.
+    pub fn deny_by_default(mut self) -> Self {
+        self.deny_by_default = true;
+        self
+    }
+
+    /// Holds back at most `limit` bytes of each direction; rules that need more data than that cannot decide, so that
+    /// `deny` rules fall back to the default action, and other rules do not match.
+    pub fn with_inspect_limit(
+        mut self,
+        limit: usize,
+    ) -> Self {
+        self.inspect_limit = limit;
+        self
+    }
+
+    /// Returns the rules, in the order in which they are evaluated.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+}
+
+impl FromStr for Firewall {
+    type Err = anyhow::Error;
+
+    /// Parses rules, one per line, and an optional `default allow|deny` line.
+    fn from_str(rules: &str) -> Result<Self> {
+        let mut firewall = Firewall::new();
+
+        for (number, line) in rules.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let context = || format!("Invalid firewall rule on line {}", number + 1);
+            firewall = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["default", "allow"] => Firewall {
+                    deny_by_default: false,
+                    ..firewall
+                },
+                ["default", "deny"] => firewall.deny_by_default(),
+                ["default", ..] => bail!("{}: the default action is either `allow` or `deny`", context()),
+                _ => firewall.with_rule(line.parse().with_context(context)?),
+            };
+        }
+
+        Ok(firewall)
+    }
+}
+
+impl FunctionFactory for Firewall {
+    fn create(
+        &self,
+        flow: &Flow,
+    ) -> io::Result<Box<dyn StreamFunction>> {
+        Ok(Box::new(FirewallFunction {
+            firewall: self.clone(),
+            flow: flow.clone(),
+            upstream: Inspector::default(),
+            downstream: Inspector::default(),
+        }))
+    }
+}
+
+/// The state of the rules for one direction of a flow.
+#[derive(Default)]
+struct Inspector {
+    /// The data that is held back until the rules are decided.
+    buffer: BytesMut,
+    /// The next rule to evaluate.
+    next_rule: usize,
+    decided: bool,
+}
+
+/// Applies the rules of a `Firewall` to a single flow.
+pub struct FirewallFunction {
+    firewall: Firewall,
+    flow: Flow,
+    upstream: Inspector,
+    downstream: Inspector,
+}
+
+impl FirewallFunction {
+    /// Evaluates the rules for `direction`, and passes on the held back data once they are decided.
+    fn evaluate(
+        &mut self,
+        direction: Direction,
+        eof: bool,
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        let inspector = match direction {
+            Direction::Upstream => &mut self.upstream,
+            Direction::Downstream => &mut self.downstream,
+        };
+
+        let limit = self.firewall.inspect_limit;
+        let complete = eof || inspector.buffer.len() >= limit;
+        let data = &inspector.buffer[..inspector.buffer.len().min(limit)];
+
+        while let Some(rule) = self.firewall.rules.get(inspector.next_rule) {
+            if rule.matcher.direction() != direction {
+                inspector.next_rule += 1;
+                continue;
+            }
+
+            let inspection = match rule.matcher.inspect(data) {
+                Inspection::NeedMore if !complete => return Ok(()),
+                // Data that ended before the matcher could decide did not match, while data beyond the limit might.
+                Inspection::NeedMore if !eof => Inspection::Undecided,
+                inspection => inspection,
+            };
+
+            match inspection {
+                Inspection::Undecided if rule.action == Action::Deny => {
+                    debug!("Rule `{}` cannot inspect flow {}, denying it.", rule, describe(&self.flow));
+                    return Err(deny(&self.flow, &rule.to_string()));
+                }
+                Inspection::NeedMore | Inspection::NoMatch | Inspection::Undecided => {}
+                Inspection::Match => match &rule.action {
+                    Action::Allow => break,
+                    Action::Deny => return Err(deny(&self.flow, &rule.to_string())),
+                    Action::Tag(tag) => info!("Tagged flow {} as {} (rule `{}`).", describe(&self.flow), tag, rule),
+                },
+            }
+
+            inspector.next_rule += 1;
+        }
+
+        let exhausted = inspector.next_rule >= self.firewall.rules.len();
+        if exhausted && direction == Direction::Upstream && self.firewall.deny_by_default {
+            return Err(deny(&self.flow, "default deny"));
+        }
+
+        inspector.decided = true;
+        output.extend_from_slice(&inspector.buffer.split());
+
+        Ok(())
+    }
+}
+
+impl StreamFunction for FirewallFunction {
+    fn on_chunk(
+        &mut self,
+        direction: Direction,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        let inspector = match direction {
+            Direction::Upstream => &mut self.upstream,
+            Direction::Downstream => &mut self.downstream,
+        };
+
+        if inspector.decided {
+            output.extend_from_slice(chunk);
+            return Ok(());
+        }
+
+        inspector.buffer.extend_from_slice(chunk);
+        self.evaluate(direction, false, output)
+    }
+
+    fn on_eof(
+        &mut self,
+        direction: Direction,
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        let decided = match direction {
+            Direction::Upstream => self.upstream.decided,
+            Direction::Downstream => self.downstream.decided,
+        };
+
+        if decided {
+            Ok(())
+        } else {
+            self.evaluate(direction, true, output)
+        }
+    }
+}
+
+/// Describes a flow for the log, e.g., `127.0.0.1:40000 -> example.com:443`.
+fn describe(flow: &Flow) -> String {
+    let source = flow.source.map(|source| source.to_string()).unwrap_or_else(|| String::from("?"));
+    let destination = match (&flow.target, flow.destination) {
+        (Some(target), _) => target.to_string(),
+        (None, Some(destination)) => destination.to_string(),
+        (None, None) => String::from("?"),
+    };
+
+    format!("{} -> {}", source, destination)
+}
+
+/// Logs that a flow is denied, and returns the error that closes it.
+fn deny(
+    flow: &Flow,
+    reason: &str,
+) -> io::Error {
+    warn!("Denied flow {} (rule `{}`).", describe(flow), reason);
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("denied by firewall rule `{}`", reason))
+}
+
+/// Decodes a non-empty byte pattern, written as hexadecimal digits.
+fn decode_hex(pattern: &str) -> Option<Vec<u8>> {
+    if pattern.is_empty() || !pattern.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..pattern.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(pattern.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The outcome of parsing the start of a stream.
+#[derive(Debug, Eq, PartialEq)]
+enum Parse<T> {
+    Done(T),
+    Incomplete,
+    Invalid,
+}
+
+/// A cursor over a byte slice, for the length-prefixed fields of TLS.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(
+        &mut self,
+        length: usize,
+    ) -> Option<&'a [u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn number(
+        &mut self,
+        length: usize,
+    ) -> Option<usize> {
+        Some(self.take(length)?.iter().fold(0, |number, byte| number << 8 | *byte as usize))
+    }
+
+    /// Takes a field that is prefixed with its length, in `length` bytes.
+    fn prefixed(
+        &mut self,
+        length: usize,
+    ) -> Option<&'a [u8]> {
+        let length = self.number(length)?;
+        self.take(length)
+    }
+}
+
+/// Extracts the server name from a TLS ClientHello, if it has one.
+fn tls_server_name(data: &[u8]) -> Parse<Option<String>> {
+    const EXTENSION_SERVER_NAME: usize = 0x0000;
+    const NAME_TYPE_HOST_NAME: usize = 0x00;
+
+    // The ClientHello may be split across several records, whose fragments are joined until it is complete.
+    let mut records = Reader(data);
+    let mut handshake = vec![];
+    loop {
+        match records.take(3) {
+            Some([CONTENT_TYPE_HANDSHAKE, 0x03, _]) => {}
+            Some(_) => return Parse::Invalid,
+            None if records.0.is_empty() || records.0[0] == CONTENT_TYPE_HANDSHAKE => return Parse::Incomplete,
+            None => return Parse::Invalid,
+        }
+
+        match records.prefixed(2) {
+            Some(fragment) => handshake.extend_from_slice(fragment),
+            None => return Parse::Incomplete,
+        }
+
+        // The handshake message starts with its type, and its length in three bytes.
+        if handshake.len() >= 4 && handshake.len() - 4 >= Reader(&handshake[1..4]).number(3).unwrap_or_default() {
+            break;
+        }
+    }
+
+    let parse = || -> Option<Option<String>> {
+        let mut handshake = Reader(&handshake);
+        if handshake.number(1)? != HANDSHAKE_TYPE_CLIENT_HELLO as usize {
+            return None;
+        }
+
+        let mut hello = Reader(handshake.prefixed(3)?);
+        hello.take(2 + 32)?; // Version and random
+        hello.prefixed(1)?; // Session ID
+        hello.prefixed(2)?; // Cipher suites
+        hello.prefixed(1)?; // Compression methods
+
+        if hello.0.is_empty() {
+            return Some(None);
+        }
+
+        let mut extensions = Reader(hello.prefixed(2)?);
+        while !extensions.0.is_empty() {
+            let extension_type = extensions.number(2)?;
+            let extension = extensions.prefixed(2)?;
+            if extension_type != EXTENSION_SERVER_NAME {
+                continue;
+            }
+
+            let mut names = Reader(Reader(extension).prefixed(2)?);
+            while !names.0.is_empty() {
+                let name_type = names.number(1)?;
+                let name = names.prefixed(2)?;
+                if name_type == NAME_TYPE_HOST_NAME {
+                    return Some(Some(String::from_utf8(name.to_vec()).ok()?));
+                }
+            }
+        }
+
+        Some(None)
+    };
+
+    match parse() {
+        Some(name) => Parse::Done(name),
+        None => Parse::Invalid,
+    }
+}
+
+/// Extracts the host, without its port, from the `Host` header of an HTTP/1 request, if it has one.
+fn http_host(data: &[u8]) -> Parse<Option<String>> {
+    // The request line starts with a method, an upper-case token followed by a space.
+    let method_length = data.iter().take_while(|byte| byte.is_ascii_uppercase()).count();
+    match data.get(method_length) {
+        Some(b' ') if method_length > 0 => {}
+        None if method_length == data.len() => return Parse::Incomplete,
+        _ => return Parse::Invalid,
+    }
+
+    let end = match data.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Parse::Incomplete,
+    };
+
+    let headers = match std::str::from_utf8(&data[..end]) {
+        Ok(headers) => headers,
+        Err(_) => return Parse::Invalid,
+    };
+
+    let host = headers.split("\r\n").skip(1).find_map(|header| {
+        let (name, value) = header.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("host").then(|| value.trim())
+    });
+
+    let host = host.map(|host| match host.strip_prefix('[') {
+        // IPv6 addresses are enclosed in brackets, and contain colons themselves.
+        Some(host) => host.split(']').next().unwrap_or_default().to_string(),
+        None => host.split(':').next().unwrap_or_default().to_string(),
+    });
+
+    Parse::Done(host)
+}
+
+/// Returns whether `data` starts with the method of an HTTP request line, an upper-case token followed by a space.
+fn starts_with_method(data: &[u8]) -> bool {
+    let method_length = data.iter().take_while(|byte| byte.is_ascii_uppercase()).count();
+    method_length > 0 && data.get(method_length) == Some(&b' ')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal ClientHello for `server_name`.
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let name = server_name.as_bytes();
+
+        let mut server_name = vec![];
+        server_name.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        server_name.push(0x00);
+        server_name.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(name);
+
+        let mut extensions = vec![];
+        // An unrelated extension (supported groups) before the server name.
+        extensions.extend_from_slice(&[0x00, 0x0a, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1d]);
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&server_name);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0x42; 32]);
+        hello.extend_from_slice(&[0x00]); // Session ID
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // Cipher suites
+        hello.extend_from_slice(&[0x01, 0x00]); // Compression methods
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+    /// Splits the handshake of the single record in `record` across two records, after `at` bytes.
+    fn split_record(
+        record: &[u8],
+        at: usize,
+    ) -> Vec<u8> {
+        let (first, second) = record[5..].split_at(at);
+
+        let mut records = vec![];
+        for fragment in [first, second] {
+            records.extend_from_slice(&record[..3]);
+            records.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            records.extend_from_slice(fragment);
+        }
+        records
+    }
+
+    fn run(
+        firewall: &Firewall,
+        direction: Direction,
+        chunks: &[&[u8]],
+    ) -> io::Result<Vec<u8>> {
+        let mut function = firewall.create(&Flow::default())?;
+
+        let mut output = BytesMut::new();
+        for chunk in chunks {
+            function.on_chunk(direction, chunk, &mut output)?;
+        }
+        function.on_eof(direction, &mut output)?;
+
+        Ok(output.to_vec())
+    }
+
+    #[test]
+    fn test_tls_server_name() {
+        let hello = client_hello("www.example.com");
+        assert_eq!(tls_server_name(&hello), Parse::Done(Some(String::from("www.example.com"))));
+        assert_eq!(tls_server_name(&hello[..20]), Parse::Incomplete);
+        assert_eq!(tls_server_name(b"GET / HTTP/1.1\r\n"), Parse::Invalid);
+
+        let records = split_record(&hello, 20);
+        assert_eq!(tls_server_name(&records), Parse::Done(Some(String::from("www.example.com"))));
+        assert_eq!(tls_server_name(&records[..40]), Parse::Incomplete);
+    }
+
+    #[test]
+    fn test_http_host() {
+        let request = b"GET / HTTP/1.1\r\nUser-Agent: test\r\nhost: Example.org:8080\r\n\r\n";
+        assert_eq!(http_host(request), Parse::Done(Some(String::from("Example.org"))));
+        assert_eq!(http_host(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"), Parse::Done(Some(String::from("::1"))));
+        assert_eq!(http_host(&request[..20]), Parse::Incomplete);
+        assert_eq!(http_host(b"GE"), Parse::Incomplete);
+        assert_eq!(http_host(&client_hello("example.org")), Parse::Invalid);
+    }
+
+    #[test]
+    fn test_host_pattern() {
+        let wildcard: HostPattern = "*.example.com".parse().unwrap();
+        assert!(wildcard.matches("www.Example.com."));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+
+        let exact: HostPattern = "example.com".parse().unwrap();
+        assert!(exact.matches("EXAMPLE.COM"));
+        assert!(!exact.matches("www.example.com"));
+
+        assert!("www.*.com".parse::<HostPattern>().is_err());
+    }
+
+    #[test]
+    fn test_sni_rules() {
+        let firewall: Firewall = "default deny\nallow sni *.example.com".parse().unwrap();
+
+        let hello = client_hello("www.example.com");
+        let (first, second) = hello.split_at(10);
+        assert_eq!(run(&firewall, Direction::Upstream, &[first, second]).unwrap(), hello);
+
+        let error = run(&firewall, Direction::Upstream, &[&client_hello("www.example.org")]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_sni_split_across_records() {
+        let firewall: Firewall = "deny sni *.example.org".parse().unwrap();
+
+        let records = split_record(&client_hello("www.example.org"), 20);
+        let error = run(&firewall, Direction::Upstream, &[&records]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        let records = split_record(&client_hello("www.example.com"), 20);
+        assert_eq!(run(&firewall, Direction::Upstream, &[&records]).unwrap(), records);
+    }
+
+    #[test]
+    fn test_sni_undecided() {
+        // A handshake record that does not hold a ClientHello.
+        let malformed: &[u8] = &[0x16, 0x03, 0x01, 0x00, 0x04, 0x02, 0x00, 0x00, 0x00];
+
+        // The deny rule cannot decide, so it denies the flow, even though the default and a later rule allow it.
+        let firewall: Firewall = "deny sni *.example.org\nallow bytes 16".parse().unwrap();
+        let error = run(&firewall, Direction::Upstream, &[malformed]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        // Rules that allow do not decide on data they cannot inspect.
+        let firewall: Firewall = "allow sni *.example.org".parse().unwrap();
+        assert_eq!(run(&firewall, Direction::Upstream, &[malformed]).unwrap(), malformed);
+
+        // Data that is not TLS at all is not affected.
+        let firewall: Firewall = "default deny\ndeny sni *.example.org\nallow bytes 474554".parse().unwrap();
+        assert_eq!(run(&firewall, Direction::Upstream, &[b"GET /"]).unwrap(), b"GET /");
+    }
+
+    #[test]
+    fn test_host_rules() {
+        let firewall: Firewall = "deny host example.org".parse().unwrap();
+
+        let request: &[u8] = b"GET / HTTP/1.1\r\nHost: example.org\r\n\r\n";
+        assert!(run(&firewall, Direction::Upstream, &[request]).is_err());
+
+        let request: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(run(&firewall, Direction::Upstream, &[request]).unwrap(), request);
+    }
+
+    #[test]
+    fn test_host_undecided() {
+        // Headers that are not UTF-8 hide the host, so the deny rule denies the flow, although a later rule allows it.
+        let request: &[u8] = b"GET / HTTP/1.1\r\nHost: example.org\r\nX-Padding: \xff\r\n\r\n";
+        let firewall: Firewall = "default deny\ndeny host example.org\nallow bytes 474554".parse().unwrap();
+        let error = run(&firewall, Direction::Upstream, &[request]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        // Data that is not HTTP at all is not affected.
+        let firewall: Firewall = "deny host example.org".parse().unwrap();
+        let data: &[u8] = b"\xffGET / HTTP/1.1\r\n\r\n";
+        assert_eq!(run(&firewall, Direction::Upstream, &[data]).unwrap(), data);
+    }
+
+    #[test]
+    fn test_held_back_until_decided() {
+        let firewall: Firewall = "deny host example.org".parse().unwrap();
+        let mut function = firewall.create(&Flow::default()).unwrap();
+
+        let mut output = BytesMut::new();
+        function.on_chunk(Direction::Upstream, b"GET / HTTP/1.1\r\n", &mut output).unwrap();
+        assert!(output.is_empty());
+
+        function.on_chunk(Direction::Upstream, b"Host: example.com\r\n\r\nbody", &mut output).unwrap();
+        function.on_chunk(Direction::Upstream, b", more", &mut output).unwrap();
+        assert_eq!(&output[..], b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\nbody, more");
+    }
+
+    #[test]
+    fn test_bytes_rules() {
+        let firewall: Firewall = "tag:ssh bytes 5353482d\ndeny bytes:downstream 5353482d".parse().unwrap();
+
+        // Tags do not decide, and downstream rules only see downstream data.
+        assert_eq!(run(&firewall, Direction::Upstream, &[b"SS", b"H-2.0"]).unwrap(), b"SSH-2.0");
+        assert!(run(&firewall, Direction::Downstream, &[b"SSH-2.0"]).is_err());
+        assert_eq!(run(&firewall, Direction::Downstream, &[b"HTTP"]).unwrap(), b"HTTP");
+    }
+
+    #[test]
+    fn test_inspect_limit() {
+        let firewall = Firewall::from_str("deny host example.org").unwrap().with_inspect_limit(8);
+
+        // The headers do not end within the limit, so the rule cannot decide, and denies the flow although the default
+        // allows it.
+        let request: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let error = run(&firewall, Direction::Upstream, &[request]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        // Rules that allow do not decide on what they cannot inspect either, so the default applies.
+        let firewall = Firewall::from_str("allow host example.com").unwrap().with_inspect_limit(8);
+        assert_eq!(run(&firewall, Direction::Upstream, &[request]).unwrap(), request);
+        let firewall = Firewall::from_str("default deny\nallow host example.com").unwrap().with_inspect_limit(8);
+        assert!(run(&firewall, Direction::Upstream, &[request]).is_err());
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = "# comment\n\nallow sni example.com # trailing\ntag:web host *.example.com\n";
+        let firewall: Firewall = rules.parse().unwrap();
+        assert_eq!(firewall.rules().len(), 2);
+        assert_eq!(firewall.rules()[1].to_string(), "tag:web host *.example.com");
+
+        assert!("allow sni".parse::<Firewall>().is_err());
+        assert!("permit sni example.com".parse::<Firewall>().is_err());
+        assert!("deny bytes xyz".parse::<Firewall>().is_err());
+        assert!("default maybe".parse::<Firewall>().is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+
+use crate::addresses::Address;
+
+pub use stream::FunctionStream;
+
+pub mod chacha20;
+pub mod compression;
+pub mod counter;
+pub mod firewall;
+mod stream;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+/// The direction in which data travels through a function.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+    /// From the source (the client) towards the destination.
+    Upstream,
+    /// From the destination back to the source.
+    Downstream,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            Direction::Upstream => write!(f, "upstream"),
+            Direction::Downstream => write!(f, "downstream"),
+        }
+    }
+}
+
+/// The connection that a function is applied to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Flow {
+    /// The peer that sent the SOCKS request.
+    pub source: Option<SocketAddr>,
+    /// The destination, or the next proxy in the chain.
+    pub destination: Option<SocketAddr>,
+    /// The destination that the source requested.
+    pub target: Option<Address>,
+    /// The position of this proxy in the chain of the request, if it is part of one.
+    pub chain_index: Option<usize>,
+}
+
+impl Flow {
+    /// Creates a new `Flow`.
+    pub fn new(
+        source: Option<SocketAddr>,
+        destination: Option<SocketAddr>,
+    ) -> Self {
+        Self {
+            source,
+            destination,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the destination that the source requested.
+    pub fn with_target(
+        mut self,
+        target: Address,
+    ) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Sets the position of this proxy in the chain of the request.
+    pub fn with_chain_index(
+        mut self,
+        chain_index: Option<usize>,
+    ) -> Self {
+        self.chain_index = chain_index;
+        self
+    }
+}
+
+/// A network function: transforms or observes the data of a connection, one chunk at a time.
+///
+/// Every connection gets its own instance, so functions are free to keep per-connection state. Returning an error
+/// closes both ends of the connection.
+pub trait StreamFunction: Send {
+    /// Handles a chunk of data travelling in `direction`, appending whatever should be passed on to `output`.
+    ///
+    /// Chunk boundaries are arbitrary: they need not match the writes of the peer.
+    fn on_chunk(
+        &mut self,
+        direction: Direction,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()>;
+
+    /// Handles the end of the data travelling in `direction`, appending any remaining data to `output`.
+    fn on_eof(
+        &mut self,
+        _direction: Direction,
+        _output: &mut BytesMut,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Appends data that the function passes on in `direction` of its own accord to `output`, e.g., a handshake at the
+    /// start of the connection, or data that it held back until data travelling the other way arrived.
+    ///
+    /// Called before every chunk in `direction`, and whenever the connection is polled for data in it.
+    fn on_poll(
+        &mut self,
+        _direction: Direction,
+        _output: &mut BytesMut,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns whether the function waits for data travelling the other way, before it takes data in `direction`.
+    ///
+    /// Until none of the functions waits anymore, no more data is read in `direction`.
+    fn is_waiting(
+        &self,
+        _direction: Direction,
+    ) -> bool {
+        false
+    }
+}
+
+/// Creates a `StreamFunction` for every connection.
+pub trait FunctionFactory: Send + Sync {
+    /// Creates the function for a new connection.
+    fn create(
+        &self,
+        flow: &Flow,
+    ) -> io::Result<Box<dyn StreamFunction>>;
+}
+
+impl<F, T> FunctionFactory for F
+where
+    F: Fn(&Flow) -> io::Result<T> + Send + Sync,
+    T: StreamFunction + 'static,
+{
+    fn create(
+        &self,
+        flow: &Flow,
+    ) -> io::Result<Box<dyn StreamFunction>> {
+        Ok(Box::new(self(flow)?))
+    }
+}
+
+/// An ordered list of functions, applied between the source and the destination of a connection.
+///
+/// The first function is closest to the source: it sees upstream data first, and downstream data last.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    factories: Vec<Arc<dyn FunctionFactory>>,
+}
+
+impl Pipeline {
+    /// Creates an empty `Pipeline`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a function, on the destination side of the functions that are already in the pipeline.
+    pub fn with<F>(
+        mut self,
+        factory: F,
+    ) -> Self
+    where
+        F: FunctionFactory + 'static,
+    {
+        self.factories.push(Arc::new(factory));
+        self
+    }
+
+    /// Returns whether the pipeline has no functions.
+    pub fn is_empty(&self) -> bool {
+        self.factories.is_empty()
+    }
+
+    /// Returns the number of functions in the pipeline.
+    pub fn len(&self) -> usize {
+        self.factories.len()
+    }
+
+    /// Creates the functions of the pipeline for a new connection.
+    pub fn instantiate(
+        &self,
+        flow: &Flow,
+    ) -> io::Result<Vec<Box<dyn StreamFunction>>> {
+        self.factories.iter().map(|factory| factory.create(flow)).collect()
+    }
+
+    /// Applies the pipeline to `source`, a stream to the client of a new connection.
+    pub fn apply<S>(
+        &self,
+        source: S,
+        flow: &Flow,
+    ) -> io::Result<FunctionStream<S>> {
+        Ok(FunctionStream::new(source, self.instantiate(flow)?))
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("Pipeline").field("functions", &self.factories.len()).finish()
+    }
+}
+
+/// Passes data travelling in `direction` through `functions`, in the order in which it meets them, along with the data
+/// that they pass on of their own accord. At the end of the data, every function is also given the chance to flush,
+/// after the remaining data of the functions before it.
+pub(crate) fn transform(
+    functions: &mut [Box<dyn StreamFunction>],
+    direction: Direction,
+    chunk: &[u8],
+    eof: bool,
+) -> io::Result<BytesMut> {
+    let mut data = BytesMut::from(chunk);
+
+    let mut apply = |function: &mut Box<dyn StreamFunction>| -> io::Result<()> {
+        let mut output = BytesMut::new();
+        function.on_poll(direction, &mut output)?;
+        if !data.is_empty() {
+            function.on_chunk(direction, &data, &mut output)?;
+        }
+        if eof {
+            function.on_eof(direction, &mut output)?;
+        }
+
+        data = output;
+        Ok(())
+    };
+
+    match direction {
+        Direction::Upstream => functions.iter_mut().try_for_each(&mut apply)?,
+        Direction::Downstream => functions.iter_mut().rev().try_for_each(&mut apply)?,
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends its tag to every chunk, and to the end of the data.
+    struct Tag(&'static [u8]);
+
+    impl StreamFunction for Tag {
+        fn on_chunk(
+            &mut self,
+            _direction: Direction,
+            chunk: &[u8],
+            output: &mut BytesMut,
+        ) -> io::Result<()> {
+            output.extend_from_slice(chunk);
+            output.extend_from_slice(self.0);
+            Ok(())
+        }
+
+        fn on_eof(
+            &mut self,
+            _direction: Direction,
+            output: &mut BytesMut,
+        ) -> io::Result<()> {
+            output.extend_from_slice(b"$");
+            output.extend_from_slice(self.0);
+            Ok(())
+        }
+    }
+
+    fn pipeline() -> Vec<Box<dyn StreamFunction>> {
+        Pipeline::new()
+            .with(|_: &Flow| Ok(Tag(b"a")))
+            .with(|_: &Flow| Ok(Tag(b"b")))
+            .instantiate(&Flow::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_transform_order() {
+        let mut functions = pipeline();
+
+        assert_eq!(&transform(&mut functions, Direction::Upstream, b"x", false).unwrap()[..], b"xab");
+        assert_eq!(&transform(&mut functions, Direction::Downstream, b"x", false).unwrap()[..], b"xba");
+    }
+
+    #[test]
+    fn test_transform_eof() {
+        let mut functions = pipeline();
+
+        // The remaining data of the first function passes through the second, before the second flushes.
+        assert_eq!(&transform(&mut functions, Direction::Upstream, b"", true).unwrap()[..], b"$ab$b");
+        assert_eq!(&transform(&mut functions, Direction::Upstream, b"x", true).unwrap()[..], b"xa$ab$b");
+    }
+
+    #[test]
+    fn test_factory_error() {
+        let pipeline = Pipeline::new().with(|_: &Flow| -> io::Result<Tag> { Err(io::ErrorKind::Other.into()) });
+        assert!(pipeline.instantiate(&Flow::default()).is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll, Waker};
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::functions::{self, Direction, StreamFunction};
+
+/// The size of the chunks read from the wrapped stream.
+pub(crate) const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Wraps the stream to the source of a connection, and passes everything read from it (upstream data) and
+/// written to it (downstream data) through a pipeline of functions.
+pub struct FunctionStream<S> {
+    inner: S,
+    functions: Vec<Box<dyn StreamFunction>>,
+    chunk: Box<[u8]>,
+    /// Upstream data that went through the functions, but has not been read yet.
+    readable: BytesMut,
+    read_eof: bool,
+    /// Downstream data that went through the functions, but has not been written to the inner stream yet.
+    writable: BytesMut,
+    write_eof: bool,
+    /// The tasks that wait for the functions to stop waiting, see `StreamFunction::is_waiting`.
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl<S> FunctionStream<S> {
+    /// Creates a new `FunctionStream`.
+    ///
+    /// # Parameters
+    /// - `inner`: The stream to the source of the connection.
+    /// - `functions`: The functions to apply, starting with the one closest to the source.
+    pub fn new(
+        inner: S,
+        functions: Vec<Box<dyn StreamFunction>>,
+    ) -> Self {
+        Self {
+            inner,
+            functions,
+            chunk: vec![0; CHUNK_SIZE].into_boxed_slice(),
+            readable: BytesMut::new(),
+            read_eof: false,
+            writable: BytesMut::new(),
+            write_eof: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Returns whether any of the functions waits for data travelling the other way, before it takes data in
+    /// `direction`.
+    fn is_waiting(
+        &self,
+        direction: Direction,
+    ) -> bool {
+        self.functions.iter().any(|function| function.is_waiting(direction))
+    }
+
+    /// Returns a reference to the wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the wrapped stream. Data that went through the functions, but was not passed on yet, is lost.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> FunctionStream<S> {
+    /// Writes out the downstream data that went through the functions.
+    fn poll_drain(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while !self.writable.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.writable))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            self.writable.advance(written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Collects the data that the functions pass on of their own accord, in either direction. Downstream data starts
+    /// going out right away if the inner stream allows it, as it may be a while before anything is written.
+    fn poll_functions(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> io::Result<()> {
+        let upstream = functions::transform(&mut self.functions, Direction::Upstream, &[], false)?;
+        self.readable.extend_from_slice(&upstream);
+
+        if !self.write_eof {
+            let downstream = functions::transform(&mut self.functions, Direction::Downstream, &[], false)?;
+            self.writable.extend_from_slice(&downstream);
+
+            if let Poll::Ready(Err(error)) = self.poll_drain(cx) {
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for FunctionStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        // Functions may hold back data, or emit nothing at all for a chunk, so read until there is output.
+        while this.readable.is_empty() && !this.read_eof {
+            this.poll_functions(cx)?;
+            if !this.readable.is_empty() {
+                break;
+            }
+
+            if this.is_waiting(Direction::Upstream) {
+                this.read_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let mut chunk = ReadBuf::new(&mut this.chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+
+            let chunk = chunk.filled();
+            this.read_eof = chunk.is_empty();
+            this.readable = functions::transform(&mut this.functions, Direction::Upstream, chunk, this.read_eof)?;
+
+            // The upstream data may be what a function waited for to take downstream data.
+            if let Some(waker) = this.write_waker.take() {
+                waker.wake();
+            }
+        }
+
+        let length = this.readable.len().min(buf.remaining());
+        buf.put_slice(&this.readable.split_to(length));
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FunctionStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Only take on more data once the output of the previous write is out, to keep backpressure.
+        ready!(this.poll_drain(cx))?;
+        if this.write_eof {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if this.is_waiting(Direction::Downstream) {
+            this.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        this.writable = functions::transform(&mut this.functions, Direction::Downstream, buf, false)?;
+
+        // The downstream data may be what a function waited for to take upstream data.
+        if let Some(waker) = this.read_waker.take() {
+            waker.wake();
+        }
+
+        // The data is accepted either way, but starts going out right away if the inner stream allows it.
+        if let Poll::Ready(Err(error)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(error));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.write_eof {
+            ready!(this.poll_drain(cx))?;
+            if this.is_waiting(Direction::Downstream) {
+                this.write_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            this.write_eof = true;
+            this.writable = functions::transform(&mut this.functions, Direction::Downstream, &[], true)?;
+        }
+
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for FunctionStream<S> {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("FunctionStream")
+            .field("inner", &self.inner)
+            .field("functions", &self.functions.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Upper-cases upstream data, and reverses every downstream chunk.
+    struct Shout;
+
+    impl StreamFunction for Shout {
+        fn on_chunk(
+            &mut self,
+            direction: Direction,
+            chunk: &[u8],
+            output: &mut BytesMut,
+        ) -> io::Result<()> {
+            match direction {
+                Direction::Upstream => output.extend(chunk.iter().map(u8::to_ascii_uppercase)),
+                Direction::Downstream => output.extend(chunk.iter().rev()),
+            }
+            Ok(())
+        }
+
+        fn on_eof(
+            &mut self,
+            _direction: Direction,
+            output: &mut BytesMut,
+        ) -> io::Result<()> {
+            output.extend_from_slice(b"!");
+            Ok(())
+        }
+    }
+
+    /// Swallows everything until the end, as a function that buffers would.
+    #[derive(Default)]
+    struct Hold(BytesMut);
+
+    impl StreamFunction for Hold {
+        fn on_chunk(
+            &mut self,
+            _direction: Direction,
+            chunk: &[u8],
+            _output: &mut BytesMut,
+        ) -> io::Result<()> {
+            self.0.extend_from_slice(chunk);
+            Ok(())
+        }
+
+        fn on_eof(
+            &mut self,
+            _direction: Direction,
+            output: &mut BytesMut,
+        ) -> io::Result<()> {
+            output.extend_from_slice(&self.0.split());
+            Ok(())
+        }
+    }
+
+    /// Rejects any data.
+    struct Reject;
+
+    impl StreamFunction for Reject {
+        fn on_chunk(
+            &mut self,
+            _direction: Direction,
+            _chunk: &[u8],
+            _output: &mut BytesMut,
+        ) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "rejected"))
+        }
+    }
+
+    /// Greets upstream, and holds back upstream data until a downstream reply arrived, as a handshake would.
+    #[derive(Default)]
+    struct Handshake {
+        greeted: bool,
+        replied: bool,
+    }
+
+    impl StreamFunction for Handshake {
+        fn on_chunk(
+            &mut self,
+            direction: Direction,
+            chunk: &[u8],
+            output: &mut BytesMut,
+        ) -> io::Result<()> {
+            self.replied |= direction == Direction::Downstream;
+            output.extend_from_slice(chunk);
+            Ok(())
+        }
+
+        fn on_poll(
+            &mut self,
+            direction: Direction,
+            output: &mut BytesMut,
+        ) -> io::Result<()> {
+            if direction == Direction::Upstream && !self.greeted {
+                self.greeted = true;
+                output.extend_from_slice(b"hi ");
+            }
+            Ok(())
+        }
+
+        fn is_waiting(
+            &self,
+            direction: Direction,
+        ) -> bool {
+            direction == Direction::Upstream && !self.replied
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_and_write() {
+        let (source, mut client) = tokio::io::duplex(64);
+        let mut stream = FunctionStream::new(source, vec![Box::new(Shout)]);
+
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut upstream = vec![];
+        stream.read_to_end(&mut upstream).await.unwrap();
+        assert_eq!(upstream, b"HELLO!");
+
+        stream.write_all(b"olleh").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut downstream = vec![];
+        client.read_to_end(&mut downstream).await.unwrap();
+        assert_eq!(downstream, b"hello!");
+    }
+
+    #[tokio::test]
+    async fn test_held_back_data() {
+        let (source, mut client) = tokio::io::duplex(64);
+        let mut stream = FunctionStream::new(source, vec![Box::new(Hold::default())]);
+
+        client.write_all(b"first, ").await.unwrap();
+        client.write_all(b"second").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut upstream = vec![];
+        stream.read_to_end(&mut upstream).await.unwrap();
+        assert_eq!(upstream, b"first, second");
+    }
+
+    #[tokio::test]
+    async fn test_waiting_function() {
+        let (source, mut client) = tokio::io::duplex(64);
+        let mut stream = FunctionStream::new(source, vec![Box::new(Handshake::default())]);
+        client.write_all(b"data").await.unwrap();
+
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hi ");
+        let mut upstream = [0u8; 4];
+        let read = tokio::time::timeout(Duration::from_millis(50), stream.read(&mut upstream)).await;
+        assert!(read.is_err());
+
+        stream.write_all(b"reply").await.unwrap();
+        stream.read_exact(&mut upstream).await.unwrap();
+        assert_eq!(&upstream, b"data");
+        let mut downstream = [0u8; 5];
+        client.read_exact(&mut downstream).await.unwrap();
+        assert_eq!(&downstream, b"reply");
+    }
+
+    #[tokio::test]
+    async fn test_error_closes_stream() {
+        let (source, mut client) = tokio::io::duplex(64);
+        let mut stream = FunctionStream::new(source, vec![Box::new(Reject)]);
+
+        client.write_all(b"hello").await.unwrap();
+        let error = stream.read(&mut [0u8; 8]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,389 @@
+//! Network functions that are loaded from WebAssembly modules, and run with wasmtime.
+//!
+//! Every connection gets its own instance of the module, with its own memory. A module exports:
+//! - `memory`: its linear memory;
+//! - `alloc(length: i32) -> i32`: returns the address of a buffer of `length` bytes, to which the host copies a chunk;
+//! - `on_data(direction: i32, pointer: i32, length: i32) -> i64`: handles the chunk in the buffer, where `direction` is
+//!   `0` for upstream and `1` for downstream data;
+//! - `on_end(direction: i32) -> i64` (optional): handles the end of the data of a direction.
+//!
+//! Both handlers return the output to pass on as its address and length, packed as `(pointer << 32) | length`, or `-1`
+//! to reject the connection. Traps, such as running out of fuel, reject the connection too.
+//!
+//! Fuel bounds the work of an instance twice: over the whole connection, and for every single call, so that neither a
+//! long connection nor a single chunk can keep a module running indefinitely.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+use wasmtime::{Config, Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+use crate::functions::{Direction, Flow, FunctionFactory, StreamFunction};
+
+/// The memory that an instance may use, unless configured otherwise.
+pub const DEFAULT_MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+/// The fuel that an instance may consume over its connection, unless configured otherwise.
+pub const DEFAULT_FUEL_LIMIT: u64 = 10_000_000_000;
+/// The fuel that every call into an instance may consume, unless configured otherwise.
+pub const DEFAULT_FUEL_PER_CALL: u64 = 10_000_000;
+
+/// The resources that every instance of a module gets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WasmLimits {
+    /// The size, in bytes, up to which the memory of an instance may grow.
+    pub memory: usize,
+    /// The fuel (roughly, the number of instructions) that an instance may consume over its connection.
+    pub fuel: u64,
+    /// The fuel that a single call may consume, out of what is left of `fuel`.
+    pub fuel_per_call: u64,
+}
+
+impl Default for WasmLimits {
+    fn default() -> Self {
+        Self {
+            memory: DEFAULT_MEMORY_LIMIT,
+            fuel: DEFAULT_FUEL_LIMIT,
+            fuel_per_call: DEFAULT_FUEL_PER_CALL,
+        }
+    }
+}
+
+/// A compiled WebAssembly module, that is instantiated for every connection.
+#[derive(Clone)]
+pub struct WasmModule {
+    engine: Engine,
+    module: Module,
+    limits: WasmLimits,
+}
+
+impl WasmModule {
+    /// Compiles a module from its binary (or text) format.
+    pub fn new(
+        bytes: impl AsRef<[u8]>,
+        limits: WasmLimits,
+    ) -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+
+        let engine = Engine::new(&config)?;
+        let module = Module::new(&engine, bytes)?;
+
+        for export in ["memory", "alloc", "on_data"] {
+            ensure!(module.get_export(export).is_some(), "The module does not export `{}`.", export);
+        }
+
+        Ok(Self { engine, module, limits })
+    }
+
+    /// Compiles the module in the given file.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        limits: WasmLimits,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+
+        Self::new(bytes, limits).with_context(|| format!("Failed to load {:?}", path))
+    }
+
+    /// Returns the limits that every instance gets.
+    pub fn limits(&self) -> WasmLimits {
+        self.limits
+    }
+
+    /// Creates a new instance, with a store of its own.
+    fn instantiate(&self) -> Result<WasmFunction> {
+        let limits = StoreLimitsBuilder::new().memory_size(self.limits.memory).instances(1).build();
+
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        // Instantiation runs the start function of the module, if any, which counts as a call too.
+        let granted = self.limits.fuel.min(self.limits.fuel_per_call);
+        store.set_fuel(granted)?;
+
+        let instance = Instance::new(&mut store, &self.module, &[])?;
+        let memory = instance.get_memory(&mut store, "memory").context("The module does not export `memory`.")?;
+        let alloc = instance.get_typed_func(&mut store, "alloc")?;
+        let on_data = instance.get_typed_func(&mut store, "on_data")?;
+        let on_end = match instance.get_export(&mut store, "on_end") {
+            Some(_) => Some(instance.get_typed_func(&mut store, "on_end")?),
+            None => None,
+        };
+
+        let fuel = self.limits.fuel - (granted - store.get_fuel()?);
+        Ok(WasmFunction {
+            store,
+            fuel,
+            fuel_per_call: self.limits.fuel_per_call,
+            memory,
+            alloc,
+            on_data,
+            on_end,
+        })
+    }
+}
+
+impl fmt::Debug for WasmModule {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("WasmModule").field("limits", &self.limits).finish()
+    }
+}
+
+impl FunctionFactory for WasmModule {
+    fn create(
+        &self,
+        _flow: &Flow,
+    ) -> io::Result<Box<dyn StreamFunction>> {
+        self.instantiate().map(|function| Box::new(function) as Box<dyn StreamFunction>).map_err(into_io_error)
+    }
+}
+
+/// An instance of a `WasmModule`, for a single connection.
+pub struct WasmFunction {
+    store: Store<StoreLimits>,
+    /// The fuel that is left for the rest of the connection.
+    fuel: u64,
+    fuel_per_call: u64,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_data: TypedFunc<(i32, i32, i32), i64>,
+    on_end: Option<TypedFunc<i32, i64>>,
+}
+
+impl WasmFunction {
+    /// Copies the output of a handler, packed as `(pointer << 32) | length`, out of the memory of the instance.
+    fn output(
+        &self,
+        packed: i64,
+        output: &mut BytesMut,
+    ) -> Result<()> {
+        ensure!(packed != -1, "The module rejected the connection.");
+
+        let (pointer, length) = ((packed as u64 >> 32) as usize, (packed as u64 & 0xffff_ffff) as usize);
+        let data = self
+            .memory
+            .data(&self.store)
+            .get(pointer..pointer + length)
+            .context("The module returned output outside of its memory.")?;
+
+        output.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Runs `call` with the fuel of a single call, out of what is left for the connection, and deducts what it used.
+    fn metered<T>(
+        &mut self,
+        call: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        ensure!(self.fuel > 0, "The module ran out of fuel for the connection.");
+
+        let granted = self.fuel.min(self.fuel_per_call);
+        self.store.set_fuel(granted)?;
+        let result = call(self);
+        self.fuel -= granted - self.store.get_fuel()?;
+
+        result
+    }
+
+    fn handle_chunk(
+        &mut self,
+        direction: Direction,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> Result<()> {
+        let packed = self.metered(|function| {
+            let length = i32::try_from(chunk.len())?;
+            let pointer = function.alloc.call(&mut function.store, length)?;
+            function.memory.write(&mut function.store, pointer as u32 as usize, chunk)?;
+
+            function.on_data.call(&mut function.store, (direction_code(direction), pointer, length))
+        })?;
+
+        self.output(packed, output)
+    }
+
+    fn handle_end(
+        &mut self,
+        direction: Direction,
+        output: &mut BytesMut,
+    ) -> Result<()> {
+        if let Some(on_end) = self.on_end.clone() {
+            let packed = self.metered(|function| on_end.call(&mut function.store, direction_code(direction)))?;
+            self.output(packed, output)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StreamFunction for WasmFunction {
+    fn on_chunk(
+        &mut self,
+        direction: Direction,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        self.handle_chunk(direction, chunk, output).map_err(into_io_error)
+    }
+
+    fn on_eof(
+        &mut self,
+        direction: Direction,
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        self.handle_end(direction, output).map_err(into_io_error)
+    }
+}
+
+/// The code of a direction, as passed to the module.
+fn direction_code(direction: Direction) -> i32 {
+    match direction {
+        Direction::Upstream => 0,
+        Direction::Downstream => 1,
+    }
+}
+
+fn into_io_error(error: anyhow::Error) -> io::Error {
+    io::Error::other(format!("{:#}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Upper-cases upstream data in place, passes downstream data on as is, and ends upstream data with `!`.
+    const SHOUT: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (data (i32.const 0) "!")
+
+            (func (export "alloc") (param $length i32) (result i32)
+                i32.const 1024)
+
+            (func (export "on_data") (param $direction i32) (param $pointer i32) (param $length i32) (result i64)
+                (local $i i32)
+                (local $byte i32)
+                (if (i32.eqz (local.get $direction))
+                    (then
+                        (loop $next
+                            (if (i32.lt_u (local.get $i) (local.get $length))
+                                (then
+                                    (local.set $byte (i32.load8_u (i32.add (local.get $pointer) (local.get $i))))
+                                    (if (i32.and
+                                            (i32.ge_u (local.get $byte) (i32.const 97))
+                                            (i32.le_u (local.get $byte) (i32.const 122)))
+                                        (then
+                                            (i32.store8
+                                                (i32.add (local.get $pointer) (local.get $i))
+                                                (i32.sub (local.get $byte) (i32.const 32)))))
+                                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                                    (br $next))))))
+                (i64.or
+                    (i64.shl (i64.extend_i32_u (local.get $pointer)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $length))))
+
+            (func (export "on_end") (param $direction i32) (result i64)
+                (if (result i64) (i32.eqz (local.get $direction))
+                    (then (i64.const 1))
+                    (else (i64.const 0)))))
+    "#;
+
+    /// Rejects downstream data, and loops forever on upstream data.
+    const HOSTILE: &str = r#"
+        (module
+            (memory (export "memory") 1)
+
+            (func (export "alloc") (param $length i32) (result i32)
+                i32.const 0)
+
+            (func (export "on_data") (param $direction i32) (param $pointer i32) (param $length i32) (result i64)
+                (if (local.get $direction)
+                    (then (return (i64.const -1))))
+                (loop $forever
+                    (br $forever))
+                i64.const 0))
+    "#;
+
+    fn create(
+        module: &str,
+        limits: WasmLimits,
+    ) -> io::Result<Box<dyn StreamFunction>> {
+        WasmModule::new(module, limits).unwrap().create(&Flow::default())
+    }
+
+    #[test]
+    fn test_transform() {
+        let mut function = create(SHOUT, WasmLimits::default()).unwrap();
+
+        let mut output = BytesMut::new();
+        function.on_chunk(Direction::Upstream, b"hello, world", &mut output).unwrap();
+        function.on_eof(Direction::Upstream, &mut output).unwrap();
+        assert_eq!(&output[..], b"HELLO, WORLD!");
+
+        let mut output = BytesMut::new();
+        function.on_chunk(Direction::Downstream, b"hello", &mut output).unwrap();
+        function.on_eof(Direction::Downstream, &mut output).unwrap();
+        assert_eq!(&output[..], b"hello");
+    }
+
+    #[test]
+    fn test_reject() {
+        let mut function = create(HOSTILE, WasmLimits::default()).unwrap();
+
+        let error = function.on_chunk(Direction::Downstream, b"hello", &mut BytesMut::new()).unwrap_err();
+        assert!(error.to_string().contains("rejected"));
+    }
+
+    #[test]
+    fn test_fuel_per_call() {
+        let limits = WasmLimits {
+            fuel_per_call: 10_000,
+            ..Default::default()
+        };
+        let mut function = create(HOSTILE, limits).unwrap();
+
+        assert!(function.on_chunk(Direction::Upstream, b"hello", &mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn test_fuel_per_connection() {
+        // Every call fits within the limit of a single call, but not all of them within that of the connection.
+        let limits = WasmLimits {
+            fuel: 100_000,
+            ..Default::default()
+        };
+        let mut function = create(SHOUT, limits).unwrap();
+
+        let chunk = [b'a'; 1024];
+        let mut calls = 0;
+        while function.on_chunk(Direction::Upstream, &chunk, &mut BytesMut::new()).is_ok() {
+            calls += 1;
+            assert!(calls < 100, "The module never ran out of fuel");
+        }
+        assert!(calls > 0);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        // One page of memory is 64 KiB.
+        let limits = WasmLimits {
+            memory: 32 * 1024,
+            ..Default::default()
+        };
+
+        assert!(create(SHOUT, limits).is_err());
+    }
+
+    #[test]
+    fn test_missing_exports() {
+        assert!(WasmModule::new("(module (memory (export \"memory\") 1))", WasmLimits::default()).is_err());
+        assert!(WasmModule::new(b"not a module", WasmLimits::default()).is_err());
+    }
+}
@@ -16,24 +16,46 @@
 #[macro_use]
 extern crate anyhow;
 #[macro_use]
-extern crate log;
+extern crate tracing;
 #[macro_use]
 extern crate num_derive;
 
-pub use tokio::io::copy_bidirectional;
+/// Relays data between two streams, in both directions.
+pub use relay::copy_bidirectional;
 
 /// Represents network addresses.
 pub use addresses::{Address, ProxyAddress};
 /// Manages user credentials.
 pub use credentials::Credentials;
+/// Errors for malformed SOCKS messages.
+pub use errors::ProtocolError;
+/// Prometheus metrics of the handlers.
+pub use metrics::Metrics;
 /// Handles SOCKS protocol.
 pub use interface::SocksHandler;
 /// SOCKS5 client and handler.
-pub use socks5::{Socks5Client, Socks5Handler};
+pub use socks5::{Socks5Client, Socks5Handler, Socks5UdpSession};
 /// SOCKS6 client and handler.
 pub use socks6::{Socks6Client, Socks6Handler};
 pub use util::{get_original_dst, resolve_addr, try_read_initial_data};
 
+/// Sans-IO encoding and decoding of SOCKS messages.
+#[path = "./common/codec.rs"]
+pub mod codec;
+
+/// Proptest strategies for the types that make up SOCKS messages.
+#[cfg(test)]
+#[path = "./common/arbitrary.rs"]
+pub(crate) mod arbitrary;
+
+/// HTTP API to inspect and manage the sessions of handlers.
+#[path = "./common/admin.rs"]
+pub mod admin;
+
+/// Access logs, with a record per session, and the sinks they are written to.
+#[path = "./common/access_log.rs"]
+pub mod access_log;
+
 /// Common network address representations
 #[path = "./common/addresses.rs"]
 pub mod addresses;
@@ -46,10 +68,62 @@ pub mod constants;
 #[path = "./common/credentials.rs"]
 pub mod credentials;
 
+/// Errors raised while parsing SOCKS messages.
+#[path = "./common/errors.rs"]
+pub mod errors;
+
+/// Network functions that transform or observe the data of proxied connections.
+pub mod functions;
+
+/// Minimal HTTP/1 server support, for the built-in endpoints.
+#[path = "./common/http.rs"]
+pub(crate) mod http;
+
 /// Main interface for handling SOCKS.
 #[path = "./common/interface.rs"]
 pub mod interface;
 
+/// Prometheus metrics of the handlers, and an endpoint that serves them.
+#[path = "./common/metrics.rs"]
+pub mod metrics;
+
+/// Pools of connections to upstream proxies, that are established before they are needed.
+#[path = "./common/pool.rs"]
+pub mod pool;
+
+/// Quotas on the concurrent connections and connection rate of every client and user.
+#[path = "./common/quotas.rs"]
+pub mod quotas;
+
+/// Token-bucket rate limits on the bandwidth of connections.
+#[path = "./common/rate_limit.rs"]
+pub mod rate_limit;
+
+/// Bidirectional relaying of data between two streams, with idle timeouts and cancellation.
+#[path = "./common/relay.rs"]
+pub mod relay;
+
+/// Transparent redirection of intercepted connections through a SOCKS proxy.
+#[path = "./common/redirect.rs"]
+pub mod redirect;
+
+/// Registry of the active sessions of handlers.
+#[path = "./common/sessions.rs"]
+pub mod sessions;
+
+/// Zero-copy relaying between sockets with `splice(2)`.
+#[cfg(target_os = "linux")]
+#[path = "./common/splice.rs"]
+pub mod splice;
+
+/// Tuning of TCP sockets, and listeners that share an address with `SO_REUSEPORT`.
+#[path = "./common/sockets.rs"]
+pub mod sockets;
+
+/// Tracing spans of the connections that handlers accept.
+#[path = "./common/spans.rs"]
+pub mod spans;
+
 /// SOCKS5-specific implementations.
 pub mod socks5;
 
@@ -8,49 +8,285 @@
 #[macro_use]
 extern crate human_panic;
 
-use std::{convert::TryInto, sync::Arc};
+use std::{
+    convert::TryInto,
+    fs::{self, OpenOptions},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, ensure, Context, Result};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use dotenv::dotenv;
 use itertools::Itertools;
-use log::LevelFilter;
+use serde::Serialize;
+use serde_json::{json, Value};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
-use tokio::time::Instant;
+use tokio::runtime;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-use socksx::{self, Socks5Handler, Socks6Handler, SocksHandler};
+use ipnet::IpNet;
+use socksx::{self, ProxyAddress, Socks5Handler, Socks6Handler, SocksHandler};
+use socksx::access_log::{
+    AccessLogger, FileLogger, JsonLinesLogger, SyslogLogger, DEFAULT_KEEP_FILES, DEFAULT_MAX_FILE_SIZE,
+};
+use socksx::admin::{self, Admin};
+use socksx::functions::chacha20::{self, ChaCha20Function, SecretKey};
+use socksx::functions::compression::{self, CompressionConfig, CompressionFunction, Flush};
+use socksx::functions::counter::Counter;
+use socksx::functions::firewall::Firewall;
+#[cfg(feature = "wasm")]
+use socksx::functions::wasm::{WasmLimits, WasmModule, DEFAULT_FUEL_LIMIT, DEFAULT_FUEL_PER_CALL, DEFAULT_MEMORY_LIMIT};
+use socksx::functions::{Flow, Pipeline};
+use socksx::metrics::{self, Metrics};
+use socksx::pool::{self, UpstreamPool};
+use socksx::quotas::{Quota, Quotas};
+use socksx::rate_limit::{RateLimit, RateLimiter};
+use socksx::redirect::{RedirectMode, Redirector, UdpRedirector};
+use socksx::relay::{self, Relay};
+use socksx::sessions::SessionRegistry;
+use socksx::sockets::{self, SocketOptions};
 
 // Alias for SOCKS handler with Arc and Sync/Send trait bounds
 type Handler = Arc<dyn SocksHandler + Sync + Send>;
 
 /// CLI arguments structure
 #[derive(Parser)]
-#[clap(version = env!("CARGO_PKG_VERSION"))]
+#[clap(version = env!("CARGO_PKG_VERSION"), disable_help_flag = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Address on which the admin API is served (e.g., `127.0.0.1:9091`), which has no authentication of its own
+    #[clap(long, env = "ADMIN")]
+    admin: Option<SocketAddr>,
+
+    /// Where the record of every session is written to: `stdout`, `syslog` (the local daemon), `syslog:<address>`
+    /// (a remote daemon, over UDP) or a file
+    #[clap(long, env = "ACCESS_LOG", value_delimiter = ',')]
+    access_log: Vec<String>,
+
+    /// Number of rotated access log files to keep
+    #[clap(long, env = "ACCESS_LOG_KEEP", default_value_t = DEFAULT_KEEP_FILES)]
+    access_log_keep: usize,
+
+    /// Size, in bytes, at which an access log file is rotated
+    #[clap(long, env = "ACCESS_LOG_MAX_SIZE", default_value_t = DEFAULT_MAX_FILE_SIZE)]
+    access_log_max_size: u64,
+
+    /// Length of the queue of connections that were not accepted yet, per listener
+    #[clap(long, env = "BACKLOG", default_value_t = sockets::DEFAULT_BACKLOG)]
+    backlog: i32,
+
     /// Entry in the proxy chain, the order is preserved
     #[clap(short, long, env = "CHAIN")]
     chain: Vec<String>,
 
+    /// Passphrase from which the key of the `chacha20` function is derived
+    #[clap(long, env = "CHACHA20_KEY", hide_env_values = true)]
+    chacha20_key: Option<String>,
+
+    /// File with the key of the `chacha20` function (32 bytes, or 64 hexadecimal characters)
+    #[clap(long, env = "CHACHA20_KEY_FILE", conflicts_with = "chacha20_key")]
+    chacha20_key_file: Option<PathBuf>,
+
+    /// When the `compress` and `decompress` functions flush: after every `chunk`, or after a number of bytes
+    #[clap(long, env = "COMPRESSION_FLUSH", default_value = "chunk")]
+    compression_flush: Flush,
+
+    /// Level of the `compress` and `decompress` functions (default: 3 for zstd, 6 for deflate)
+    #[clap(long, env = "COMPRESSION_LEVEL", allow_negative_numbers = true)]
+    compression_level: Option<i32>,
+
+    /// How many times larger data may get when the `compress` and `decompress` functions decompress it (else rejected)
+    #[clap(long, env = "COMPRESSION_MAX_RATIO", default_value_t = compression::DEFAULT_MAX_RATIO)]
+    compression_max_ratio: u64,
+
+    /// New connections per second that every client IP may open, in bursts of up to as many
+    #[clap(long, env = "CONNECTION_RATE_PER_IP")]
+    connection_rate_per_ip: Option<u32>,
+
+    /// New connections per second that every authenticated user may open, in bursts of up to as many
+    #[clap(long, env = "CONNECTION_RATE_PER_USER")]
+    connection_rate_per_user: Option<u32>,
+
+    /// File to which the `counter` function appends a JSON record per flow (default: standard output)
+    #[clap(long, env = "COUNTER_LOG")]
+    counter_log: Option<PathBuf>,
+
+    /// File with the rules of the `firewall` function, one per line (e.g., `deny sni *.example.com`)
+    #[clap(long, env = "FIREWALL_RULES")]
+    firewall_rules: Option<PathBuf>,
+
     /// Prints debug information
-    #[clap(short, long, env = "DEBUG")]
+    #[clap(short, long, env = "DEBUG", global = true)]
     debug: bool,
 
+    /// Network function to run on every connection, closest to the client first (e.g., `compress:zstd`)
+    #[clap(short, long = "function", env = "FUNCTIONS", value_delimiter = ',')]
+    functions: Vec<String>,
+
+    /// Print help
+    // Only the long flag, as `-h` is taken by `--host`.
+    #[clap(long, action = ArgAction::Help, global = true)]
+    help: Option<bool>,
+
     /// Host (IP) for the SOCKS server
     #[clap(short, long, env = "HOST", default_value = "0.0.0.0")]
     host: String,
 
+    /// Seconds after which connections that relayed no data in either direction are closed (0=never)
+    #[clap(long, env = "IDLE_TIMEOUT", default_value = "0")]
+    idle_timeout: u64,
+
     /// Concurrent connections limit (0=unlimted)
     #[clap(short, long, env = "LIMIT", default_value = "256")]
     limit: usize,
 
+    /// Number of listeners that share the address with `SO_REUSEPORT`, each accepting on its own task (Unix only)
+    #[clap(long, env = "LISTENERS", default_value = "1")]
+    listeners: usize,
+
+    /// Format of the log on stderr: `human`, or `json` (one object per line, with the fields of the connection)
+    #[clap(long, env = "LOG_FORMAT", value_enum, default_value = "human")]
+    log_format: LogFormat,
+
+    /// Concurrent connections that every client IP may have
+    #[clap(long, env = "MAX_CONNECTIONS_PER_IP")]
+    max_connections_per_ip: Option<usize>,
+
+    /// Concurrent connections that every authenticated user may have
+    #[clap(long, env = "MAX_CONNECTIONS_PER_USER")]
+    max_connections_per_user: Option<usize>,
+
+    /// Address on which Prometheus metrics are served, at `/metrics` (e.g., `127.0.0.1:9090`)
+    #[clap(long, env = "METRICS")]
+    metrics: Option<SocketAddr>,
+
+    /// Runs every listener, and the connections it accepts, on a runtime of its own, on a thread pinned to a CPU
+    #[clap(long, env = "PIN_LISTENERS")]
+    pin_listeners: bool,
+
+    /// Seconds between the checks of the connections in the upstream pool
+    #[clap(long, env = "POOL_HEALTH_CHECK", default_value_t = pool::DEFAULT_HEALTH_CHECK_INTERVAL.as_secs())]
+    pool_health_check: u64,
+
+    /// Seconds after which connections in the upstream pool are closed, if they were not used
+    #[clap(long, env = "POOL_MAX_IDLE", default_value_t = pool::DEFAULT_MAX_IDLE.as_secs())]
+    pool_max_idle: u64,
+
+    /// TCP connections to every next hop that are opened ahead, to skip their TCP handshake (SOCKS6 only, 0=none)
+    #[clap(long, env = "POOL_SIZE", default_value = "0")]
+    pool_size: usize,
+
     /// Port for the SOCKS server
     #[clap(short, long, env = "PORT", default_value = "1080")]
     port: u16,
 
+    /// Milliseconds that requests over a per-IP or per-user quota wait for it to free up, before they are refused
+    #[clap(long, env = "QUOTA_WAIT", default_value = "0")]
+    quota_wait: u64,
+
+    /// Bandwidth limit, in bytes per second, for a scope of connections (e.g., `user:* up 1M down 5M`)
+    #[clap(long = "rate-limit", env = "RATE_LIMITS", value_delimiter = ',')]
+    rate_limits: Vec<RateLimit>,
+
+    /// Size, in bytes, of the receive buffer (`SO_RCVBUF`) of accepted and outbound connections
+    #[clap(long, env = "RECV_BUFFER_SIZE")]
+    recv_buffer_size: Option<usize>,
+
+    /// Size, in bytes, of the buffer of either direction of a connection, when its data passes through userspace
+    #[clap(long, env = "RELAY_BUFFER_SIZE", default_value_t = relay::DEFAULT_BUFFER_SIZE)]
+    relay_buffer_size: usize,
+
+    /// Size, in bytes, of the send buffer (`SO_SNDBUF`) of accepted and outbound connections
+    #[clap(long, env = "SEND_BUFFER_SIZE")]
+    send_buffer_size: Option<usize>,
+
     /// SOCKS version
     #[clap(short, long, env = "SOCKS", default_value = "6")]
     socks: u8,
+
+    /// Seconds that accepted and outbound connections are idle before keepalive probes are sent (0=no keepalive)
+    #[clap(long, env = "TCP_KEEPALIVE", default_value = "0")]
+    tcp_keepalive: u64,
+
+    /// Disables Nagle's algorithm (`TCP_NODELAY`) on accepted and outbound connections
+    #[clap(long, env = "TCP_NODELAY")]
+    tcp_nodelay: bool,
+
+    /// Fuel that every instance of a `wasm` function may consume over its connection
+    #[cfg(feature = "wasm")]
+    #[clap(long, env = "WASM_FUEL", default_value_t = DEFAULT_FUEL_LIMIT)]
+    wasm_fuel: u64,
+
+    /// Fuel that every call into a `wasm` function may consume, out of what is left for its connection
+    #[cfg(feature = "wasm")]
+    #[clap(long, env = "WASM_FUEL_PER_CALL", default_value_t = DEFAULT_FUEL_PER_CALL)]
+    wasm_fuel_per_call: u64,
+
+    /// Size, in bytes, up to which the memory of every instance of a `wasm` function may grow
+    #[cfg(feature = "wasm")]
+    #[clap(long, env = "WASM_MEMORY_LIMIT", default_value_t = DEFAULT_MEMORY_LIMIT)]
+    wasm_memory_limit: usize,
+}
+
+/// Formats of the log
+#[derive(Clone, Copy, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    Human,
+    Json,
+}
+
+/// Modes other than running a SOCKS server
+#[derive(Subcommand)]
+enum Command {
+    /// Forwards connections intercepted by iptables (REDIRECT or TPROXY) through a SOCKS proxy
+    Redirect(RedirectArgs),
+}
+
+/// CLI arguments of the `redirect` mode
+#[derive(clap::Args)]
+struct RedirectArgs {
+    /// Entry in the proxy chain after the upstream (SOCKS6 only), the order is preserved
+    #[clap(short, long, env = "CHAIN")]
+    chain: Vec<String>,
+
+    /// Destination (IP or CIDR) to connect to directly, instead of through the upstream
+    #[clap(short, long, env = "EXCLUDE", value_parser = parse_network)]
+    exclude: Vec<IpNet>,
+
+    /// Seconds after which connections that relayed no data in either direction are closed (0=never)
+    #[clap(long, env = "IDLE_TIMEOUT", default_value = "0")]
+    idle_timeout: u64,
+
+    /// Address on which intercepted connections are accepted
+    #[clap(short, long, env = "LISTEN", default_value = "127.0.0.1:42000")]
+    listen: SocketAddr,
+
+    /// How connections are intercepted: `nat` (iptables REDIRECT) or `tproxy` (iptables TPROXY)
+    #[clap(short, long, env = "MODE", default_value = "nat")]
+    mode: RedirectMode,
+
+    /// Size, in bytes, of the buffer of either direction of a connection
+    #[clap(long, env = "RELAY_BUFFER_SIZE", default_value_t = relay::DEFAULT_BUFFER_SIZE)]
+    relay_buffer_size: usize,
+
+    /// Also forward UDP datagrams intercepted on the same address (`tproxy` mode and SOCKS5 upstream only)
+    #[clap(long, env = "UDP")]
+    udp: bool,
+
+    /// SOCKS proxy to forward connections through (e.g., socks6://127.0.0.1:1080)
+    #[clap(short, long, env = "UPSTREAM")]
+    upstream: String,
 }
 
 /// Main asynchronous function
@@ -60,23 +296,37 @@ async fn main() -> Result<()> {
     dotenv().ok();
     let args = Args::parse();
 
-    // Setup logger
-    let mut logger = env_logger::builder();
-    logger.format_module_path(false);
+    // Setup tracing, where `RUST_LOG` takes precedence over `--debug`. The log goes to stderr, so that the access log
+    // and the counter can write JSON lines to stdout.
+    let level = if args.debug { LevelFilter::DEBUG } else { LevelFilter::INFO };
+    let filter = EnvFilter::builder().with_default_directive(level.into()).from_env_lossy();
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).with_target(false).with_writer(io::stderr);
 
-    if args.debug {
-        logger.filter_level(LevelFilter::Debug).init();
-    } else {
-        logger.filter_level(LevelFilter::Info).init();
+    match args.log_format {
+        LogFormat::Human => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(false).init(),
+    }
 
+    if !args.debug {
         // Setup human-friendly panic messages
         setup_panic!(metadata!());
     }
 
+    if let Some(Command::Redirect(args)) = args.command {
+        return redirect(args).await;
+    }
+
     // TODO: validate host
 
-    // Convert and collect chain arguments
-    let chain = args.chain.iter().cloned().map(|c| c.try_into()).try_collect()?;
+    // Build the handler, along with the metrics, sessions, counter, rate limits, quotas and pool that outlive reloads
+    let metrics = Metrics::new();
+    let sessions = SessionRegistry::new();
+    let counter = counter(&args)?;
+    let rate_limiter = RateLimiter::new(args.rate_limits.clone());
+    let quotas = quotas(&args);
+    let pool = upstream_pool(&args).await?;
+    let handler = handler(&args, &metrics, &sessions, counter.as_ref(), &rate_limiter, &quotas, pool.as_ref())?;
+    let handler = Arc::new(RwLock::new(handler));
 
     // Create a semaphore for connection limiting
     let semaphore = if args.limit > 0 {
@@ -85,26 +335,293 @@ async fn main() -> Result<()> {
         None
     };
 
-    // Bind TCP listener to the specified host and port
-    let listener = TcpListener::bind(format!("{}:{}", args.host, args.port)).await?;
-    // Determine the appropriate SOCKS handler based on the specified version and restricting them to 5 and 6
-    let handler: Handler = match args.socks {
-        5 => Arc::new(Socks5Handler::new(chain)),
-        6 => Arc::new(Socks6Handler::new(chain)),
-        _ => unreachable!(),
-    };
+    // Serve metrics, if requested
+    if let Some(address) = args.metrics {
+        let listener = TcpListener::bind(address).await?;
+        info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+
+        tokio::spawn(metrics::serve(listener, metrics.clone()));
+    }
+
+    // Bind the TCP listeners to the specified host and port
+    let address = socksx::resolve_addr(format!("{}:{}", args.host, args.port)).await?;
+    let options = socket_options(&args);
+    let listeners = sockets::bind_many(address, args.listeners.max(1), args.backlog, &options)?;
+    let pin_listeners = args.pin_listeners;
+    info!("Listening on {} ({} listeners).", address, listeners.len());
+
+    // Serve the admin API, if requested
+    if let Some(address) = args.admin {
+        let admin_listener = TcpListener::bind(address).await?;
+        info!("Serving the admin API on http://{}", admin_listener.local_addr()?);
+
+        let config = effective_config(&args);
+        let mut admin = Admin::new(sessions.clone()).with_config(config);
+        if let Some(counter) = &counter {
+            admin = admin.with_counter(counter.clone());
+        }
+
+        let admin = admin.with_reload({
+            let handler = Arc::clone(&handler);
+
+            // Sessions keep the handler that accepted them, new connections get the new one.
+            move || {
+                let counter = counter.as_ref();
+                let reloaded =
+                    self::handler(&args, &metrics, &sessions, counter, &rate_limiter, &quotas, pool.as_ref())?;
+                *handler.write().unwrap() = reloaded;
+
+                info!("Reloaded the configuration.");
+                Ok(())
+            }
+        });
+
+        tokio::spawn(admin::serve(admin_listener, admin));
+    }
+
+    // Accept connections on every listener, until one of them fails
+    let (finished, mut results) = mpsc::unbounded_channel();
+    for (index, listener) in listeners.into_iter().enumerate() {
+        let accept = accept(listener, Arc::clone(&handler), semaphore.clone(), options);
+        let finished = finished.clone();
+
+        if pin_listeners {
+            thread::Builder::new().name(format!("listener-{}", index)).spawn(move || {
+                #[cfg(target_os = "linux")]
+                if let Err(error) = sockets::pin_to_cpu(index) {
+                    warn!("Failed to pin listener {} to a CPU: {}", index, error);
+                }
+
+                let runtime = runtime::Builder::new_current_thread().enable_all().build();
+                let _ = finished.send(runtime.map_err(Into::into).and_then(|runtime| runtime.block_on(accept)));
+            })?;
+        } else {
+            tokio::spawn(async move {
+                let _ = finished.send(accept.await);
+            });
+        }
+    }
+    drop(finished);
+
+    results.recv().await.unwrap_or(Ok(()))
+}
+
+/// Main event loop for accepting incoming connections on `listener`, that spawns a task for every connection on the
+/// current runtime.
+///
+/// # Parameters
+///
+/// - `listener`: The listener, which is registered with the current runtime.
+/// - `handler`: The SOCKS handler, which may be reloaded in the meantime.
+/// - `semaphore`: An optional semaphore for limiting concurrent connections.
+/// - `options`: The options of the accepted sockets.
+///
+/// # Returns
+///
+/// Only returns if accepting connections fails.
+async fn accept(
+    listener: std::net::TcpListener,
+    handler: Arc<RwLock<Handler>>,
+    semaphore: Option<Arc<Semaphore>>,
+    options: SocketOptions,
+) -> Result<()> {
+    let listener = TcpListener::from_std(listener)?;
 
-    // Main event loop for accepting incoming connections
     loop {
         let (incoming, _) = listener.accept().await?;
+        if let Err(error) = options.apply(&incoming) {
+            warn!("Failed to set the options of an accepted socket: {}", error);
+        }
 
-        let handler = Arc::clone(&handler);
+        let handler = Arc::clone(&handler.read().unwrap());
         let semaphore = semaphore.clone();
 
         tokio::spawn(process(incoming, handler, semaphore));
     }
 }
 
+/// Builds the SOCKS handler, with the chain, the network functions and the sinks of the access log of the arguments.
+///
+/// # Parameters
+///
+/// - `args`: The CLI arguments.
+/// - `metrics`: The metrics that the handler records.
+/// - `sessions`: The registry in which the handler registers its sessions.
+/// - `counter`: The counter of the `counter` function, if any.
+/// - `rate_limiter`: The rate limits of the connections of the handler.
+/// - `quotas`: The quotas of the clients and users of the handler.
+/// - `pool`: The pool of connections to the next hops, if any.
+///
+/// # Returns
+///
+/// The handler, or an error if its configuration is invalid, or if one of the files it refers to cannot be read.
+fn handler(
+    args: &Args,
+    metrics: &Metrics,
+    sessions: &SessionRegistry,
+    counter: Option<&Counter>,
+    rate_limiter: &RateLimiter,
+    quotas: &Quotas,
+    pool: Option<&UpstreamPool>,
+) -> Result<Handler> {
+    // Convert and collect chain arguments
+    let chain = args.chain.iter().cloned().map(|c| c.try_into()).try_collect()?;
+
+    // Build the pipeline of network functions, and the sinks of the access log
+    let functions = pipeline(args, counter)?;
+    let access_logger = access_logger(args)?;
+
+    // Determine the appropriate SOCKS handler based on the specified version and restricting them to 5 and 6
+    let handler: Handler = match args.socks {
+        5 => Arc::new(
+            Socks5Handler::new(chain)
+                .with_functions(functions)
+                .with_metrics(metrics.clone())
+                .with_access_logger(access_logger)
+                .with_sessions(sessions.clone())
+                .with_rate_limiter(rate_limiter.clone())
+                .with_quotas(quotas.clone())
+                .with_relay(relay(args.idle_timeout, args.relay_buffer_size))
+                .with_socket_options(socket_options(args)),
+        ),
+        6 => {
+            let handler = Socks6Handler::new(chain)
+                .with_functions(functions)
+                .with_metrics(metrics.clone())
+                .with_access_logger(access_logger)
+                .with_sessions(sessions.clone())
+                .with_rate_limiter(rate_limiter.clone())
+                .with_quotas(quotas.clone())
+                .with_relay(relay(args.idle_timeout, args.relay_buffer_size))
+                .with_socket_options(socket_options(args));
+
+            match pool {
+                Some(pool) => Arc::new(handler.with_pool(pool.clone())),
+                None => Arc::new(handler),
+            }
+        }
+        _ => unreachable!(),
+    };
+
+    Ok(handler)
+}
+
+/// Builds the per-IP and per-user quotas of the arguments.
+fn quotas(args: &Args) -> Quotas {
+    let per_client = Quota {
+        max_connections: args.max_connections_per_ip,
+        connection_rate: args.connection_rate_per_ip,
+    };
+    let per_user = Quota {
+        max_connections: args.max_connections_per_user,
+        connection_rate: args.connection_rate_per_user,
+    };
+
+    Quotas::new()
+        .per_client(per_client)
+        .per_user(per_user)
+        .with_wait(Duration::from_millis(args.quota_wait))
+}
+
+/// Builds the pool of connections to the next hops, if requested, and pre-warms it for the first link of the chain.
+async fn upstream_pool(args: &Args) -> Result<Option<UpstreamPool>> {
+    if args.pool_size == 0 {
+        return Ok(None);
+    }
+    ensure!(args.socks == 6, "The upstream pool is only supported by the SOCKS6 handler.");
+
+    let pool = UpstreamPool::new(args.pool_size)
+        .with_max_idle(Duration::from_secs(args.pool_max_idle))
+        .with_health_check_interval(Duration::from_secs(args.pool_health_check));
+
+    // Other next hops, e.g., of dynamic chains, are pooled once they are first used.
+    if let Some(link) = args.chain.first() {
+        let link: ProxyAddress = link.clone().try_into()?;
+        let address = socksx::resolve_addr(format!("{}:{}", link.host, link.port)).await?;
+
+        pool.prewarm(address);
+        info!("Keeping {} connections to {} ready.", args.pool_size, address);
+    }
+
+    Ok(Some(pool))
+}
+
+/// Builds the options of accepted and outbound sockets of the arguments.
+fn socket_options(args: &Args) -> SocketOptions {
+    SocketOptions {
+        nodelay: args.tcp_nodelay,
+        keepalive: Some(Duration::from_secs(args.tcp_keepalive)).filter(|keepalive| !keepalive.is_zero()),
+        recv_buffer_size: args.recv_buffer_size,
+        send_buffer_size: args.send_buffer_size,
+    }
+}
+
+/// Builds how connections are relayed, with an idle timeout in seconds (none if zero) and a buffer size in bytes.
+fn relay(
+    idle_timeout: u64,
+    buffer_size: usize,
+) -> Relay {
+    let relay = Relay::new().with_buffer_size(buffer_size);
+    if idle_timeout == 0 {
+        relay
+    } else {
+        relay.with_idle_timeout(Duration::from_secs(idle_timeout))
+    }
+}
+
+/// Describes the effective configuration, for the admin API, without secrets.
+fn effective_config(args: &Args) -> Value {
+    // Show chain entries without their credentials.
+    let chain: Vec<_> = args
+        .chain
+        .iter()
+        .map(|link| match TryInto::<ProxyAddress>::try_into(link.clone()) {
+            Ok(link) => format!("socks{}://{}:{}", link.socks_version, link.host, link.port),
+            Err(_) => String::from("<invalid>"),
+        })
+        .collect();
+
+    json!({
+        "host": args.host,
+        "port": args.port,
+        "socks": args.socks,
+        "limit": args.limit,
+        "listeners": args.listeners,
+        "pin_listeners": args.pin_listeners,
+        "backlog": args.backlog,
+        "tcp_nodelay": args.tcp_nodelay,
+        "tcp_keepalive": args.tcp_keepalive,
+        "recv_buffer_size": args.recv_buffer_size,
+        "send_buffer_size": args.send_buffer_size,
+        "idle_timeout": args.idle_timeout,
+        "relay_buffer_size": args.relay_buffer_size,
+        "max_connections_per_ip": args.max_connections_per_ip,
+        "max_connections_per_user": args.max_connections_per_user,
+        "quota_wait": args.quota_wait,
+        "pool_size": args.pool_size,
+        "pool_max_idle": args.pool_max_idle,
+        "pool_health_check": args.pool_health_check,
+        "chain": chain,
+        "functions": args.functions,
+        "chacha20_key": args.chacha20_key.as_ref().map(|_| "<redacted>"),
+        "chacha20_key_file": args.chacha20_key_file,
+        "compression_level": args.compression_level,
+        "compression_max_ratio": args.compression_max_ratio,
+        "connection_rate_per_ip": args.connection_rate_per_ip,
+        "connection_rate_per_user": args.connection_rate_per_user,
+        "counter_log": args.counter_log,
+        "firewall_rules": args.firewall_rules,
+        "access_log": args.access_log,
+        "access_log_keep": args.access_log_keep,
+        "access_log_max_size": args.access_log_max_size,
+        "metrics": args.metrics,
+        "rate_limits": args.rate_limits.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "admin": args.admin,
+        "log_format": args.log_format,
+        "debug": args.debug,
+    })
+}
+
 /// Asynchronously processes an incoming connection
 ///
 /// # Parameters
@@ -122,7 +639,6 @@ async fn process(
     semaphore: Option<Arc<Semaphore>>,
 ) -> Result<()> {
     let mut incoming = incoming;
-    let start_time = Instant::now();
 
     // Handle the incoming connection based on the availability of permits
     if let Some(semaphore) = semaphore {
@@ -136,8 +652,375 @@ async fn process(
         handler.accept_request(&mut incoming).await?;
     }
 
-    // Log the time taken to process the request
-    println!("{}ms", Instant::now().saturating_duration_since(start_time).as_millis());
-
     Ok(())
 }
+
+/// The built-in functions that `--function` accepts.
+const FUNCTIONS: &str = "`chacha20`, `compress`, `counter`, `decompress`, `firewall`, `wasm`";
+
+/// Builds the pipeline of network functions that the handler runs on every connection.
+///
+/// # Parameters
+///
+/// - `args`: The CLI arguments, with the functions in the form `name[:argument]`.
+/// - `counter`: The counter of the `counter` function, as built by `counter`.
+///
+/// # Returns
+///
+/// The `Pipeline`, or an error if a function or its configuration is invalid.
+fn pipeline(
+    args: &Args,
+    counter: Option<&Counter>,
+) -> Result<Pipeline> {
+    let mut pipeline = Pipeline::new();
+
+    for function in &args.functions {
+        let (name, argument) = function.split_once(':').unwrap_or((function, ""));
+
+        pipeline = match name {
+            "chacha20" => {
+                let role: chacha20::Role = argument.parse()?;
+                let key = match (&args.chacha20_key, &args.chacha20_key_file) {
+                    (Some(passphrase), _) => SecretKey::from_passphrase(passphrase)?,
+                    (None, Some(path)) => SecretKey::from_file(path)?,
+                    (None, None) => bail!("The `chacha20` function requires --chacha20-key or --chacha20-key-file."),
+                };
+
+                pipeline.with(move |_: &Flow| Ok(ChaCha20Function::new(role, key.clone())))
+            }
+            "compress" | "decompress" => {
+                let role = match name {
+                    "compress" => compression::Role::Compress,
+                    _ => compression::Role::Decompress,
+                };
+
+                let mut config = CompressionConfig::new(argument.parse()?)
+                    .with_flush(args.compression_flush)
+                    .with_max_ratio(args.compression_max_ratio);
+                if let Some(level) = args.compression_level {
+                    config = config.with_level(level)?;
+                }
+
+                pipeline.with(move |_: &Flow| Ok(CompressionFunction::new(role, config)))
+            }
+            "counter" => match counter {
+                Some(counter) => pipeline.with(counter.clone()),
+                None => bail!("The `counter` function cannot be added by a reload, only at startup."),
+            },
+            "firewall" => {
+                let path = match &args.firewall_rules {
+                    Some(path) => path,
+                    None => bail!("The `firewall` function requires --firewall-rules."),
+                };
+
+                let rules = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+                let firewall: Firewall = rules.parse().with_context(|| format!("Invalid rules in {:?}", path))?;
+
+                pipeline.with(firewall)
+            }
+            #[cfg(feature = "wasm")]
+            "wasm" => {
+                ensure!(!argument.is_empty(), "The `wasm` function requires a module, e.g., `wasm:function.wasm`.");
+
+                let limits = WasmLimits {
+                    memory: args.wasm_memory_limit,
+                    fuel: args.wasm_fuel,
+                    fuel_per_call: args.wasm_fuel_per_call,
+                };
+                pipeline.with(WasmModule::from_file(argument, limits)?)
+            }
+            #[cfg(not(feature = "wasm"))]
+            "wasm" => bail!("The `wasm` function requires socksx to be built with the `wasm` feature."),
+            name => bail!("Unrecognized function: {} (supported: {})", name, FUNCTIONS),
+        };
+    }
+
+    Ok(pipeline)
+}
+
+/// Builds the counter of the `counter` function, if it is one of the functions, which keeps its running totals across
+/// reloads.
+///
+/// # Returns
+///
+/// The `Counter`, or an error if its log cannot be opened.
+fn counter(args: &Args) -> Result<Option<Counter>> {
+    if !args.functions.iter().any(|function| function.split(':').next() == Some("counter")) {
+        return Ok(None);
+    }
+
+    let counter = match &args.counter_log {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open counter log: {:?}", path))?;
+
+            Counter::new().with_sink(file)
+        }
+        None => Counter::new().with_sink(io::stdout()),
+    };
+
+    Ok(Some(counter))
+}
+
+/// Opens the sinks of the access log.
+///
+/// # Parameters
+///
+/// - `args`: The CLI arguments, with the sinks in the form `stdout`, `syslog[:address]` or a path.
+///
+/// # Returns
+///
+/// The sinks, or an error if one of them cannot be opened.
+fn access_logger(args: &Args) -> Result<Vec<Box<dyn AccessLogger>>> {
+    let mut loggers: Vec<Box<dyn AccessLogger>> = vec![];
+
+    for sink in &args.access_log {
+        let logger: Box<dyn AccessLogger> = match sink.split_once(':') {
+            _ if sink == "stdout" => Box::new(JsonLinesLogger::stdout()),
+            #[cfg(unix)]
+            _ if sink == "syslog" => {
+                Box::new(SyslogLogger::unix("/dev/log").context("Failed to connect to the local syslog daemon")?)
+            }
+            Some(("syslog", address)) => {
+                let address = address.parse().with_context(|| format!("Invalid syslog address: {}", address))?;
+                Box::new(SyslogLogger::udp(address)?)
+            }
+            _ => {
+                let logger = FileLogger::new(sink).with_context(|| format!("Failed to open access log: {}", sink))?;
+                Box::new(logger.with_max_size(args.access_log_max_size).with_keep(args.access_log_keep))
+            }
+        };
+
+        loggers.push(logger);
+    }
+
+    Ok(loggers)
+}
+
+/// Runs the `redirect` mode: accepts intercepted connections (and datagrams) and forwards them through the upstream.
+///
+/// # Parameters
+///
+/// - `args`: The CLI arguments of the `redirect` mode.
+///
+/// # Returns
+///
+/// Only returns if accepting connections fails.
+async fn redirect(args: RedirectArgs) -> Result<()> {
+    let upstream: ProxyAddress = args.upstream.try_into()?;
+    let chain: Vec<ProxyAddress> = args.chain.into_iter().map(|c| c.try_into()).try_collect()?;
+
+    if args.udp {
+        ensure!(args.mode == RedirectMode::Tproxy, "UDP redirection requires the `tproxy` mode.");
+        // SOCKS5 UDP associations cannot be chained, so datagrams would skip the rest of the chain.
+        ensure!(chain.is_empty(), "UDP redirection does not support chains.");
+
+        let redirector = UdpRedirector::bind(upstream.clone(), args.listen)
+            .await?
+            .with_exclusions(args.exclude.clone());
+        info!("Redirecting datagrams received on {}.", args.listen);
+
+        tokio::spawn(async move {
+            if let Err(error) = redirector.run().await {
+                error!("Failed to redirect datagrams: {:?}", error);
+            }
+        });
+    }
+
+    let redirector = Redirector::new(upstream, chain, args.mode)
+        .await?
+        .with_exclusions(args.exclude)
+        .with_relay(relay(args.idle_timeout, args.relay_buffer_size));
+    let listener = redirector.bind(args.listen)?;
+    info!("Redirecting connections accepted on {} ({} mode).", args.listen, args.mode);
+
+    let redirector = Arc::new(redirector);
+    loop {
+        let (incoming, _) = listener.accept().await?;
+        let redirector = Arc::clone(&redirector);
+
+        tokio::spawn(async move {
+            if let Err(error) = redirector.redirect(incoming).await {
+                warn!("Failed to redirect connection: {:?}", error);
+            }
+        });
+    }
+}
+
+/// Parses a network in CIDR notation, or a single IP address.
+fn parse_network(network: &str) -> Result<IpNet> {
+    match network.parse::<IpAddr>() {
+        Ok(ip) => Ok(IpNet::from(ip)),
+        Err(_) => Ok(network.parse()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn test_cli_functions() {
+        let args = Args::try_parse_from([
+            "socksx",
+            "--function",
+            "chacha20:encrypt",
+            "--chacha20-key",
+            "passphrase",
+        ])
+        .unwrap();
+        assert_eq!(pipeline(&args, None).unwrap().len(), 1);
+
+        let args = Args::try_parse_from(["socksx", "-f", "chacha20:encrypt"]).unwrap();
+        assert!(pipeline(&args, None).is_err());
+
+        let args = Args::try_parse_from(["socksx", "-f", "chacha20:both", "--chacha20-key", "passphrase"]).unwrap();
+        assert!(pipeline(&args, None).is_err());
+
+        let args = Args::try_parse_from([
+            "socksx",
+            "--function",
+            "compress:zstd,chacha20:encrypt",
+            "--chacha20-key",
+            "passphrase",
+        ])
+        .unwrap();
+        assert_eq!(pipeline(&args, None).unwrap().len(), 2);
+
+        let args = Args::try_parse_from(["socksx", "-f", "decompress:deflate", "--compression-level", "12"]).unwrap();
+        assert!(pipeline(&args, None).is_err());
+
+        let args = Args::try_parse_from(["socksx", "-f", "compress:lz4"]).unwrap();
+        assert!(pipeline(&args, None).is_err());
+
+        // The counter is built once, and kept across reloads.
+        let args = Args::try_parse_from(["socksx", "-f", "counter"]).unwrap();
+        let built = counter(&args).unwrap();
+        assert!(built.is_some());
+        assert_eq!(pipeline(&args, built.as_ref()).unwrap().len(), 1);
+        assert!(pipeline(&args, None).is_err());
+
+        let args = Args::try_parse_from([
+            "socksx",
+            "--function",
+            "counter",
+            "--counter-log",
+            "/nonexistent/counter.log",
+        ])
+        .unwrap();
+        assert!(counter(&args).is_err());
+
+        let args = Args::try_parse_from(["socksx", "-f", "firewall"]).unwrap();
+        assert!(pipeline(&args, None).is_err());
+
+        let args = Args::try_parse_from(["socksx", "-f", "wasm:/nonexistent/function.wasm"]).unwrap();
+        assert!(pipeline(&args, None).is_err());
+
+        let args = Args::try_parse_from(["socksx", "-f", "rot13"]).unwrap();
+        assert!(pipeline(&args, None).is_err());
+    }
+
+    #[test]
+    fn test_cli_redirect() {
+        let args = Args::try_parse_from([
+            "socksx",
+            "redirect",
+            "--mode",
+            "tproxy",
+            "--upstream",
+            "socks6://127.0.0.1:1080",
+            "--exclude",
+            "10.0.0.0/8",
+            "--exclude",
+            "192.168.1.1",
+            "--udp",
+            "--idle-timeout",
+            "300",
+        ])
+        .unwrap();
+
+        let Some(Command::Redirect(args)) = args.command else {
+            panic!("Expected the redirect command");
+        };
+        assert_eq!(args.mode, RedirectMode::Tproxy);
+        assert!(args.udp);
+        assert_eq!(args.idle_timeout, 300);
+        assert_eq!(args.relay_buffer_size, relay::DEFAULT_BUFFER_SIZE);
+        assert_eq!(args.listen, "127.0.0.1:42000".parse().unwrap());
+        assert_eq!(
+            args.exclude,
+            vec!["10.0.0.0/8".parse::<IpNet>().unwrap(), "192.168.1.1/32".parse().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_udp_redirect_rejects_chain() {
+        let args = Args::try_parse_from([
+            "socksx",
+            "redirect",
+            "--mode",
+            "tproxy",
+            "--udp",
+            "--upstream",
+            "socks5://127.0.0.1:1080",
+            "--chain",
+            "socks6://127.0.0.1:1081",
+        ])
+        .unwrap();
+
+        let Some(Command::Redirect(args)) = args.command else {
+            panic!("Expected the redirect command");
+        };
+        let error = redirect(args).await.unwrap_err();
+        assert!(error.to_string().contains("chains"));
+    }
+
+    #[test]
+    fn test_cli_rate_limits() {
+        let args = Args::try_parse_from([
+            "socksx",
+            "--rate-limit",
+            "global up 100M down 100M,user:* down 5M",
+            "--rate-limit",
+            "client:10.0.0.0/8 up 1M",
+        ])
+        .unwrap();
+        assert_eq!(args.rate_limits.len(), 3);
+        assert_eq!(args.rate_limits[1].to_string(), "user:* down 5242880");
+
+        assert!(Args::try_parse_from(["socksx", "--rate-limit", "user:alice 5M"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_listeners() {
+        let args = Args::try_parse_from([
+            "socksx",
+            "--listeners",
+            "4",
+            "--pin-listeners",
+            "--tcp-nodelay",
+            "--tcp-keepalive",
+            "60",
+            "--recv-buffer-size",
+            "262144",
+        ])
+        .unwrap();
+        assert_eq!((args.listeners, args.backlog), (4, sockets::DEFAULT_BACKLOG));
+        assert!(args.pin_listeners);
+
+        let options = socket_options(&args);
+        assert!(options.nodelay);
+        assert_eq!(options.keepalive, Some(Duration::from_secs(60)));
+        assert_eq!((options.recv_buffer_size, options.send_buffer_size), (Some(262144), None));
+    }
+}
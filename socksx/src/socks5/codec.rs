@@ -0,0 +1,348 @@
+use bytes::{BufMut, BytesMut};
+use num_traits::FromPrimitive;
+
+use crate::codec::{Cursor, Decode, DecodeError, Encode};
+use crate::constants::*;
+use crate::socks5::{Socks5Command, Socks5Reply, Socks5Request};
+use crate::{Address, Credentials, ProtocolError};
+
+/// The version identifier/method selection message sent by the client to open a session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MethodSelectionRequest {
+    /// The authentication methods the client supports.
+    pub methods: Vec<u8>,
+}
+
+impl MethodSelectionRequest {
+    /// Creates a new `MethodSelectionRequest`.
+    pub fn new(methods: Vec<u8>) -> Self {
+        Self { methods }
+    }
+}
+
+impl Decode for MethodSelectionRequest {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let version = cursor.read_u8()?;
+        if version != SOCKS_VER_5 {
+            return Err(ProtocolError::UnsupportedVersion(version).into());
+        }
+
+        let nmethods = cursor.read_u8()?;
+        let methods = cursor.read_bytes(nmethods as usize)?.to_vec();
+
+        Ok(Self::new(methods))
+    }
+}
+
+impl Encode for MethodSelectionRequest {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_u8(SOCKS_VER_5);
+        dst.put_u8(self.methods.len() as u8);
+        dst.put_slice(&self.methods);
+    }
+}
+
+/// The method selection message sent by the server in response to a `MethodSelectionRequest`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MethodSelectionReply {
+    /// The authentication method selected by the server.
+    pub method: u8,
+}
+
+impl MethodSelectionReply {
+    /// Creates a new `MethodSelectionReply`.
+    pub fn new(method: u8) -> Self {
+        Self { method }
+    }
+}
+
+impl Decode for MethodSelectionReply {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let version = cursor.read_u8()?;
+        if version != SOCKS_VER_5 {
+            return Err(ProtocolError::UnsupportedVersion(version).into());
+        }
+
+        Ok(Self::new(cursor.read_u8()?))
+    }
+}
+
+impl Encode for MethodSelectionReply {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_u8(SOCKS_VER_5);
+        dst.put_u8(self.method);
+    }
+}
+
+/// The username/password authentication request ([rfc1929]).
+///
+/// [rfc1929]: https://tools.ietf.org/html/rfc1929
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordAuthRequest {
+    pub credentials: Credentials,
+}
+
+impl PasswordAuthRequest {
+    /// Creates a new `PasswordAuthRequest`.
+    pub fn new(credentials: Credentials) -> Self {
+        Self { credentials }
+    }
+}
+
+impl Decode for PasswordAuthRequest {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let version = cursor.read_u8()?;
+        if version != SOCKS_AUTH_VER {
+            return Err(ProtocolError::UnsupportedVersion(version).into());
+        }
+
+        let ulen = cursor.read_u8()?;
+        let username = cursor.read_bytes(ulen as usize)?.to_vec();
+        let plen = cursor.read_u8()?;
+        let password = cursor.read_bytes(plen as usize)?.to_vec();
+
+        Ok(Self::new(Credentials::new(username, password)))
+    }
+}
+
+impl Encode for PasswordAuthRequest {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_u8(SOCKS_AUTH_VER);
+        dst.put_slice(&self.credentials.as_socks_bytes());
+    }
+}
+
+/// The username/password authentication reply ([rfc1929]).
+///
+/// [rfc1929]: https://tools.ietf.org/html/rfc1929
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordAuthReply {
+    /// The status of the authentication, `SOCKS_AUTH_SUCCESS` indicates success.
+    pub status: u8,
+}
+
+impl PasswordAuthReply {
+    /// Creates a new `PasswordAuthReply`.
+    pub fn new(status: u8) -> Self {
+        Self { status }
+    }
+}
+
+impl Decode for PasswordAuthReply {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let version = cursor.read_u8()?;
+        if version != SOCKS_AUTH_VER {
+            return Err(ProtocolError::UnsupportedVersion(version).into());
+        }
+
+        Ok(Self::new(cursor.read_u8()?))
+    }
+}
+
+impl Encode for PasswordAuthReply {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_u8(SOCKS_AUTH_VER);
+        dst.put_u8(self.status);
+    }
+}
+
+impl Decode for Socks5Request {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let version = cursor.read_u8()?;
+        if version != SOCKS_VER_5 {
+            return Err(ProtocolError::UnsupportedVersion(version).into());
+        }
+
+        let command = cursor.read_u8()?;
+        let command = Socks5Command::from_u8(command).ok_or(ProtocolError::UnsupportedCommand(command))?;
+
+        let _reserved = cursor.read_u8()?;
+        let destination = Address::decode_from(cursor)?;
+
+        Ok(Self { command, destination })
+    }
+}
+
+impl Encode for Socks5Request {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_u8(SOCKS_VER_5);
+        dst.put_u8(self.command.clone() as u8);
+        dst.put_u8(SOCKS_RSV);
+        self.destination.encode(dst);
+    }
+}
+
+/// The reply sent by the server once it has processed a `Socks5Request`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Socks5OperationReply {
+    pub reply: Socks5Reply,
+    pub binding: Address,
+}
+
+impl Socks5OperationReply {
+    /// Creates a new `Socks5OperationReply`.
+    pub fn new(
+        reply: Socks5Reply,
+        binding: Address,
+    ) -> Self {
+        Self { reply, binding }
+    }
+}
+
+impl Decode for Socks5OperationReply {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let version = cursor.read_u8()?;
+        if version != SOCKS_VER_5 {
+            return Err(ProtocolError::UnsupportedVersion(version).into());
+        }
+
+        let reply = cursor.read_u8()?;
+        let reply = Socks5Reply::from_u8(reply).ok_or(ProtocolError::UnknownReplyCode(reply))?;
+
+        let _reserved = cursor.read_u8()?;
+        let binding = Address::decode_from(cursor)?;
+
+        Ok(Self::new(reply, binding))
+    }
+}
+
+impl Encode for Socks5OperationReply {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_u8(SOCKS_VER_5);
+        dst.put_u8(self.reply.clone() as u8);
+        dst.put_u8(SOCKS_RSV);
+        self.binding.encode(dst);
+    }
+}
+
+/// The header that precedes every datagram relayed through a UDP association.
+///
+/// The payload is not part of the header: after decoding, the remaining bytes of the datagram are the payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UdpHeader {
+    /// The fragment number, `0` for standalone datagrams.
+    pub fragment: u8,
+    /// The destination (or, for replies, the source) of the datagram.
+    pub destination: Address,
+}
+
+impl UdpHeader {
+    /// Creates a new `UdpHeader`.
+    pub fn new(
+        fragment: u8,
+        destination: Address,
+    ) -> Self {
+        Self { fragment, destination }
+    }
+}
+
+impl Decode for UdpHeader {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let _reserved = cursor.read_u16()?;
+        let fragment = cursor.read_u8()?;
+        let destination = Address::decode_from(cursor)?;
+
+        Ok(Self::new(fragment, destination))
+    }
+}
+
+impl Encode for UdpHeader {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_u8(SOCKS_RSV);
+        dst.put_u8(SOCKS_RSV);
+        dst.put_u8(self.fragment);
+        self.destination.encode(dst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_selection_request() {
+        let mut src = BytesMut::from(&[SOCKS_VER_5, 0x02, SOCKS_AUTH_NOT_REQUIRED][..]);
+        assert_eq!(MethodSelectionRequest::decode(&mut src), Ok(None));
+
+        src.put_u8(SOCKS_AUTH_USERNAME_PASSWORD);
+        let request = MethodSelectionRequest::decode(&mut src).unwrap().unwrap();
+        assert_eq!(request.methods, vec![SOCKS_AUTH_NOT_REQUIRED, SOCKS_AUTH_USERNAME_PASSWORD]);
+        assert_eq!(&request.to_bytes()[..], &[SOCKS_VER_5, 0x02, 0x00, 0x02]);
+    }
+
+    #[test]
+    fn test_method_selection_request_wrong_version() {
+        let mut src = BytesMut::from(&[SOCKS_VER_6, 0x01, 0x00][..]);
+        assert_eq!(
+            MethodSelectionRequest::decode(&mut src),
+            Err(ProtocolError::UnsupportedVersion(SOCKS_VER_6))
+        );
+    }
+
+    #[test]
+    fn test_password_auth_request() {
+        let request = PasswordAuthRequest::new(Credentials::new("user", "pass"));
+        let mut bytes = request.to_bytes();
+        assert_eq!(&bytes[..], &[0x01, 4, b'u', b's', b'e', b'r', 4, b'p', b'a', b's', b's']);
+        assert_eq!(PasswordAuthRequest::decode(&mut bytes), Ok(Some(request)));
+    }
+
+    #[test]
+    fn test_socks5_request() {
+        let request = Socks5Request::new(SOCKS_CMD_CONNECT, Address::new("192.168.1.1", 80));
+        let mut bytes = request.to_bytes();
+        assert_eq!(&bytes[..], &[5, 1, 0, 1, 192, 168, 1, 1, 0, 80]);
+
+        let decoded = Socks5Request::decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(decoded.command, Socks5Command::Connect);
+        assert_eq!(decoded.destination, Address::new("192.168.1.1", 80));
+    }
+
+    #[test]
+    fn test_socks5_request_unknown_command() {
+        let mut src = BytesMut::from(&[SOCKS_VER_5, 0x09, 0x00][..]);
+        assert_eq!(
+            Socks5Request::decode(&mut src).unwrap_err(),
+            ProtocolError::UnsupportedCommand(0x09)
+        );
+    }
+
+    #[test]
+    fn test_socks5_operation_reply() {
+        let reply = Socks5OperationReply::new(Socks5Reply::HostUnreachable, Address::new("::1", 1080));
+        let mut bytes = reply.to_bytes();
+        assert_eq!(Socks5OperationReply::decode(&mut bytes), Ok(Some(reply)));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_udp_header() {
+        let header = UdpHeader::new(0, Address::new("example.com", 53));
+        let mut bytes = header.to_bytes();
+        bytes.put_slice(b"payload");
+
+        assert_eq!(UdpHeader::decode(&mut bytes), Ok(Some(header)));
+        assert_eq!(&bytes[..], b"payload");
+    }
+}
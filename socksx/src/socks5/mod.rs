@@ -1,13 +1,21 @@
+use std::io;
+
 use anyhow::Result;
 use num_traits::FromPrimitive;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 
-pub use s5_client::Socks5Client;
+pub use codec::{
+    MethodSelectionReply, MethodSelectionRequest, PasswordAuthReply, PasswordAuthRequest, Socks5OperationReply,
+    UdpHeader,
+};
+pub use s5_client::{Socks5Client, Socks5UdpSession};
 pub use s5_handler::Socks5Handler;
 
-use crate::addresses::{self, Address};
-use crate::constants::*;
+use crate::addresses::Address;
+use crate::codec::{self as socks_codec, Encode};
+use crate::ProtocolError;
 
+pub mod codec;
 mod s5_client;
 mod s5_handler;
 
@@ -21,7 +29,7 @@ pub enum Socks5Command {
 }
 
 /// Represents a SOCKS5 request.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Socks5Request {
     pub command: Socks5Command,
     pub destination: Address,
@@ -54,10 +62,7 @@ impl Socks5Request {
     ///
     /// A vector of bytes representing the request.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = vec![SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV];
-        data.extend(self.destination.as_socks_bytes());
-
-        data
+        self.to_bytes().to_vec()
     }
 }
 
@@ -77,6 +82,30 @@ pub enum Socks5Reply {
     ConnectionAttemptTimeOut = 0x09,
 }
 
+impl From<&ProtocolError> for Socks5Reply {
+    /// Selects the failure reply that matches a protocol error.
+    fn from(error: &ProtocolError) -> Self {
+        match error {
+            ProtocolError::UnsupportedCommand(_) => Socks5Reply::CommandNotSupported,
+            ProtocolError::UnsupportedAddressType(_) => Socks5Reply::AddressTypeNotSupported,
+            _ => Socks5Reply::GeneralFailure,
+        }
+    }
+}
+
+impl From<&io::Error> for Socks5Reply {
+    /// Selects the failure reply that matches an error while connecting to the destination.
+    fn from(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => Socks5Reply::ConnectionRefused,
+            io::ErrorKind::NetworkUnreachable => Socks5Reply::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => Socks5Reply::HostUnreachable,
+            io::ErrorKind::TimedOut => Socks5Reply::TTLExpired,
+            _ => Socks5Reply::GeneralFailure,
+        }
+    }
+}
+
 /// Writes a SOCKS5 reply to the provided stream.
 ///
 /// # Arguments
@@ -94,22 +123,8 @@ pub async fn write_reply<S>(
     where
         S: AsyncWrite + Unpin,
 {
-    let reply = [
-        SOCKS_VER_5,
-        reply as u8,
-        SOCKS_RSV,
-        SOCKS_ATYP_IPV4,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-    ];
-
-    stream.write(&reply).await?;
-
-    Ok(())
+    let reply = Socks5OperationReply::new(reply, Address::unspecified());
+    socks_codec::write_message(stream, &reply).await
 }
 
 /// Reads a SOCKS5 reply from the provided stream and returns the associated address.
@@ -125,17 +140,12 @@ pub async fn read_reply<S>(stream: &mut S) -> Result<Address>
     where
         S: AsyncRead + Unpin,
 {
-    let mut operation_reply = [0; 3];
-    stream.read_exact(&mut operation_reply).await?;
-
-    let reply_code = operation_reply[1];
+    let Socks5OperationReply { reply, binding } = socks_codec::read_message(stream).await?;
     ensure!(
-        reply_code == SOCKS_REP_SUCCEEDED,
+        reply == Socks5Reply::Success,
         "CONNECT operation failed: {}",
-        reply_code
+        reply.clone() as u8
     );
 
-    let binding = addresses::read_address(stream).await?;
-
     Ok(binding)
 }
@@ -1,12 +1,16 @@
 use std::convert::TryInto;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
 
-use crate::{Address, constants::*, Credentials};
-use crate::socks5::{self, Socks5Request};
+use crate::{Address, codec, constants::*, Credentials};
+use crate::codec::{Cursor, Decode, Encode};
+use crate::socks5::{
+    self, MethodSelectionReply, MethodSelectionRequest, PasswordAuthReply, PasswordAuthRequest, Socks5Request,
+    UdpHeader,
+};
 
 /// Represents a SOCKS5 client for connecting to proxy servers.
 #[derive(Clone)]
@@ -54,14 +58,64 @@ impl Socks5Client {
         where
             A: TryInto<Address, Error = anyhow::Error>,
     {
-        if let Some(Credentials { username, password }) = &self.credentials {
-            ensure!(username.len() > 255, "Username MUST NOT be larger than 255 bytes.");
-            ensure!(password.len() > 255, "Password MUST NOT be larger than 255 bytes.");
-        }
-
         // Create SOCKS5 CONNECT request.
         let request = Socks5Request::new(SOCKS_CMD_CONNECT, destination.try_into()?);
 
+        self.request(request).await
+    }
+
+    /// Establishes a SOCKS5 UDP association ([rfc1928], section 7) with the proxy server.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a `Socks5UdpSession` that relays datagrams through the proxy.
+    ///
+    /// [rfc1928]: https://tools.ietf.org/html/rfc1928
+    pub async fn udp_associate(&self) -> Result<Socks5UdpSession> {
+        let socket = match self.proxy_addr {
+            SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+            SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+        };
+
+        // The address the client will send from is not known yet, so all-zeros is sent instead.
+        let request = Socks5Request::new(SOCKS_CMD_UDP_ASSOCIATE, Address::unspecified());
+        let (control, binding) = self.request(request).await?;
+
+        let relay_addr = match binding {
+            Address::Ip(relay_addr) if relay_addr.ip().is_unspecified() => {
+                SocketAddr::new(self.proxy_addr.ip(), relay_addr.port())
+            }
+            Address::Ip(relay_addr) => relay_addr,
+            Address::Domainname { .. } => crate::resolve_addr(binding.to_string()).await?,
+        };
+        socket.connect(relay_addr).await?;
+
+        Ok(Socks5UdpSession {
+            _control: control,
+            socket,
+            relay_addr,
+            datagram: Mutex::new(vec![0; u16::MAX as usize]),
+        })
+    }
+
+    /// Sends a request to the SOCKS5 proxy server, after negotiating authentication.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request to send.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with the `TcpStream` to the proxy and the bound address.
+    async fn request(
+        &self,
+        request: Socks5Request,
+    ) -> Result<(TcpStream, Address)> {
+        if let Some(Credentials { username, password }) = &self.credentials {
+            ensure!(username.len() <= 255, "Username MUST NOT be larger than 255 bytes.");
+            ensure!(password.len() <= 255, "Password MUST NOT be larger than 255 bytes.");
+        }
+
         let mut stream = TcpStream::connect(&self.proxy_addr).await?;
 
         // Enter authentication negotiation.
@@ -75,8 +129,7 @@ impl Socks5Client {
         }
 
         // Send SOCKS request information.
-        let request_bytes = request.into_socks_bytes();
-        stream.write(&request_bytes).await?;
+        codec::write_message(&mut stream, &request).await?;
 
         // Read operation reply.
         let binding = socks5::read_reply(&mut stream).await?;
@@ -97,23 +150,15 @@ impl Socks5Client {
         &self,
         stream: &mut TcpStream,
     ) -> Result<u8> {
-        let mut request = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_NOT_REQUIRED];
+        let mut methods = vec![SOCKS_AUTH_NOT_REQUIRED];
         if self.credentials.is_some() {
-            request[1] = 0x02;
-            request.push(SOCKS_AUTH_USERNAME_PASSWORD);
+            methods.push(SOCKS_AUTH_USERNAME_PASSWORD);
         }
 
-        stream.write(&request).await?;
-
-        let mut reply = [0; 2];
-        stream.read_exact(&mut reply).await?;
+        let request = MethodSelectionRequest::new(methods);
+        codec::write_message(stream, &request).await?;
 
-        let socks_version = reply[0];
-        if socks_version != SOCKS_VER_5 {
-            bail!("Proxy uses a different SOCKS version: {}.", socks_version);
-        }
-
-        let auth_method = reply[1];
+        let MethodSelectionReply { method: auth_method } = codec::read_message(stream).await?;
         match auth_method {
             0x00 => Ok(auth_method),
             0x02 => {
@@ -143,24 +188,11 @@ impl Socks5Client {
         stream: &mut TcpStream,
         credentials: &Credentials,
     ) -> Result<()> {
-        let mut request = vec![SOCKS_AUTH_VER];
-        request.extend(credentials.as_socks_bytes());
-
-        stream.write(&request).await?;
-
-        let mut reply = [0; 2];
-        stream.read_exact(&mut reply).await?;
-
-        let auth_version = reply[0];
-        if auth_version != SOCKS_AUTH_VER {
-            bail!(
-                "Proxy uses a different authentication method version: {}.",
-                auth_version
-            );
-        }
+        let request = PasswordAuthRequest::new(credentials.clone());
+        codec::write_message(stream, &request).await?;
 
         // Check if status indicates success. If not, bail to close the connection.
-        let status = reply[1];
+        let PasswordAuthReply { status } = codec::read_message(stream).await?;
         if status != SOCKS_AUTH_SUCCESS {
             bail!("Authentication with the provided credentials failed.");
         }
@@ -168,3 +200,91 @@ impl Socks5Client {
         Ok(())
     }
 }
+
+/// A UDP association with a SOCKS5 proxy server.
+///
+/// The association lasts as long as the session: dropping it closes the TCP connection that the proxy ties it to.
+pub struct Socks5UdpSession {
+    _control: TcpStream,
+    socket: UdpSocket,
+    relay_addr: SocketAddr,
+    /// The buffer that datagrams from the relay are received into, reused across calls.
+    datagram: Mutex<Vec<u8>>,
+}
+
+impl Socks5UdpSession {
+    /// Returns the address of the proxy's UDP relay.
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    /// Sends a datagram to `destination` through the proxy.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The contents of the datagram.
+    /// * `destination` - The address the datagram is meant for.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of payload bytes sent.
+    pub async fn send_to<A>(
+        &self,
+        payload: &[u8],
+        destination: A,
+    ) -> Result<usize>
+        where
+            A: TryInto<Address, Error = anyhow::Error>,
+    {
+        let header = UdpHeader::new(0, destination.try_into()?);
+
+        let mut datagram = header.to_bytes();
+        datagram.extend_from_slice(payload);
+        self.socket.send(&datagram).await?;
+
+        Ok(payload.len())
+    }
+
+    /// Receives a datagram relayed by the proxy. Payloads larger than `buffer` are truncated.
+    ///
+    /// Concurrent calls receive one datagram at a time, as they share the buffer of the session.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - The buffer to receive the payload into.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the length of the payload and the address it was sent from.
+    pub async fn recv_from(
+        &self,
+        buffer: &mut [u8],
+    ) -> Result<(usize, Address)> {
+        let mut datagram = self.datagram.lock().await;
+
+        loop {
+            let length = self.socket.recv(&mut datagram).await?;
+
+            let mut cursor = Cursor::new(&datagram[..length]);
+            let header = match UdpHeader::decode_from(&mut cursor) {
+                Ok(header) => header,
+                Err(_) => {
+                    debug!("Dropping malformed datagram from the UDP relay.");
+                    continue;
+                }
+            };
+
+            // Fragmentation is not supported, so fragments MUST be dropped.
+            if header.fragment != 0 {
+                debug!("Dropping fragmented datagram from {}.", header.destination);
+                continue;
+            }
+
+            let received = &datagram[cursor.position()..length];
+            let length = received.len().min(buffer.len());
+            buffer[..length].copy_from_slice(&received[..length]);
+
+            return Ok((length, header.destination));
+        }
+    }
+}
@@ -1,17 +1,43 @@
+use std::sync::Arc;
+use std::time::Instant;
+
 use anyhow::Result;
 use async_trait::async_trait;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use tracing::field::display;
+use tracing::{Instrument, Span};
 
-use crate::{constants::*, Credentials};
-use crate::addresses::{self, ProxyAddress};
-use crate::socks5::{self, Socks5Reply};
+use crate::{codec, constants::*, Credentials, ProtocolError};
+use crate::addresses::ProxyAddress;
+use crate::access_log::{AccessLogger, AccessRecord, CloseReason};
+use crate::errors::{AuthenticationError, SessionKilled};
+use crate::functions::{Flow, Pipeline};
+use crate::metrics::{ConnectTarget, FailureReason, Metrics};
+use crate::quotas::{QuotaGuard, Quotas};
+use crate::rate_limit::RateLimiter;
+use crate::relay::{Relay, RelayEnd, RelayStats};
+use crate::sessions::{self, Session, SessionRegistry};
+use crate::sockets::SocketOptions;
+use crate::spans;
+use crate::socks5::{
+    self, MethodSelectionReply, MethodSelectionRequest, PasswordAuthReply, PasswordAuthRequest, Socks5Command,
+    Socks5Reply, Socks5Request,
+};
 use crate::SocksHandler;
 
 /// Represents a SOCKS5 handler for processing client requests.
 #[derive(Clone)]
 pub struct Socks5Handler {
     credentials: Option<Credentials>,
+    functions: Pipeline,
+    metrics: Metrics,
+    access_logger: Option<Arc<dyn AccessLogger>>,
+    sessions: SessionRegistry,
+    rate_limiter: RateLimiter,
+    quotas: Quotas,
+    relay: Relay,
+    socket_options: SocketOptions,
     //chain: Vec<ProxyAddress>,
 }
 
@@ -34,9 +60,365 @@ impl Socks5Handler {
     pub fn new(_chain: Vec<ProxyAddress>) -> Self {
         Socks5Handler {
             credentials: None,
+            functions: Pipeline::default(),
+            metrics: Metrics::default(),
+            access_logger: None,
+            sessions: SessionRegistry::default(),
+            rate_limiter: RateLimiter::default(),
+            quotas: Quotas::default(),
+            relay: Relay::default(),
+            socket_options: SocketOptions::default(),
             //chain,
         }
     }
+
+    /// Requires clients to authenticate with the given username and password ([rfc1929]).
+    ///
+    /// [rfc1929]: https://tools.ietf.org/html/rfc1929
+    pub fn with_credentials(
+        mut self,
+        credentials: Credentials,
+    ) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Runs the given functions on the data of every connection, between the source and the destination.
+    pub fn with_functions(
+        mut self,
+        functions: Pipeline,
+    ) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    /// Records the connections, handshakes and replies of this handler in the given metrics.
+    pub fn with_metrics(
+        mut self,
+        metrics: Metrics,
+    ) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Hands the record of every session to the given logger, once the session ends.
+    pub fn with_access_logger<L>(
+        mut self,
+        logger: L,
+    ) -> Self
+    where
+        L: AccessLogger + 'static,
+    {
+        self.access_logger = Some(Arc::new(logger));
+        self
+    }
+
+    /// Registers every session of this handler in the given registry, e.g., to list and kill them from the admin API.
+    pub fn with_sessions(
+        mut self,
+        sessions: SessionRegistry,
+    ) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Limits the bandwidth of every connection of this handler with the given limiter, whose buckets are shared with
+    /// the other handlers it was given to.
+    pub fn with_rate_limiter(
+        mut self,
+        rate_limiter: RateLimiter,
+    ) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Admits the requests of this handler within the given quotas, whose usage is shared with the other handlers it
+    /// was given to.
+    pub fn with_quotas(
+        mut self,
+        quotas: Quotas,
+    ) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Relays the data of every connection of this handler as configured by `relay`, e.g., with an idle timeout.
+    ///
+    /// Sessions that are killed cancel their relay, regardless of a cancellation token of `relay`.
+    pub fn with_relay(
+        mut self,
+        relay: Relay,
+    ) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Applies `options` to the connections of this handler to destinations and next hops, once connected.
+    ///
+    /// Accepted connections are tuned by the listener instead, see `sockets::bind`.
+    pub fn with_socket_options(
+        mut self,
+        options: SocketOptions,
+    ) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Sends a reply to the client, counts it, and records it as the last reply of the session.
+    async fn reply(
+        &self,
+        source: &mut TcpStream,
+        reply: Socks5Reply,
+        record: &mut AccessRecord,
+    ) -> Result<()> {
+        self.metrics.reply(SOCKS_VER_5, &reply);
+        record.reply = Some(format!("{:?}", reply));
+
+        socks5::write_reply(source, reply).await
+    }
+
+    /// Completes the record of a session that started at `started`, and hands it to the access logger.
+    fn log_access(
+        &self,
+        mut record: AccessRecord,
+        started: Instant,
+    ) {
+        if let Some(logger) = &self.access_logger {
+            record.duration_ms = started.elapsed().as_millis() as u64;
+            logger.log(&record);
+        }
+    }
+
+    /// Negotiates authentication, reads the request, and connects to its destination.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The TCP stream representing the client connection.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the TCP stream to the destination, and the flow that connects the two.
+    async fn handshake(
+        &self,
+        source: &mut TcpStream,
+        record: &mut AccessRecord,
+    ) -> Result<(TcpStream, Flow, QuotaGuard)> {
+        // Get all authentication methods the client proposes.
+        let MethodSelectionRequest { methods } = codec::read_message(source).await?;
+
+        // Once credentials are configured, clients can no longer skip authentication.
+        let method = if self.credentials.is_some() {
+            if methods.contains(&SOCKS_AUTH_USERNAME_PASSWORD) {
+                SOCKS_AUTH_USERNAME_PASSWORD
+            } else {
+                SOCKS_AUTH_NO_ACCEPTABLE_METHODS
+            }
+        } else if methods.contains(&SOCKS_AUTH_NOT_REQUIRED) {
+            SOCKS_AUTH_NOT_REQUIRED
+        } else {
+            SOCKS_AUTH_NO_ACCEPTABLE_METHODS
+        };
+
+        debug!(method, "Selected authentication method.");
+
+        codec::write_message(source, &MethodSelectionReply::new(method)).await?;
+        if method == SOCKS_AUTH_NO_ACCEPTABLE_METHODS {
+            return Err(AuthenticationError::NoAcceptableMethods.into());
+        }
+
+        // Enter method-specific sub-negotiation
+        if method == SOCKS_AUTH_USERNAME_PASSWORD {
+            let PasswordAuthRequest { credentials } = codec::read_message(source).await?;
+
+            let status = if self.credentials.as_ref() == Some(&credentials) {
+                SOCKS_AUTH_SUCCESS
+            } else {
+                SOCKS_AUTH_FAILED
+            };
+
+            codec::write_message(source, &PasswordAuthReply::new(status)).await?;
+
+            if status != SOCKS_AUTH_SUCCESS {
+                return Err(AuthenticationError::Failed.into());
+            }
+
+            let user = String::from_utf8_lossy(&credentials.username).into_owned();
+            Span::current().record("user", display(&user));
+            record.user = Some(user);
+        }
+
+        let request = match codec::read_message::<Socks5Request, _>(source).await {
+            Ok(request) => request,
+            Err(error) => {
+                // Malformed requests are answered with a failure reply before closing.
+                if let Some(protocol_error) = error.downcast_ref::<ProtocolError>() {
+                    self.reply(source, protocol_error.into(), record).await?;
+                }
+
+                return Err(error);
+            }
+        };
+        Span::current().record("destination", display(&request.destination));
+        record.command = Some(format!("{:?}", request.command));
+        record.destination = Some(request.destination.to_string());
+
+        if request.command != Socks5Command::Connect {
+            let error = ProtocolError::UnsupportedCommand(request.command as u8);
+            self.reply(source, (&error).into(), record).await?;
+
+            return Err(error.into());
+        }
+
+        // Wait for the quotas of the client and user, and refuse the request if they are still exceeded after that.
+        let client = source.peer_addr().ok().map(|peer| peer.ip());
+        let quota = match self.quotas.admit(client, record.user.as_deref()).await {
+            Ok((quota, waited)) => {
+                if !waited.is_zero() {
+                    self.metrics.quota_waited(waited);
+                    debug!(waited_ms = waited.as_millis() as u64, "Waited for the quotas.");
+                }
+
+                quota
+            }
+            Err(exceeded) => {
+                self.metrics.quota_exceeded(&exceeded);
+                self.reply(source, Socks5Reply::ConnectionNotAllowed, record).await?;
+
+                return Err(exceeded.into());
+            }
+        };
+
+        let started = Instant::now();
+        let destination = match TcpStream::connect(request.destination.to_string()).await {
+            Ok(destination) => {
+                self.metrics.connected(ConnectTarget::Direct, started.elapsed());
+                destination
+            }
+            Err(error) => {
+                // Let the client know why the destination could not be reached.
+                self.reply(source, (&error).into(), record).await?;
+
+                return Err(error.into());
+            }
+        };
+        self.socket_options.apply(&destination)?;
+
+        // Notify source that the connection has been set up.
+        self.reply(source, Socks5Reply::Success, record).await?;
+        source.flush().await?;
+
+        let flow = Flow::new(source.peer_addr().ok(), destination.peer_addr().ok()).with_target(request.destination);
+        record.resolved = flow.destination;
+
+        Ok((destination, flow, quota))
+    }
+
+    /// Handles a connection, from the handshake until the relay ends, in the span of the connection.
+    async fn serve(
+        &self,
+        source: &mut TcpStream,
+        session: &Session,
+    ) -> Result<()> {
+        let _connection = self.metrics.connection_opened();
+        debug!("Accepted connection.");
+
+        let started = Instant::now();
+        let mut record = AccessRecord::new("socks5", source.peer_addr().ok());
+
+        let handshake = tokio::select! {
+            handshake = self.handshake(source, &mut record) => handshake,
+            _ = session.killed() => Err(SessionKilled.into()),
+        };
+
+        let (mut destination, flow, _quota) = match handshake {
+            Ok(handshake) => handshake,
+            Err(error) => {
+                let reason = FailureReason::of(&error);
+                self.metrics.handshake_failed(reason);
+                warn!(reason = reason.as_str(), "Handshake failed: {:#}", error);
+
+                record.close_reason = if error.is::<SessionKilled>() {
+                    CloseReason::Killed
+                } else {
+                    reason.into()
+                };
+                self.log_access(record, started);
+
+                return Err(error);
+            }
+        };
+        self.metrics.handshake_completed(started.elapsed());
+        session.update(&record);
+
+        let throttle = self.rate_limiter.throttle(&flow, record.user.as_deref());
+        let relay = self.relay.clone().with_cancellation(session.cancellation());
+        let relayed = async {
+            // Without functions or rate limits, the data does not need to pass through userspace.
+            #[cfg(target_os = "linux")]
+            if self.functions.is_empty() && throttle.is_empty() {
+                let progress = |direction, bytes| session.count(direction, bytes);
+                return relay.splice(source, &mut destination, progress).await;
+            }
+
+            let mut source = throttle.apply(session.track(source));
+            if self.functions.is_empty() {
+                // Start bidirectional copy, after this the connection closes.
+                relay.run(&mut source, &mut destination).await
+            } else {
+                let mut source = self.functions.apply(&mut source, &flow)?;
+
+                relay.run(&mut source, &mut destination).await
+            }
+        };
+
+        let stats = match relayed.await {
+            Ok(stats) => stats,
+            Err(error) => {
+                let RelayStats {
+                    upstream,
+                    downstream,
+                    ..
+                } = error.stats;
+                info!(upstream, downstream, "Relay failed: {}", error);
+
+                record.upstream = upstream;
+                record.downstream = downstream;
+                record.close_reason = CloseReason::Relay;
+                self.log_access(record, started);
+
+                return Err(error.into());
+            }
+        };
+        let RelayStats {
+            upstream,
+            downstream,
+            end,
+            ..
+        } = stats;
+
+        record.upstream = upstream;
+        record.downstream = downstream;
+
+        let duration_ms = started.elapsed().as_millis() as u64;
+        match end {
+            RelayEnd::Completed | RelayEnd::Failed => info!(upstream, downstream, duration_ms, "Connection closed."),
+            RelayEnd::Idle => {
+                info!(upstream, downstream, duration_ms, "Connection closed, as it was idle.");
+                record.close_reason = CloseReason::Idle;
+            }
+            RelayEnd::Cancelled => {
+                info!(upstream, downstream, duration_ms, "Session killed.");
+
+                record.close_reason = CloseReason::Killed;
+                self.log_access(record, started);
+
+                return Err(SessionKilled.into());
+            }
+        }
+        self.log_access(record, started);
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -54,12 +436,11 @@ impl SocksHandler for Socks5Handler {
         &self,
         source: &mut TcpStream,
     ) -> Result<()> {
-        let mut destination = self.setup(source).await?;
+        let session = self.sessions.register("socks5", source.peer_addr().ok());
+        let session = session.with_relayed_bytes(self.metrics.relayed_bytes());
+        let span = spans::connection(session.id(), SOCKS_VER_5, source.peer_addr().ok());
 
-        // Start bidirectional copy, after this the connection closes.
-        tokio::io::copy_bidirectional(source, &mut destination).await?;
-
-        Ok(())
+        self.serve(source, &session).instrument(span).await
     }
 
     /// Refuses a SOCKS5 client request and notifies the client.
@@ -75,10 +456,24 @@ impl SocksHandler for Socks5Handler {
         &self,
         source: &mut TcpStream,
     ) -> Result<()> {
-        // Notify source that the connection is refused.
-        socks5::write_reply(source, Socks5Reply::ConnectionRefused).await?;
+        let span = spans::connection(sessions::next_id(), SOCKS_VER_5, source.peer_addr().ok());
 
-        Ok(())
+        async {
+            self.metrics.connection_refused();
+            info!("Refused connection, as the connection limit is reached.");
+
+            let started = Instant::now();
+            let mut record = AccessRecord::new("socks5", source.peer_addr().ok());
+            record.close_reason = CloseReason::Refused;
+
+            // Notify source that the connection is refused.
+            let result = self.reply(source, Socks5Reply::ConnectionRefused, &mut record).await;
+            self.log_access(record, started);
+
+            result
+        }
+        .instrument(span)
+        .await
     }
 
     /// Sets up the SOCKS5 connection with a client.
@@ -94,86 +489,8 @@ impl SocksHandler for Socks5Handler {
         &self,
         source: &mut TcpStream,
     ) -> Result<TcpStream> {
-        let mut request = [0; 2];
-        source.read_exact(&mut request).await?;
-
-        let socks_version = request[0];
-
-        if socks_version != SOCKS_VER_5 {
-            bail!("Client uses a different SOCKS version: {}.", socks_version);
-        }
-
-        // Get all authentication methods the client proposes.
-        let nmethods = request[1] as usize;
-
-        let mut methods = vec![0; nmethods];
-        source.read_exact(&mut methods).await?;
-
-        let method = if self.credentials.is_some() && methods.contains(&SOCKS_AUTH_USERNAME_PASSWORD) {
-            SOCKS_AUTH_USERNAME_PASSWORD
-        } else if methods.contains(&SOCKS_AUTH_NOT_REQUIRED) {
-            SOCKS_AUTH_NOT_REQUIRED
-        } else {
-            SOCKS_AUTH_NO_ACCEPTABLE_METHODS
-        };
-
-        info!("Use authentication method: {}", method);
-
-        let response = [SOCKS_VER_5, method];
-        source.write(&response).await?;
-
-        // Enter method-specific sub-negotiation
-        if method == SOCKS_AUTH_USERNAME_PASSWORD {
-            let mut request = [0; 2];
-            source.read_exact(&mut request).await?;
-
-            let auth_version = request[0];
-            if auth_version != SOCKS_AUTH_VER {
-                bail!(
-                    "Client uses a different authentication method version: {}.",
-                    auth_version
-                );
-            }
-
-            let ulen = request[1] as usize;
-            let mut uname = vec![0; ulen];
-            source.read_exact(&mut uname).await?;
-
-            let plen = request[1] as usize;
-            let mut passwd = vec![0; plen];
-            source.read_exact(&mut passwd).await?;
-
-            let status = if let Some(Credentials { username, password }) = &self.credentials {
-                if &uname != username || &passwd != password {
-                    SOCKS_AUTH_SUCCESS
-                } else {
-                    0x01u8
-                }
-            } else {
-                unreachable!()
-            };
-
-            let response = [SOCKS_VER_5, status];
-            source.write(&response).await?;
-
-            ensure!(status == SOCKS_AUTH_SUCCESS, "Username/password authentication failed.");
-        }
-
-        let mut request = [0; 3];
-        source.read_exact(&mut request).await?;
-
-        let command = request[1];
-        if command != SOCKS_CMD_CONNECT {
-            unimplemented!();
-        }
-
-        let destination = addresses::read_address(source).await?;
-        let destination = TcpStream::connect(destination.to_string()).await?;
-
-        // Notify source that the connection has been set up.
-        socks5::write_reply(source, Socks5Reply::Success).await?;
-        source.flush().await?;
+        let mut record = AccessRecord::new("socks5", source.peer_addr().ok());
 
-        Ok(destination)
+        self.handshake(source, &mut record).await.map(|(destination, ..)| destination)
     }
 }
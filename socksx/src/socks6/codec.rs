@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+
+use bytes::{BufMut, BytesMut};
+use num_traits::FromPrimitive;
+
+use crate::codec::{Cursor, Decode, DecodeError, Encode};
+use crate::constants::*;
+use crate::socks6::options::{
+    AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption, UnrecognizedOption,
+};
+use crate::socks6::{Socks6Command, Socks6Reply, Socks6Request};
+use crate::{Address, ProtocolError};
+
+/// Decodes an options section: a two-byte length followed by that many bytes of options.
+impl Decode for Vec<SocksOption> {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let options_length = cursor.read_u16()?;
+        if options_length > SOCKS6_MAX_OPTIONS_LENGTH {
+            return Err(ProtocolError::OptionsTooLong(options_length, SOCKS6_MAX_OPTIONS_LENGTH).into());
+        }
+
+        let mut options = Vec::new();
+        let mut options_bytes_read = 0;
+        while options_bytes_read < options_length {
+            if options.len() == SOCKS6_MAX_OPTIONS {
+                return Err(ProtocolError::TooManyOptions(SOCKS6_MAX_OPTIONS).into());
+            }
+
+            let kind = cursor.read_u16()?;
+            let length = cursor.read_u16()?;
+
+            // An option covers at least its own header, is padded to a multiple of four bytes,
+            // and must fit within the declared length of the options section.
+            if length < 4 || !length.is_multiple_of(4) || length > options_length - options_bytes_read {
+                return Err(ProtocolError::InvalidOptionLength(length).into());
+            }
+
+            // Read remaining bytes of this option.
+            let options_data = cursor.read_bytes((length - 4) as usize)?.to_vec();
+
+            let option = match kind {
+                SOCKS_OKIND_AUTH_METH_ADV => AuthMethodAdvertisementOption::from_socks_bytes(options_data)?,
+                SOCKS_OKIND_AUTH_METH_SEL => AuthMethodSelectionOption::from_socks_bytes(options_data)?,
+                SOCKS_OKIND_METADATA => MetadataOption::from_socks_bytes(options_data)?,
+                _ => UnrecognizedOption::new(kind, options_data).wrap(),
+            };
+
+            options.push(option);
+            options_bytes_read += length;
+        }
+
+        Ok(options)
+    }
+}
+
+/// Encodes an options section: a two-byte length followed by the options.
+impl Encode for Vec<SocksOption> {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        let options_bytes: Vec<u8> = self.iter().flat_map(|o| o.as_socks_bytes()).collect();
+
+        dst.put_u16(options_bytes.len() as u16);
+        dst.put_slice(&options_bytes);
+    }
+}
+
+impl Decode for Socks6Request {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let version = cursor.read_u8()?;
+        if version != SOCKS_VER_6 {
+            return Err(ProtocolError::UnsupportedVersion(version).into());
+        }
+
+        let command = cursor.read_u8()?;
+        let command = Socks6Command::from_u8(command).ok_or(ProtocolError::UnsupportedCommand(command))?;
+
+        let destination = Address::decode_from(cursor)?;
+        let _padding = cursor.read_u8()?;
+        let options = Vec::<SocksOption>::decode_from(cursor)?;
+
+        let mut initial_data_length = 0;
+        let mut metadata = HashMap::new();
+        for option in &options {
+            match option {
+                SocksOption::AuthMethodAdvertisement(advertisement) => {
+                    // Make note of initial data length for convenience.
+                    initial_data_length = advertisement.initial_data_length;
+                }
+                SocksOption::Metadata(key_value) => {
+                    metadata.insert(key_value.key, key_value.value.clone());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Socks6Request {
+            command,
+            destination,
+            initial_data_length,
+            options,
+            metadata,
+        })
+    }
+}
+
+impl Encode for Socks6Request {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_u8(SOCKS_VER_6);
+        dst.put_u8(self.command.clone() as u8);
+        self.destination.encode(dst);
+        dst.put_u8(SOCKS_PADDING);
+        self.options.encode(dst);
+    }
+}
+
+/// The authentication reply sent by the server after receiving a `Socks6Request`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Socks6AuthReply {
+    /// The status of the authentication, `SOCKS_AUTH_SUCCESS` indicates success.
+    pub status: u8,
+    pub options: Vec<SocksOption>,
+}
+
+impl Socks6AuthReply {
+    /// Creates a new `Socks6AuthReply`.
+    pub fn new(
+        status: u8,
+        options: Vec<SocksOption>,
+    ) -> Self {
+        Self { status, options }
+    }
+}
+
+impl Decode for Socks6AuthReply {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let version = cursor.read_u8()?;
+        if version != SOCKS_VER_6 {
+            return Err(ProtocolError::UnsupportedVersion(version).into());
+        }
+
+        let status = cursor.read_u8()?;
+        let options = Vec::<SocksOption>::decode_from(cursor)?;
+
+        Ok(Self::new(status, options))
+    }
+}
+
+impl Encode for Socks6AuthReply {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_u8(SOCKS_VER_6);
+        dst.put_u8(self.status);
+        self.options.encode(dst);
+    }
+}
+
+/// The reply sent by the server once it has processed a `Socks6Request`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Socks6OperationReply {
+    pub reply: Socks6Reply,
+    pub binding: Address,
+    pub options: Vec<SocksOption>,
+}
+
+impl Socks6OperationReply {
+    /// Creates a new `Socks6OperationReply`.
+    pub fn new(
+        reply: Socks6Reply,
+        binding: Address,
+        options: Vec<SocksOption>,
+    ) -> Self {
+        Self {
+            reply,
+            binding,
+            options,
+        }
+    }
+}
+
+impl Decode for Socks6OperationReply {
+    fn decode_from(cursor: &mut Cursor<'_>) -> Result<Self, DecodeError> {
+        let version = cursor.read_u8()?;
+        if version != SOCKS_VER_6 {
+            return Err(ProtocolError::UnsupportedVersion(version).into());
+        }
+
+        let reply = cursor.read_u8()?;
+        let reply = Socks6Reply::from_u8(reply).ok_or(ProtocolError::UnknownReplyCode(reply))?;
+
+        let _padding = cursor.read_u8()?;
+        let binding = Address::decode_from(cursor)?;
+        let options = Vec::<SocksOption>::decode_from(cursor)?;
+
+        Ok(Self::new(reply, binding, options))
+    }
+}
+
+impl Encode for Socks6OperationReply {
+    fn encode(
+        &self,
+        dst: &mut BytesMut,
+    ) {
+        dst.put_u8(SOCKS_VER_6);
+        dst.put_u8(self.reply.clone() as u8);
+        dst.put_u8(SOCKS_PADDING);
+        self.binding.encode(dst);
+        self.options.encode(dst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::arbitrary;
+    use crate::socks6::options::AuthMethod;
+
+    #[test]
+    fn test_socks6_request() {
+        let options = vec![
+            AuthMethodAdvertisementOption::new(5, vec![AuthMethod::UsernamePassword]).wrap(),
+            MetadataOption::new(999, String::from("2")).wrap(),
+        ];
+        let request = Socks6Request::new(SOCKS_CMD_CONNECT, Address::new("example.com", 443), 5, options, None);
+
+        let mut bytes = request.to_bytes();
+        let decoded = Socks6Request::decode(&mut bytes).unwrap().unwrap();
+
+        assert!(bytes.is_empty());
+        assert_eq!(decoded.command, Socks6Command::Connect);
+        assert_eq!(decoded.destination, request.destination);
+        assert_eq!(decoded.initial_data_length, 5);
+        assert_eq!(decoded.options, request.options);
+        assert_eq!(decoded.metadata.get(&999), Some(&String::from("2")));
+    }
+
+    #[test]
+    fn test_socks6_request_needs_more_data() {
+        let request = Socks6Request::new(SOCKS_CMD_CONNECT, Address::new("10.0.0.1", 80), 0, vec![], None);
+        let bytes = request.to_bytes();
+
+        for length in 0..bytes.len() {
+            let mut partial = BytesMut::from(&bytes[..length]);
+            assert!(Socks6Request::decode(&mut partial).unwrap().is_none());
+            assert_eq!(partial.len(), length);
+        }
+    }
+
+    // Regression (fuzzing): re-encoding an unrecognized option used to grow it by four padding bytes.
+    #[test]
+    fn test_options_round_trip_unrecognized() {
+        let mut src = BytesMut::from(&[0x00, 0x0C, 0x12, 0x34, 0x00, 0x0C, b'a', b'b', b'c', b'd', b'e', b'f', b'g', b'h'][..]);
+        let options = Vec::<SocksOption>::decode(&mut src).unwrap().unwrap();
+        assert_eq!(Vec::<SocksOption>::decode(&mut options.to_bytes()), Ok(Some(options)));
+    }
+
+    #[test]
+    fn test_socks6_auth_reply() {
+        let reply = Socks6AuthReply::new(SOCKS_AUTH_SUCCESS, vec![]);
+        let mut bytes = reply.to_bytes();
+        assert_eq!(&bytes[..], &[SOCKS_VER_6, SOCKS_AUTH_SUCCESS, 0x00, 0x00]);
+        assert_eq!(Socks6AuthReply::decode(&mut bytes), Ok(Some(reply)));
+    }
+
+    #[test]
+    fn test_socks6_operation_reply() {
+        let reply = Socks6OperationReply::new(
+            Socks6Reply::ConnectionRefused,
+            Address::new("10.0.0.1", 1080),
+            vec![MetadataOption::new(1000, String::from("socks6://10.0.0.2:1080")).wrap()],
+        );
+        let mut bytes = reply.to_bytes();
+        assert_eq!(Socks6OperationReply::decode(&mut bytes), Ok(Some(reply)));
+    }
+
+    #[test]
+    fn test_socks6_operation_reply_unknown_code() {
+        let mut src = BytesMut::from(&[SOCKS_VER_6, 0x42, 0x00][..]);
+        assert_eq!(
+            Socks6OperationReply::decode(&mut src),
+            Err(ProtocolError::UnknownReplyCode(0x42))
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_options_round_trip(options in arbitrary::socks_options()) {
+            let mut bytes = options.to_bytes();
+            prop_assert_eq!(Vec::<SocksOption>::decode(&mut bytes), Ok(Some(options)));
+            prop_assert!(bytes.is_empty());
+        }
+
+        #[test]
+        fn prop_socks6_request_round_trip(destination in arbitrary::address(), options in arbitrary::socks_options()) {
+            let request = Socks6Request::new(SOCKS_CMD_CONNECT, destination, 0, options, None);
+            let mut bytes = request.to_bytes();
+            let decoded = Socks6Request::decode(&mut bytes).unwrap().unwrap();
+
+            prop_assert!(bytes.is_empty());
+            prop_assert_eq!(decoded.destination, request.destination);
+            prop_assert_eq!(decoded.options, request.options);
+        }
+
+        #[test]
+        fn prop_socks6_operation_reply_round_trip(binding in arbitrary::address(), options in arbitrary::socks_options()) {
+            let reply = Socks6OperationReply::new(Socks6Reply::Success, binding, options);
+            let mut bytes = reply.to_bytes();
+            prop_assert_eq!(Socks6OperationReply::decode(&mut bytes), Ok(Some(reply)));
+        }
+    }
+}
@@ -1,24 +1,26 @@
 // General purpose SOCKS6 module.
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::io;
 
 use anyhow::{ensure, Result};
 use num_traits::FromPrimitive;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 
 // Module imports
 pub use chain::SocksChain;
+pub use codec::{Socks6AuthReply, Socks6OperationReply};
 pub use s6_client::Socks6Client;
 pub use s6_handler::Socks6Handler;
 
-use crate::{constants::*, ProxyAddress};
-use crate::addresses::{self, Address};
-use crate::socks6::options::{
-    AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption, UnrecognizedOption,
-};
+use crate::{constants::*, ProtocolError, ProxyAddress};
+use crate::addresses::Address;
+use crate::codec::{self as socks_codec, Encode};
+use crate::socks6::options::SocksOption;
 
 // Sub-modules
 pub mod chain;
+pub mod codec;
 pub mod options;
 mod s6_client;
 mod s6_handler;
@@ -44,7 +46,7 @@ pub enum Socks6Command {
 }
 
 /// Represents a SOCKS6 request.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Socks6Request {
     pub command: Socks6Command,
     pub destination: Address,
@@ -79,14 +81,28 @@ impl Socks6Request {
         let length = self.metadata.get(&999u16);
 
         let mut chain = if let Some(length) = length {
-            let length: usize = length.parse()?;
-            let index = self.metadata.get(&998u16).unwrap().parse()?;
+            let invalid = |reason: String| ProtocolError::InvalidChain(reason);
 
-            let links: Vec<ProxyAddress> = (1000..1000 + length)
-                .map(|i| i as u16)
-                .map(|i| self.metadata.get(&i).unwrap().clone())
-                .map(|x| x.try_into().unwrap())
-                .collect();
+            // Every link is carried by a separate option, so the length is bounded by the number of options.
+            let length: usize = length.parse().map_err(|_| invalid(format!("invalid length: {:?}", length)))?;
+            if length > SOCKS6_MAX_OPTIONS {
+                return Err(invalid(format!("length {} exceeds the limit of {}", length, SOCKS6_MAX_OPTIONS)).into());
+            }
+
+            let index = self.metadata.get(&998u16).ok_or_else(|| invalid(String::from("missing index")))?;
+            let index: usize = index.parse().map_err(|_| invalid(format!("invalid index: {:?}", index)))?;
+            if index >= length {
+                return Err(invalid(format!("index {} is out of bounds for length {}", index, length)).into());
+            }
+
+            let links: Vec<ProxyAddress> = (1000..1000 + length as u16)
+                .map(|i| {
+                    let link = self.metadata.get(&i).ok_or_else(|| invalid(format!("missing link {}", i - 1000)))?;
+                    link.clone()
+                        .try_into()
+                        .map_err(|e| invalid(format!("invalid link {}: {}", i - 1000, e)))
+                })
+                .collect::<Result<_, _>>()?;
 
             SocksChain::new(index, links)
         } else {
@@ -106,17 +122,7 @@ impl Socks6Request {
 
     /// Convert the request into a byte sequence for SOCKS6.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = vec![SOCKS_VER_6, SOCKS_CMD_CONNECT];
-        data.extend(self.destination.as_socks_bytes());
-        data.push(SOCKS_PADDING);
-
-        let options_bytes: Vec<_> = self.options.into_iter().flat_map(|o| o.as_socks_bytes()).collect();
-        let options_bytes_length = (options_bytes.len() as u16).to_be_bytes();
-
-        data.extend(options_bytes_length.iter());
-        data.extend(options_bytes.iter());
-
-        data
+        self.to_bytes().to_vec()
     }
 }
 
@@ -125,49 +131,14 @@ pub async fn read_request<S>(stream: &mut S) -> Result<Socks6Request>
 where
     S: AsyncRead + Unpin,
 {
-    // Read SOCKS version and command type.
-    let mut request = [0; 2];
-    stream.read_exact(&mut request).await?;
-
-    let [version, command] = request;
+    let request: Socks6Request = socks_codec::read_message(stream).await?;
 
     // Validate the request.
-    ensure!(version == SOCKS_VER_6, "Version mismatch!");
-    ensure!(command == SOCKS_CMD_CONNECT, "Only COMMAND is supported!");
-
-    let destination = addresses::read_address(stream).await?;
-
-    let mut padding = [0; 1];
-    stream.read_exact(&mut padding).await?;
-
-    let options = read_options(stream).await?;
-
-    let mut initial_data_length = 0;
-    let mut metadata = HashMap::new();
-    for option in &options {
-        match option {
-            SocksOption::AuthMethodAdvertisement(advertisement) => {
-                // Make note of initial data length for convenience.
-                initial_data_length = advertisement.initial_data_length;
-            }
-            SocksOption::Metadata(key_value) => {
-                metadata.insert(key_value.key, key_value.value.clone());
-            }
-            _ => {}
-        }
-
-        if let SocksOption::Metadata(key_value) = option {
-            metadata.insert(key_value.key, key_value.value.clone());
-        }
+    if request.command != Socks6Command::Connect {
+        return Err(ProtocolError::UnsupportedCommand(request.command as u8).into());
     }
 
-    Ok(Socks6Request::new(
-        command,
-        destination,
-        initial_data_length,
-        options,
-        Some(metadata),
-    ))
+    Ok(request)
 }
 
 /// Reads the SOCKS6 options from the stream.
@@ -175,38 +146,7 @@ pub async fn read_options<S>(stream: &mut S) -> Result<Vec<SocksOption>>
 where
     S: AsyncRead + Unpin,
 {
-    let mut options = Vec::new();
-
-    let mut options_length = [0; 2];
-    stream.read_exact(&mut options_length).await?;
-
-    let options_length = ((options_length[0] as u16) << 8) | options_length[1] as u16;
-    let mut options_bytes_read = 0;
-
-    while options_bytes_read < options_length {
-        let mut buffer = [0; 4];
-        stream.read_exact(&mut buffer).await?;
-
-        let [kind_0, kind_1, length_0, length_1] = buffer;
-        let kind = ((kind_0 as u16) << 8) | kind_1 as u16;
-        let length = ((length_0 as u16) << 8) | length_1 as u16;
-
-        // Read remaining bytes of this option.
-        let mut options_data = vec![0; (length - 4) as usize];
-        stream.read_exact(&mut options_data).await?;
-
-        let option = match kind {
-            0x0002 => AuthMethodAdvertisementOption::from_socks_bytes(options_data)?,
-            0x0003 => AuthMethodSelectionOption::from_socks_bytes(options_data)?,
-            0xFDE8 => MetadataOption::from_socks_bytes(options_data)?,
-            _ => UnrecognizedOption::new(kind, options_data.to_vec()).wrap(),
-        };
-
-        options.push(option);
-        options_bytes_read += length;
-    }
-
-    Ok(options)
+    socks_codec::read_message(stream).await
 }
 
 /// Reads the authentication response.
@@ -215,28 +155,13 @@ where
     S: AsyncRead + Unpin,
 {
     // Read auth reply
-    let mut reply = [0; 1];
-    stream.read_exact(&mut reply).await?;
-
-    let socks_version = reply[0];
-    ensure!(
-        socks_version == SOCKS_VER_6,
-        "Proxy uses a different SOCKS version: {}",
-        socks_version
-    );
-
-    let mut reply = [0; 1];
-    stream.read_exact(&mut reply).await?;
-
-    let status = reply[0];
+    let Socks6AuthReply { status, options } = socks_codec::read_message(stream).await?;
     ensure!(
         status == SOCKS_AUTH_SUCCESS,
         "Authentication with proxy failed: {}",
         status
     );
 
-    let options = read_options(stream).await?;
-
     Ok(options)
 }
 
@@ -246,10 +171,8 @@ where
     S: AsyncWrite + Unpin,
 {
     // Write auth reply
-    let auth_reply = [SOCKS_VER_6, SOCKS_AUTH_SUCCESS, 0x00u8, 0x00u8];
-    stream.write(&auth_reply).await?;
-
-    Ok(())
+    let auth_reply = Socks6AuthReply::new(SOCKS_AUTH_SUCCESS, vec![]);
+    socks_codec::write_message(stream, &auth_reply).await
 }
 
 /// Writes the initial data for the SOCKS6 request.
@@ -280,6 +203,30 @@ pub enum Socks6Reply {
     ConnectionAttemptTimeOut = 0x09,
 }
 
+impl From<&ProtocolError> for Socks6Reply {
+    /// Selects the failure reply that matches a protocol error.
+    fn from(error: &ProtocolError) -> Self {
+        match error {
+            ProtocolError::UnsupportedCommand(_) => Socks6Reply::CommandNotSupported,
+            ProtocolError::UnsupportedAddressType(_) => Socks6Reply::AddressTypeNotSupported,
+            _ => Socks6Reply::GeneralFailure,
+        }
+    }
+}
+
+impl From<&io::Error> for Socks6Reply {
+    /// Selects the failure reply that matches an error while connecting to the destination.
+    fn from(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => Socks6Reply::ConnectionRefused,
+            io::ErrorKind::NetworkUnreachable => Socks6Reply::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => Socks6Reply::HostUnreachable,
+            io::ErrorKind::TimedOut => Socks6Reply::TTLExpired,
+            _ => Socks6Reply::GeneralFailure,
+        }
+    }
+}
+
 /// Writes a SOCKS6 reply to the stream.
 pub async fn write_reply<S>(
     stream: &mut S,
@@ -288,24 +235,8 @@ pub async fn write_reply<S>(
 where
     S: AsyncWrite + Unpin,
 {
-    let reply = [
-        SOCKS_VER_6,
-        reply as u8,
-        SOCKS_PADDING,
-        SOCKS_ATYP_IPV4,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-    ];
-
-    stream.write(&reply).await?;
-
-    Ok(())
+    let reply = Socks6OperationReply::new(reply, Address::unspecified(), vec![]);
+    socks_codec::write_message(stream, &reply).await
 }
 
 /// Reads a SOCKS6 reply from the stream.
@@ -313,25 +244,20 @@ pub async fn read_reply<S>(stream: &mut S) -> Result<(Address, Vec<SocksOption>)
 where
     S: AsyncRead + Unpin,
 {
-    let mut operation_reply = [0; 3];
-    stream.read_exact(&mut operation_reply).await?;
-
-    let reply_code = operation_reply[1];
+    let Socks6OperationReply { reply, binding, options } = socks_codec::read_message(stream).await?;
     ensure!(
-        reply_code == SOCKS_REP_SUCCEEDED,
+        reply == Socks6Reply::Success,
         "CONNECT operation failed: {:?}",
-        Socks6Reply::from_u8(reply_code)
+        reply
     );
 
-    let binding = addresses::read_address(stream).await?;
-    let options = read_options(stream).await?;
-
     Ok((binding, options))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::socks6::options::MetadataOption;
 
     // Test creation of a new Socks6Request.
     #[test]
@@ -369,4 +295,136 @@ mod tests {
         let expected_result: Vec<u8> = vec![6, 1, 1, 192, 168, 1, 1, 0, 80, 0, 0, 0];
         assert_eq!(result, expected_result);
     }
+
+    // Creates a request that carries the given chain metadata.
+    fn request_with_metadata(metadata: &[(u16, &str)]) -> Socks6Request {
+        let metadata = metadata.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        Socks6Request::new(SOCKS_CMD_CONNECT, Address::new("10.0.0.1", 80), 0, vec![], Some(metadata))
+    }
+
+    // Test that a chain is reconstructed from valid metadata.
+    #[test]
+    fn test_chain_from_metadata() {
+        let request = request_with_metadata(&[
+            (998, "1"),
+            (999, "2"),
+            (1000, "socks6://root:1080"),
+            (1001, "socks6://10.0.0.2:1080"),
+        ]);
+
+        let chain = request.chain(&[]).unwrap().unwrap();
+        assert_eq!(chain.index, 1);
+        assert_eq!(chain.links[1], ProxyAddress::new(6, String::from("10.0.0.2"), 1080, None));
+    }
+
+    // Regression: a missing index used to panic on `unwrap`.
+    #[test]
+    fn test_chain_missing_index() {
+        let request = request_with_metadata(&[(999, "1"), (1000, "socks6://root:1080")]);
+        let error = request.chain(&[]).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ProtocolError>(), Some(ProtocolError::InvalidChain(_))));
+    }
+
+    // Regression: a missing or malformed link used to panic on `unwrap`.
+    #[test]
+    fn test_chain_invalid_link() {
+        let request = request_with_metadata(&[(998, "0"), (999, "2"), (1000, "socks6://root:1080")]);
+        assert!(request.chain(&[]).is_err());
+
+        let request = request_with_metadata(&[(998, "0"), (999, "1"), (1000, "not a proxy")]);
+        assert!(request.chain(&[]).is_err());
+    }
+
+    // Regression: an index beyond the links used to panic when splicing in static links.
+    #[test]
+    fn test_chain_index_out_of_bounds() {
+        let request = request_with_metadata(&[(998, "5"), (999, "1"), (1000, "socks6://root:1080")]);
+        let detour = [ProxyAddress::new(6, String::from("127.0.0.1"), 1080, None)];
+        assert!(request.chain(&detour).is_err());
+    }
+
+    // Regression: a huge length used to overflow the metadata key range.
+    #[test]
+    fn test_chain_length_too_large() {
+        let request = request_with_metadata(&[(998, "0"), (999, "18446744073709551615")]);
+        assert!(request.chain(&[]).is_err());
+    }
+
+    // Test that an option length below the header size is rejected instead of underflowing.
+    #[tokio::test]
+    async fn test_read_options_length_too_short() {
+        let mut bytes: &[u8] = &[0x00, 0x04, 0x00, 0x02, 0x00, 0x02];
+        let error = read_options(&mut bytes).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::InvalidOptionLength(2))
+        );
+    }
+
+    // Test that an option cannot extend beyond the options section.
+    #[tokio::test]
+    async fn test_read_options_length_exceeds_section() {
+        let mut bytes: &[u8] = &[0x00, 0x04, 0x00, 0x02, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00];
+        let error = read_options(&mut bytes).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::InvalidOptionLength(8))
+        );
+    }
+
+    // Test that the options section length is limited.
+    #[tokio::test]
+    async fn test_read_options_section_too_long() {
+        let mut bytes: &[u8] = &[0xFF, 0xFF];
+        let error = read_options(&mut bytes).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::OptionsTooLong(0xFFFF, SOCKS6_MAX_OPTIONS_LENGTH))
+        );
+    }
+
+    // Test that the number of options is limited.
+    #[tokio::test]
+    async fn test_read_options_too_many() {
+        let count = SOCKS6_MAX_OPTIONS + 1;
+        let mut bytes = ((count * 4) as u16).to_be_bytes().to_vec();
+        for _ in 0..count {
+            bytes.extend([0x12, 0x34, 0x00, 0x04]);
+        }
+
+        let error = read_options(&mut &bytes[..]).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::TooManyOptions(SOCKS6_MAX_OPTIONS))
+        );
+    }
+
+    // Test that well-formed options are still parsed.
+    #[tokio::test]
+    async fn test_read_options_metadata() {
+        let option = MetadataOption::new(998, String::from("1")).wrap().as_socks_bytes();
+        let mut bytes = (option.len() as u16).to_be_bytes().to_vec();
+        bytes.extend(option);
+
+        let options = read_options(&mut &bytes[..]).await.unwrap();
+        assert_eq!(options.len(), 1);
+        match &options[0] {
+            SocksOption::Metadata(option) => {
+                assert_eq!(option.key, 998);
+                assert_eq!(option.value, "1");
+            }
+            _ => panic!("Expected Metadata variant"),
+        }
+    }
+
+    // Test that a request with an unsupported command is rejected with the matching error.
+    #[tokio::test]
+    async fn test_read_request_unsupported_command() {
+        let mut bytes: &[u8] = &[SOCKS_VER_6, SOCKS_CMD_BIND, SOCKS_ATYP_IPV4, 127, 0, 0, 1, 0, 80, 0, 0, 0];
+        let error = read_request(&mut bytes).await.unwrap_err();
+        let error = error.downcast_ref::<ProtocolError>().unwrap();
+
+        assert_eq!(error, &ProtocolError::UnsupportedCommand(SOCKS_CMD_BIND));
+        assert_eq!(Socks6Reply::from(error), Socks6Reply::CommandNotSupported);
+    }
 }
\ No newline at end of file
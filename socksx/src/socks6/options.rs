@@ -1,6 +1,8 @@
-use anyhow::Result;
 use num_traits::FromPrimitive;
 
+use crate::constants::*;
+use crate::ProtocolError;
+
 /// Represents SOCKS authentication methods.
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
@@ -12,7 +14,7 @@ pub enum AuthMethod {
 }
 
 /// Enumerates the types of SOCKS options.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SocksOption {
     AuthMethodAdvertisement(AuthMethodAdvertisementOption),
     AuthMethodSelection(AuthMethodSelectionOption),
@@ -35,7 +37,7 @@ impl SocksOption {
 }
 
 /// Represents the authentication methods supported by the server.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AuthMethodAdvertisementOption {
     pub initial_data_length: u16,
     pub methods: Vec<AuthMethod>,
@@ -59,19 +61,19 @@ impl AuthMethodAdvertisementOption {
     }
 
     /// Deserializes the option from bytes.
-    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption> {
-        ensure!(bytes.len() >= 2, "Expected at least two bytes, got: {}", bytes.len());
+    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption, ProtocolError> {
+        if bytes.len() < 2 {
+            let reason = format!("expected at least two bytes, got: {}", bytes.len());
+            return Err(ProtocolError::MalformedOption(SOCKS_OKIND_AUTH_METH_ADV, reason));
+        }
         let initial_data_length = ((bytes[0] as u16) << 8) | bytes[1] as u16;
 
+        // Ignore "No Authentication Required" (implied), padding bytes, and methods we don't know.
         let methods = bytes
             .iter()
             .skip(2)
-            .filter(|m| {
-                let m = **m;
-                // Ingore "No Authentication Required" (implied) and padding bytes.
-                m > 0 && m < 3
-            })
-            .map(|m| AuthMethod::from_u8(*m).unwrap())
+            .filter_map(|m| AuthMethod::from_u8(*m))
+            .filter(|m| !matches!(m, AuthMethod::NoAuthentication | AuthMethod::NoAcceptableMethods))
             .collect();
 
         Ok(Self::new(initial_data_length, methods).wrap())
@@ -82,12 +84,12 @@ impl AuthMethodAdvertisementOption {
         let mut data = self.initial_data_length.to_be_bytes().to_vec();
         data.extend(self.methods.iter().cloned().map(|m| m as u8));
 
-        combine_and_pad(0x02, data)
+        combine_and_pad(SOCKS_OKIND_AUTH_METH_ADV, data)
     }
 }
 
 /// Represents the authentication methods selected by the client.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AuthMethodSelectionOption {
     pub method: AuthMethod,
 }
@@ -104,14 +106,18 @@ impl AuthMethodSelectionOption {
     }
 
     /// Deserializes the option from bytes.
-    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption> {
-        ensure!(bytes.len() == 4, "Expected exactly four bytes, got: {}", bytes.len());
+    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption, ProtocolError> {
+        if bytes.len() != 4 {
+            let reason = format!("expected exactly four bytes, got: {}", bytes.len());
+            return Err(ProtocolError::MalformedOption(SOCKS_OKIND_AUTH_METH_SEL, reason));
+        }
 
         let method = bytes[0];
         if let Some(method) = AuthMethod::from_u8(method) {
             Ok(Self::new(method).wrap())
         } else {
-            bail!("Not a valid authentication method selection: {}", method)
+            let reason = format!("not a valid authentication method selection: {}", method);
+            Err(ProtocolError::MalformedOption(SOCKS_OKIND_AUTH_METH_SEL, reason))
         }
     }
 
@@ -119,12 +125,12 @@ impl AuthMethodSelectionOption {
     pub fn into_socks_bytes(self) -> Vec<u8> {
         let data = vec![self.method as u8];
 
-        combine_and_pad(0x03, data)
+        combine_and_pad(SOCKS_OKIND_AUTH_METH_SEL, data)
     }
 }
 
 /// Represents a metadata option.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct MetadataOption {
     pub key: u16,
     pub value: String,
@@ -145,16 +151,27 @@ impl MetadataOption {
     }
 
     /// Deserializes the option from bytes.
-    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption> {
-        ensure!(bytes.len() >= 4, "Expected at least four bytes, got: {}", bytes.len());
+    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption, ProtocolError> {
+        if bytes.len() < 4 {
+            let reason = format!("expected at least four bytes, got: {}", bytes.len());
+            return Err(ProtocolError::MalformedOption(SOCKS_OKIND_METADATA, reason));
+        }
         let key = ((bytes[0] as u16) << 8) | bytes[1] as u16;
         let length = ((bytes[2] as u16) << 8) | bytes[3] as u16;
 
-        let value = bytes[4..(length as usize) + 4].to_vec();
+        let value = match bytes.get(4..(length as usize) + 4) {
+            Some(value) => value.to_vec(),
+            None => {
+                let reason = format!("value length {} exceeds the {} available bytes", length, bytes.len() - 4);
+                return Err(ProtocolError::MalformedOption(SOCKS_OKIND_METADATA, reason));
+            }
+        };
+
         if let Ok(value) = String::from_utf8(value) {
             Ok(Self::new(key, value).wrap())
         } else {
-            bail!("Not a valid metadata UTF-8 string: {:?}", bytes[2..].to_vec())
+            let reason = format!("not a valid UTF-8 string: {:?}", &bytes[4..(length as usize) + 4]);
+            Err(ProtocolError::MalformedOption(SOCKS_OKIND_METADATA, reason))
         }
     }
 
@@ -164,13 +181,12 @@ impl MetadataOption {
         data.extend((self.value.len() as u16).to_be_bytes().iter());
         data.extend(self.value.as_bytes().iter());
 
-        // kind: 65000
-        combine_and_pad(0xFDE8, data)
+        combine_and_pad(SOCKS_OKIND_METADATA, data)
     }
 }
 
 /// Represents an unrecognized option.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct UnrecognizedOption {
     kind: u16,
     data: Vec<u8>,
@@ -213,7 +229,7 @@ fn combine_and_pad(
     // The total length of the option is the combined number of bytes of
     // the kind, length, and data fields, plus the number of padding bytes.
     let option_length = data.len() + 2 + 2;
-    let padding_bytes = vec![0; 4 - (option_length % 4)];
+    let padding_bytes = vec![0; (4 - (option_length % 4)) % 4];
     let total_length: u16 = (option_length + padding_bytes.len()) as u16;
 
     let mut bytes = vec![];
@@ -227,7 +243,26 @@ fn combine_and_pad(
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
+    use crate::arbitrary;
+
+    // Splits an encoded option into its header fields and deserializes its data by kind.
+    fn parse(bytes: &[u8]) -> (u16, u16, SocksOption) {
+        let kind = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let length = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let data = bytes[4..].to_vec();
+
+        let option = match kind {
+            SOCKS_OKIND_AUTH_METH_ADV => AuthMethodAdvertisementOption::from_socks_bytes(data).unwrap(),
+            SOCKS_OKIND_AUTH_METH_SEL => AuthMethodSelectionOption::from_socks_bytes(data).unwrap(),
+            SOCKS_OKIND_METADATA => MetadataOption::from_socks_bytes(data).unwrap(),
+            _ => UnrecognizedOption::new(kind, data).wrap(),
+        };
+
+        (kind, length, option)
+    }
 
     // Test the AuthMethod enum conversion from primitive types
     #[test]
@@ -249,11 +284,10 @@ mod tests {
     fn test_auth_method_advertisement_option_wrap() {
         let option = AuthMethodAdvertisementOption::new(0, vec![]);
         let wrapped = option.wrap();
-        if let SocksOption::AuthMethodAdvertisement(_) = wrapped {
-            assert!(true);
-        } else {
-            assert!(false, "Expected AuthMethodAdvertisement variant");
-        }
+        assert!(
+            matches!(wrapped, SocksOption::AuthMethodAdvertisement(_)),
+            "Expected AuthMethodAdvertisement variant"
+        );
     }
 
     // Test the from_socks_bytes function for AuthMethodAdvertisementOption
@@ -264,4 +298,73 @@ mod tests {
         // Verify the result according to your expectations
         assert!(result.is_ok());
     }
-}
\ No newline at end of file
+
+    // Test that unknown authentication methods are skipped instead of causing a panic
+    #[test]
+    fn test_from_socks_bytes_auth_method_advertisement_unknown_method() {
+        let bytes = vec![0x00, 0x00, 0x02, 0x7F, 0xFE, 0x00];
+        let option = AuthMethodAdvertisementOption::from_socks_bytes(bytes).unwrap();
+        match option {
+            SocksOption::AuthMethodAdvertisement(option) => {
+                assert_eq!(option.methods, vec![AuthMethod::UsernamePassword])
+            }
+            _ => panic!("Expected AuthMethodAdvertisement variant"),
+        }
+    }
+
+    // Test that a metadata value longer than the option data is rejected
+    #[test]
+    fn test_from_socks_bytes_metadata_length_out_of_bounds() {
+        let bytes = vec![0x03, 0xE8, 0x00, 0xFF, b'a', b'b', 0x00, 0x00];
+        let result = MetadataOption::from_socks_bytes(bytes);
+
+        assert!(matches!(
+            result,
+            Err(ProtocolError::MalformedOption(SOCKS_OKIND_METADATA, _))
+        ));
+    }
+
+    // Regression (fuzzing): options that were already aligned used to receive four extra padding bytes.
+    #[test]
+    fn test_combine_and_pad_aligned() {
+        let bytes = UnrecognizedOption::new(0x1234, b"abcdefgh".to_vec()).into_socks_bytes();
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(&bytes[..4], &[0x12, 0x34, 0x00, 0x0C]);
+
+        let bytes = MetadataOption::new(1, String::from("a")).into_socks_bytes();
+        assert_eq!(bytes, vec![0xFD, 0xE8, 0x00, 0x0C, 0x00, 0x01, 0x00, 0x01, b'a', 0x00, 0x00, 0x00]);
+    }
+
+    // Test that a metadata option shorter than its header is rejected
+    #[test]
+    fn test_from_socks_bytes_metadata_too_short() {
+        let result = MetadataOption::from_socks_bytes(vec![0x03]);
+        assert!(result.is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_option_round_trip(option in arbitrary::socks_option()) {
+            let bytes = option.as_socks_bytes();
+            let (_, length, decoded) = parse(&bytes);
+
+            prop_assert_eq!(length as usize, bytes.len());
+            prop_assert_eq!(bytes.len() % 4, 0);
+            prop_assert_eq!(decoded, option);
+        }
+
+        #[test]
+        fn prop_combine_and_pad_minimal(kind in any::<u16>(), data in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let bytes = combine_and_pad(kind, data.clone());
+            // Only the header is decoded, as the data is not valid for every kind.
+            let decoded_kind = u16::from_be_bytes([bytes[0], bytes[1]]);
+            let length = u16::from_be_bytes([bytes[2], bytes[3]]);
+
+            prop_assert_eq!(decoded_kind, kind);
+            prop_assert_eq!(length as usize, bytes.len());
+            // At most three padding bytes are needed to reach a multiple of four.
+            prop_assert!(bytes.len() - (data.len() + 4) < 4);
+            prop_assert_eq!(&bytes[4..4 + data.len()], &data[..]);
+        }
+    }
+}
@@ -39,6 +39,11 @@ impl Socks6Client {
         })
     }
 
+    /// Returns the resolved address of the SOCKS6 proxy.
+    pub fn proxy_addr(&self) -> SocketAddr {
+        self.proxy_addr
+    }
+
     /// Connects to a given destination through the SOCKS6 proxy.
     ///
     /// # Parameters
@@ -87,14 +92,14 @@ impl Socks6Client {
         A: TryInto<Address, Error = anyhow::Error>,
     {
         if let Some(Credentials { username, password }) = &self.credentials {
-            ensure!(username.len() > 255, "Username MUST NOT be larger than 255 bytes.");
-            ensure!(password.len() > 255, "Password MUST NOT be larger than 255 bytes.");
+            ensure!(username.len() <= 255, "Username MUST NOT be larger than 255 bytes.");
+            ensure!(password.len() <= 255, "Password MUST NOT be larger than 255 bytes.");
         }
 
         // Prepare initial data.
         let initial_data = initial_data.unwrap_or_default();
         ensure!(
-            initial_data.len() <= 2 ^ 14,
+            initial_data.len() <= 1 << 14,
             "Initial data MUST NOT be larger than 16384 bytes."
         );
         let initial_data_length = initial_data.len() as u16;
@@ -118,9 +123,10 @@ impl Socks6Client {
             None,
         );
 
-        // Send SOCKS request information.
+        // Send SOCKS request information, directly followed by the initial data.
         let request_bytes = request.into_socks_bytes();
-        stream.write(&request_bytes).await?;
+        stream.write_all(&request_bytes).await?;
+        stream.write_all(&initial_data).await?;
 
         // Wait for authentication and operation reply.
         let _ = socks6::read_no_authentication(stream).await?;
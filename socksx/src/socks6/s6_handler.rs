@@ -1,16 +1,45 @@
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+
 use anyhow::Result;
 use async_trait::async_trait;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tracing::field::display;
+use tracing::{Instrument, Span};
 
-use crate::{Socks6Client, SocksHandler};
-use crate::addresses::ProxyAddress;
-use crate::socks6::{self, Socks6Reply};
+use crate::{ProtocolError, Socks6Client, SocksHandler};
+use crate::access_log::{AccessLogger, AccessRecord, CloseReason};
+use crate::addresses::{Address, ProxyAddress};
+use crate::constants::SOCKS_VER_6;
+use crate::functions::{Flow, Pipeline};
+use crate::errors::SessionKilled;
+use crate::metrics::{ConnectTarget, FailureReason, Metrics};
+use crate::pool::UpstreamPool;
+use crate::quotas::{QuotaGuard, Quotas};
+use crate::rate_limit::RateLimiter;
+use crate::relay::{Relay, RelayEnd, RelayStats};
+use crate::sessions::{self, Session, SessionRegistry};
+use crate::sockets::SocketOptions;
+use crate::spans;
+use crate::socks6::{self, Socks6Reply, Socks6Request, SocksChain};
+use crate::socks6::options::SocksOption;
 
 /// Implements a SOCKS6 handler.
 #[derive(Clone)]
 pub struct Socks6Handler {
     static_links: Vec<ProxyAddress>,
+    functions: Pipeline,
+    metrics: Metrics,
+    access_logger: Option<Arc<dyn AccessLogger>>,
+    sessions: SessionRegistry,
+    rate_limiter: RateLimiter,
+    quotas: Quotas,
+    relay: Relay,
+    pool: Option<UpstreamPool>,
+    socket_options: SocketOptions,
 }
 
 impl Default for Socks6Handler {
@@ -29,7 +58,415 @@ impl Socks6Handler {
     /// # Returns
     /// A new `Socks6Handler`.
     pub fn new(static_links: Vec<ProxyAddress>) -> Self {
-        Socks6Handler { static_links }
+        Socks6Handler {
+            static_links,
+            functions: Pipeline::default(),
+            metrics: Metrics::default(),
+            access_logger: None,
+            sessions: SessionRegistry::default(),
+            rate_limiter: RateLimiter::default(),
+            quotas: Quotas::default(),
+            relay: Relay::default(),
+            pool: None,
+            socket_options: SocketOptions::default(),
+        }
+    }
+
+    /// Runs the given functions on the data of every connection, between the source and the destination.
+    pub fn with_functions(
+        mut self,
+        functions: Pipeline,
+    ) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    /// Records the connections, handshakes, replies and upstream proxies of this handler in the given metrics.
+    pub fn with_metrics(
+        mut self,
+        metrics: Metrics,
+    ) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Hands the record of every session to the given logger, once the session ends.
+    pub fn with_access_logger<L>(
+        mut self,
+        logger: L,
+    ) -> Self
+    where
+        L: AccessLogger + 'static,
+    {
+        self.access_logger = Some(Arc::new(logger));
+        self
+    }
+
+    /// Registers every session of this handler in the given registry, e.g., to list and kill them from the admin API.
+    pub fn with_sessions(
+        mut self,
+        sessions: SessionRegistry,
+    ) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Limits the bandwidth of every connection of this handler with the given limiter, whose buckets are shared with
+    /// the other handlers it was given to.
+    pub fn with_rate_limiter(
+        mut self,
+        rate_limiter: RateLimiter,
+    ) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Admits the requests of this handler within the given quotas, whose usage is shared with the other handlers it
+    /// was given to.
+    pub fn with_quotas(
+        mut self,
+        quotas: Quotas,
+    ) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Relays the data of every connection of this handler as configured by `relay`, e.g., with an idle timeout.
+    ///
+    /// Sessions that are killed cancel their relay, regardless of a cancellation token of `relay`.
+    pub fn with_relay(
+        mut self,
+        relay: Relay,
+    ) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Sends requests to the next hop of a chain over connections from `pool`, that are opened ahead (see
+    /// `UpstreamPool` for what that saves).
+    pub fn with_pool(
+        mut self,
+        pool: UpstreamPool,
+    ) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Applies `options` to the connections of this handler to destinations and next hops, once connected.
+    ///
+    /// Accepted connections are tuned by the listener instead, see `sockets::bind`.
+    pub fn with_socket_options(
+        mut self,
+        options: SocketOptions,
+    ) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Sends a reply to the source, counts it, and records it as the last reply of the session.
+    async fn reply(
+        &self,
+        source: &mut TcpStream,
+        reply: Socks6Reply,
+        record: &mut AccessRecord,
+    ) -> Result<()> {
+        self.metrics.reply(SOCKS_VER_6, &reply);
+        record.reply = Some(format!("{:?}", reply));
+
+        socks6::write_reply(source, reply).await
+    }
+
+    /// Completes the record of a session that started at `started`, and hands it to the access logger.
+    fn log_access(
+        &self,
+        mut record: AccessRecord,
+        started: Instant,
+    ) {
+        if let Some(logger) = &self.access_logger {
+            record.duration_ms = started.elapsed().as_millis() as u64;
+            logger.log(&record);
+        }
+    }
+
+    /// Connects to the destination of a request, through the next link of the chain if there is one.
+    ///
+    /// # Parameters
+    /// - `request`: The request received from the source.
+    /// - `chain`: The chain the request is part of, if any.
+    ///
+    /// # Returns
+    /// A `Result` containing the `TcpStream` to the destination or next link, otherwise an error.
+    async fn connect(
+        &self,
+        request: &Socks6Request,
+        chain: Option<SocksChain>,
+    ) -> Result<TcpStream> {
+        let destination = request.destination.to_string();
+        let started = Instant::now();
+
+        if let Some(mut chain) = chain {
+            if let Some(next) = chain.next_link() {
+                let next = next.clone();
+
+                // Label upstream proxies by their address only, to keep their credentials out of the metrics.
+                let proxy_addr = Address::try_from(&next)?.to_string();
+                let outgoing = async {
+                    let client = Socks6Client::new(proxy_addr.clone(), next.credentials).await?;
+                    let options = chain.as_options();
+                    match &self.pool {
+                        Some(pool) => self.connect_pooled(pool, &client, &proxy_addr, destination, options).await,
+                        None => client.connect(destination, None, Some(options)).await,
+                    }
+                }
+                .await;
+
+                self.metrics.upstream_result(&proxy_addr, outgoing.is_ok());
+                let (outgoing, _) = outgoing?;
+                self.metrics.connected(ConnectTarget::Upstream, started.elapsed());
+
+                // The next hop sees this connection coming from `local`.
+                info!(local = %outgoing.local_addr()?, next = %proxy_addr, "Connected to the next hop.");
+
+                return Ok(outgoing);
+            }
+        }
+
+        let outgoing = TcpStream::connect(destination).await?;
+        self.metrics.connected(ConnectTarget::Direct, started.elapsed());
+
+        Ok(outgoing)
+    }
+
+    /// Sends a request to the next hop over a connection from `pool`.
+    ///
+    /// If the pooled connection turns out to be closed by the next hop, the request is sent again over a new one.
+    async fn connect_pooled(
+        &self,
+        pool: &UpstreamPool,
+        client: &Socks6Client,
+        proxy_addr: &str,
+        destination: String,
+        options: Vec<SocksOption>,
+    ) -> Result<(TcpStream, Address)> {
+        let (mut outgoing, pooled) = pool.get(client.proxy_addr()).await?;
+        self.metrics.pool_checkout(proxy_addr, pooled);
+
+        match client.handshake(destination.clone(), None, Some(options.clone()), &mut outgoing).await {
+            Ok(binding) => Ok((outgoing, binding)),
+            Err(error) if pooled && error.is::<io::Error>() => {
+                debug!(next = %proxy_addr, "Pooled connection failed, retrying: {}", error);
+
+                self.metrics.pool_checkout(proxy_addr, false);
+                client.connect(destination, None, Some(options)).await
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Reads the request, and connects to its destination (or the next link of its chain).
+    ///
+    /// # Parameters
+    /// - `source`: A mutable reference to the source TCP stream.
+    ///
+    /// # Returns
+    /// A `Result` containing the destination `TcpStream`, and the flow that connects the two, otherwise an error.
+    async fn handshake(
+        &self,
+        source: &mut TcpStream,
+        record: &mut AccessRecord,
+    ) -> Result<(TcpStream, Flow, QuotaGuard)> {
+        // Receive SOCKS request, and allow unauthenticated access.
+        let request = match socks6::read_request(source).await {
+            Ok(request) => request,
+            Err(error) => {
+                // Malformed requests are answered with a failure reply before closing.
+                if let Some(protocol_error) = error.downcast_ref::<ProtocolError>() {
+                    self.reply(source, protocol_error.into(), record).await?;
+                }
+
+                return Err(error);
+            }
+        };
+        socks6::write_no_authentication(source).await?;
+        Span::current().record("destination", display(&request.destination));
+        record.command = Some(format!("{:?}", request.command));
+        record.destination = Some(request.destination.to_string());
+
+        let chain = match request.chain(&self.static_links) {
+            Ok(chain) => chain,
+            Err(error) => {
+                self.reply(source, Socks6Reply::GeneralFailure, record).await?;
+                return Err(error);
+            }
+        };
+
+        let chain_index = chain.as_ref().map(|chain| chain.index);
+        if let Some(chain) = &chain {
+            Span::current().record("chain_index", chain.index);
+
+            // Record the links by their address only, to keep their credentials out of the access log.
+            record.chain = chain.links.iter().map(|link| format!("{}:{}", link.host, link.port)).collect();
+            record.chain_index = chain_index;
+        }
+
+        // Wait for the quotas of the client and user, and refuse the request if they are still exceeded after that.
+        let client = source.peer_addr().ok().map(|peer| peer.ip());
+        let quota = match self.quotas.admit(client, record.user.as_deref()).await {
+            Ok((quota, waited)) => {
+                if !waited.is_zero() {
+                    self.metrics.quota_waited(waited);
+                    debug!(waited_ms = waited.as_millis() as u64, "Waited for the quotas.");
+                }
+
+                quota
+            }
+            Err(exceeded) => {
+                self.metrics.quota_exceeded(&exceeded);
+                self.reply(source, Socks6Reply::ConnectionNotAllowed, record).await?;
+
+                return Err(exceeded.into());
+            }
+        };
+
+        let mut destination = match self.connect(&request, chain).await {
+            Ok(destination) => destination,
+            Err(error) => {
+                // Let the source know why the destination (or next hop) could not be reached.
+                let reply = match error.downcast_ref::<io::Error>() {
+                    Some(error) => error.into(),
+                    None => Socks6Reply::GeneralFailure,
+                };
+                self.reply(source, reply, record).await?;
+
+                return Err(error);
+            }
+        };
+        self.socket_options.apply(&destination)?;
+
+        // Send initial data
+        if request.initial_data_length > 0 {
+            let mut initial_data = vec![0; request.initial_data_length as usize];
+            source.read_exact(&mut initial_data).await?;
+            destination.write_all(&initial_data).await?;
+        }
+
+        // Notify source that the connection has been set up.
+        self.reply(source, Socks6Reply::Success, record).await?;
+        source.flush().await?;
+
+        let flow = Flow::new(source.peer_addr().ok(), destination.peer_addr().ok())
+            .with_target(request.destination)
+            .with_chain_index(chain_index);
+        record.resolved = flow.destination;
+
+        Ok((destination, flow, quota))
+    }
+
+    /// Handles a connection, from the handshake until the relay ends, in the span of the connection.
+    async fn serve(
+        &self,
+        source: &mut TcpStream,
+        session: &Session,
+    ) -> Result<()> {
+        let _connection = self.metrics.connection_opened();
+        debug!("Accepted connection.");
+
+        let started = Instant::now();
+        let mut record = AccessRecord::new("socks6", source.peer_addr().ok());
+
+        let handshake = tokio::select! {
+            handshake = self.handshake(source, &mut record) => handshake,
+            _ = session.killed() => Err(SessionKilled.into()),
+        };
+
+        let (mut destination, flow, _quota) = match handshake {
+            Ok(handshake) => handshake,
+            Err(error) => {
+                let reason = FailureReason::of(&error);
+                self.metrics.handshake_failed(reason);
+                warn!(reason = reason.as_str(), "Handshake failed: {:#}", error);
+
+                record.close_reason = if error.is::<SessionKilled>() {
+                    CloseReason::Killed
+                } else {
+                    reason.into()
+                };
+                self.log_access(record, started);
+
+                return Err(error);
+            }
+        };
+        self.metrics.handshake_completed(started.elapsed());
+        session.update(&record);
+
+        let throttle = self.rate_limiter.throttle(&flow, record.user.as_deref());
+        let relay = self.relay.clone().with_cancellation(session.cancellation());
+        let relayed = async {
+            // Without functions or rate limits, the data does not need to pass through userspace.
+            #[cfg(target_os = "linux")]
+            if self.functions.is_empty() && throttle.is_empty() {
+                let progress = |direction, bytes| session.count(direction, bytes);
+                return relay.splice(source, &mut destination, progress).await;
+            }
+
+            let mut source = throttle.apply(session.track(source));
+            if self.functions.is_empty() {
+                // Start bidirectional copy, after this the connection closes.
+                relay.run(&mut source, &mut destination).await
+            } else {
+                let mut source = self.functions.apply(&mut source, &flow)?;
+
+                relay.run(&mut source, &mut destination).await
+            }
+        };
+
+        let stats = match relayed.await {
+            Ok(stats) => stats,
+            Err(error) => {
+                let RelayStats {
+                    upstream,
+                    downstream,
+                    ..
+                } = error.stats;
+                info!(upstream, downstream, "Relay failed: {}", error);
+
+                record.upstream = upstream;
+                record.downstream = downstream;
+                record.close_reason = CloseReason::Relay;
+                self.log_access(record, started);
+
+                return Err(error.into());
+            }
+        };
+        let RelayStats {
+            upstream,
+            downstream,
+            end,
+            ..
+        } = stats;
+
+        record.upstream = upstream;
+        record.downstream = downstream;
+
+        let duration_ms = started.elapsed().as_millis() as u64;
+        match end {
+            RelayEnd::Completed | RelayEnd::Failed => info!(upstream, downstream, duration_ms, "Connection closed."),
+            RelayEnd::Idle => {
+                info!(upstream, downstream, duration_ms, "Connection closed, as it was idle.");
+                record.close_reason = CloseReason::Idle;
+            }
+            RelayEnd::Cancelled => {
+                info!(upstream, downstream, duration_ms, "Session killed.");
+
+                record.close_reason = CloseReason::Killed;
+                self.log_access(record, started);
+
+                return Err(SessionKilled.into());
+            }
+        }
+        self.log_access(record, started);
+
+        Ok(())
     }
 }
 
@@ -46,12 +483,11 @@ impl SocksHandler for Socks6Handler {
         &self,
         source: &mut TcpStream,
     ) -> Result<()> {
-        let mut destination = self.setup(source).await?;
-
-        // Start bidirectional copy, after this the connection closes.
-        tokio::io::copy_bidirectional(source, &mut destination).await?;
+        let session = self.sessions.register("socks6", source.peer_addr().ok());
+        let session = session.with_relayed_bytes(self.metrics.relayed_bytes());
+        let span = spans::connection(session.id(), SOCKS_VER_6, source.peer_addr().ok());
 
-        Ok(())
+        self.serve(source, &session).instrument(span).await
     }
 
     /// Refuses a request from the source.
@@ -65,10 +501,24 @@ impl SocksHandler for Socks6Handler {
         &self,
         source: &mut TcpStream,
     ) -> Result<()> {
-        // Notify source that the connection is refused.
-        socks6::write_reply(source, Socks6Reply::ConnectionRefused).await?;
+        let span = spans::connection(sessions::next_id(), SOCKS_VER_6, source.peer_addr().ok());
 
-        Ok(())
+        async {
+            self.metrics.connection_refused();
+            info!("Refused connection, as the connection limit is reached.");
+
+            let started = Instant::now();
+            let mut record = AccessRecord::new("socks6", source.peer_addr().ok());
+            record.close_reason = CloseReason::Refused;
+
+            // Notify source that the connection is refused.
+            let result = self.reply(source, Socks6Reply::ConnectionRefused, &mut record).await;
+            self.log_access(record, started);
+
+            result
+        }
+        .instrument(span)
+        .await
     }
 
     /// Sets up the connection to the destination.
@@ -82,40 +532,8 @@ impl SocksHandler for Socks6Handler {
         &self,
         source: &mut TcpStream,
     ) -> Result<TcpStream> {
-        // Receive SOCKS request, and allow unauthenticated access.
-        let request = socks6::read_request(source).await?;
-        socks6::write_no_authentication(source).await?;
-
-        let destination = request.destination.to_string();
-        let chain = request.chain(&self.static_links)?;
-
-        let mut destination = if let Some(mut chain) = chain {
-            if let Some(next) = chain.next_link() {
-                let next = next.clone();
-
-                let proxy_addr = format!("{}:{}", next.host, next.port);
-                let client = Socks6Client::new(proxy_addr, next.credentials).await?;
-
-                let (outgoing, _) = client.connect(destination, None, Some(chain.as_options())).await?;
-                outgoing
-            } else {
-                TcpStream::connect(destination).await?
-            }
-        } else {
-            TcpStream::connect(destination).await?
-        };
-
-        // Send initial data
-        if request.initial_data_length > 0 {
-            let mut initial_data = vec![0; request.initial_data_length as usize];
-            source.read_exact(&mut initial_data).await?;
-            destination.write(&initial_data).await?;
-        }
-
-        // Notify source that the connection has been set up.
-        socks6::write_reply(source, Socks6Reply::Success).await?;
-        source.flush().await?;
+        let mut record = AccessRecord::new("socks6", source.peer_addr().ok());
 
-        Ok(destination)
+        self.handshake(source, &mut record).await.map(|(destination, ..)| destination)
     }
 }
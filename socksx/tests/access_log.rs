@@ -0,0 +1,217 @@
+//! Collects the access records that handlers emit at the end of every session.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+
+use socksx::access_log::{AccessLogger, AccessRecord, CloseReason};
+use socksx::rate_limit::RateLimiter;
+use socksx::relay::Relay;
+use socksx::{Credentials, ProxyAddress, Socks5Client, Socks5Handler, Socks6Client, Socks6Handler};
+
+mod common;
+
+/// Keeps every record, to be inspected by the test.
+#[derive(Clone, Default)]
+struct Collector(Arc<Mutex<Vec<AccessRecord>>>);
+
+impl AccessLogger for Collector {
+    fn log(
+        &self,
+        record: &AccessRecord,
+    ) {
+        self.0.lock().unwrap().push(record.clone());
+    }
+}
+
+impl Collector {
+    /// Waits until `count` records were logged, as handlers log them after the client is done.
+    async fn wait_for(
+        &self,
+        count: usize,
+    ) -> Vec<AccessRecord> {
+        for _ in 0..50 {
+            if self.0.lock().unwrap().len() >= count {
+                break;
+            }
+
+            time::sleep(Duration::from_millis(20)).await;
+        }
+
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[tokio::test]
+async fn test_socks5_records() {
+    let collector = Collector::default();
+    let credentials = Credentials::new("alice", "secret");
+    let handler = Socks5Handler::default().with_credentials(credentials.clone()).with_access_logger(collector.clone());
+    let proxy = common::spawn_handler(handler).await;
+
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let client = Socks5Client::new(proxy.to_string(), Some(credentials)).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string()).await.unwrap();
+    common::assert_echo(&mut stream).await;
+    let local = stream.local_addr().unwrap();
+    drop(stream);
+
+    let records = collector.wait_for(1).await;
+    assert_eq!(records.len(), 1);
+
+    let record = &records[0];
+    assert_eq!(record.client, Some(local));
+    assert_eq!(record.user.as_deref(), Some("alice"));
+    assert_eq!(record.protocol, "socks5");
+    assert_eq!(record.command.as_deref(), Some("Connect"));
+    assert_eq!(record.destination, Some(echo.to_string()));
+    assert_eq!(record.resolved, Some(echo));
+    assert_eq!(record.reply.as_deref(), Some("Success"));
+    assert_eq!((record.upstream, record.downstream), (12, 12));
+    assert_eq!(record.close_reason, CloseReason::Completed);
+
+    let client = Socks5Client::new(proxy.to_string(), Some(Credentials::new("alice", "wrong"))).await.unwrap();
+    assert!(client.connect(echo.to_string()).await.is_err());
+
+    let records = collector.wait_for(2).await;
+    assert_eq!(records[1].close_reason, CloseReason::Authentication);
+    assert_eq!(records[1].user, None);
+    assert_eq!(records[1].destination, None);
+}
+
+#[tokio::test]
+async fn test_socks6_records() {
+    let collector = Collector::default();
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+
+    let far = common::spawn_handler(Socks6Handler::default()).await;
+    let link = ProxyAddress::new(6, far.ip().to_string(), far.port(), None);
+    let near = common::spawn_handler(Socks6Handler::new(vec![link]).with_access_logger(collector.clone())).await;
+
+    let client = Socks6Client::new(near.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+    common::assert_echo(&mut stream).await;
+    drop(stream);
+
+    let records = collector.wait_for(1).await;
+    assert_eq!(records.len(), 1);
+
+    let record = &records[0];
+    assert_eq!(record.protocol, "socks6");
+    assert_eq!(record.destination, Some(echo.to_string()));
+    assert_eq!(record.chain, vec![String::from("root:1080"), far.to_string()]);
+    assert_eq!(record.chain_index, Some(0));
+    assert_eq!(record.resolved, Some(far));
+    assert_eq!(record.close_reason, CloseReason::Completed);
+}
+
+#[tokio::test]
+async fn test_unreachable_link_record() {
+    let collector = Collector::default();
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+
+    let closed = common::closed_port().await;
+    let link = ProxyAddress::new(6, closed.ip().to_string(), closed.port(), None);
+    let near = common::spawn_handler(Socks6Handler::new(vec![link]).with_access_logger(collector.clone())).await;
+
+    let client = Socks6Client::new(near.to_string(), None).await.unwrap();
+    assert!(client.connect(echo.to_string(), None, None).await.is_err());
+
+    let records = collector.wait_for(1).await;
+    assert_eq!(records[0].reply.as_deref(), Some("ConnectionRefused"));
+    assert_eq!(records[0].close_reason, CloseReason::Connect);
+    assert_eq!(records[0].resolved, None);
+}
+
+/// Waits for the proxy to close `stream`, after its idle timeout.
+async fn assert_closed(stream: &mut TcpStream) {
+    let mut buffer = [0u8; 16];
+    let read = time::timeout(Duration::from_secs(2), stream.read(&mut buffer)).await.unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+}
+
+#[tokio::test]
+async fn test_idle_records() {
+    let collector = Collector::default();
+    let relay = Relay::new().with_idle_timeout(Duration::from_millis(200));
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+
+    // Without functions or rate limits, the SOCKS5 handler may relay in the kernel.
+    let handler = Socks5Handler::default().with_relay(relay.clone()).with_access_logger(collector.clone());
+    let proxy = common::spawn_handler(handler).await;
+    let client = Socks5Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string()).await.unwrap();
+    common::assert_echo(&mut stream).await;
+    assert_closed(&mut stream).await;
+
+    // A rate limit keeps the data of the SOCKS6 handler in userspace.
+    let limiter = RateLimiter::parse("global up 1G").unwrap();
+    let handler = Socks6Handler::default()
+        .with_relay(relay)
+        .with_rate_limiter(limiter)
+        .with_access_logger(collector.clone());
+    let proxy = common::spawn_handler(handler).await;
+    let client = Socks6Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+    common::assert_echo(&mut stream).await;
+    assert_closed(&mut stream).await;
+
+    let records = collector.wait_for(2).await;
+    for record in &records {
+        assert_eq!(record.close_reason, CloseReason::Idle);
+        assert_eq!((record.upstream, record.downstream), (12, 12));
+        assert!(record.duration_ms >= 200, "{}", record.duration_ms);
+    }
+}
+
+/// Spawns a server that echoes the first 12 bytes of every connection, and then resets it.
+async fn spawn_resetting_server() -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buffer = [0u8; 12];
+            stream.read_exact(&mut buffer).await.unwrap();
+            stream.write_all(&buffer).await.unwrap();
+
+            time::sleep(Duration::from_millis(50)).await;
+            socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO)).unwrap();
+        }
+    });
+
+    address
+}
+
+#[tokio::test]
+async fn test_failed_relay_records() {
+    let collector = Collector::default();
+    let destination = spawn_resetting_server().await;
+
+    // Without functions or rate limits, the SOCKS5 handler may relay in the kernel.
+    let handler = Socks5Handler::default().with_access_logger(collector.clone());
+    let proxy = common::spawn_handler(handler).await;
+    let client = Socks5Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(destination.to_string()).await.unwrap();
+    common::assert_echo(&mut stream).await;
+    assert_closed(&mut stream).await;
+
+    // A rate limit keeps the data of the SOCKS6 handler in userspace.
+    let limiter = RateLimiter::parse("global up 1G").unwrap();
+    let handler = Socks6Handler::default().with_rate_limiter(limiter).with_access_logger(collector.clone());
+    let proxy = common::spawn_handler(handler).await;
+    let client = Socks6Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(destination.to_string(), None, None).await.unwrap();
+    common::assert_echo(&mut stream).await;
+    assert_closed(&mut stream).await;
+
+    // The bytes relayed before the destination reset the connection are recorded.
+    let records = collector.wait_for(2).await;
+    assert_eq!(records.len(), 2);
+    for record in &records {
+        assert_eq!(record.close_reason, CloseReason::Relay);
+        assert_eq!((record.upstream, record.downstream), (12, 12));
+    }
+}
@@ -0,0 +1,93 @@
+//! Inspects and kills live sessions through the admin API.
+
+use std::net::SocketAddr;
+
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+
+use socksx::admin::{self, Admin};
+use socksx::sessions::SessionRegistry;
+use socksx::{Socks5Client, Socks5Handler};
+
+mod common;
+
+/// Serves the admin API for `sessions`, and returns its address.
+async fn spawn_admin(sessions: &SessionRegistry) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(admin::serve(listener, Admin::new(sessions.clone())));
+    address
+}
+
+/// Sends a request to the admin API, and returns the status line and the JSON body of the response.
+async fn request(
+    admin: SocketAddr,
+    method: &str,
+    path: &str,
+) -> (String, Value) {
+    let mut stream = TcpStream::connect(admin).await.unwrap();
+    let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.lines().next().unwrap().to_string();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+/// Lists the sessions until `ready` holds for them, as handlers update sessions asynchronously.
+async fn wait_for_sessions<F>(
+    admin: SocketAddr,
+    ready: F,
+) -> Vec<Value>
+where
+    F: Fn(&[Value]) -> bool,
+{
+    for _ in 0..50 {
+        let (_, sessions) = request(admin, "GET", "/sessions").await;
+        let sessions = sessions.as_array().unwrap().clone();
+        if ready(&sessions) {
+            return sessions;
+        }
+
+        time::sleep(Duration::from_millis(20)).await;
+    }
+
+    panic!("The sessions never became ready");
+}
+
+#[tokio::test]
+async fn test_kill_session() {
+    let sessions = SessionRegistry::new();
+    let admin = spawn_admin(&sessions).await;
+    let proxy = common::spawn_handler(Socks5Handler::default().with_sessions(sessions.clone())).await;
+
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let client = Socks5Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string()).await.unwrap();
+    common::assert_echo(&mut stream).await;
+
+    let listed = wait_for_sessions(admin, |sessions| sessions.len() == 1 && sessions[0]["downstream"] == 12).await;
+    let session = &listed[0];
+    assert_eq!(session["protocol"], "socks5");
+    assert_eq!(session["client"], stream.local_addr().unwrap().to_string());
+    assert_eq!(session["destination"], echo.to_string());
+    assert_eq!(session["upstream"], 12);
+
+    let (status, _) = request(admin, "DELETE", &format!("/sessions/{}", session["id"])).await;
+    assert!(status.contains("202"), "{}", status);
+
+    // The proxy closes the connection, without relaying anything further.
+    let mut buffer = [0u8; 16];
+    let read = time::timeout(Duration::from_secs(1), stream.read(&mut buffer)).await.unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    wait_for_sessions(admin, |sessions| sessions.is_empty()).await;
+    let (status, _) = request(admin, "DELETE", &format!("/sessions/{}", session["id"])).await;
+    assert!(status.contains("404"), "{}", status);
+}
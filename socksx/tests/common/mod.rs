@@ -0,0 +1,78 @@
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use socksx::SocksHandler;
+
+pub mod socks5;
+
+/// Spawns a server that echoes back everything it receives, and returns its address.
+pub async fn spawn_echo_server(host: &str) -> SocketAddr {
+    let listener = TcpListener::bind((host, 0)).await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await
+            });
+        }
+    });
+
+    address
+}
+
+/// Spawns a UDP server that echoes back every datagram it receives, and returns its address.
+pub async fn spawn_udp_echo_server(host: &str) -> SocketAddr {
+    let socket = UdpSocket::bind((host, 0)).await.unwrap();
+    let address = socket.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let mut datagram = vec![0u8; u16::MAX as usize];
+        loop {
+            let (length, from) = socket.recv_from(&mut datagram).await.unwrap();
+            socket.send_to(&datagram[..length], from).await.unwrap();
+        }
+    });
+
+    address
+}
+
+/// Spawns a proxy that serves every connection with `handler`, and returns its address.
+pub async fn spawn_handler<H>(handler: H) -> SocketAddr
+where
+    H: SocksHandler + Clone + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let handler = handler.clone();
+            tokio::spawn(async move { handler.accept_request(&mut stream).await });
+        }
+    });
+
+    address
+}
+
+/// Returns an address on which nothing is listening.
+pub async fn closed_port() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// Asserts that data sent over `stream` is echoed back.
+pub async fn assert_echo(stream: &mut TcpStream) {
+    stream.write_all(b"hello, world").await.unwrap();
+
+    let mut echo = [0u8; 12];
+    stream.read_exact(&mut echo).await.unwrap();
+    assert_eq!(&echo, b"hello, world");
+}
@@ -0,0 +1,344 @@
+//! A minimal reference implementation of SOCKS5 ([rfc1928]), including UDP ASSOCIATE, and username/password
+//! authentication ([rfc1929]), written directly against the RFCs.
+//!
+//! It deliberately shares no code with `socksx`, so that both sides of the
+//! conversation can be checked against an independent reading of the protocol.
+//!
+//! [rfc1928]: https://tools.ietf.org/html/rfc1928
+//! [rfc1929]: https://tools.ietf.org/html/rfc1929
+
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+pub const METHOD_NO_AUTH: u8 = 0x00;
+pub const METHOD_GSSAPI: u8 = 0x01;
+pub const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+pub const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_BIND: u8 = 0x02;
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+pub const REP_SUCCEEDED: u8 = 0x00;
+pub const REP_GENERAL_FAILURE: u8 = 0x01;
+pub const REP_CONNECTION_REFUSED: u8 = 0x05;
+pub const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// A destination as it appears on the wire.
+#[derive(Clone, Debug)]
+pub enum Target {
+    Ip(SocketAddr),
+    Domain(String, u16),
+    /// An address with an arbitrary type byte, to exercise unsupported address types.
+    Raw(u8, Vec<u8>),
+}
+
+impl Target {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Target::Ip(SocketAddr::V4(addr)) => [&[0x01][..], &addr.ip().octets(), &addr.port().to_be_bytes()].concat(),
+            Target::Ip(SocketAddr::V6(addr)) => [&[0x04][..], &addr.ip().octets(), &addr.port().to_be_bytes()].concat(),
+            Target::Domain(name, port) => [&[0x03, name.len() as u8][..], name.as_bytes(), &port.to_be_bytes()].concat(),
+            Target::Raw(atyp, bytes) => [&[*atyp][..], bytes].concat(),
+        }
+    }
+}
+
+/// Why the reference client did not get a connection.
+#[derive(Debug)]
+pub enum Failure {
+    NoAcceptableMethods,
+    UnexpectedMethod(u8),
+    AuthenticationFailed,
+    Reply(u8),
+    Io(io::Error),
+}
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        Failure::Io(error)
+    }
+}
+
+/// Connects to `target` through the proxy, proposing `methods`.
+pub async fn connect(
+    proxy: SocketAddr,
+    methods: &[u8],
+    credentials: Option<(&[u8], &[u8])>,
+    command: u8,
+    target: &Target,
+) -> Result<(TcpStream, SocketAddr), Failure> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    stream.write_all(&[&[0x05, methods.len() as u8][..], methods].concat()).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    assert_eq!(reply[0], 0x05, "method selection reply has the wrong version");
+
+    match reply[1] {
+        METHOD_NO_AUTH if methods.contains(&METHOD_NO_AUTH) => {}
+        METHOD_USERNAME_PASSWORD if methods.contains(&METHOD_USERNAME_PASSWORD) => {
+            let (username, password) = credentials.expect("username/password proposed without credentials");
+
+            let request = [
+                &[0x01, username.len() as u8][..],
+                username,
+                &[password.len() as u8][..],
+                password,
+            ]
+            .concat();
+            stream.write_all(&request).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            assert_eq!(status[0], 0x01, "authentication reply has the wrong version");
+            if status[1] != 0x00 {
+                return Err(Failure::AuthenticationFailed);
+            }
+        }
+        METHOD_NO_ACCEPTABLE => return Err(Failure::NoAcceptableMethods),
+        method => return Err(Failure::UnexpectedMethod(method)),
+    }
+
+    stream.write_all(&[&[0x05, command, 0x00][..], &target.to_bytes()].concat()).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    assert_eq!(header[0], 0x05, "reply has the wrong version");
+    let binding = read_socket_addr(&mut stream, header[3]).await?;
+
+    if header[1] != REP_SUCCEEDED {
+        return Err(Failure::Reply(header[1]));
+    }
+
+    Ok((stream, binding))
+}
+
+/// Reads the bound address of a reply; servers reply with IP addresses only.
+async fn read_socket_addr(
+    stream: &mut TcpStream,
+    atyp: u8,
+) -> io::Result<SocketAddr> {
+    let ip = match atyp {
+        0x01 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(Ipv4Addr::from(octets))
+        }
+        0x04 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(Ipv6Addr::from(octets))
+        }
+        atyp => panic!("reply has an unexpected address type: {}", atyp),
+    };
+
+    Ok(SocketAddr::new(ip, stream.read_u16().await?))
+}
+
+/// How the reference server behaves.
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    /// Requires username/password authentication with these credentials.
+    pub credentials: Option<(Vec<u8>, Vec<u8>)>,
+    /// Selects this method, regardless of what the client proposes.
+    pub method: Option<u8>,
+    /// Answers every request with this reply, instead of connecting.
+    pub reply: Option<u8>,
+    /// Accepts UDP ASSOCIATE requests, instead of replying that the command is not supported.
+    pub udp_associate: bool,
+    /// Makes the UDP relay answer every datagram itself, as if it came back from its destination.
+    pub udp_echo: bool,
+}
+
+/// Spawns the reference server and returns its address.
+pub async fn spawn_server(config: ServerConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(serve(stream, config.clone()));
+        }
+    });
+
+    address
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    config: ServerConfig,
+) -> io::Result<()> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = match (&config.method, &config.credentials) {
+        (Some(method), _) => *method,
+        (None, Some(_)) if methods.contains(&METHOD_USERNAME_PASSWORD) => METHOD_USERNAME_PASSWORD,
+        (None, None) if methods.contains(&METHOD_NO_AUTH) => METHOD_NO_AUTH,
+        _ => METHOD_NO_ACCEPTABLE,
+    };
+    stream.write_all(&[0x05, method]).await?;
+
+    match method {
+        METHOD_NO_AUTH => {}
+        METHOD_USERNAME_PASSWORD => {
+            let mut version = [0u8; 1];
+            stream.read_exact(&mut version).await?;
+            let username = read_short_string(&mut stream).await?;
+            let password = read_short_string(&mut stream).await?;
+
+            let accepted = config.credentials == Some((username, password));
+            stream.write_all(&[0x01, if accepted { 0x00 } else { 0x01 }]).await?;
+            if !accepted {
+                return Ok(());
+            }
+        }
+        _ => return Ok(()),
+    }
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+
+    let destination = match header[3] {
+        0x01 | 0x04 => vec![read_socket_addr(&mut stream, header[3]).await?],
+        0x03 => {
+            let name = String::from_utf8(read_short_string(&mut stream).await?).unwrap();
+            let port = stream.read_u16().await?;
+            tokio::net::lookup_host((name, port)).await?.collect()
+        }
+        _ => return write_reply(&mut stream, REP_ADDRESS_TYPE_NOT_SUPPORTED, None).await,
+    };
+
+    let udp_associate = header[1] == CMD_UDP_ASSOCIATE && config.udp_associate;
+    if header[1] != CMD_CONNECT && !udp_associate {
+        return write_reply(&mut stream, REP_COMMAND_NOT_SUPPORTED, None).await;
+    }
+    if let Some(reply) = config.reply {
+        return write_reply(&mut stream, reply, None).await;
+    }
+    if udp_associate {
+        return associate(stream, config.udp_echo).await;
+    }
+
+    let mut outgoing = match TcpStream::connect(&destination[..]).await {
+        Ok(outgoing) => outgoing,
+        Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+            return write_reply(&mut stream, REP_CONNECTION_REFUSED, None).await;
+        }
+        Err(_) => return write_reply(&mut stream, REP_GENERAL_FAILURE, None).await,
+    };
+
+    write_reply(&mut stream, REP_SUCCEEDED, Some(outgoing.local_addr()?)).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut outgoing).await?;
+
+    Ok(())
+}
+
+/// Relays datagrams for a UDP association, until the client closes the TCP connection.
+async fn associate(
+    mut stream: TcpStream,
+    echo: bool,
+) -> io::Result<()> {
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    write_reply(&mut stream, REP_SUCCEEDED, Some(relay.local_addr()?)).await?;
+
+    // The client did not say where it sends from, so it is whoever sends the first datagram.
+    let mut client = None;
+    let mut datagram = vec![0u8; u16::MAX as usize];
+    let mut eof = [0u8; 1];
+
+    loop {
+        let (length, from) = tokio::select! {
+            received = relay.recv_from(&mut datagram) => received?,
+            _ = stream.read(&mut eof) => return Ok(()),
+        };
+
+        if client.is_none() || client == Some(from) {
+            client = Some(from);
+
+            // Datagrams with a fragment number, or that cannot be parsed, are dropped.
+            let (target, payload) = match parse_udp_header(&datagram[..length]) {
+                Some((0x00, target, payload)) => (target, payload),
+                _ => continue,
+            };
+
+            if echo {
+                relay.send_to(&[&[0x00, 0x00, 0x00][..], &target.to_bytes(), payload].concat(), from).await?;
+            } else {
+                let destination = match target {
+                    Target::Ip(address) => address,
+                    Target::Domain(name, port) => match tokio::net::lookup_host((name, port)).await?.next() {
+                        Some(address) => address,
+                        None => continue,
+                    },
+                    Target::Raw(..) => continue,
+                };
+                relay.send_to(payload, destination).await?;
+            }
+        } else if let Some(client) = client {
+            let header = [&[0x00, 0x00, 0x00][..], &Target::Ip(from).to_bytes()].concat();
+            relay.send_to(&[&header[..], &datagram[..length]].concat(), client).await?;
+        }
+    }
+}
+
+/// Splits a relayed datagram into its fragment number, its target, and its payload.
+fn parse_udp_header(datagram: &[u8]) -> Option<(u8, Target, &[u8])> {
+    let (fragment, atyp, rest) = match datagram {
+        [0x00, 0x00, fragment, atyp, rest @ ..] => (*fragment, *atyp, rest),
+        _ => return None,
+    };
+
+    let (target, length) = match atyp {
+        0x01 if rest.len() >= 6 => {
+            let octets: [u8; 4] = rest[..4].try_into().unwrap();
+            (Target::Ip(SocketAddr::new(Ipv4Addr::from(octets).into(), port_at(rest, 4))), 6)
+        }
+        0x04 if rest.len() >= 18 => {
+            let octets: [u8; 16] = rest[..16].try_into().unwrap();
+            (Target::Ip(SocketAddr::new(Ipv6Addr::from(octets).into(), port_at(rest, 16))), 18)
+        }
+        0x03 if !rest.is_empty() && rest.len() >= rest[0] as usize + 3 => {
+            let end = 1 + rest[0] as usize;
+            let name = String::from_utf8(rest[1..end].to_vec()).ok()?;
+            (Target::Domain(name, port_at(rest, end)), end + 2)
+        }
+        _ => return None,
+    };
+
+    Some((fragment, target, &rest[length..]))
+}
+
+fn port_at(
+    bytes: &[u8],
+    offset: usize,
+) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+async fn read_short_string(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut bytes).await?;
+
+    Ok(bytes)
+}
+
+async fn write_reply(
+    stream: &mut TcpStream,
+    reply: u8,
+    binding: Option<SocketAddr>,
+) -> io::Result<()> {
+    let binding = binding.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let binding = Target::Ip(binding).to_bytes();
+
+    stream.write_all(&[&[0x05, reply, 0x00][..], &binding].concat()).await
+}
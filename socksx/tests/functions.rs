@@ -0,0 +1,290 @@
+//! Runs pipelines of functions in `Socks5Handler` and `Socks6Handler`, between a client and an echo server.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{self, Duration};
+
+use socksx::functions::chacha20::{ChaCha20Function, Role, SecretKey};
+use socksx::functions::compression::{self, Algorithm, CompressionConfig, CompressionFunction};
+use socksx::functions::counter::Counter;
+use socksx::functions::firewall::Firewall;
+use socksx::functions::{Direction, Flow, Pipeline, StreamFunction};
+use socksx::{ProxyAddress, Socks5Client, Socks5Handler, Socks6Client, Socks6Handler};
+
+mod common;
+
+/// Upper-cases upstream data, and passes downstream data on as is.
+struct UpperCase;
+
+impl StreamFunction for UpperCase {
+    fn on_chunk(
+        &mut self,
+        direction: Direction,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        match direction {
+            Direction::Upstream => output.extend(chunk.iter().map(u8::to_ascii_uppercase)),
+            Direction::Downstream => output.extend_from_slice(chunk),
+        }
+        Ok(())
+    }
+}
+
+/// A chunk of data, and the direction it was travelling in.
+type Chunk = (Direction, Vec<u8>);
+
+/// Records the data it sees, per direction.
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<Chunk>>>);
+
+impl StreamFunction for Recorder {
+    fn on_chunk(
+        &mut self,
+        direction: Direction,
+        chunk: &[u8],
+        output: &mut BytesMut,
+    ) -> io::Result<()> {
+        self.0.lock().unwrap().push((direction, chunk.to_vec()));
+        output.extend_from_slice(chunk);
+        Ok(())
+    }
+}
+
+fn pipeline(
+    recorder: &Recorder,
+    flows: &Arc<Mutex<Vec<Flow>>>,
+) -> Pipeline {
+    let recorder = recorder.clone();
+    let flows = Arc::clone(flows);
+
+    // The recorder sits on the destination side of the upper-casing.
+    Pipeline::new().with(|_: &Flow| Ok(UpperCase)).with(move |flow: &Flow| {
+        flows.lock().unwrap().push(flow.clone());
+        Ok(recorder.clone())
+    })
+}
+
+#[tokio::test]
+async fn test_socks5_pipeline() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let (recorder, flows) = (Recorder::default(), Arc::default());
+    let proxy = common::spawn_handler(Socks5Handler::default().with_functions(pipeline(&recorder, &flows))).await;
+
+    let client = Socks5Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string()).await.unwrap();
+
+    stream.write_all(b"hello, world").await.unwrap();
+    let mut echoed = [0u8; 12];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"HELLO, WORLD");
+
+    let recorded: Vec<u8> = recorder.0.lock().unwrap().iter().flat_map(|(_, chunk)| chunk.clone()).collect();
+    assert_eq!(recorded, b"HELLO, WORLDHELLO, WORLD");
+    assert!(recorder.0.lock().unwrap().iter().any(|(direction, _)| *direction == Direction::Downstream));
+
+    let flows = flows.lock().unwrap();
+    assert_eq!(flows.len(), 1);
+    assert_eq!(flows[0].source, Some(stream.local_addr().unwrap()));
+    assert_eq!(flows[0].destination, Some(echo));
+    assert_eq!(flows[0].target.as_ref().map(ToString::to_string), Some(echo.to_string()));
+    assert_eq!(flows[0].chain_index, None);
+}
+
+#[tokio::test]
+async fn test_socks6_pipeline() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let (recorder, flows) = (Recorder::default(), Arc::default());
+    let proxy = common::spawn_handler(Socks6Handler::default().with_functions(pipeline(&recorder, &flows))).await;
+
+    let client = Socks6Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string(), Some(b"early, ".to_vec()), None).await.unwrap();
+
+    // Initial data is passed on to the destination before the handler gets to the functions.
+    stream.write_all(b"late").await.unwrap();
+    let mut echoed = [0u8; 11];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"early, LATE");
+}
+
+#[tokio::test]
+async fn test_rejecting_function_closes_connection() {
+    struct Reject;
+
+    impl StreamFunction for Reject {
+        fn on_chunk(
+            &mut self,
+            _direction: Direction,
+            _chunk: &[u8],
+            _output: &mut BytesMut,
+        ) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "rejected"))
+        }
+    }
+
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let functions = Pipeline::new().with(|_: &Flow| Ok(Reject));
+    let proxy = common::spawn_handler(Socks6Handler::default().with_functions(functions)).await;
+
+    let client = Socks6Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+
+    stream.write_all(b"hello").await.unwrap();
+    let mut buffer = vec![];
+    let result = stream.read_to_end(&mut buffer).await;
+    assert!(result.is_err() || buffer.is_empty());
+}
+
+#[tokio::test]
+async fn test_chacha20_link() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let key = SecretKey::from_bytes([42; 32]);
+
+    // The far end records what it receives, before decrypting it.
+    let recorder = Recorder::default();
+    let far_key = key.clone();
+    let far_recorder = recorder.clone();
+    let far_functions = Pipeline::new()
+        .with(move |_: &Flow| Ok(far_recorder.clone()))
+        .with(move |_: &Flow| Ok(ChaCha20Function::new(Role::Decrypt, far_key.clone())));
+    let far = common::spawn_handler(Socks6Handler::default().with_functions(far_functions)).await;
+
+    let near_link = ProxyAddress::new(6, far.ip().to_string(), far.port(), None);
+    let near_functions = Pipeline::new().with(move |_: &Flow| Ok(ChaCha20Function::new(Role::Encrypt, key.clone())));
+    let near = common::spawn_handler(Socks6Handler::new(vec![near_link]).with_functions(near_functions)).await;
+
+    let client = Socks6Client::new(near.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+    common::assert_echo(&mut stream).await;
+
+    let upstream: Vec<u8> = recorder
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(direction, _)| *direction == Direction::Upstream)
+        .flat_map(|(_, chunk)| chunk.clone())
+        .collect();
+    assert!(!upstream.is_empty());
+    assert!(!upstream.windows(5).any(|window| window == b"hello"));
+}
+
+#[tokio::test]
+async fn test_chacha20_wrong_key_closes_connection() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+
+    let far_functions = Pipeline::new()
+        .with(|_: &Flow| Ok(ChaCha20Function::new(Role::Decrypt, SecretKey::from_bytes([1; 32]))));
+    let far = common::spawn_handler(Socks6Handler::default().with_functions(far_functions)).await;
+
+    let near_link = ProxyAddress::new(6, far.ip().to_string(), far.port(), None);
+    let near_functions = Pipeline::new()
+        .with(|_: &Flow| Ok(ChaCha20Function::new(Role::Encrypt, SecretKey::from_bytes([2; 32]))));
+    let near = common::spawn_handler(Socks6Handler::new(vec![near_link]).with_functions(near_functions)).await;
+
+    let client = Socks6Client::new(near.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+
+    stream.write_all(b"hello").await.unwrap();
+    let mut buffer = vec![];
+    let result = stream.read_to_end(&mut buffer).await;
+    assert!(result.is_err() || buffer.is_empty());
+}
+
+#[tokio::test]
+async fn test_compression_link() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+
+    for algorithm in [Algorithm::Deflate, Algorithm::Zstd] {
+        let config = CompressionConfig::new(algorithm);
+
+        // The far end records what it receives, before decompressing it.
+        let recorder = Recorder::default();
+        let far_recorder = recorder.clone();
+        let far_functions = Pipeline::new()
+            .with(move |_: &Flow| Ok(far_recorder.clone()))
+            .with(move |_: &Flow| Ok(CompressionFunction::new(compression::Role::Decompress, config)));
+        let far = common::spawn_handler(Socks6Handler::default().with_functions(far_functions)).await;
+
+        let near_link = ProxyAddress::new(6, far.ip().to_string(), far.port(), None);
+        let near_functions =
+            Pipeline::new().with(move |_: &Flow| Ok(CompressionFunction::new(compression::Role::Compress, config)));
+        let near = common::spawn_handler(Socks6Handler::new(vec![near_link]).with_functions(near_functions)).await;
+
+        // Every chunk is flushed, so the echo comes back without waiting for the end of the stream.
+        let client = Socks6Client::new(near.to_string(), None).await.unwrap();
+        let (mut stream, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+        common::assert_echo(&mut stream).await;
+
+        let upstream: Vec<u8> = recorder
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(direction, _)| *direction == Direction::Upstream)
+            .flat_map(|(_, chunk)| chunk.clone())
+            .collect();
+        assert!(upstream.starts_with(b"SXC"), "{}", algorithm);
+    }
+}
+
+#[tokio::test]
+async fn test_counter_chain() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+
+    let far_counter = Counter::new();
+    let far_functions = Pipeline::new().with(far_counter.clone());
+    let far = common::spawn_handler(Socks6Handler::default().with_functions(far_functions)).await;
+
+    let near_counter = Counter::new();
+    let near_link = ProxyAddress::new(6, far.ip().to_string(), far.port(), None);
+    let near_functions = Pipeline::new().with(near_counter.clone());
+    let near = common::spawn_handler(Socks6Handler::new(vec![near_link]).with_functions(near_functions)).await;
+
+    let client = Socks6Client::new(near.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+    common::assert_echo(&mut stream).await;
+    drop(stream);
+
+    // Flows are accounted for once the handlers notice that the connection closed.
+    let closed = async {
+        while near_counter.total().active > 0 || far_counter.total().active > 0 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    time::timeout(Duration::from_secs(5), closed).await.unwrap();
+
+    for (counter, chain_index) in [(near_counter, 0), (far_counter, 1)] {
+        let totals = counter.totals();
+        assert_eq!(totals.len(), 1);
+
+        let (key, totals) = totals.into_iter().next().unwrap();
+        assert_eq!(key.destination, Some(echo.to_string()));
+        assert_eq!(key.chain_index, Some(chain_index));
+        assert_eq!(totals.flows, 1);
+        assert_eq!(totals.upstream.bytes, 12);
+        assert_eq!(totals.downstream.bytes, 12);
+    }
+}
+
+#[tokio::test]
+async fn test_firewall() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+
+    // `hello, world` is allowed, while anything that starts with `SSH-` is denied.
+    let firewall: Firewall = "deny bytes 5353482d".parse().unwrap();
+    let proxy = common::spawn_handler(Socks5Handler::default().with_functions(Pipeline::new().with(firewall))).await;
+
+    let client = Socks5Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string()).await.unwrap();
+    common::assert_echo(&mut stream).await;
+
+    let (mut stream, _) = client.connect(echo.to_string()).await.unwrap();
+    stream.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").await.unwrap();
+    let mut buffer = vec![];
+    let result = stream.read_to_end(&mut buffer).await;
+    assert!(result.is_err() || buffer.is_empty());
+}
@@ -0,0 +1,154 @@
+//! Scrapes the metrics endpoint, after proxying connections through handlers that record their metrics.
+
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+
+use socksx::metrics::{self, Metrics};
+use socksx::rate_limit::RateLimiter;
+use socksx::{ProxyAddress, Socks5Client, Socks5Handler, Socks6Client, Socks6Handler};
+
+mod common;
+
+/// Serves `metrics`, and returns the address of the endpoint.
+async fn spawn_endpoint(metrics: &Metrics) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(metrics::serve(listener, metrics.clone()));
+    address
+}
+
+/// Sends a request to the endpoint, and returns the complete response.
+async fn request(
+    endpoint: SocketAddr,
+    method: &str,
+    path: &str,
+) -> String {
+    let mut stream = TcpStream::connect(endpoint).await.unwrap();
+    let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+/// Scrapes the endpoint until the metrics contain `line`, as handlers record some metrics after the client is done.
+async fn wait_for(
+    endpoint: SocketAddr,
+    line: &str,
+) -> String {
+    for _ in 0..50 {
+        let response = request(endpoint, "GET", "/metrics").await;
+        if response.lines().any(|l| l == line) {
+            return response;
+        }
+
+        time::sleep(Duration::from_millis(20)).await;
+    }
+
+    panic!("The metrics never contained {:?}", line);
+}
+
+#[tokio::test]
+async fn test_socks5_metrics() {
+    let metrics = Metrics::new();
+    let endpoint = spawn_endpoint(&metrics).await;
+
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let proxy = common::spawn_handler(Socks5Handler::default().with_metrics(metrics.clone())).await;
+    let client = Socks5Client::new(proxy.to_string(), None).await.unwrap();
+
+    let (mut stream, _) = client.connect(echo.to_string()).await.unwrap();
+    common::assert_echo(&mut stream).await;
+    drop(stream);
+
+    let closed = common::closed_port().await;
+    assert!(client.connect(closed.to_string()).await.is_err());
+
+    let response = wait_for(endpoint, "socksx_relayed_bytes_total{direction=\"downstream\"} 12").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+
+    let lines: Vec<_> = response.lines().collect();
+    for line in [
+        "socksx_connections_total 2",
+        "socksx_connections_active 0",
+        "socksx_relayed_bytes_total{direction=\"upstream\"} 12",
+        "socksx_replies_total{reply=\"Success\",version=\"5\"} 1",
+        "socksx_replies_total{reply=\"ConnectionRefused\",version=\"5\"} 1",
+        "socksx_handshake_failures_total{reason=\"connect\"} 1",
+        "socksx_handshake_duration_seconds_count 1",
+        "socksx_connect_duration_seconds_count{target=\"direct\"} 1",
+    ] {
+        assert!(lines.contains(&line), "missing {:?} in:\n{}", line, response);
+    }
+}
+
+#[tokio::test]
+async fn test_socks6_upstream_metrics() {
+    let metrics = Metrics::new();
+    let endpoint = spawn_endpoint(&metrics).await;
+
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let far = common::spawn_handler(Socks6Handler::default()).await;
+    let closed = common::closed_port().await;
+
+    for upstream in [far, closed] {
+        let link = ProxyAddress::new(6, upstream.ip().to_string(), upstream.port(), None);
+        let near = common::spawn_handler(Socks6Handler::new(vec![link]).with_metrics(metrics.clone())).await;
+
+        let client = Socks6Client::new(near.to_string(), None).await.unwrap();
+        if let Ok((mut stream, _)) = client.connect(echo.to_string(), None, None).await {
+            common::assert_echo(&mut stream).await;
+        }
+    }
+
+    let response = wait_for(endpoint, &format!("socksx_upstream_up{{upstream=\"{}\"}} 0", closed)).await;
+    let lines: Vec<_> = response.lines().collect();
+    for line in [
+        format!("socksx_upstream_up{{upstream=\"{}\"}} 1", far),
+        format!("socksx_upstream_requests_total{{result=\"success\",upstream=\"{}\"}} 1", far),
+        format!("socksx_upstream_requests_total{{result=\"failure\",upstream=\"{}\"}} 1", closed),
+        String::from("socksx_connect_duration_seconds_count{target=\"upstream\"} 1"),
+    ] {
+        assert!(lines.contains(&line.as_str()), "missing {:?} in:\n{}", line, response);
+    }
+}
+
+#[tokio::test]
+async fn test_relayed_bytes_while_open() {
+    let metrics = Metrics::new();
+    let endpoint = spawn_endpoint(&metrics).await;
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+
+    // Without functions or rate limits, the SOCKS5 handler may relay in the kernel.
+    let proxy = common::spawn_handler(Socks5Handler::default().with_metrics(metrics.clone())).await;
+    let client = Socks5Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut first, _) = client.connect(echo.to_string()).await.unwrap();
+    common::assert_echo(&mut first).await;
+
+    // A rate limit keeps the data of the SOCKS6 handler in userspace.
+    let limiter = RateLimiter::parse("global up 1G").unwrap();
+    let handler = Socks6Handler::default().with_rate_limiter(limiter).with_metrics(metrics.clone());
+    let proxy = common::spawn_handler(handler).await;
+    let client = Socks6Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut second, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+    common::assert_echo(&mut second).await;
+
+    // The bytes count while the connections are still open.
+    let response = wait_for(endpoint, "socksx_relayed_bytes_total{direction=\"upstream\"} 24").await;
+    assert!(response.lines().any(|line| line == "socksx_relayed_bytes_total{direction=\"downstream\"} 24"));
+    assert!(response.lines().any(|line| line == "socksx_connections_active 2"));
+}
+
+#[tokio::test]
+async fn test_endpoint() {
+    let endpoint = spawn_endpoint(&Metrics::new()).await;
+
+    assert!(request(endpoint, "GET", "/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(request(endpoint, "POST", "/metrics").await.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+}
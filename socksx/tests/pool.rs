@@ -0,0 +1,44 @@
+//! Sends requests through SOCKS6 chains, whose first hop takes its connections to the next one from a pool.
+
+use tokio::time::{self, Duration};
+
+use socksx::metrics::Metrics;
+use socksx::pool::UpstreamPool;
+use socksx::{ProxyAddress, Socks6Client, Socks6Handler};
+
+mod common;
+
+#[tokio::test]
+async fn test_pooled_next_hop() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let far = common::spawn_handler(Socks6Handler::default()).await;
+
+    let pool = UpstreamPool::new(2);
+    pool.prewarm(far);
+    for _ in 0..50 {
+        if pool.idle(far) == 2 {
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(pool.idle(far), 2);
+
+    let metrics = Metrics::new();
+    let link = ProxyAddress::new(6, far.ip().to_string(), far.port(), None);
+    let near = Socks6Handler::new(vec![link]).with_pool(pool.clone()).with_metrics(metrics.clone());
+    let near = common::spawn_handler(near).await;
+
+    let client = Socks6Client::new(near.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+    common::assert_echo(&mut stream).await;
+
+    let text = metrics.encode();
+    let pooled = format!("socksx_upstream_pool_checkouts_total{{source=\"pooled\",upstream=\"{}\"}} 1", far);
+    assert!(text.contains(&pooled), "{}", text);
+
+    // Requests keep going through the chain, as the pool is refilled.
+    for _ in 0..4 {
+        let (mut stream, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+        common::assert_echo(&mut stream).await;
+    }
+}
@@ -0,0 +1,59 @@
+//! Opens more connections through handlers than the quotas of their clients allow.
+
+use tokio::time::{self, Duration};
+
+use socksx::metrics::Metrics;
+use socksx::quotas::{Quota, Quotas};
+use socksx::{Socks5Client, Socks5Handler, Socks6Client, Socks6Handler};
+
+mod common;
+
+fn max_connections(max: usize) -> Quota {
+    Quota {
+        max_connections: Some(max),
+        connection_rate: None,
+    }
+}
+
+#[tokio::test]
+async fn test_refuse_over_quota() {
+    let metrics = Metrics::new();
+    let quotas = Quotas::new().per_client(max_connections(1));
+    let handler = Socks5Handler::default().with_quotas(quotas).with_metrics(metrics.clone());
+    let proxy = common::spawn_handler(handler).await;
+
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let client = Socks5Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo.to_string()).await.unwrap();
+    common::assert_echo(&mut stream).await;
+
+    assert!(client.connect(echo.to_string()).await.is_err());
+
+    let text = metrics.encode();
+    assert!(text.contains("socksx_replies_total{reply=\"ConnectionNotAllowed\",version=\"5\"} 1"));
+    assert!(text.contains("socksx_quota_rejections_total{limit=\"connections\",scope=\"client\"} 1"));
+    assert!(text.contains("socksx_handshake_failures_total{reason=\"quota\"} 1"));
+}
+
+#[tokio::test]
+async fn test_wait_for_quota() {
+    let metrics = Metrics::new();
+    let quotas = Quotas::new().per_client(max_connections(1)).with_wait(Duration::from_secs(5));
+    let handler = Socks6Handler::default().with_quotas(quotas).with_metrics(metrics.clone());
+    let proxy = common::spawn_handler(handler).await;
+
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let client = Socks6Client::new(proxy.to_string(), None).await.unwrap();
+    let (first, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+
+    // The second request is queued until the first connection closes.
+    tokio::spawn(async move {
+        time::sleep(Duration::from_millis(100)).await;
+        drop(first);
+    });
+    let (mut second, _) = client.connect(echo.to_string(), None, None).await.unwrap();
+    common::assert_echo(&mut second).await;
+
+    let text = metrics.encode();
+    assert!(text.contains("socksx_quota_wait_duration_seconds_count 1"), "{}", text);
+}
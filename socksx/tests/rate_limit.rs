@@ -0,0 +1,57 @@
+//! Relays data through handlers whose connections are rate limited.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+
+use socksx::rate_limit::RateLimiter;
+use socksx::{Credentials, Socks5Client, Socks5Handler, Socks6Client, Socks6Handler};
+
+mod common;
+
+/// Sends `length` bytes over `stream`, waits until they are echoed back, and returns how long that took.
+async fn echo(
+    stream: &mut TcpStream,
+    length: usize,
+) -> Duration {
+    let started = Instant::now();
+
+    stream.write_all(&vec![7; length]).await.unwrap();
+    let mut echo = vec![0; length];
+    stream.read_exact(&mut echo).await.unwrap();
+    assert!(echo.iter().all(|byte| *byte == 7));
+
+    started.elapsed()
+}
+
+#[tokio::test]
+async fn test_user_limit_is_shared() {
+    let credentials = Credentials::new("alice", "secret");
+    let limiter = RateLimiter::parse("user:* up 16K").unwrap();
+    let handler = Socks5Handler::default().with_credentials(credentials.clone()).with_rate_limiter(limiter);
+    let proxy = common::spawn_handler(handler).await;
+
+    let echo_server = common::spawn_echo_server("127.0.0.1").await;
+    let client = Socks5Client::new(proxy.to_string(), Some(credentials)).await.unwrap();
+    let (mut first, _) = client.connect(echo_server.to_string()).await.unwrap();
+    let (mut second, _) = client.connect(echo_server.to_string()).await.unwrap();
+
+    // The first connection uses up the burst, so the second has to wait for the bucket they share to refill.
+    assert!(echo(&mut first, 16 * 1024).await < Duration::from_millis(400));
+    let elapsed = echo(&mut second, 16 * 1024).await;
+    assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn test_download_limit() {
+    let limiter = RateLimiter::parse("global down 8K").unwrap();
+    let proxy = common::spawn_handler(Socks6Handler::default().with_rate_limiter(limiter)).await;
+
+    let echo_server = common::spawn_echo_server("127.0.0.1").await;
+    let client = Socks6Client::new(proxy.to_string(), None).await.unwrap();
+    let (mut stream, _) = client.connect(echo_server.to_string(), None, None).await.unwrap();
+
+    // After the burst, the proxy waits before it writes more to the client.
+    let elapsed = echo(&mut stream, 24 * 1024).await;
+    assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+}
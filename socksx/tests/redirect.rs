@@ -0,0 +1,156 @@
+//! Forwards connections with a `Redirector`, as the `socksx redirect` mode does once it has
+//! recovered their original destination, and datagrams with a `UdpRedirector`.
+
+use std::net::SocketAddr;
+
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use socksx::redirect::{RedirectMode, Redirector, UdpRedirector};
+use socksx::{ProxyAddress, Socks5Handler, Socks6Handler};
+
+use common::socks5::{self as reference, ServerConfig};
+
+mod common;
+
+fn upstream(
+    version: u8,
+    address: SocketAddr,
+) -> ProxyAddress {
+    ProxyAddress::new(version, address.ip().to_string(), address.port(), None)
+}
+
+/// Forwards a new connection to `destination`, and returns the other end of it.
+async fn forward(
+    redirector: Redirector,
+    destination: SocketAddr,
+) -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (incoming, _) = listener.accept().await.unwrap();
+
+    tokio::spawn(async move { redirector.forward(incoming, destination).await });
+
+    stream
+}
+
+#[tokio::test]
+async fn test_forward_socks5() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let proxy = common::spawn_handler(Socks5Handler::default()).await;
+
+    let redirector = Redirector::new(upstream(5, proxy), vec![], RedirectMode::Nat).await.unwrap();
+    common::assert_echo(&mut forward(redirector, echo).await).await;
+}
+
+#[tokio::test]
+async fn test_forward_socks6_chain() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let first = common::spawn_handler(Socks6Handler::default()).await;
+    let second = common::spawn_handler(Socks6Handler::default()).await;
+
+    let chain = vec![upstream(6, second)];
+    let redirector = Redirector::new(upstream(6, first), chain, RedirectMode::Tproxy).await.unwrap();
+    common::assert_echo(&mut forward(redirector, echo).await).await;
+}
+
+#[tokio::test]
+async fn test_forward_excluded() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let closed = common::closed_port().await;
+
+    // Connections to excluded destinations never reach the (unreachable) upstream.
+    let redirector = Redirector::new(upstream(6, closed), vec![], RedirectMode::Nat)
+        .await
+        .unwrap()
+        .with_exclusions(vec!["127.0.0.0/8".parse().unwrap()]);
+    common::assert_echo(&mut forward(redirector, echo).await).await;
+}
+
+#[tokio::test]
+async fn test_forward_upstream_unreachable() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let closed = common::closed_port().await;
+
+    let redirector = Redirector::new(upstream(6, closed), vec![], RedirectMode::Nat).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let _stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (incoming, _) = listener.accept().await.unwrap();
+
+    assert!(redirector.forward(incoming, echo).await.is_err());
+}
+
+// Transparent sockets require `CAP_NET_ADMIN`, which test environments do not always grant. The CI runs these tests
+// separately, as root, with `--ignored`.
+#[tokio::test]
+#[ignore = "requires CAP_NET_ADMIN"]
+async fn test_tproxy_original_dst() {
+    let closed = common::closed_port().await;
+    let redirector = Redirector::new(upstream(6, closed), vec![], RedirectMode::Tproxy).await.unwrap();
+    let listener = redirector.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+    let address = listener.local_addr().unwrap();
+    let _stream = TcpStream::connect(address).await.unwrap();
+    let (incoming, _) = listener.accept().await.unwrap();
+
+    assert_eq!(redirector.original_dst(&incoming).unwrap(), address);
+}
+
+#[tokio::test]
+#[ignore = "requires CAP_NET_ADMIN"]
+async fn test_udp_redirect() {
+    // The relay answers on behalf of every destination, so that replies can be spoofed from any of them.
+    let proxy = reference::spawn_server(ServerConfig {
+        udp_associate: true,
+        udp_echo: true,
+        ..Default::default()
+    })
+    .await;
+
+    let redirector = UdpRedirector::bind(upstream(5, proxy), "127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let redirector = redirector.with_idle_timeout(Duration::from_secs(5));
+
+    // Without a TPROXY rule, the original destination of a datagram is the redirector itself.
+    let address = redirector.local_addr().unwrap();
+    tokio::spawn(async move { redirector.run().await });
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for payload in [&b"first"[..], &b"second"[..]] {
+        client.send_to(payload, address).await.unwrap();
+
+        let mut reply = [0u8; 16];
+        let (length, from) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(&reply[..length], payload);
+        assert_eq!(from, address);
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires CAP_NET_ADMIN"]
+async fn test_udp_exclusions() {
+    let proxy = reference::spawn_server(ServerConfig {
+        udp_associate: true,
+        ..Default::default()
+    })
+    .await;
+
+    let redirector = UdpRedirector::bind(upstream(5, proxy), "127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let redirector = redirector.with_exclusions(vec!["10.0.0.0/8".parse().unwrap()]);
+
+    assert!(redirector.is_excluded(&proxy));
+    assert!(redirector.is_excluded(&"10.1.2.3:53".parse().unwrap()));
+    assert!(!redirector.is_excluded(&"11.1.2.3:53".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn test_udp_redirect_requires_socks5() {
+    let closed = common::closed_port().await;
+
+    let result = UdpRedirector::bind(upstream(6, closed), "127.0.0.1:0".parse().unwrap()).await;
+    assert!(result.is_err());
+}
@@ -0,0 +1,268 @@
+//! Checks that `Socks5Client` and `Socks5Handler` interoperate with an independent
+//! SOCKS5 implementation, by running every combination of clients and servers.
+
+use std::net::SocketAddr;
+
+use socksx::{Address, Credentials, Socks5Client, Socks5Handler};
+
+use common::socks5::{self as reference, Failure, ServerConfig, Target};
+
+mod common;
+
+#[derive(Clone, Copy, Debug)]
+enum Client {
+    Socksx,
+    Reference,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Server {
+    Socksx,
+    Reference,
+}
+
+const CLIENTS: [Client; 2] = [Client::Socksx, Client::Reference];
+const SERVERS: [Server; 2] = [Server::Socksx, Server::Reference];
+
+/// The result of a connection attempt, in terms both clients can report.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Connected,
+    NoAcceptableMethods,
+    UnexpectedMethod(u8),
+    AuthenticationFailed,
+    Reply(u8),
+}
+
+async fn spawn_server(
+    server: Server,
+    credentials: Option<Credentials>,
+) -> SocketAddr {
+    match server {
+        Server::Socksx => {
+            let mut handler = Socks5Handler::default();
+            if let Some(credentials) = credentials {
+                handler = handler.with_credentials(credentials);
+            }
+
+            common::spawn_handler(handler).await
+        }
+        Server::Reference => {
+            let credentials = credentials.map(|c| (c.username, c.password));
+            reference::spawn_server(ServerConfig {
+                credentials,
+                ..Default::default()
+            })
+            .await
+        }
+    }
+}
+
+async fn connect(
+    client: Client,
+    proxy: SocketAddr,
+    credentials: Option<Credentials>,
+    destination: Address,
+) -> Outcome {
+    match client {
+        Client::Socksx => {
+            let client = Socks5Client::new(proxy.to_string(), credentials).await.unwrap();
+            match client.connect(destination.to_string()).await {
+                Ok((mut stream, _)) => {
+                    common::assert_echo(&mut stream).await;
+                    Outcome::Connected
+                }
+                Err(error) => socksx_failure(&error.to_string()),
+            }
+        }
+        Client::Reference => {
+            let target = match destination {
+                Address::Ip(address) => Target::Ip(address),
+                Address::Domainname { host, port } => Target::Domain(host, port),
+            };
+
+            let methods = match credentials {
+                Some(_) => vec![reference::METHOD_NO_AUTH, reference::METHOD_USERNAME_PASSWORD],
+                None => vec![reference::METHOD_NO_AUTH],
+            };
+            let credentials = credentials.as_ref().map(|c| (&c.username[..], &c.password[..]));
+
+            let result = reference::connect(proxy, &methods, credentials, reference::CMD_CONNECT, &target).await;
+            match result {
+                Ok((mut stream, _)) => {
+                    common::assert_echo(&mut stream).await;
+                    Outcome::Connected
+                }
+                Err(failure) => reference_failure(failure),
+            }
+        }
+    }
+}
+
+fn socksx_failure(message: &str) -> Outcome {
+    if let Some(reply) = message.strip_prefix("CONNECT operation failed: ") {
+        Outcome::Reply(reply.parse().unwrap())
+    } else if let Some(method) = message.strip_prefix("Proxy proposed unsupported authentication method: ") {
+        Outcome::UnexpectedMethod(method.trim_end_matches('.').parse().unwrap())
+    } else if message == "Proxy did not accept authentication method." {
+        Outcome::NoAcceptableMethods
+    } else if message == "Authentication with the provided credentials failed." {
+        Outcome::AuthenticationFailed
+    } else {
+        panic!("unexpected error: {}", message)
+    }
+}
+
+fn reference_failure(failure: Failure) -> Outcome {
+    match failure {
+        Failure::NoAcceptableMethods => Outcome::NoAcceptableMethods,
+        Failure::UnexpectedMethod(method) => Outcome::UnexpectedMethod(method),
+        Failure::AuthenticationFailed => Outcome::AuthenticationFailed,
+        Failure::Reply(reply) => Outcome::Reply(reply),
+        Failure::Io(error) => panic!("unexpected error: {}", error),
+    }
+}
+
+#[tokio::test]
+async fn test_connect_all_address_types() {
+    let ipv4 = common::spawn_echo_server("127.0.0.1").await;
+    let ipv6 = common::spawn_echo_server("::1").await;
+    let domain = Address::new("localhost", ipv4.port());
+
+    for server in SERVERS {
+        let proxy = spawn_server(server, None).await;
+
+        for client in CLIENTS {
+            for destination in [Address::Ip(ipv4), Address::Ip(ipv6), domain.clone()] {
+                let outcome = connect(client, proxy, None, destination.clone()).await;
+                assert_eq!(outcome, Outcome::Connected, "{:?} -> {:?} -> {}", client, server, destination);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_username_password() {
+    let echo = Address::Ip(common::spawn_echo_server("127.0.0.1").await);
+    let credentials = Credentials::new("username", "password");
+    let longest = Credentials::new(vec![b'u'; 255], vec![b'p'; 255]);
+
+    let cases = [
+        (credentials.clone(), Some(credentials.clone()), Outcome::Connected),
+        (longest.clone(), Some(longest), Outcome::Connected),
+        (credentials.clone(), Some(Credentials::new("username", "wrong")), Outcome::AuthenticationFailed),
+        (credentials.clone(), Some(Credentials::new("", "")), Outcome::AuthenticationFailed),
+        (credentials, None, Outcome::NoAcceptableMethods),
+    ];
+
+    for server in SERVERS {
+        for (expected, offered, outcome) in &cases {
+            let proxy = spawn_server(server, Some(expected.clone())).await;
+
+            for client in CLIENTS {
+                let actual = connect(client, proxy, offered.clone(), echo.clone()).await;
+                assert_eq!(&actual, outcome, "{:?} -> {:?} offering {:?}", client, server, offered);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_gssapi_is_not_acceptable() {
+    let echo = Target::Ip(common::spawn_echo_server("127.0.0.1").await);
+
+    for server in SERVERS {
+        let proxy = spawn_server(server, None).await;
+        let methods = [reference::METHOD_GSSAPI];
+
+        let result = reference::connect(proxy, &methods, None, reference::CMD_CONNECT, &echo).await;
+        assert!(matches!(result, Err(Failure::NoAcceptableMethods)), "{:?}", server);
+    }
+}
+
+#[tokio::test]
+async fn test_unexpected_method_is_rejected() {
+    let echo = Address::Ip(common::spawn_echo_server("127.0.0.1").await);
+    let proxy = reference::spawn_server(ServerConfig {
+        method: Some(reference::METHOD_GSSAPI),
+        ..Default::default()
+    })
+    .await;
+
+    for client in CLIENTS {
+        let outcome = connect(client, proxy, None, echo.clone()).await;
+        assert_eq!(outcome, Outcome::UnexpectedMethod(reference::METHOD_GSSAPI), "{:?}", client);
+    }
+}
+
+#[tokio::test]
+async fn test_reply_codes_are_reported() {
+    let echo = Address::Ip(common::spawn_echo_server("127.0.0.1").await);
+
+    for reply in 0x01..=0x08 {
+        let proxy = reference::spawn_server(ServerConfig {
+            reply: Some(reply),
+            ..Default::default()
+        })
+        .await;
+
+        for client in CLIENTS {
+            let outcome = connect(client, proxy, None, echo.clone()).await;
+            assert_eq!(outcome, Outcome::Reply(reply), "{:?}", client);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_connection_refused() {
+    let closed = Address::Ip(common::closed_port().await);
+
+    for server in SERVERS {
+        let proxy = spawn_server(server, None).await;
+
+        for client in CLIENTS {
+            let outcome = connect(client, proxy, None, closed.clone()).await;
+            assert_eq!(outcome, Outcome::Reply(reference::REP_CONNECTION_REFUSED), "{:?} -> {:?}", client, server);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_unsupported_commands() {
+    let echo = Target::Ip(common::spawn_echo_server("127.0.0.1").await);
+
+    for server in SERVERS {
+        let proxy = spawn_server(server, None).await;
+
+        for command in [reference::CMD_BIND, reference::CMD_UDP_ASSOCIATE] {
+            let methods = [reference::METHOD_NO_AUTH];
+            let result = reference::connect(proxy, &methods, None, command, &echo).await;
+
+            assert!(
+                matches!(result, Err(Failure::Reply(reference::REP_COMMAND_NOT_SUPPORTED))),
+                "{:?} with command {}: {:?}",
+                server,
+                command,
+                result.map(|(_, binding)| binding)
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_unsupported_address_type() {
+    let target = Target::Raw(0x05, vec![127, 0, 0, 1, 0, 80]);
+
+    for server in SERVERS {
+        let proxy = spawn_server(server, None).await;
+        let methods = [reference::METHOD_NO_AUTH];
+        let result = reference::connect(proxy, &methods, None, reference::CMD_CONNECT, &target).await;
+
+        assert!(
+            matches!(result, Err(Failure::Reply(reference::REP_ADDRESS_TYPE_NOT_SUPPORTED))),
+            "{:?}: {:?}",
+            server,
+            result.map(|(_, binding)| binding)
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! Relays datagrams through SOCKS5 UDP associations set up by `Socks5Client`.
+
+use std::net::SocketAddr;
+
+use socksx::{Address, Credentials, Socks5Client, Socks5Handler, Socks5UdpSession};
+
+use common::socks5::{self as reference, ServerConfig};
+
+mod common;
+
+async fn spawn_relay(credentials: Option<Credentials>) -> SocketAddr {
+    reference::spawn_server(ServerConfig {
+        credentials: credentials.map(|c| (c.username, c.password)),
+        udp_associate: true,
+        ..Default::default()
+    })
+    .await
+}
+
+/// Asserts that a datagram sent to `destination` is echoed back, from `destination`.
+async fn assert_udp_echo(
+    session: &Socks5UdpSession,
+    destination: Address,
+    expected_source: Address,
+) {
+    session.send_to(b"hello, world", destination.to_string()).await.unwrap();
+
+    let mut echo = [0u8; 32];
+    let (length, source) = session.recv_from(&mut echo).await.unwrap();
+    assert_eq!(&echo[..length], b"hello, world");
+    assert_eq!(source, expected_source);
+}
+
+#[tokio::test]
+async fn test_udp_associate() {
+    let echo = common::spawn_udp_echo_server("127.0.0.1").await;
+    let proxy = spawn_relay(None).await;
+
+    let client = Socks5Client::new(proxy.to_string(), None).await.unwrap();
+    let session = client.udp_associate().await.unwrap();
+    assert_eq!(session.relay_addr().ip(), proxy.ip());
+
+    assert_udp_echo(&session, Address::Ip(echo), Address::Ip(echo)).await;
+    // Domain names are resolved by the proxy, and replies carry the address they came from.
+    assert_udp_echo(&session, Address::new("localhost", echo.port()), Address::Ip(echo)).await;
+}
+
+#[tokio::test]
+async fn test_udp_associate_with_credentials() {
+    let echo = common::spawn_udp_echo_server("127.0.0.1").await;
+    let credentials = Credentials::new("username", "password");
+    let proxy = spawn_relay(Some(credentials.clone())).await;
+
+    let client = Socks5Client::new(proxy.to_string(), Some(credentials)).await.unwrap();
+    let session = client.udp_associate().await.unwrap();
+
+    assert_udp_echo(&session, Address::Ip(echo), Address::Ip(echo)).await;
+}
+
+#[tokio::test]
+async fn test_udp_associate_not_supported() {
+    let reference = reference::spawn_server(ServerConfig::default()).await;
+    let handler = common::spawn_handler(Socks5Handler::default()).await;
+
+    for proxy in [reference, handler] {
+        let client = Socks5Client::new(proxy.to_string(), None).await.unwrap();
+        let error = client.udp_associate().await.err().unwrap();
+
+        assert_eq!(error.to_string(), "CONNECT operation failed: 7");
+    }
+}
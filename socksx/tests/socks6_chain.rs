@@ -0,0 +1,306 @@
+//! Runs SOCKS6 chains of in-process `Socks6Handler`s, and checks the route that requests
+//! take through them, along with the chain metadata that every hop receives.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use socksx::codec::Decode;
+use socksx::socks6::{Socks6Request, SocksChain};
+use socksx::{ProxyAddress, Socks6Client, Socks6Handler, SocksHandler};
+
+mod common;
+
+/// What a proxy observed of a request that passed through it.
+#[derive(Debug, PartialEq)]
+struct Hop {
+    proxy: &'static str,
+    /// The chain index and links (by proxy name) in the metadata of the request.
+    chain: Option<(usize, Vec<&'static str>)>,
+}
+
+impl Hop {
+    fn new(proxy: &'static str) -> Self {
+        Self { proxy, chain: None }
+    }
+
+    fn with_chain(
+        proxy: &'static str,
+        index: usize,
+        links: &[&'static str],
+    ) -> Self {
+        Self {
+            proxy,
+            chain: Some((index, links.to_vec())),
+        }
+    }
+}
+
+/// A set of named proxies on ephemeral localhost ports, which record every request they receive.
+struct Topology {
+    proxies: Vec<(&'static str, SocketAddr)>,
+    listeners: Vec<Option<TcpListener>>,
+    hops: Arc<Mutex<Vec<Hop>>>,
+}
+
+impl Topology {
+    /// Binds a listener for every proxy, so that all addresses are known before any handler is created.
+    async fn new(names: &[&'static str]) -> Self {
+        let mut proxies = vec![];
+        let mut listeners = vec![];
+        for name in names {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            proxies.push((*name, listener.local_addr().unwrap()));
+            listeners.push(Some(listener));
+        }
+
+        Self {
+            proxies,
+            listeners,
+            hops: Arc::default(),
+        }
+    }
+
+    fn address(
+        &self,
+        name: &str,
+    ) -> SocketAddr {
+        self.proxies.iter().find(|(n, _)| *n == name).unwrap().1
+    }
+
+    fn link(
+        &self,
+        name: &str,
+    ) -> ProxyAddress {
+        let address = self.address(name);
+        ProxyAddress::new(6, address.ip().to_string(), address.port(), None)
+    }
+
+    fn chain(
+        &self,
+        index: usize,
+        names: &[&str],
+    ) -> SocksChain {
+        SocksChain::new(index, names.iter().map(|n| self.link(n)).collect())
+    }
+
+    /// Starts serving a proxy with a `Socks6Handler` that detours through `static_links`.
+    fn start(
+        &mut self,
+        name: &'static str,
+        static_links: &[&str],
+    ) {
+        let position = self.proxies.iter().position(|(n, _)| *n == name).unwrap();
+        let listener = self.listeners[position].take().expect("proxy already started");
+        let handler = Socks6Handler::new(static_links.iter().map(|n| self.link(n)).collect());
+
+        let proxies = self.proxies.clone();
+        let hops = Arc::clone(&self.hops);
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = peek_request(&stream).await;
+
+                let chain = request.chain(&[]).unwrap().map(|chain| {
+                    let links = chain.links.iter().map(|link| name_of(&proxies, link)).collect();
+                    (chain.index, links)
+                });
+                hops.lock().unwrap().push(Hop { proxy: name, chain });
+
+                let handler = handler.clone();
+                tokio::spawn(async move { handler.accept_request(&mut stream).await });
+            }
+        });
+    }
+
+    /// Starts serving all proxies that have not been started yet, without static links.
+    fn start_remaining(&mut self) {
+        let remaining: Vec<_> = self
+            .proxies
+            .iter()
+            .zip(&self.listeners)
+            .filter(|(_, listener)| listener.is_some())
+            .map(|((name, _), _)| *name)
+            .collect();
+
+        for name in remaining {
+            self.start(name, &[]);
+        }
+    }
+
+    fn hops(&self) -> Vec<Hop> {
+        std::mem::take(&mut self.hops.lock().unwrap())
+    }
+}
+
+/// Maps a link back to the name of the proxy it points to.
+fn name_of(
+    proxies: &[(&'static str, SocketAddr)],
+    link: &ProxyAddress,
+) -> &'static str {
+    if link == &ProxyAddress::root() {
+        return "root";
+    }
+
+    proxies
+        .iter()
+        .find(|(_, address)| address.port() == link.port)
+        .map(|(name, _)| *name)
+        .unwrap_or("unknown")
+}
+
+/// Decodes the request at the start of the stream, without consuming it.
+async fn peek_request(stream: &TcpStream) -> Socks6Request {
+    let mut buffer = vec![0; u16::MAX as usize];
+    loop {
+        let length = stream.peek(&mut buffer).await.unwrap();
+        if let Some(request) = Socks6Request::decode(&mut BytesMut::from(&buffer[..length])).unwrap() {
+            return request;
+        }
+
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+/// Connects to the echo server through `entry`, and checks that traffic makes it there and back.
+async fn connect(
+    entry: SocketAddr,
+    destination: SocketAddr,
+    chain: Option<SocksChain>,
+) {
+    let client = Socks6Client::new(entry.to_string(), None).await.unwrap();
+    let options = chain.map(|chain| chain.as_options());
+
+    let (mut stream, _) = client.connect(destination.to_string(), None, options).await.unwrap();
+    common::assert_echo(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_without_chain() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let mut topology = Topology::new(&["a"]).await;
+    topology.start_remaining();
+
+    connect(topology.address("a"), echo, None).await;
+    assert_eq!(topology.hops(), vec![Hop::new("a")]);
+}
+
+#[tokio::test]
+async fn test_static_chain() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let mut topology = Topology::new(&["a", "b", "c"]).await;
+    topology.start("a", &["b", "c"]);
+    topology.start_remaining();
+
+    connect(topology.address("a"), echo, None).await;
+
+    // The first proxy takes the place of the root, as the client did not send a chain.
+    let links = ["root", "b", "c"];
+    assert_eq!(
+        topology.hops(),
+        vec![Hop::new("a"), Hop::with_chain("b", 1, &links), Hop::with_chain("c", 2, &links)]
+    );
+}
+
+#[tokio::test]
+async fn test_dynamic_chain() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let mut topology = Topology::new(&["a", "b", "c"]).await;
+    topology.start_remaining();
+
+    let chain = topology.chain(0, &["a", "b", "c"]);
+    connect(topology.address("a"), echo, Some(chain)).await;
+
+    let links = ["a", "b", "c"];
+    assert_eq!(
+        topology.hops(),
+        vec![
+            Hop::with_chain("a", 0, &links),
+            Hop::with_chain("b", 1, &links),
+            Hop::with_chain("c", 2, &links),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_dynamic_chain_with_detour() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let mut topology = Topology::new(&["a", "b", "c", "d", "e"]).await;
+    topology.start("b", &["d", "e"]);
+    topology.start_remaining();
+
+    let chain = topology.chain(0, &["a", "b", "c"]);
+    connect(topology.address("a"), echo, Some(chain)).await;
+
+    // The detour is spliced in right after the proxy that takes it, and the rest of the chain is kept.
+    let before = ["a", "b", "c"];
+    let after = ["a", "b", "d", "e", "c"];
+    assert_eq!(
+        topology.hops(),
+        vec![
+            Hop::with_chain("a", 0, &before),
+            Hop::with_chain("b", 1, &before),
+            Hop::with_chain("d", 2, &after),
+            Hop::with_chain("e", 3, &after),
+            Hop::with_chain("c", 4, &after),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_nested_detours() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let mut topology = Topology::new(&["a", "b", "c", "d"]).await;
+    topology.start("a", &["b"]);
+    topology.start("b", &["c"]);
+    topology.start_remaining();
+
+    let chain = topology.chain(0, &["a", "d"]);
+    connect(topology.address("a"), echo, Some(chain)).await;
+
+    let proxies: Vec<_> = topology.hops().iter().map(|hop| hop.proxy).collect();
+    assert_eq!(proxies, vec!["a", "b", "c", "d"]);
+}
+
+#[tokio::test]
+async fn test_chain_with_initial_data() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let mut topology = Topology::new(&["a", "b"]).await;
+    topology.start_remaining();
+
+    let client = Socks6Client::new(topology.address("a").to_string(), None).await.unwrap();
+    let options = topology.chain(0, &["a", "b"]).as_options();
+    let (mut stream, _) = client
+        .connect(echo.to_string(), Some(b"early".to_vec()), Some(options))
+        .await
+        .unwrap();
+
+    stream.write_all(b" and late").await.unwrap();
+    let mut echoed = [0u8; 14];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"early and late");
+}
+
+#[tokio::test]
+async fn test_unreachable_link() {
+    let echo = common::spawn_echo_server("127.0.0.1").await;
+    let mut topology = Topology::new(&["a"]).await;
+    topology.start_remaining();
+
+    let closed = common::closed_port().await;
+    let mut chain = topology.chain(0, &["a"]);
+    chain.links.push(ProxyAddress::new(6, closed.ip().to_string(), closed.port(), None));
+
+    let client = Socks6Client::new(topology.address("a").to_string(), None).await.unwrap();
+    let error = client
+        .connect(echo.to_string(), None, Some(chain.as_options()))
+        .await
+        .unwrap_err();
+
+    assert_eq!(error.to_string(), "CONNECT operation failed: ConnectionRefused");
+}